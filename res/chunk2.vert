@@ -92,6 +92,9 @@ void init_vert_data(){
     // if the direction is positive add 1 to the axis of it 
     vpos[direction >> 1] += (1 - (direction & 1));
 
+    // lod meshes are built from cells of 2^lod voxels
+    vpos *= float(1 << ((data_1 >> 8) & 3));
+
     vec3 normal_table[6] = {
         vec3( 1.0, 0.0, 0.0),
         vec3(-1.0, 0.0, 0.0),
@@ -5,10 +5,132 @@ layout(location = 0) in vec2 screen_pos;
 layout(location = 0) out vec4 color;
 
 layout(set = 0,binding = 0) uniform sampler2D albedo_spec;
+layout(set = 0,binding = 1) uniform sampler2D normal_tex;
+layout(set = 0,binding = 2) uniform sampler2D depth_tex;
 
+//matches GpuLightingData in src/render/lighting.rs
+layout(set = 1,binding = 0) uniform LightingData{
+    mat4 inv_proj_view;
+    vec4 camera_pos;
+    vec4 sun_direction;
+    vec4 sun_color;
+    vec4 sky_ambient;
+    vec4 ground_ambient;
+    vec4 sky_zenith;
+    vec4 sky_horizon;
+    vec4 fog; //mode (0 off,1 linear,2 exponential),start,end,density
+    uvec4 point_light_count; //point lights,shadow cascades
+    mat4 shadow_matrices[4];
+    vec4 cascade_splits;
+};
 
+struct PointLight{
+    vec4 position_radius;
+    vec4 color_intensity;
+};
+
+layout(set = 2,binding = 0) uniform sampler2D shadow_map;
+
+layout(std430,set = 1,binding = 1) readonly buffer PointLights{
+    PointLight point_lights[];
+};
+
+vec3 world_pos_from_depth(vec2 ndc,float depth){
+    vec4 pos = inv_proj_view * vec4(ndc,depth,1.0);
+    return pos.xyz / pos.w;
+}
+
+//cascades are laid out in rows of 2 in the shadow map like ShadowSettings::atlas_grid, 3x3 pcf
+float sun_shadow(vec3 world_pos,vec3 normal){
+    uint cascade_count = point_light_count.y;
+    float dist = length(world_pos - camera_pos.xyz);
+
+    uint cascade = 0;
+    while(cascade < cascade_count && dist > cascade_splits[cascade]) cascade++;
+    if(cascade >= cascade_count) return 1.0;
+
+    //offset along the normal against shadow acne, grows with the cascade size
+    vec3 offset_pos = world_pos + normal * 0.05 * float(cascade + 1);
+    vec4 light_pos = shadow_matrices[cascade] * vec4(offset_pos,1.0);
+    vec3 shadow_coord = light_pos.xyz / light_pos.w;
+    if(shadow_coord.z >= 1.0) return 1.0;
+
+    vec2 uv = shadow_coord.xy * .5 + .5;
+    vec2 grid = vec2(min(cascade_count,2u),(cascade_count + 1u) / 2u);
+    uv = (uv + vec2(cascade % 2u,cascade / 2u)) / grid;
+
+    vec2 texel = 1.0 / vec2(textureSize(shadow_map,0));
+    float lit = 0.0;
+    for(int x = -1;x <= 1;x++){
+        for(int y = -1;y <= 1;y++){
+            float shadow_depth = texture(shadow_map,uv + vec2(x,y) * texel).x;
+            lit += shadow_coord.z - 0.0005 <= shadow_depth ? 1.0 : 0.0;
+        }
+    }
+
+    return lit / 9.0;
+}
+
+vec3 sky_color(vec3 view_dir){
+    float up = max(view_dir.y,0.0);
+    vec3 sky = mix(sky_horizon.xyz,sky_zenith.xyz,sqrt(up));
+
+    float sun = smoothstep(0.9990,0.9995,dot(view_dir,sun_direction.xyz));
+    return sky + sun_color.xyz * sun;
+}
+
+float fog_factor(float dist){
+    float range = max(fog.z - fog.y,0.0001);
+    if(fog.x == 1.0) return clamp((dist - fog.y) / range,0.0,1.0);
+    if(fog.x == 2.0) return 1.0 - exp(-fog.w * max(dist - fog.y,0.0) / range);
+    return 0.0;
+}
+
+vec3 apply_fog(vec3 surface,vec3 world_pos){
+    vec3 to_pos = world_pos - camera_pos.xyz;
+    float dist = length(to_pos);
+    vec3 fog_color = mix(sky_horizon.xyz,sky_zenith.xyz,sqrt(max(to_pos.y / dist,0.0)));
+    return mix(surface,fog_color,fog_factor(dist));
+}
 
 void main(){
-    color = texture(albedo_spec,screen_pos * .5 + .5);
-    // color = vec4(1.0,0.0,0.0,1.0);
-}
\ No newline at end of file
+    vec2 uv = screen_pos * .5 + .5;
+    vec3 albedo = texture(albedo_spec,uv).xyz;
+    vec3 normal = texture(normal_tex,uv).xyz;
+    float depth = texture(depth_tex,uv).x;
+
+    if(depth >= 1.0){
+        vec3 view_dir = normalize(world_pos_from_depth(screen_pos,1.0) - camera_pos.xyz);
+        color = vec4(sky_color(view_dir),1.0);
+        return;
+    }
+
+    vec3 world_pos = world_pos_from_depth(screen_pos,depth);
+
+    //surfaces that don't write normals are left unlit
+    if(dot(normal,normal) < 0.01){
+        color = vec4(apply_fog(albedo,world_pos),1.0);
+        return;
+    }
+
+    normal = normalize(normal);
+
+    vec3 ambient = mix(ground_ambient.xyz,sky_ambient.xyz,normal.y * .5 + .5);
+    float sun_ndotl = max(dot(normal,sun_direction.xyz),0.0);
+    float shadow = sun_ndotl > 0.0 ? sun_shadow(world_pos,normal) : 1.0;
+    vec3 light = ambient + sun_color.xyz * sun_ndotl * shadow;
+
+    for(uint i = 0;i < point_light_count.x;i++){
+        PointLight l = point_lights[i];
+        vec3 to_light = l.position_radius.xyz - world_pos;
+        float dist = length(to_light);
+        float radius = l.position_radius.w;
+        if(dist >= radius) continue;
+
+        float falloff = 1.0 - dist / radius;
+        float ndotl = max(dot(normal,to_light / dist),0.0);
+        light += l.color_intensity.xyz * l.color_intensity.w * ndotl * falloff * falloff;
+    }
+
+    color = vec4(apply_fog(albedo * light,world_pos),1.0);
+}
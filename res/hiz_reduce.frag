@@ -0,0 +1,27 @@
+#version 450
+
+layout (location = 0) in vec2 screen_pos;
+layout (location = 0) out float out_depth;
+
+layout (set = 0, binding = 0) uniform sampler2D src_depth;
+
+layout(push_constant) uniform Push{
+    ivec2 src_size;
+    ivec2 dst_size;
+};
+
+// keeps the furthest depth of the source texels, odd sizes make the last texel cover three
+void main(){
+    ivec2 texel = ivec2(gl_FragCoord.xy);
+    ivec2 start = texel * src_size / dst_size;
+    ivec2 end = min(((texel + 1) * src_size + dst_size - 1) / dst_size, src_size);
+
+    float depth = 0.0;
+    for(int y = start.y;y < end.y;y++){
+        for(int x = start.x;x < end.x;x++){
+            depth = max(depth,texelFetch(src_depth,ivec2(x,y),0).r);
+        }
+    }
+
+    out_depth = depth;
+}
@@ -0,0 +1,16 @@
+#version 450
+
+layout (location = 0) in vec2 f_uv;
+layout (location = 1) in vec3 f_normal;
+layout (location = 2) in vec4 f_color;
+
+layout (location = 0) out vec4 albedo;
+layout (location = 1) out vec4 normal;
+
+layout(set = 1,binding = 0) uniform sampler2D textures[1];
+
+void main()
+{
+    albedo = vec4(texture(textures[0],f_uv).xyz * f_color.xyz,1.0);
+    normal = vec4(normalize(f_normal),0.0);
+}
@@ -0,0 +1,35 @@
+#version 450
+
+layout(set = 0,binding = 0) uniform CameraData_{
+    mat4 proj_view;
+};
+
+//uv is packed into the w components, see ModelVertex
+struct Vertex{
+    vec4 pos_u;
+    vec4 normal_v;
+};
+
+layout(std430,set = 2,binding = 0) readonly buffer VertexBuffer{
+    Vertex vertices[];
+};
+
+layout(push_constant) uniform Push{
+    mat4 model;
+    vec4 color;
+    vec4 uv_rect; //offset xy, scale zw
+};
+
+layout(location = 0) out vec2 f_uv;
+layout(location = 1) out vec3 f_normal;
+layout(location = 2) out vec4 f_color;
+
+void main() {
+    Vertex v = vertices[gl_VertexIndex];
+
+    f_uv = uv_rect.xy + vec2(v.pos_u.w,v.normal_v.w) * uv_rect.zw;
+    f_normal = transpose(inverse(mat3(model))) * v.normal_v.xyz;
+    f_color = color;
+
+    gl_Position = proj_view * model * vec4(v.pos_u.xyz,1.0);
+}
@@ -0,0 +1,42 @@
+#version 450
+
+layout(location = 0) in vec2 screen_pos;
+
+layout(location = 0) out vec4 color;
+
+layout(set = 0,binding = 0) uniform sampler2D hdr;
+
+layout(push_constant) uniform Push{
+    uint enabled;
+};
+
+const float THRESHOLD = 1.0;
+
+vec3 bright_part(vec2 uv){
+    vec3 c = texture(hdr,uv).rgb;
+    float brightness = max(c.r,max(c.g,c.b));
+    return c * max(brightness - THRESHOLD,0.0) / max(brightness,0.0001);
+}
+
+//single pass gaussian over the bright parts of the image, tonemap adds it back
+void main(){
+    if(enabled == 0){
+        color = vec4(0.0);
+        return;
+    }
+
+    vec2 uv = screen_pos * .5 + .5;
+    vec2 texel = 3.0 / vec2(textureSize(hdr,0));
+
+    vec3 sum = vec3(0.0);
+    float total = 0.0;
+    for(int x = -3;x <= 3;x++){
+        for(int y = -3;y <= 3;y++){
+            float w = exp(-float(x * x + y * y) / 8.0);
+            sum += bright_part(uv + vec2(x,y) * texel) * w;
+            total += w;
+        }
+    }
+
+    color = vec4(sum / total,1.0);
+}
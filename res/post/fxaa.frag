@@ -0,0 +1,55 @@
+#version 450
+
+layout(location = 0) in vec2 screen_pos;
+
+layout(location = 0) out vec4 color;
+
+layout(set = 0,binding = 0) uniform sampler2D ldr;
+
+layout(push_constant) uniform Push{
+    uint enabled;
+};
+
+const float EDGE_THRESHOLD_MIN = 0.0312;
+const float EDGE_THRESHOLD_MAX = 0.125;
+const float SPAN_MAX = 8.0;
+
+float luma(vec3 c){ return dot(c,vec3(0.299,0.587,0.114)); }
+
+//fxaa 3.11 console version, blends along the detected edge direction
+void main(){
+    vec2 uv = screen_pos * .5 + .5;
+    vec3 center = texture(ldr,uv).rgb;
+
+    if(enabled == 0){
+        color = vec4(center,1.0);
+        return;
+    }
+
+    vec2 texel = 1.0 / vec2(textureSize(ldr,0));
+
+    float luma_nw = luma(texture(ldr,uv + vec2(-1.0,-1.0) * texel).rgb);
+    float luma_ne = luma(texture(ldr,uv + vec2( 1.0,-1.0) * texel).rgb);
+    float luma_sw = luma(texture(ldr,uv + vec2(-1.0, 1.0) * texel).rgb);
+    float luma_se = luma(texture(ldr,uv + vec2( 1.0, 1.0) * texel).rgb);
+    float luma_m = luma(center);
+
+    float luma_min = min(luma_m,min(min(luma_nw,luma_ne),min(luma_sw,luma_se)));
+    float luma_max = max(luma_m,max(max(luma_nw,luma_ne),max(luma_sw,luma_se)));
+
+    if(luma_max - luma_min < max(EDGE_THRESHOLD_MIN,luma_max * EDGE_THRESHOLD_MAX)){
+        color = vec4(center,1.0);
+        return;
+    }
+
+    vec2 dir = vec2(-((luma_nw + luma_ne) - (luma_sw + luma_se)),(luma_nw + luma_sw) - (luma_ne + luma_se));
+    float dir_reduce = max((luma_nw + luma_ne + luma_sw + luma_se) * 0.03125,1.0 / 128.0);
+    float rcp_dir_min = 1.0 / (min(abs(dir.x),abs(dir.y)) + dir_reduce);
+    dir = clamp(dir * rcp_dir_min,vec2(-SPAN_MAX),vec2(SPAN_MAX)) * texel;
+
+    vec3 rgb_a = 0.5 * (texture(ldr,uv + dir * (1.0 / 3.0 - 0.5)).rgb + texture(ldr,uv + dir * (2.0 / 3.0 - 0.5)).rgb);
+    vec3 rgb_b = rgb_a * 0.5 + 0.25 * (texture(ldr,uv - dir * 0.5).rgb + texture(ldr,uv + dir * 0.5).rgb);
+
+    float luma_b = luma(rgb_b);
+    color = vec4((luma_b < luma_min || luma_b > luma_max) ? rgb_a : rgb_b,1.0);
+}
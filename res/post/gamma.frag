@@ -0,0 +1,17 @@
+#version 450
+
+layout(location = 0) in vec2 screen_pos;
+
+layout(location = 0) out vec4 color;
+
+layout(set = 0,binding = 0) uniform sampler2D linear_color;
+
+layout(push_constant) uniform Push{
+    uint enabled;
+};
+
+void main(){
+    vec3 c = texture(linear_color,screen_pos * .5 + .5).rgb;
+
+    color = vec4(enabled == 0 ? c : pow(c,vec3(1.0 / 2.2)),1.0);
+}
@@ -0,0 +1,72 @@
+#version 450
+
+layout(location = 0) in vec2 screen_pos;
+
+layout(location = 0) out vec4 color;
+
+layout(set = 0,binding = 0) uniform sampler2D hdr;
+layout(set = 0,binding = 1) uniform sampler2D depth_tex;
+layout(set = 0,binding = 2) uniform sampler2D normal_tex;
+
+layout(set = 1,binding = 0) uniform PostGlobals{
+    mat4 proj_view;
+    mat4 inv_proj_view;
+};
+
+layout(push_constant) uniform Push{
+    uint enabled;
+};
+
+const int SAMPLE_COUNT = 16;
+const float RADIUS = 0.75;
+
+vec3 world_pos_from_depth(vec2 ndc,float depth){
+    vec4 pos = inv_proj_view * vec4(ndc,depth,1.0);
+    return pos.xyz / pos.w;
+}
+
+float hash(vec2 p){
+    return fract(sin(dot(p,vec2(12.9898,78.233))) * 43758.5453);
+}
+
+//hemisphere samples around the normal in world space, projected back to compare against the depth buffer
+float ambient_occlusion(vec2 uv,float depth,vec3 normal){
+    vec3 pos = world_pos_from_depth(screen_pos,depth);
+
+    vec3 tangent = normalize(abs(normal.y) < 0.99 ? cross(normal,vec3(0.0,1.0,0.0)) : cross(normal,vec3(1.0,0.0,0.0)));
+    vec3 bitangent = cross(normal,tangent);
+    float rotation = hash(uv) * 6.2831853;
+
+    float occlusion = 0.0;
+    for(int i = 0;i < SAMPLE_COUNT;i++){
+        float t = (float(i) + 0.5) / float(SAMPLE_COUNT);
+        float angle = float(i) * 2.3999632 + rotation; //golden angle spiral
+        float r = sqrt(t);
+        vec3 dir = tangent * cos(angle) * r + bitangent * sin(angle) * r + normal * sqrt(1.0 - t);
+        vec3 sample_pos = pos + dir * RADIUS * mix(0.1,1.0,t * t);
+
+        vec4 clip = proj_view * vec4(sample_pos,1.0);
+        vec3 ndc = clip.xyz / clip.w;
+        float scene_depth = texture(depth_tex,ndc.xy * .5 + .5).x;
+
+        vec3 scene_pos = world_pos_from_depth(ndc.xy,scene_depth);
+        float range_check = smoothstep(0.0,1.0,RADIUS / max(distance(pos,scene_pos),0.0001));
+        occlusion += (scene_depth < ndc.z - 0.00001 ? 1.0 : 0.0) * range_check;
+    }
+
+    return 1.0 - occlusion / float(SAMPLE_COUNT);
+}
+
+void main(){
+    vec2 uv = screen_pos * .5 + .5;
+    vec4 c = texture(hdr,uv);
+    float depth = texture(depth_tex,uv).x;
+    vec3 normal = texture(normal_tex,uv).xyz;
+
+    if(enabled == 0 || depth >= 1.0 || dot(normal,normal) < 0.01){
+        color = c;
+        return;
+    }
+
+    color = vec4(c.rgb * ambient_occlusion(uv,depth,normalize(normal)),c.a);
+}
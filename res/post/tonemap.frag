@@ -0,0 +1,24 @@
+#version 450
+
+layout(location = 0) in vec2 screen_pos;
+
+layout(location = 0) out vec4 color;
+
+layout(set = 0,binding = 0) uniform sampler2D hdr;
+layout(set = 0,binding = 1) uniform sampler2D bloom;
+
+layout(push_constant) uniform Push{
+    uint enabled;
+};
+
+//aces filmic curve fit by Krzysztof Narkowicz
+vec3 aces(vec3 x){
+    return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14),0.0,1.0);
+}
+
+void main(){
+    vec2 uv = screen_pos * .5 + .5;
+    vec3 c = texture(hdr,uv).rgb + texture(bloom,uv).rgb * 0.5;
+
+    color = vec4(enabled == 0 ? c : aces(c),1.0);
+}
@@ -0,0 +1,176 @@
+use std::time::{Duration, Instant};
+
+use eyre::Result;
+use specs::World;
+
+use super::{input::InputRecording, Game};
+
+//ticks a headless game with a fixed time step, for servers and for testing world logic without a gpu
+pub struct HeadlessRunner {
+    pub game: Box<Game>,
+    pub delta_time: f64,
+    steps: u64,
+}
+
+impl HeadlessRunner {
+    pub fn new(delta_time: f64) -> Result<HeadlessRunner> {
+        Ok(Self { game: Game::new_headless()?, delta_time, steps: 0 })
+    }
+
+    pub fn world(&self) -> &World { &self.game.world }
+    pub fn world_mut(&mut self) -> &mut World { &mut self.game.world }
+    pub fn steps(&self) -> u64 { self.steps }
+
+    pub fn run(&mut self, steps: u64) {
+        for _ in 0..steps {
+            self.game.step(self.delta_time);
+            self.steps += 1;
+        }
+    }
+
+    //applies every recorded frame to the player and steps the simulation with the recorded delta time
+    pub fn replay(&mut self, recording: &InputRecording) {
+        for (delta_time, actions) in recording.frames() {
+            self.game.apply_player_input(actions, *delta_time);
+            self.game.step(*delta_time);
+            self.steps += 1;
+        }
+    }
+
+    //steps until the predicate holds, worldgen runs on other threads so the limit is wall clock time.
+    //returns false on timeout
+    pub fn run_until(&mut self, timeout: Duration, mut predicate: impl FnMut(&World) -> bool) -> bool {
+        let start = Instant::now();
+
+        while !predicate(&self.game.world) {
+            if start.elapsed() > timeout {
+                return false;
+            }
+
+            self.run(1);
+            std::thread::yield_now();
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::*;
+    use specs::{Builder, WorldExt};
+
+    use super::*;
+    use crate::game::{
+        input::ActionFrame,
+        physics::{query::VoxelQuery, Collider, Velocity},
+        voxels::{ViewDistance, VoxelWorld, STONE},
+        Transform,
+    };
+
+    const STEP: f64 = 1.0 / 60.0;
+
+    //worldgen queues a disc of columns three chunks high around the origin
+    fn queued_chunk_count(world: &World) -> usize {
+        let r = world.fetch::<ViewDistance>().0;
+        (-r..=r).flat_map(|x| (-r..=r).map(move |z| x * x + z * z)).filter(|d| *d <= r * r).count() * 3
+    }
+
+    fn loaded_runner() -> HeadlessRunner {
+        let mut runner = HeadlessRunner::new(STEP).unwrap();
+        let expected = queued_chunk_count(runner.world());
+        let loaded = runner.run_until(Duration::from_secs(120), |world| {
+            world.fetch::<VoxelWorld>().chunk_positions().count() == expected
+        });
+        assert!(loaded, "worldgen didn't finish in time");
+        runner
+    }
+
+    #[test]
+    fn recorded_input_replays_to_the_same_position() {
+        let mut frames = Vec::new();
+        let mut turn = ActionFrame::default();
+        turn.set_axis("look_x", std::f32::consts::FRAC_PI_2);
+        frames.push(turn);
+        for i in 0..90 {
+            let mut frame = ActionFrame::default();
+            frame.set_axis(if i < 60 { "move_forward" } else { "move_up" }, 1.0);
+            frames.push(frame);
+        }
+
+        let mut recorder = HeadlessRunner::new(STEP).unwrap();
+        let start = recorder.game.player().pos;
+        recorder.game.input_recording = Some(InputRecording::default());
+        for frame in &frames {
+            recorder.game.apply_player_input(frame, STEP);
+            recorder.run(1);
+        }
+        let recording = recorder.game.input_recording.take().unwrap();
+        assert_eq!(recording.len(), frames.len());
+
+        let mut replayer = HeadlessRunner::new(STEP).unwrap();
+        replayer.replay(&recording);
+        assert_eq!(replayer.steps(), frames.len() as u64);
+
+        //a second of walking after turning to z+, then half a second up
+        let speed = recorder.game.player_controller.speed;
+        let end = replayer.game.player().pos;
+        assert!((end - (start + vec3(0.0, speed * 0.5, speed))).length() < 1e-3, "ended at {end}");
+        assert_eq!(end, recorder.game.player().pos);
+    }
+
+    #[test]
+    fn chunks_get_loaded() {
+        let runner = loaded_runner();
+        let voxels = runner.world().fetch::<VoxelWorld>();
+
+        for pos in [[0, 0, 0], [0, 2, 0], [10, 1, 0], [-7, 0, 7]] {
+            assert!(voxels.get_chunk(&pos).is_some(), "chunk {pos:?} isn't loaded");
+        }
+        assert!(voxels.get_chunk(&[11, 0, 0]).is_none());
+        assert!(voxels.find_surface_height(0, 0).is_some());
+    }
+
+    #[test]
+    fn tile_edits_are_visible() {
+        let mut runner = loaded_runner();
+        let surface = runner.world().fetch::<VoxelWorld>().find_surface_height(3, 5).unwrap();
+        let pos = [3, surface + 2, 5];
+
+        runner.world_mut().write_resource::<VoxelWorld>().set_tile(pos, STONE).unwrap();
+        runner.run(2);
+
+        let voxels = runner.world().fetch::<VoxelWorld>();
+        assert_eq!(voxels.get_tile(pos), Some(STONE));
+        assert_eq!(voxels.find_surface_height(3, 5), Some(surface + 2));
+        //the edits were handed to this frame's systems and cleared
+        assert!(voxels.edited_sections().is_empty());
+    }
+
+    #[test]
+    fn falling_body_lands() {
+        let mut runner = loaded_runner();
+        let surface = runner.world().fetch::<VoxelWorld>().find_surface_height(0, 0).unwrap();
+        let start = surface as f32 + 20.0;
+
+        //aligned with the tile grid so only the column under it is hit
+        let body = runner
+            .world_mut()
+            .create_entity()
+            .with(Transform::new(0.0, start, 0.0))
+            .with(Velocity { mass: 1.0, affected_by_gravity: true, ..Default::default() })
+            .with(Collider { box_size: Vec3::ONE })
+            .build();
+
+        runner.run(30);
+        let falling = runner.world().read_storage::<Transform>().get(body).unwrap().pos.y;
+        assert!(falling < start);
+
+        runner.run(600);
+        let transforms = runner.world().read_storage::<Transform>();
+        let pos = transforms.get(body).unwrap().pos;
+        assert!((pos.y - (surface + 1) as f32).abs() < 1e-3, "body rests at {pos} above the surface at {surface}");
+        assert_eq!((pos.x, pos.z), (0.0, 0.0));
+        assert_eq!(runner.world().read_storage::<Velocity>().get(body).unwrap().velocity, Vec3::ZERO);
+    }
+}
@@ -0,0 +1,317 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use eyre::{eyre, Result, WrapErr};
+use magma_renderer::window::*;
+
+pub const DEFAULT_INPUT_CONFIG: &str = include_str!("../../res/input.cfg");
+
+#[derive(Clone, Copy)]
+pub enum InputCode {
+    Key(Key),
+    Mouse(MouseButton),
+}
+
+#[derive(Clone, Copy)]
+pub struct Binding {
+    pub name: &'static str,
+    pub code: InputCode,
+}
+
+#[rustfmt::skip]
+const BINDING_NAMES: &[(&str, InputCode)] = &[
+    ("A", InputCode::Key(Key::A)), ("B", InputCode::Key(Key::B)), ("C", InputCode::Key(Key::C)),
+    ("D", InputCode::Key(Key::D)), ("E", InputCode::Key(Key::E)), ("F", InputCode::Key(Key::F)),
+    ("G", InputCode::Key(Key::G)), ("H", InputCode::Key(Key::H)), ("I", InputCode::Key(Key::I)),
+    ("J", InputCode::Key(Key::J)), ("K", InputCode::Key(Key::K)), ("L", InputCode::Key(Key::L)),
+    ("M", InputCode::Key(Key::M)), ("N", InputCode::Key(Key::N)), ("O", InputCode::Key(Key::O)),
+    ("P", InputCode::Key(Key::P)), ("Q", InputCode::Key(Key::Q)), ("R", InputCode::Key(Key::R)),
+    ("S", InputCode::Key(Key::S)), ("T", InputCode::Key(Key::T)), ("U", InputCode::Key(Key::U)),
+    ("V", InputCode::Key(Key::V)), ("W", InputCode::Key(Key::W)), ("X", InputCode::Key(Key::X)),
+    ("Y", InputCode::Key(Key::Y)), ("Z", InputCode::Key(Key::Z)),
+    ("Space", InputCode::Key(Key::Space)),
+    ("Escape", InputCode::Key(Key::Escape)),
+    ("LeftControl", InputCode::Key(Key::LeftControl)),
+    ("LeftShift", InputCode::Key(Key::LeftShift)),
+    ("Button1", InputCode::Mouse(MouseButton::Button1)),
+    ("Button2", InputCode::Mouse(MouseButton::Button2)),
+];
+
+impl Binding {
+    pub fn from_name(name: &str) -> Option<Binding> {
+        BINDING_NAMES.iter().find(|(n, _)| *n == name).map(|&(name, code)| Binding { name, code })
+    }
+
+    fn is_held(&self, window: &mut Window) -> bool {
+        match self.code {
+            InputCode::Key(key) => window.get_key(key) == InputState::Pressed,
+            InputCode::Mouse(button) => window.get_mouse_button(button) == InputState::Pressed,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum AxisSource {
+    Buttons { positive: Vec<Binding>, negative: Vec<Binding> },
+    MouseX(f32), //scale
+    MouseY(f32),
+}
+
+//state of every action and axis for a single frame
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ActionFrame {
+    pub actions: HashSet<String>,
+    pub axes: HashMap<String, f32>,
+}
+
+impl ActionFrame {
+    pub fn pressed(&self, action: &str) -> bool { self.actions.contains(action) }
+    pub fn axis(&self, axis: &str) -> f32 { self.axes.get(axis).copied().unwrap_or(0.0) }
+
+    pub fn set_pressed(&mut self, action: &str) { self.actions.insert(action.to_string()); }
+    pub fn set_axis(&mut self, axis: &str, value: f32) { self.axes.insert(axis.to_string(), value); }
+}
+
+/*
+    Maps window input to named actions and axes. Config format, one binding per line:
+
+    action <name> = <input> [<input>...]
+    axis <name> = +<input> -<input> [...]
+    axis <name> = mouse_x|mouse_y [scale]
+*/
+#[derive(Clone, Default)]
+pub struct InputMap {
+    actions: HashMap<String, Vec<Binding>>,
+    axes: HashMap<String, AxisSource>,
+}
+
+fn parse_binding(name: &str, line: usize) -> Result<Binding> {
+    Binding::from_name(name).ok_or_else(|| eyre!("line {}: unknown input \"{}\"", line, name))
+}
+
+impl InputMap {
+    pub fn from_config(config: &str) -> Result<InputMap> {
+        let mut map = InputMap::default();
+
+        for (i, line) in config.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (lhs, rhs) = line.split_once('=').ok_or_else(|| eyre!("line {}: expected '='", line_number))?;
+            let (kind, name) = match lhs.split_whitespace().collect::<Vec<_>>().as_slice() {
+                [kind, name] => (*kind, *name),
+                _ => return Err(eyre!("line {}: expected \"action <name>\" or \"axis <name>\"", line_number)),
+            };
+            let inputs: Vec<&str> = rhs.split_whitespace().collect();
+
+            match kind {
+                "action" => {
+                    let bindings = inputs.iter().map(|n| parse_binding(n, line_number)).collect::<Result<_>>()?;
+                    map.actions.insert(name.to_string(), bindings);
+                }
+                "axis" => {
+                    let source = match inputs[..] {
+                        [mouse @ ("mouse_x" | "mouse_y"), ref rest @ ..] => {
+                            let scale = match rest {
+                                [] => 1.0,
+                                [scale] => scale
+                                    .parse::<f32>()
+                                    .map_err(|_| eyre!("line {}: invalid axis scale \"{}\"", line_number, scale))?,
+                                _ => return Err(eyre!("line {}: too many arguments for a mouse axis", line_number)),
+                            };
+                            if mouse == "mouse_x" { AxisSource::MouseX(scale) } else { AxisSource::MouseY(scale) }
+                        }
+                        _ => {
+                            let mut positive = Vec::new();
+                            let mut negative = Vec::new();
+                            for input in &inputs {
+                                if let Some(n) = input.strip_prefix('+') {
+                                    positive.push(parse_binding(n, line_number)?);
+                                } else if let Some(n) = input.strip_prefix('-') {
+                                    negative.push(parse_binding(n, line_number)?);
+                                } else {
+                                    return Err(eyre!(
+                                        "line {}: axis inputs need a '+' or '-' prefix, got \"{}\"",
+                                        line_number,
+                                        input
+                                    ));
+                                }
+                            }
+                            AxisSource::Buttons { positive, negative }
+                        }
+                    };
+                    map.axes.insert(name.to_string(), source);
+                }
+                _ => return Err(eyre!("line {}: unknown binding kind \"{}\"", line_number, kind)),
+            }
+        }
+
+        Ok(map)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<InputMap> {
+        let path = path.as_ref();
+        let config = std::fs::read_to_string(path).wrap_err_with(|| format!("couldn't read {}", path.display()))?;
+        Self::from_config(&config).wrap_err_with(|| format!("invalid input config {}", path.display()))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        std::fs::write(path, self.to_config())?;
+        Ok(())
+    }
+
+    pub fn to_config(&self) -> String {
+        let mut lines = Vec::new();
+
+        let mut actions: Vec<_> = self.actions.iter().collect();
+        actions.sort_by_key(|(name, _)| *name);
+        for (name, bindings) in actions {
+            let inputs: Vec<_> = bindings.iter().map(|b| b.name).collect();
+            lines.push(format!("action {} = {}", name, inputs.join(" ")));
+        }
+
+        let mut axes: Vec<_> = self.axes.iter().collect();
+        axes.sort_by_key(|(name, _)| *name);
+        for (name, source) in axes {
+            let rhs = match source {
+                AxisSource::Buttons { positive, negative } => {
+                    let inputs: Vec<_> = positive
+                        .iter()
+                        .map(|b| format!("+{}", b.name))
+                        .chain(negative.iter().map(|b| format!("-{}", b.name)))
+                        .collect();
+                    inputs.join(" ")
+                }
+                AxisSource::MouseX(scale) => format!("mouse_x {}", scale),
+                AxisSource::MouseY(scale) => format!("mouse_y {}", scale),
+            };
+            lines.push(format!("axis {} = {}", name, rhs));
+        }
+
+        lines.join("\n") + "\n"
+    }
+
+    //replaces the bindings of an action, inputs are given by name like in the config file
+    pub fn rebind_action(&mut self, action: &str, inputs: &[&str]) -> Result<()> {
+        let bindings = inputs
+            .iter()
+            .map(|n| Binding::from_name(n).ok_or_else(|| eyre!("unknown input \"{}\"", n)))
+            .collect::<Result<_>>()?;
+        self.actions.insert(action.to_string(), bindings);
+        Ok(())
+    }
+
+    pub fn rebind_axis(&mut self, axis: &str, source: AxisSource) { self.axes.insert(axis.to_string(), source); }
+
+    pub fn sample(&self, window: &mut Window) -> ActionFrame {
+        let mut frame = ActionFrame::default();
+
+        for (name, bindings) in &self.actions {
+            if bindings.iter().any(|b| b.is_held(window)) {
+                frame.set_pressed(name);
+            }
+        }
+
+        let (mx, my) = window.get_mouse_movement();
+
+        for (name, source) in &self.axes {
+            let value = match source {
+                AxisSource::Buttons { positive, negative } => {
+                    let positive = positive.iter().any(|b| b.is_held(window)) as i32 as f32;
+                    let negative = negative.iter().any(|b| b.is_held(window)) as i32 as f32;
+                    positive - negative
+                }
+                AxisSource::MouseX(scale) => mx * scale,
+                AxisSource::MouseY(scale) => my * scale,
+            };
+            frame.set_axis(name, value);
+        }
+
+        frame
+    }
+}
+
+//action frames together with their delta times, replaying them reproduces the player's movement
+#[derive(Clone, Default)]
+pub struct InputRecording {
+    frames: Vec<(f64, ActionFrame)>,
+}
+
+impl InputRecording {
+    pub fn record(&mut self, delta_time: f64, frame: &ActionFrame) { self.frames.push((delta_time, frame.clone())); }
+    pub fn frames(&self) -> &[(f64, ActionFrame)] { &self.frames }
+    pub fn len(&self) -> usize { self.frames.len() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "
+        # comment
+        action jump = Space
+        action use = Button1 E
+
+        axis walk = +W -S
+        axis turn = mouse_x 0.5
+    ";
+
+    fn error(config: &str) -> String { InputMap::from_config(config).err().unwrap().to_string() }
+
+    #[test]
+    fn config_is_written_sorted() {
+        let map = InputMap::from_config(CONFIG).unwrap();
+        assert_eq!(
+            map.to_config(),
+            "action jump = Space\naction use = Button1 E\naxis turn = mouse_x 0.5\naxis walk = +W -S\n"
+        );
+    }
+
+    #[test]
+    fn config_round_trips() {
+        for config in [CONFIG, DEFAULT_INPUT_CONFIG] {
+            let written = InputMap::from_config(config).unwrap().to_config();
+            assert_eq!(InputMap::from_config(&written).unwrap().to_config(), written);
+        }
+    }
+
+    #[test]
+    fn mouse_axis_scale_defaults_to_one() {
+        let map = InputMap::from_config("axis look = mouse_y").unwrap();
+        assert_eq!(map.to_config(), "axis look = mouse_y 1\n");
+    }
+
+    #[test]
+    fn invalid_lines_name_their_line() {
+        assert_eq!(error("action jump = Space\naction use = Foo"), "line 2: unknown input \"Foo\"");
+        assert_eq!(error("action jump Space"), "line 1: expected '='");
+        assert_eq!(error("action = Space"), "line 1: expected \"action <name>\" or \"axis <name>\"");
+        assert_eq!(error("button jump = Space"), "line 1: unknown binding kind \"button\"");
+        assert_eq!(error("\naxis walk = W -S"), "line 2: axis inputs need a '+' or '-' prefix, got \"W\"");
+        assert_eq!(error("axis look = mouse_x fast"), "line 1: invalid axis scale \"fast\"");
+        assert_eq!(error("axis look = mouse_x 1 2"), "line 1: too many arguments for a mouse axis");
+    }
+
+    #[test]
+    fn rebinding_replaces_bindings() {
+        let mut map = InputMap::from_config(CONFIG).unwrap();
+        map.rebind_action("jump", &["LeftShift", "J"]).unwrap();
+        map.rebind_action("crouch", &["LeftControl"]).unwrap();
+        map.rebind_axis("turn", AxisSource::MouseY(2.0));
+
+        let config = map.to_config();
+        assert!(config.contains("action jump = LeftShift J\n"));
+        assert!(config.contains("action crouch = LeftControl\n"));
+        assert!(config.contains("axis turn = mouse_y 2\n"));
+        assert_eq!(InputMap::from_config(&config).unwrap().to_config(), config);
+
+        //an unknown input keeps the old bindings
+        assert!(map.rebind_action("jump", &["Space", "Nope"]).is_err());
+        assert_eq!(map.to_config(), config);
+    }
+}
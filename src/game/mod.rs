@@ -1,14 +1,15 @@
 use std::{
     f32::consts::PI,
+    path::Path,
     sync::{
         mpsc::{sync_channel, SyncSender},
-        Arc, Mutex,
+        Arc, Mutex, MutexGuard,
     },
 };
 
 use ash::vk;
 use bytemuck::{Zeroable, Pod};
-use eyre::Result;
+use eyre::{eyre, Result};
 use glam::*;
 use magma_renderer::{core::*, window::*, engine::renderer::MeshPass};
 use specs::prelude::*;
@@ -16,10 +17,25 @@ use std::sync::mpsc::channel;
 
 pub mod voxels;
 pub mod physics;
+pub mod plugin;
+pub mod headless;
+pub mod input;
+pub mod time;
+
+use crate::{
+    game::physics::{query::VoxelQuery, ray::Ray, Collider, Velocity},
+    render::{
+        descriptor_cache::{DescriptorCache, DescriptorKey},
+        CubePrefab,
+    },
+};
 
-use crate::{game::physics::{Velocity, Collider}, render::CubePrefab};
-
-use self::voxels::VoxelWorld;
+use self::{
+    input::{ActionFrame, InputMap, InputRecording},
+    plugin::PluginSet,
+    time::WorldTime,
+    voxels::{Tile, ViewDistance, VoxelWorld, AIR, STONE},
+};
 
 use super::render;
 
@@ -102,15 +118,35 @@ impl Camera {
     }
 }
 
-pub type FrameTask = Box<dyn Fn(&mut World, &mut DispatcherBuilder)>;
+//systems are grouped into stages, every stage finishes before the next one starts
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    Input,
+    Simulation,
+    Meshing,
+    RenderPrep,
+}
 
-pub struct Game {
-    pub world: World,
+type SystemRegistration = Box<dyn FnOnce(&mut DispatcherBuilder<'static, 'static>)>;
+
+//gpu side of the game, headless games don't have one
+pub struct RenderLayer {
     pub core: Arc<Core>,
+    pub descriptor_pool: DescriptorPool,
+    pub input_map: InputMap,
     camera: Camera,
+}
+
+pub struct Game {
+    pub world: World,
+    render: Option<RenderLayer>,
     player: Transform,
-    frame_tasks: Vec<FrameTask>,
-    pub descriptor_pool: DescriptorPool,
+    pub player_controller: PlayerController,
+    //every action frame of the player is appended to the recording while it is set
+    pub input_recording: Option<InputRecording>,
+    systems: Vec<(Stage, SystemRegistration)>,
+    thread_local_systems: Vec<SystemRegistration>,
+    dispatcher: Option<Dispatcher<'static, 'static>>,
     // gpass: DeferedPass,
 }
 
@@ -122,6 +158,13 @@ pub struct CamareBuffer{
 
 pub struct CameraData {
     pub proj_view: Mat4,
+    pub position: Vec3,
+    //projection parameters, used to split the view frustum into shadow cascades
+    pub view: Mat4,
+    pub fovy: f32,
+    pub aspect_ratio: f32,
+    pub znear: f32,
+    pub zfar: f32,
     cam_buffers:Box<[Buffer<CamareBuffer>]>,
     pub dset:vk::DescriptorSet,
     pub dset_layout:vk::DescriptorSetLayout,
@@ -131,6 +174,12 @@ impl CameraData{
     pub fn new(core:&Arc<Core>) -> eyre::Result<CameraData>{
         let camdata = Self{
             proj_view: Mat4::IDENTITY,
+            position: Vec3::ZERO,
+            view: Mat4::IDENTITY,
+            fovy: 90.0,
+            aspect_ratio: 1.0,
+            znear: 0.1,
+            zfar: 200.0,
             cam_buffers: (0..2).map(|_| core.create_buffer(vk::BufferUsageFlags::UNIFORM_BUFFER, 1, true)).collect::<Result<_>>()?,
             dset: vk::DescriptorSet::null(),
             dset_layout: DescriptorSetLayoutBuilder::new().add_ubo(vk::ShaderStageFlags::VERTEX , 1).build(core)?,
@@ -139,10 +188,12 @@ impl CameraData{
         Ok(camdata)
     }
 
-    pub fn update_and_get_buffer_data(&mut self,frame_index:usize,pool:&mut DescriptorPool) -> eyre::Result<&mut CamareBuffer>{
+    pub fn update_and_get_buffer_data(&mut self,frame_index:usize,cache:&mut DescriptorCache) -> eyre::Result<&mut CamareBuffer>{
         let buffer = &mut self.cam_buffers[frame_index];
 
-        self.dset = DescriptorSetBuilder::new().add_ubo(&[buffer]).build(self.dset_layout, pool)?;
+        self.dset = cache.get_or_build(DescriptorKey::new(self.dset_layout).buffer(buffer.inner()), |pool| {
+            Ok(DescriptorSetBuilder::new().add_ubo(&[&*buffer]).build(self.dset_layout, pool)?)
+        })?;
 
         Ok(&mut buffer.get_data_mut().unwrap()[0])
     }
@@ -166,64 +217,192 @@ pub struct RenderGlobals {
     core: Arc<Core>,
     frame_datas: Box<[FrameData]>,
     frame_index: u32,
+    descriptor_cache: Mutex<DescriptorCache>,
 }
 
 impl RenderGlobals {
     pub fn frame_data(&self) -> &FrameData { &self.frame_datas[self.frame_index as usize] }
+    //sets that only change when their resources do, prefer it over the per frame pools
+    pub fn descriptor_cache(&self) -> MutexGuard<DescriptorCache> { self.descriptor_cache.lock().unwrap() }
     pub fn core(&self) -> &Arc<Core> { &self.core }
     fn next_frame(&mut self) { self.frame_index = (self.frame_index + 1) % 2; }
     fn start_frame(&mut self) -> Result<(), vk::Result> { self.frame_data().descriptor_pool.lock().unwrap().reset() }
 }
 
 impl Game {
-    pub fn insert_frame_task(&mut self, task: FrameTask) { self.frame_tasks.push(task); }
+    //systems are moved into the dispatcher when it is built, later ones could never run
+    fn assert_not_dispatching(&self, name: &str) {
+        assert!(self.dispatcher.is_none(), "system {name} was added after the dispatcher was built by the first step");
+    }
 
-    pub fn new(core: &Arc<Core>, renderpass: &dyn Renderpass) -> Box<Self> {
+    pub fn add_system<S>(&mut self, stage: Stage, system: S, name: &'static str, deps: &[&'static str])
+    where
+        S: for<'c> System<'c> + Send + 'static,
+    {
+        self.assert_not_dispatching(name);
+        let deps = deps.to_vec();
+        self.systems.push((stage, Box::new(move |d| d.add(system, name, &deps))));
+    }
+
+    //thread local systems run after all of the stages in registration order
+    pub fn add_thread_local_system<S>(&mut self, system: S)
+    where
+        S: for<'c> RunNow<'c> + 'static,
+    {
+        self.assert_not_dispatching(std::any::type_name::<S>());
+        self.thread_local_systems.push(Box::new(move |d| d.add_thread_local(system)));
+    }
+
+    fn build_dispatcher(&mut self) -> Dispatcher<'static, 'static> {
+        let mut systems = std::mem::take(&mut self.systems);
+        systems.sort_by_key(|(stage, _)| *stage); //stable sort keeps the registration order inside a stage
+
+        let mut builder = DispatcherBuilder::new();
+        let mut current_stage = None;
+
+        for (stage, register) in systems {
+            if current_stage.is_some() && current_stage != Some(stage) {
+                builder.add_barrier();
+            }
+            current_stage = Some(stage);
+            register(&mut builder);
+        }
+
+        for register in self.thread_local_systems.drain(..) {
+            register(&mut builder);
+        }
+
+        let mut dispatcher = builder.build();
+        dispatcher.setup(&mut self.world);
+        dispatcher
+    }
+
+    fn new_simulation() -> Box<Self> {
         let mut world = World::new();
         world.register::<Transform>();
-        world.insert(core.clone());
 
-        let camera = Camera { fovy: 90.0, znear: 0.1, zfar: 200.0 };
-
-        let mut game = Box::new(Game {
+        Box::new(Game {
             world,
-            camera,
+            render: None,
             player: Transform { pos: vec3(0.0,70.0,0.0), yaw: 0.0, pitch: 0.0 },
-            frame_tasks: vec![],
+            player_controller: PlayerController::default(),
+            input_recording: None,
+            systems: vec![],
+            thread_local_systems: vec![],
+            dispatcher: None,
+        })
+    }
+
+    fn simulation_plugins(plugins: PluginSet) -> PluginSet {
+        plugins.add(voxels::VoxelPlugin).add(physics::PhysicsPlugin).add(time::TimePlugin)
+    }
+
+    //game without a window or gpu, only the simulation plugins are built
+    pub fn new_headless() -> Result<Box<Self>> {
+        let mut game = Self::new_simulation();
+
+        Self::simulation_plugins(PluginSet::new()).build(&mut game)?;
+
+        Ok(game)
+    }
+
+    pub fn new(core: &Arc<Core>, renderpass: &dyn Renderpass) -> Result<Box<Self>> {
+        let mut game = Self::new_simulation();
+
+        game.render = Some(RenderLayer {
             core: core.clone(),
+            camera: Camera { fovy: 90.0, znear: 0.1, zfar: 200.0 },
             descriptor_pool: DescriptorPool::new(core),
+            input_map: InputMap::load("res/input.cfg").or_else(|e| {
+                eprintln!("{:?}\nfalling back to the default input bindings", e);
+                InputMap::from_config(input::DEFAULT_INPUT_CONFIG)
+            })?,
         });
 
+        game.world.insert(core.clone());
         game.world.insert(RenderGlobals {
             core: core.clone(),
             frame_datas: (0..2).map(|_| FrameData { descriptor_pool: Mutex::new(DescriptorPool::new(&core)) }).collect(),
             frame_index: 0,
+            descriptor_cache: Mutex::new(DescriptorCache::new(core)),
         });
 
-        render::init_material_system(&mut game);
-        render::renderpasses::init(&mut game, renderpass);
+        game.world.insert(CameraData::new(core)?);
 
-        voxels::init(&mut game);
-        render::init_cube(&mut game).unwrap();
-        render::chunk_render::init(&mut game, renderpass);
+        Self::simulation_plugins(PluginSet::new())
+            .add(render::MaterialPlugin)
+            .add(render::renderpasses::RenderPassPlugin { swapchain: renderpass })
+            .add(render::CubePlugin)
+            .add(render::chunk_render::ChunkRenderPlugin)
+            .build(&mut game)?;
 
-        physics::init(&mut game);
+        Ok(game)
+    }
 
-        game.world.insert(CameraData::new(core).unwrap());
+    pub fn is_headless(&self) -> bool { self.render.is_none() }
+    pub fn player(&self) -> &Transform { &self.player }
 
+    //moves the player, works the same for live and replayed input
+    pub fn apply_player_input(&mut self, actions: &ActionFrame, delta_time: f64) {
+        if let Some(recording) = &mut self.input_recording {
+            recording.record(delta_time, actions);
+        }
 
-        game
+        self.player_controller.apply(&mut self.player, actions, delta_time);
     }
 
-    pub fn tick(&mut self, delta_time: f64, cmd: &mut CommandBuffer, ar: &mut Window) -> Result<()> {
+    pub fn core(&self) -> Result<Arc<Core>> {
+        self.render.as_ref().map(|r| r.core.clone()).ok_or_else(|| eyre!("game is headless, it has no render layer"))
+    }
+
+    //saves the persistent parts of the world into a directory
+    pub fn save_world(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        self.world.fetch::<WorldTime>().save(dir.join("time.cfg"))
+    }
+
+    //parts missing from the directory start out fresh, a run may have ended before the first save
+    pub fn load_world(&mut self, dir: impl AsRef<Path>) -> Result<()> {
+        let time_path = dir.as_ref().join("time.cfg");
+        let time = if time_path.exists() { WorldTime::load(time_path)? } else { WorldTime::default() };
+        self.world.insert(time);
+        Ok(())
+    }
+
+    //advances the simulation by one step without rendering, systems of every plugin are run
+    pub fn step(&mut self, delta_time: f64) {
         self.world.insert(DeltaTime(delta_time));
+
+        //the dispatcher is built once, after every system is registered
+        if self.dispatcher.is_none() {
+            self.dispatcher = Some(self.build_dispatcher());
+        }
+
+        self.dispatcher.as_mut().unwrap().dispatch(&self.world);
+        self.world.maintain();
+    }
+
+    pub fn tick(&mut self, delta_time: f64, cmd: &mut CommandBuffer, ar: &mut Window) -> Result<()> {
+        let Some(render) = &mut self.render else {
+            return Err(eyre!("tick needs a render layer, use step for headless games"));
+        };
+        //the far plane follows the view distance with a chunk of margin so fogged chunks aren't clipped
+        render.camera.zfar = self.world.fetch::<ViewDistance>().blocks() + voxels::CHUNK_SIZE as f32;
+        let camera_proj = render.camera.proj(ar.renderpass.extends());
+        let (width, height) = ar.renderpass.extends();
+        let (fovy, znear, zfar) = (render.camera.fovy, render.camera.znear, render.camera.zfar);
+        let actions = render.input_map.sample(ar);
+
         self.world.insert(FrameIndex(ar.frame_index()));
-        handle_player_movement(&mut self.world,&mut self.player, delta_time, ar);
+        self.apply_player_input(&actions, delta_time);
+        handle_player_actions(&mut self.world, &self.player, &actions, delta_time, ar);
 
         render::renderpasses::prepare_render(self, &ar.renderpass).unwrap();
 
 
-        let proj_view = self.camera.proj(ar.renderpass.extends()) * Mat4::look_to_rh(self.player.pos, self.player.direction(), Vec3::Y);
+        let view = Mat4::look_to_rh(self.player.pos, self.player.direction(), Vec3::Y);
+        let proj_view = camera_proj * view;
             // * Isometry3::look_at_rh(&self.player.pos, &(self.player.pos + self.player.direction()), &UP).to_homogeneous();
 
         self.world.write_resource::<RenderGlobals>().start_frame()?;
@@ -231,22 +410,19 @@ impl Game {
 
         let mut camdata = self.world.fetch_mut::<CameraData>();
         camdata.proj_view= proj_view;
-        let cam_buffer = camdata.update_and_get_buffer_data(ar.frame_index(), &mut self.world.write_resource::<RenderGlobals>().frame_data().descriptor_pool.lock().unwrap())?;
+        camdata.position = self.player.pos;
+        camdata.view = view;
+        camdata.fovy = fovy;
+        camdata.aspect_ratio = width as f32 / height as f32;
+        camdata.znear = znear;
+        camdata.zfar = zfar;
+        let cam_buffer = camdata.update_and_get_buffer_data(ar.frame_index(), &mut self.world.fetch::<RenderGlobals>().descriptor_cache())?;
         cam_buffer.proj_view = proj_view.to_cols_array_2d();
 
         drop(camdata);
         // self.world.insert(CameraData { proj_view });
 
-
-        //build the dispatcher
-        let mut dbuilder = DispatcherBuilder::new();
-
-        self.frame_tasks.iter().for_each(|t| (*t)(&mut self.world, &mut dbuilder));
-
-        let mut dispatcher = dbuilder.build();
-
-        dispatcher.dispatch_seq(&self.world);
-        dispatcher.dispatch_thread_local(&self.world);
+        self.step(delta_time);
 
         //execute rendering commands
         render::renderpasses::render(self, cmd, &ar.renderpass).unwrap();
@@ -257,52 +433,68 @@ impl Game {
     }
 }
 
-fn handle_player_movement(world:&mut World,player_transform: &mut Transform, delta_time: f64, ar: &mut Window) {
-    // use winit::event::MouseButton;
-    if ar.get_key(Key::Escape) == InputState::Pressed {
-        ar.unlock_cursor();
-    }
+pub struct PlayerController {
+    pub speed: f32,
+    pub max_pitch: f32,
+}
 
-    if ar.get_mouse_button(MouseButton::Button1) == InputState::Pressed {
-        ar.lock_cursor();
-    }
+impl Default for PlayerController {
+    fn default() -> Self { Self { speed: 5.6, max_pitch: f32::to_radians(89.0) } }
+}
 
-    let speed = 5.6;
+impl PlayerController {
+    pub fn apply(&self, player_transform: &mut Transform, actions: &ActionFrame, delta_time: f64) {
+        player_transform.yaw += actions.axis("look_x");
+        player_transform.pitch =
+            f32::clamp(player_transform.pitch - actions.axis("look_y"), -self.max_pitch, self.max_pitch);
 
-    let sensivity = 0.005;
-    let (mx, my) = ar.get_mouse_movement();
-    let max_vertical_rotation = f32::to_radians(89.0);
+        let forward = vec3(player_transform.yaw.cos(), 0.0f32, player_transform.yaw.sin());
+        let left = vec3(forward.z, 0.0, -forward.x);
 
-    player_transform.yaw += mx * sensivity;
-    player_transform.pitch =
-        f32::clamp(player_transform.pitch + -my * sensivity, -max_vertical_rotation, max_vertical_rotation);
+        let final_vec = forward * actions.axis("move_forward") - left * actions.axis("move_right")
+            + vec3(0.0, actions.axis("move_up"), 0.0);
 
-    fn key_to_scaler(state: InputState) -> f32 {
-        match state {
-            InputState::Pressed => 1.0,
-            InputState::Released => 0.0,
-        }
+        player_transform.pos += final_vec * delta_time as f32 * self.speed;
     }
+}
 
-    let mut move_vector = Vec3::ZERO;
+fn handle_player_actions(world:&mut World,player_transform: &Transform, actions: &ActionFrame, delta_time: f64, ar: &mut Window) {
+    if actions.pressed("unlock_cursor") {
+        ar.unlock_cursor();
+    }
 
-    move_vector.x += key_to_scaler(ar.get_key(Key::A));
-    move_vector.x -= key_to_scaler(ar.get_key(Key::D));
-    move_vector.z += key_to_scaler(ar.get_key(Key::W));
-    move_vector.z -= key_to_scaler(ar.get_key(Key::S));
-    move_vector.y += key_to_scaler(ar.get_key(Key::Space));
-    move_vector.y -= key_to_scaler(ar.get_key(Key::LeftControl));
+    if actions.pressed("lock_cursor") {
+        ar.lock_cursor();
+    }
 
-    let forward = vec3(player_transform.yaw.cos(), 0.0f32, player_transform.yaw.sin());
-    let right = vec3(forward.z, 0.0, -forward.x); //90 degrees rotated clockwise
+    //holding the action repeats the edit every EDIT_INTERVAL seconds, the first press edits right away
+    const EDIT_INTERVAL: f32 = 0.25;
+    struct TimeSinceLastEdit(f32);
 
-    let final_vec = forward * move_vector.z + right * move_vector.x + vec3(0.0, move_vector.y, 0.0);
+    let edit = if actions.pressed("break_block") {
+        Some(AIR)
+    } else if actions.pressed("place_block") {
+        Some(STONE)
+    } else {
+        None
+    };
 
-    player_transform.pos += final_vec * delta_time as f32 * speed;
+    if world.get_mut::<TimeSinceLastEdit>().is_none() {
+        world.insert(TimeSinceLastEdit(EDIT_INTERVAL));
+    }
+    let time = world.get_mut::<TimeSinceLastEdit>().unwrap();
+    match edit {
+        Some(tile) if time.0 + delta_time as f32 >= EDIT_INTERVAL => {
+            time.0 = 0.0;
+            edit_looked_at_tile(&mut world.write_resource::<VoxelWorld>(), player_transform, tile);
+        }
+        Some(_) => time.0 += delta_time as f32,
+        None => time.0 = EDIT_INTERVAL,
+    }
 
     struct TimeSincelastBox(f32);
 
-    if ar.get_key(Key::G) == InputState::Pressed{
+    if actions.pressed("spawn_cube") {
         if let Some(time) = world.get_mut::<TimeSincelastBox>() {
             if time.0 < 1.0{
                 time.0 +=   delta_time as f32;
@@ -316,20 +508,38 @@ fn handle_player_movement(world:&mut World,player_transform: &mut Transform, del
             world.insert(TimeSincelastBox(0.0));
         }
 
-        let cube_prefab = world.fetch::<CubePrefab>().0.clone();
+        let (cube_model, cube_material) = {
+            let prefab = world.fetch::<CubePrefab>();
+            (prefab.0, prefab.1)
+        };
 
         world.create_entity()
             .with(Transform::new(player_transform.pos.x, player_transform.pos.y, player_transform.pos.z))
             .with(Velocity::default())
             .with(Collider{box_size:vec3(1.0,1.0,1.0)})
             // .with(Cube)
-            .with(cube_prefab)
+            .with(cube_model)
+            .with(cube_material)
             .build();
     }
+}
 
-    // if final_vec != Vector3::zeros() {
-    //     println!("move direction {}, final pos {:?}",final_vec,player_transform.pos)
-    // }
+//reach of block edits in tiles
+const EDIT_REACH: f32 = 6.0;
+
+//air breaks the looked at tile, anything else is placed against the hit face
+fn edit_looked_at_tile(voxels: &mut VoxelWorld, player_transform: &Transform, tile: Tile) {
+    let ray = Ray::new(player_transform.pos, player_transform.direction() * EDIT_REACH);
+    let Some(hit) = voxels.raycast(&ray) else {
+        return;
+    };
+
+    if tile == AIR {
+        voxels.set_tile(hit.pos.to_array(), AIR);
+    } else if hit.normal != Vec3::ZERO {
+        //without a normal the camera is inside the hit tile and there is no face to place against
+        voxels.set_tile((hit.pos + hit.normal.as_ivec3()).to_array(), tile);
+    }
 }
 
 pub struct FrameIndex(usize);
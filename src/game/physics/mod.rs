@@ -6,10 +6,12 @@ use specs::prelude::*;
 use self::ray::Ray;
 
 use super::{
+    plugin::Plugin,
     voxels::{ChunkRef, Tile, VoxelWorld, CHUNK_SIZE},
-    DeltaTime, Game, Transform,
+    DeltaTime, Game, Stage, Transform,
 };
 
+pub mod query;
 pub mod ray;
 
 pub struct Collider {
@@ -233,15 +235,22 @@ impl Tile {
     }
 }
 
+pub struct PhysicsPlugin;
+
+impl Plugin for PhysicsPlugin {
+    fn name(&self) -> &'static str { "physics" }
+    fn requires(&self) -> &'static [&'static str] { &["voxel_world"] }
+    fn build(&self, game: &mut Game) -> eyre::Result<()> {
+        init(game);
+        Ok(())
+    }
+}
+
 pub fn init(game: &mut Game) {
     game.world.register::<Velocity>();
     game.world.register::<Collider>();
     game.world.register::<AddedForces>();
 
-    return;
-
-    game.insert_frame_task(Box::new(|w, d| {
-        d.add(ForceSystem, "forces", &[]);
-        d.add(VelocitySystem, "velocities", &["forces"]);
-    }));
+    game.add_system(Stage::Simulation, ForceSystem, "forces", &[]);
+    game.add_system(Stage::Simulation, VelocitySystem, "velocities", &["forces"]);
 }
@@ -0,0 +1,322 @@
+use glam::*;
+
+use super::{ray::Ray, AABB};
+use crate::game::voxels::{ChunkView, Tile, VoxelWorld, CHUNK_SIZE};
+
+pub struct VoxelHit {
+    pub pos: IVec3,
+    pub tile: Tile,
+    pub t: f32,
+    //zero if the query started inside the hit tile
+    pub normal: Vec3,
+}
+
+impl VoxelHit {
+    pub fn hit_point(&self, ray: &Ray) -> Vec3 { ray.pos() + ray.dir() * self.t }
+}
+
+/*
+    Spatial queries over voxel data. Positions are in world tiles, rays follow the same
+    convention as Ray::test_aabb with in_range set: only hits with t in [0, 1] are reported.
+    Tiles inside unloaded chunks are treated as empty.
+*/
+pub trait VoxelQuery {
+    fn tile_at(&self, pos: IVec3) -> Option<Tile>;
+
+    //inclusive range of loaded chunk y positions for the column containing x, z
+    fn column_range(&self, x: i32, z: i32) -> Option<(i32, i32)>;
+
+    fn is_solid(&self, pos: IVec3) -> bool { self.tile_at(pos).map_or(false, |t| !t.transparent()) }
+
+    fn raycast(&self, ray: &Ray) -> Option<VoxelHit> {
+        let origin = ray.pos();
+        let dir = ray.dir();
+
+        let mut cell = origin.floor().as_ivec3();
+        let mut step = IVec3::ZERO;
+        let mut t_max = Vec3::splat(f32::INFINITY);
+        let mut t_delta = Vec3::splat(f32::INFINITY);
+
+        for i in 0..3 {
+            if dir[i] > 0.0 {
+                step[i] = 1;
+                t_max[i] = ((cell[i] + 1) as f32 - origin[i]) / dir[i];
+                t_delta[i] = 1.0 / dir[i];
+            } else if dir[i] < 0.0 {
+                step[i] = -1;
+                t_max[i] = (cell[i] as f32 - origin[i]) / dir[i];
+                t_delta[i] = -1.0 / dir[i];
+            }
+        }
+
+        let mut t = 0.0;
+        let mut normal = Vec3::ZERO;
+
+        loop {
+            if let Some(tile) = self.tile_at(cell) {
+                if !tile.transparent() {
+                    return Some(VoxelHit { pos: cell, tile, t, normal });
+                }
+            }
+
+            let axis = if t_max.x < t_max.y {
+                if t_max.x < t_max.z { 0 } else { 2 }
+            } else {
+                if t_max.y < t_max.z { 1 } else { 2 }
+            };
+
+            t = t_max[axis];
+            if t > 1.0 {
+                return None;
+            }
+
+            cell[axis] += step[axis];
+            t_max[axis] += t_delta[axis];
+            normal = Vec3::ZERO;
+            normal[axis] = -step[axis] as f32;
+        }
+    }
+
+    //returns solid tiles that overlap with the aabb, touching tiles are not included
+    fn overlap_aabb(&self, aabb: &AABB) -> Vec<(IVec3, Tile)> {
+        let beg = aabb.begin.floor().as_ivec3();
+        let end = aabb.end.ceil().as_ivec3() - IVec3::ONE;
+
+        let mut tiles = Vec::new();
+        for x in beg.x..=end.x {
+            for y in beg.y..=end.y {
+                for z in beg.z..=end.z {
+                    let pos = ivec3(x, y, z);
+                    match self.tile_at(pos) {
+                        Some(tile) if !tile.transparent() => tiles.push((pos, tile)),
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        tiles
+    }
+
+    fn sphere_cast(&self, ray: &Ray, radius: f32) -> Option<VoxelHit> {
+        let start = ray.pos();
+        let end = ray.pos() + ray.dir();
+
+        let swept = AABB { begin: start.min(end) - Vec3::splat(radius), end: start.max(end) + Vec3::splat(radius) };
+
+        let mut closest: Option<VoxelHit> = None;
+
+        for (pos, tile) in self.overlap_aabb(&swept) {
+            let tile_aabb = AABB { begin: pos.as_vec3(), end: pos.as_vec3() + Vec3::ONE };
+
+            //sphere already overlaps with the tile at the start
+            let t = if start.distance_squared(start.clamp(tile_aabb.begin, tile_aabb.end)) < radius * radius {
+                0.0
+            } else {
+                match ray.test_rounded_aabb(&tile_aabb, radius) {
+                    Some(t) if t <= 1.0 => t,
+                    _ => continue,
+                }
+            };
+
+            if closest.as_ref().map_or(false, |c| c.t <= t) {
+                continue;
+            }
+
+            let center = start + ray.dir() * t;
+            let normal = (center - center.clamp(tile_aabb.begin, tile_aabb.end)).normalize_or_zero();
+
+            closest = Some(VoxelHit { pos, tile, t, normal });
+        }
+
+        closest
+    }
+
+    //y of the highest solid tile in the column, entities stand at the returned height + 1
+    fn find_surface_height(&self, x: i32, z: i32) -> Option<i32> {
+        let (lo, hi) = self.column_range(x, z)?;
+        let cs = CHUNK_SIZE as i32;
+
+        (lo * cs..(hi + 1) * cs).rev().find(|y| self.is_solid(ivec3(x, *y, z)))
+    }
+}
+
+impl VoxelQuery for VoxelWorld {
+    fn tile_at(&self, pos: IVec3) -> Option<Tile> { self.get_tile(pos.to_array()) }
+
+    fn column_range(&self, x: i32, z: i32) -> Option<(i32, i32)> {
+        let cs = CHUNK_SIZE as i32;
+        let (cx, cz) = (x.div_euclid(cs), z.div_euclid(cs));
+
+        self.chunk_positions().filter(|p| p[0] == cx && p[2] == cz).fold(None, |range, p| match range {
+            None => Some((p[1], p[1])),
+            Some((lo, hi)) => Some((lo.min(p[1]), hi.max(p[1]))),
+        })
+    }
+}
+
+impl<'a> VoxelQuery for ChunkView<'a> {
+    fn tile_at(&self, pos: IVec3) -> Option<Tile> { self.get_world_tile(pos.to_array()) }
+
+    fn column_range(&self, x: i32, z: i32) -> Option<(i32, i32)> {
+        let cs = CHUNK_SIZE as i32;
+        let (start, end) = self.chunk_range();
+        let (cx, cz) = (x.div_euclid(cs), z.div_euclid(cs));
+
+        if cx < start[0] || cx > end[0] || cz < start[2] || cz > end[2] {
+            return None;
+        }
+
+        Some((start[1], end[1]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::voxels::{AIR, CHUNK_VOLUME, STONE};
+
+    //loaded chunks are filled with air, solid tiles have to lie in one of them
+    fn world(chunks: &[[i32; 3]], solid: &[[i32; 3]]) -> VoxelWorld {
+        let cs = CHUNK_SIZE as i32;
+        let mut world = VoxelWorld::new();
+        for cpos in chunks {
+            let mut voxels = Box::new([AIR; CHUNK_VOLUME]);
+            for pos in solid.iter().filter(|pos| pos.map(|n| n.div_euclid(cs)) == *cpos) {
+                let [x, y, z] = pos.map(|n| n.rem_euclid(cs) as usize);
+                voxels[x + z * CHUNK_SIZE + y * CHUNK_SIZE * CHUNK_SIZE] = STONE;
+            }
+            world.register_chunk(cpos, voxels);
+        }
+        world
+    }
+
+    fn assert_near(a: f32, b: f32) { assert!((a - b).abs() < 1e-4, "{a} != {b}"); }
+
+    #[test]
+    fn raycast_hits_first_solid_tile() {
+        let world = world(&[[0, 0, 0]], &[[5, 3, 3], [7, 3, 3]]);
+        let hit = world.raycast(&Ray::new(vec3(0.5, 3.5, 3.5), vec3(10.0, 0.0, 0.0))).unwrap();
+
+        assert_eq!(hit.pos, ivec3(5, 3, 3));
+        assert_eq!(hit.tile, STONE);
+        assert_eq!(hit.normal, vec3(-1.0, 0.0, 0.0));
+        assert_near(hit.t, 0.45);
+    }
+
+    #[test]
+    fn raycast_misses_beyond_ray_length() {
+        let world = world(&[[0, 0, 0]], &[[5, 3, 3]]);
+        assert!(world.raycast(&Ray::new(vec3(0.5, 3.5, 3.5), vec3(4.0, 0.0, 0.0))).is_none());
+        assert!(world.raycast(&Ray::new(vec3(0.5, 3.5, 3.5), vec3(0.0, 10.0, 0.0))).is_none());
+    }
+
+    #[test]
+    fn raycast_starting_inside_solid_tile() {
+        let world = world(&[[0, 0, 0]], &[[5, 3, 3]]);
+        let hit = world.raycast(&Ray::new(vec3(5.5, 3.5, 3.5), vec3(1.0, 2.0, 0.0))).unwrap();
+
+        assert_eq!(hit.pos, ivec3(5, 3, 3));
+        assert_eq!(hit.t, 0.0);
+        assert_eq!(hit.normal, Vec3::ZERO);
+    }
+
+    #[test]
+    fn raycast_crosses_chunk_boundaries() {
+        let world = world(&[[0, 0, 0], [1, 0, 0]], &[[33, 3, 3]]);
+        let hit = world.raycast(&Ray::new(vec3(30.5, 3.5, 3.5), vec3(5.0, 0.0, 0.0))).unwrap();
+        assert_eq!(hit.pos, ivec3(33, 3, 3));
+        assert_near(hit.t, 0.5);
+
+        let world = self::world(&[[-1, 0, 0], [0, 0, 0]], &[[-2, 3, 3]]);
+        let hit = world.raycast(&Ray::new(vec3(1.5, 3.5, 3.5), vec3(-5.0, 0.0, 0.0))).unwrap();
+        assert_eq!(hit.pos, ivec3(-2, 3, 3));
+        assert_eq!(hit.normal, vec3(1.0, 0.0, 0.0));
+        assert_near(hit.t, 0.5);
+    }
+
+    #[test]
+    fn raycast_passes_through_unloaded_chunks() {
+        let world = world(&[[1, 0, 0]], &[[34, 3, 3]]);
+        let hit = world.raycast(&Ray::new(vec3(-10.5, 3.5, 3.5), vec3(50.0, 0.0, 0.0))).unwrap();
+        assert_eq!(hit.pos, ivec3(34, 3, 3));
+    }
+
+    #[test]
+    fn overlap_aabb_excludes_touching_tiles() {
+        let world = world(&[[0, 0, 0]], &[[2, 2, 2], [3, 2, 2]]);
+
+        let inside = AABB { begin: vec3(1.5, 1.5, 1.5), end: vec3(2.5, 2.5, 2.5) };
+        assert_eq!(world.overlap_aabb(&inside).iter().map(|(p, _)| *p).collect::<Vec<_>>(), vec![ivec3(2, 2, 2)]);
+
+        let both = AABB { begin: vec3(2.5, 2.5, 2.5), end: vec3(3.5, 2.9, 2.9) };
+        assert_eq!(world.overlap_aabb(&both).len(), 2);
+
+        let touching = AABB { begin: vec3(0.0, 0.0, 0.0), end: vec3(2.0, 2.0, 2.0) };
+        assert!(world.overlap_aabb(&touching).is_empty());
+    }
+
+    #[test]
+    fn sphere_cast_stops_radius_before_the_tile() {
+        let world = world(&[[0, 0, 0]], &[[5, 3, 3]]);
+        let hit = world.sphere_cast(&Ray::new(vec3(0.5, 3.5, 3.5), vec3(10.0, 0.0, 0.0)), 0.25).unwrap();
+
+        assert_eq!(hit.pos, ivec3(5, 3, 3));
+        assert_near(hit.t, 0.425);
+        assert_eq!(hit.normal, vec3(-1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn sphere_cast_misses_and_starts_overlapping() {
+        let world = world(&[[0, 0, 0]], &[[5, 3, 3]]);
+        assert!(world.sphere_cast(&Ray::new(vec3(0.5, 4.3, 3.5), vec3(10.0, 0.0, 0.0)), 0.25).is_none());
+
+        let hit = world.sphere_cast(&Ray::new(vec3(4.9, 3.5, 3.5), vec3(-2.0, 0.0, 0.0)), 0.25).unwrap();
+        assert_eq!(hit.t, 0.0);
+    }
+
+    #[test]
+    fn surface_height_spans_stacked_chunks() {
+        let world = world(&[[0, 0, 0], [0, 1, 0]], &[[4, 10, 4], [4, 40, 4]]);
+
+        assert_eq!(world.find_surface_height(4, 4), Some(40));
+        assert_eq!(world.find_surface_height(5, 4), None);
+        assert_eq!(world.find_surface_height(40, 4), None);
+    }
+
+    #[test]
+    fn chunk_view_matches_voxel_world() {
+        let chunks = [[-1, 0, -1], [0, 0, -1], [0, 0, 0], [1, 1, 0], [0, 1, 1]];
+        let mut seed = 0x2545f491u32;
+        let mut solid = Vec::new();
+        for _ in 0..400 {
+            let mut next = |range: i32| {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                (seed % range as u32) as i32
+            };
+            let chunk = chunks[next(chunks.len() as i32) as usize];
+            solid.push([0, 1, 2].map(|i| chunk[i] * CHUNK_SIZE as i32 + next(CHUNK_SIZE as i32)));
+        }
+
+        let world = world(&chunks, &solid);
+        let view = world.get_chunk_view([-1, 0, -1], [1, 1, 1]);
+
+        for x in -40..70 {
+            for z in -40..70 {
+                assert_eq!(world.find_surface_height(x, z), view.find_surface_height(x, z));
+                for y in [-1, 0, 17, 31, 32, 50, 64] {
+                    assert_eq!(world.tile_at(ivec3(x, y, z)), view.tile_at(ivec3(x, y, z)));
+                }
+            }
+        }
+
+        for (i, pos) in solid.iter().enumerate().step_by(7) {
+            let target = IVec3::from_array(*pos).as_vec3() + Vec3::splat(0.5);
+            let ray = Ray::new(vec3(-20.5 + i as f32 * 0.37, 60.3, 3.7), target - vec3(-20.5 + i as f32 * 0.37, 60.3, 3.7));
+            let (a, b) = (world.raycast(&ray), view.raycast(&ray));
+            assert_eq!(a.as_ref().map(|h| (h.pos, h.t)), b.as_ref().map(|h| (h.pos, h.t)));
+        }
+    }
+}
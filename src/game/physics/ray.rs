@@ -72,9 +72,139 @@ impl Ray {
 
         Some(RayHit { ray: &self, t: t_hit, cn })
     }
+
+    //smallest non negative root of a*t^2 + b*t + c
+    fn first_root(a: f32, b: f32, c: f32) -> Option<f32> {
+        if a == 0.0 {
+            return None;
+        }
+
+        let discriminant = b * b - 4.0 * a * c;
+        if discriminant < 0.0 {
+            return None;
+        }
+
+        let sqrt_d = discriminant.sqrt();
+        let t0 = (-b - sqrt_d) / (2.0 * a);
+        let t1 = (-b + sqrt_d) / (2.0 * a);
+
+        if t0 >= 0.0 {
+            Some(t0)
+        } else if t1 >= 0.0 {
+            Some(t1)
+        } else {
+            None
+        }
+    }
+
+    pub fn test_sphere(&self, center: Vec3, radius: f32) -> Option<f32> {
+        let oc = self.pos - center;
+        Self::first_root(self.dir.dot(self.dir), 2.0 * self.dir.dot(oc), oc.dot(oc) - radius * radius)
+    }
+
+    //cylinder along the given axis, covering [begin, end] on that axis
+    pub fn test_cylinder(&self, center: Vec3, axis: usize, begin: f32, end: f32, radius: f32) -> Option<f32> {
+        let mut oc = self.pos - center;
+        let mut dir = self.dir;
+        oc[axis] = 0.0;
+        dir[axis] = 0.0;
+
+        let t = Self::first_root(dir.dot(dir), 2.0 * dir.dot(oc), oc.dot(oc) - radius * radius)?;
+        let hit = self.pos[axis] + self.dir[axis] * t;
+
+        if hit < begin || hit > end {
+            return None;
+        }
+
+        Some(t)
+    }
+
+    //tests against the aabb inflated by radius with rounded edges, used for sphere casts
+    pub fn test_rounded_aabb(&self, aabb: &AABB, radius: f32) -> Option<f32> {
+        let mut t_min: Option<f32> = None;
+        let mut add_hit = |t: Option<f32>| {
+            if let Some(t) = t {
+                t_min = Some(t_min.map_or(t, |m| m.min(t)));
+            }
+        };
+
+        let corners = [aabb.begin, aabb.end];
+
+        for axis in 0..3 {
+            //faces
+            let mut slab = AABB { begin: aabb.begin, end: aabb.end };
+            slab.begin[axis] -= radius;
+            slab.end[axis] += radius;
+            add_hit(self.test_aabb(&slab, false).map(|h| h.t));
+
+            //edges parallel to the axis
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+            for cu in &corners {
+                for cv in &corners {
+                    let mut center = Vec3::ZERO;
+                    center[u] = cu[u];
+                    center[v] = cv[v];
+                    add_hit(self.test_cylinder(center, axis, aabb.begin[axis], aabb.end[axis], radius));
+                }
+            }
+        }
+
+        //corners
+        for cx in &corners {
+            for cy in &corners {
+                for cz in &corners {
+                    add_hit(self.test_sphere(vec3(cx.x, cy.y, cz.z), radius));
+                }
+            }
+        }
+
+        t_min
+    }
 }
 
 impl<'a> RayHit<'a> {
     pub fn hit_point(&self) -> Vec3 { self.ray.pos + self.ray.dir * self.t }
     pub fn is_in_range(&self) -> bool { self.t <= 1.0 }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_box(begin: Vec3) -> AABB { AABB { begin, end: begin + Vec3::ONE } }
+
+    #[test]
+    fn aabb_hit_reports_entry_face() {
+        let ray = Ray::new(vec3(0.0, 0.5, 0.5), vec3(4.0, 0.0, 0.0));
+        let hit = ray.test_aabb(&unit_box(vec3(2.0, 0.0, 0.0)), true).unwrap();
+
+        assert_eq!(hit.t, 0.5);
+        assert_eq!(hit.cn, vec3(-1.0, 0.0, 0.0));
+        assert_eq!(hit.hit_point(), vec3(2.0, 0.5, 0.5));
+    }
+
+    #[test]
+    fn aabb_behind_or_out_of_range() {
+        let ray = Ray::new(vec3(0.0, 0.5, 0.5), vec3(4.0, 0.0, 0.0));
+
+        assert!(ray.test_aabb(&unit_box(vec3(-3.0, 0.0, 0.0)), false).is_none());
+        assert!(ray.test_aabb(&unit_box(vec3(6.0, 0.0, 0.0)), true).is_none());
+        assert!(!ray.test_aabb(&unit_box(vec3(6.0, 0.0, 0.0)), false).unwrap().is_in_range());
+        assert!(ray.test_aabb(&unit_box(vec3(2.0, 2.0, 0.0)), false).is_none());
+    }
+
+    #[test]
+    fn rounded_aabb_hits_corner_sphere() {
+        let ray = Ray::new(vec3(2.0, 2.0, 2.0), vec3(-3.0, -3.0, -3.0));
+        let t = ray.test_rounded_aabb(&unit_box(Vec3::ZERO), 0.5).unwrap();
+
+        let expected = (3f32.sqrt() - 0.5) / (3.0 * 3f32.sqrt());
+        assert!((t - expected).abs() < 1e-4, "{t} != {expected}");
+    }
+
+    #[test]
+    fn rounded_aabb_hits_inflated_face() {
+        let ray = Ray::new(vec3(-2.0, 0.5, 0.5), vec3(4.0, 0.0, 0.0));
+        assert_eq!(ray.test_rounded_aabb(&unit_box(Vec3::ZERO), 0.5), Some(0.375));
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+
+use eyre::{eyre, Result, WrapErr};
+
+use super::Game;
+
+/*
+    A plugin sets up one subsystem of the game. Ordering between plugins is derived from the names
+    they provide and require (resources, subpasses etc.), so a plugin only runs after everything
+    it depends on has been built.
+*/
+pub trait Plugin {
+    fn name(&self) -> &'static str;
+    fn provides(&self) -> &'static [&'static str] { &[] }
+    fn requires(&self) -> &'static [&'static str] { &[] }
+    fn build(&self, game: &mut Game) -> Result<()>;
+}
+
+pub struct PluginSet<'a> {
+    plugins: Vec<Box<dyn Plugin + 'a>>,
+}
+
+impl<'a> PluginSet<'a> {
+    pub fn new() -> PluginSet<'a> { Self { plugins: Vec::new() } }
+
+    pub fn add(mut self, plugin: impl Plugin + 'a) -> Self {
+        self.plugins.push(Box::new(plugin));
+        self
+    }
+
+    //indices of the plugins in build order, ties are broken by the order plugins were added in
+    pub fn build_order(&self) -> Result<Vec<usize>> {
+        let mut providers: HashMap<&'static str, usize> = HashMap::new();
+        for (i, plugin) in self.plugins.iter().enumerate() {
+            for name in plugin.provides() {
+                if let Some(other) = providers.insert(name, i) {
+                    return Err(eyre!(
+                        "\"{}\" is provided by both plugin \"{}\" and plugin \"{}\"",
+                        name,
+                        self.plugins[other].name(),
+                        plugin.name()
+                    ));
+                }
+            }
+        }
+
+        let mut dependencies: Vec<Vec<usize>> = Vec::with_capacity(self.plugins.len());
+        for plugin in &self.plugins {
+            let mut deps = Vec::new();
+            for name in plugin.requires() {
+                let Some(provider) = providers.get(name) else {
+                    return Err(eyre!(
+                        "plugin \"{}\" requires \"{}\" but no registered plugin provides it",
+                        plugin.name(),
+                        name
+                    ));
+                };
+                deps.push(*provider);
+            }
+            dependencies.push(deps);
+        }
+
+        let mut order = Vec::with_capacity(self.plugins.len());
+        let mut built = vec![false; self.plugins.len()];
+
+        while order.len() < self.plugins.len() {
+            let next = (0..self.plugins.len()).find(|i| !built[*i] && dependencies[*i].iter().all(|d| built[*d]));
+
+            let Some(next) = next else {
+                let remaining: Vec<_> =
+                    (0..self.plugins.len()).filter(|i| !built[*i]).map(|i| self.plugins[i].name()).collect();
+                return Err(eyre!("dependency cycle between plugins: {}", remaining.join(", ")));
+            };
+
+            built[next] = true;
+            order.push(next);
+        }
+
+        Ok(order)
+    }
+
+    pub fn build(self, game: &mut Game) -> Result<()> {
+        for i in self.build_order()? {
+            let plugin = &self.plugins[i];
+            plugin.build(game).wrap_err_with(|| format!("failed to build plugin \"{}\"", plugin.name()))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Dummy {
+        name: &'static str,
+        provides: &'static [&'static str],
+        requires: &'static [&'static str],
+    }
+
+    impl Plugin for Dummy {
+        fn name(&self) -> &'static str { self.name }
+        fn provides(&self) -> &'static [&'static str] { self.provides }
+        fn requires(&self) -> &'static [&'static str] { self.requires }
+        fn build(&self, _game: &mut Game) -> Result<()> { Ok(()) }
+    }
+
+    fn dummy(name: &'static str, provides: &'static [&'static str], requires: &'static [&'static str]) -> Dummy {
+        Dummy { name, provides, requires }
+    }
+
+    fn error(set: PluginSet) -> String { set.build_order().unwrap_err().to_string() }
+
+    #[test]
+    fn plugins_are_built_after_their_requirements() {
+        let set = PluginSet::new()
+            .add(dummy("render", &["gbuffer"], &["voxel_world", "camera"]))
+            .add(dummy("voxels", &["voxel_world"], &[]))
+            .add(dummy("lighting", &[], &["gbuffer"]))
+            .add(dummy("camera", &["camera"], &[]));
+
+        assert_eq!(set.build_order().unwrap(), vec![1, 3, 0, 2]);
+    }
+
+    #[test]
+    fn independent_plugins_keep_their_order() {
+        let set = PluginSet::new().add(dummy("a", &[], &[])).add(dummy("b", &[], &[])).add(dummy("c", &[], &[]));
+        assert_eq!(set.build_order().unwrap(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn missing_requirement_names_plugin_and_requirement() {
+        let set = PluginSet::new().add(dummy("voxels", &["voxel_world"], &[])).add(dummy("physics", &[], &["voxel_world", "time"]));
+        assert_eq!(error(set), "plugin \"physics\" requires \"time\" but no registered plugin provides it");
+    }
+
+    #[test]
+    fn duplicate_provider_names_both_plugins() {
+        let set = PluginSet::new()
+            .add(dummy("worldgen", &["voxel_world"], &[]))
+            .add(dummy("loader", &["saves", "voxel_world"], &[]));
+        assert_eq!(error(set), "\"voxel_world\" is provided by both plugin \"worldgen\" and plugin \"loader\"");
+    }
+
+    #[test]
+    fn cycle_lists_the_plugins_that_couldnt_be_built() {
+        let set = PluginSet::new()
+            .add(dummy("base", &["base"], &[]))
+            .add(dummy("a", &["a"], &["b", "base"]))
+            .add(dummy("b", &["b"], &["a"]));
+        assert_eq!(error(set), "dependency cycle between plugins: a, b");
+
+        let set = PluginSet::new().add(dummy("self", &["self"], &["self"]));
+        assert_eq!(error(set), "dependency cycle between plugins: self");
+    }
+}
@@ -0,0 +1,215 @@
+use std::{f32::consts::PI, path::Path};
+
+use eyre::{eyre, Result, WrapErr};
+use glam::*;
+use specs::prelude::*;
+
+use super::{plugin::Plugin, DeltaTime, Game, Stage};
+
+/*
+    Time of the world, time_of_day goes from 0.0 to 1.0 where 0.0 is midnight,
+    0.25 sunrise, 0.5 noon and 0.75 sunset.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorldTime {
+    pub day: u64,
+    time_of_day: f64,
+    //length of a full day in seconds
+    pub day_length: f64,
+    pub paused: bool,
+}
+
+impl Default for WorldTime {
+    fn default() -> Self { Self { day: 0, time_of_day: 0.3, day_length: 20.0 * 60.0, paused: false } }
+}
+
+impl WorldTime {
+    pub fn time_of_day(&self) -> f64 { self.time_of_day }
+    pub fn set_time_of_day(&mut self, time_of_day: f64) { self.time_of_day = time_of_day.rem_euclid(1.0); }
+
+    pub fn pause(&mut self) { self.paused = true; }
+    pub fn resume(&mut self) { self.paused = false; }
+
+    pub fn advance(&mut self, delta_time: f64) {
+        if self.paused || self.day_length <= 0.0 {
+            return;
+        }
+
+        let time = self.time_of_day + delta_time / self.day_length;
+        self.day += time.floor() as u64;
+        self.time_of_day = time.fract();
+    }
+
+    //unit vector pointing towards the sun, the sun rises at +x and sets at -x
+    pub fn sun_direction(&self) -> Vec3 {
+        let angle = (self.time_of_day as f32 - 0.25) * 2.0 * PI;
+        vec3(angle.cos(), angle.sin(), 0.2).normalize()
+    }
+
+    //0.0 at night, 1.0 during the day with a smooth transition around sunrise and sunset
+    pub fn daylight(&self) -> f32 {
+        let elevation = self.sun_direction().y;
+        let t = ((elevation + 0.1) / 0.3).clamp(0.0, 1.0);
+        t * t * (3.0 - 2.0 * t)
+    }
+
+    pub fn to_config(&self) -> String {
+        format!(
+            "day = {}\ntime_of_day = {}\nday_length = {}\npaused = {}\n",
+            self.day, self.time_of_day, self.day_length, self.paused
+        )
+    }
+
+    pub fn from_config(config: &str) -> Result<WorldTime> {
+        let mut time = WorldTime::default();
+
+        for (i, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line.split_once('=').ok_or_else(|| eyre!("line {}: expected '='", i + 1))?;
+            let (key, value) = (key.trim(), value.trim());
+            let invalid = || eyre!("line {}: invalid value \"{}\" for {}", i + 1, value, key);
+
+            match key {
+                "day" => time.day = value.parse().map_err(|_| invalid())?,
+                "time_of_day" => time.set_time_of_day(value.parse().map_err(|_| invalid())?),
+                "day_length" => time.day_length = value.parse().map_err(|_| invalid())?,
+                "paused" => time.paused = value.parse().map_err(|_| invalid())?,
+                _ => return Err(eyre!("line {}: unknown key \"{}\"", i + 1, key)),
+            }
+        }
+
+        Ok(time)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        std::fs::write(path, self.to_config())?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<WorldTime> {
+        let path = path.as_ref();
+        let config = std::fs::read_to_string(path).wrap_err_with(|| format!("couldn't read {}", path.display()))?;
+        Self::from_config(&config).wrap_err_with(|| format!("invalid world time {}", path.display()))
+    }
+}
+
+struct AdvanceTime;
+
+impl<'a> System<'a> for AdvanceTime {
+    type SystemData = (ReadExpect<'a, DeltaTime>, WriteExpect<'a, WorldTime>);
+
+    fn run(&mut self, (delta_time, mut time): Self::SystemData) { time.advance(delta_time.0); }
+}
+
+pub struct TimePlugin;
+
+impl Plugin for TimePlugin {
+    fn name(&self) -> &'static str { "time" }
+    fn provides(&self) -> &'static [&'static str] { &["world_time"] }
+    fn build(&self, game: &mut Game) -> Result<()> {
+        game.world.insert(WorldTime::default());
+        game.add_system(Stage::Simulation, AdvanceTime, "advance time", &[]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(a: f64, b: f64) { assert!((a - b).abs() < 1e-9, "{a} != {b}"); }
+
+    fn time(time_of_day: f64) -> WorldTime {
+        let mut time = WorldTime { day_length: 100.0, ..Default::default() };
+        time.set_time_of_day(time_of_day);
+        time
+    }
+
+    #[test]
+    fn advance_wraps_into_the_next_days() {
+        let mut time = time(0.3);
+        time.advance(50.0);
+        assert_eq!(time.day, 0);
+        assert_near(time.time_of_day(), 0.8);
+
+        time.advance(30.0);
+        assert_eq!(time.day, 1);
+        assert_near(time.time_of_day(), 0.1);
+
+        time.advance(250.0);
+        assert_eq!(time.day, 3);
+        assert_near(time.time_of_day(), 0.6);
+    }
+
+    #[test]
+    fn paused_time_doesnt_advance() {
+        let mut time = time(0.3);
+        time.pause();
+        time.advance(500.0);
+        assert_eq!((time.day, time.time_of_day()), (0, 0.3));
+
+        time.resume();
+        time.advance(10.0);
+        assert_near(time.time_of_day(), 0.4);
+
+        //a day without length never advances
+        time.day_length = 0.0;
+        time.advance(10.0);
+        assert_near(time.time_of_day(), 0.4);
+    }
+
+    #[test]
+    fn set_time_of_day_wraps() {
+        assert_eq!(time(0.5).time_of_day(), 0.5);
+        assert_eq!(time(1.25).time_of_day(), 0.25);
+        assert_eq!(time(-0.25).time_of_day(), 0.75);
+        assert_eq!(time(1.0).time_of_day(), 0.0);
+    }
+
+    #[test]
+    fn sun_is_up_at_noon_and_down_at_midnight() {
+        assert!(time(0.5).sun_direction().y > 0.9);
+        assert!(time(0.0).sun_direction().y < -0.9);
+        assert!(time(0.25).sun_direction().x > 0.9);
+        assert!(time(0.75).sun_direction().x < -0.9);
+
+        assert_eq!(time(0.5).daylight(), 1.0);
+        assert_eq!(time(0.0).daylight(), 0.0);
+        let dusk = time(0.75).daylight();
+        assert!(dusk > 0.0 && dusk < 1.0);
+    }
+
+    #[test]
+    fn config_round_trips() {
+        let time = WorldTime { day: 12, time_of_day: 0.123456789, day_length: 321.5, paused: true };
+        assert_eq!(WorldTime::from_config(&time.to_config()).unwrap(), time);
+
+        //missing keys keep their defaults
+        let partial = WorldTime::from_config("# saved by hand\nday = 3\n").unwrap();
+        assert_eq!(partial, WorldTime { day: 3, ..Default::default() });
+    }
+
+    #[test]
+    fn invalid_config_names_the_line() {
+        let error = |config: &str| WorldTime::from_config(config).unwrap_err().to_string();
+        assert_eq!(error("day = 1\npaused = maybe"), "line 2: invalid value \"maybe\" for paused");
+        assert_eq!(error("day 1"), "line 1: expected '='");
+        assert_eq!(error("hour = 5"), "line 1: unknown key \"hour\"");
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let path = std::env::temp_dir().join(format!("world_time_{}.cfg", std::process::id()));
+        let time = WorldTime { day: 2, time_of_day: 0.7, day_length: 60.0, paused: false };
+
+        time.save(&path).unwrap();
+        assert_eq!(WorldTime::load(&path).unwrap(), time);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(WorldTime::load(&path).is_err());
+    }
+}
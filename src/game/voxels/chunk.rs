@@ -3,13 +3,16 @@ use std::{collections::HashMap, ops::Add, sync::Mutex};
 use glam::Vec3;
 use specs::prelude::*;
 
-use crate::game::Game;
+use crate::game::{plugin::Plugin, Game, Stage};
 
 use super::{worldgen::WorldGen, *};
 
 pub const CHUNK_SIZE: usize = 32;
 pub const CHUNK_AREA: usize = CHUNK_SIZE * CHUNK_SIZE;
 pub const CHUNK_VOLUME: usize = CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE;
+//chunks are split into slabs along y that are meshed and drawn on their own, so tile edits only remesh a slab
+pub const SECTION_HEIGHT: usize = 8;
+pub const SECTION_COUNT: usize = CHUNK_SIZE / SECTION_HEIGHT;
 
 pub fn chunk_to_world_pos([cx, cy, cz]: [i32; 3]) -> Vec3 {
     Vec3::new((cx * CHUNK_SIZE as i32) as f32, (cy * CHUNK_SIZE as i32) as f32, (cz * CHUNK_SIZE as i32) as f32)
@@ -72,10 +75,12 @@ impl<'a> ChunkRefMut<'a> {
 #[derive(Debug)]
 pub struct VoxelWorld {
     chunk_voxels: HashMap<[i32; 3], Box<[Tile; CHUNK_VOLUME]>>,
+    //sections touched by set_tile since the last ClearModified, one bit per section
+    edited_sections: HashMap<[i32; 3], u8>,
 }
 
 impl VoxelWorld {
-    pub fn new() -> VoxelWorld { Self { chunk_voxels: HashMap::new() } }
+    pub fn new() -> VoxelWorld { Self { chunk_voxels: HashMap::new(), edited_sections: HashMap::new() } }
 
     pub fn get_chunk(&self, pos: &[i32; 3]) -> Option<ChunkRef> {
         self.chunk_voxels.get(pos).and_then(|c| Some(ChunkRef { voxel_ref: c, cpos: *pos }))
@@ -89,11 +94,79 @@ impl VoxelWorld {
         self.chunk_voxels.insert(*pos, voxels);
     }
 
-    pub fn remove_chunk(&mut self, pos: &[i32; 3]) -> Option<Box<[Tile; CHUNK_VOLUME]>> { self.chunk_voxels.remove(pos) }
+    pub fn remove_chunk(&mut self, pos: &[i32; 3]) -> Option<Box<[Tile; CHUNK_VOLUME]>> {
+        self.edited_sections.remove(pos);
+        self.chunk_voxels.remove(pos)
+    }
+
+    pub fn chunk_positions(&self) -> impl Iterator<Item = &[i32; 3]> { self.chunk_voxels.keys() }
+
+    //returns none if the chunk containing the tile isn't loaded
+    pub fn get_tile(&self, [x, y, z]: [i32; 3]) -> Option<Tile> {
+        let cs = CHUNK_SIZE as i32;
+        let chunk = self.get_chunk(&[x.div_euclid(cs), y.div_euclid(cs), z.div_euclid(cs)])?;
+        Some(chunk.get_block(x.rem_euclid(cs) as usize, y.rem_euclid(cs) as usize, z.rem_euclid(cs) as usize))
+    }
+
+    /*
+        Changes a single tile without marking the whole chunk as modified. Every section holding the
+        tile or one of its 26 neighbours is marked as edited, ambient occlusion reads the diagonal
+        ones. Returns the previous tile or none if the chunk containing it isn't loaded.
+    */
+    pub fn set_tile(&mut self, [x, y, z]: [i32; 3], tile: Tile) -> Option<Tile> {
+        let cs = CHUNK_SIZE as i32;
+        let mut chunk = self.get_chunk_mut(&[x.div_euclid(cs), y.div_euclid(cs), z.div_euclid(cs)])?;
+        let block = chunk.get_block(x.rem_euclid(cs) as usize, y.rem_euclid(cs) as usize, z.rem_euclid(cs) as usize);
+        let previous = std::mem::replace(block, tile);
+        if previous == tile {
+            return Some(previous);
+        }
+
+        for dz in -1..=1 {
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let pos = [x + dx, y + dy, z + dz];
+                    let cpos = pos.map(|n| n.div_euclid(cs));
+                    if self.chunk_voxels.contains_key(&cpos) {
+                        let section = pos[1].rem_euclid(cs) as usize / SECTION_HEIGHT;
+                        *self.edited_sections.entry(cpos).or_insert(0) |= 1 << section;
+                    }
+                }
+            }
+        }
+
+        Some(previous)
+    }
+
+    pub fn edited_sections(&self) -> &HashMap<[i32; 3], u8> { &self.edited_sections }
+    pub fn clear_edits(&mut self) { self.edited_sections.clear(); }
 }
 
 const empty_voxels: [Tile; CHUNK_VOLUME] = [Tile(0); CHUNK_VOLUME];
 
+//radius of loaded chunks around the origin, in chunks
+#[derive(Clone, Copy, Debug)]
+pub struct ViewDistance(pub i32);
+
+impl Default for ViewDistance {
+    fn default() -> Self { Self(10) }
+}
+
+impl ViewDistance {
+    pub fn blocks(&self) -> f32 { (self.0 * CHUNK_SIZE as i32) as f32 }
+}
+
+pub struct VoxelPlugin;
+
+impl Plugin for VoxelPlugin {
+    fn name(&self) -> &'static str { "voxels" }
+    fn provides(&self) -> &'static [&'static str] { &["voxel_world", "worldgen"] }
+    fn build(&self, game: &mut Game) -> eyre::Result<()> {
+        init(game);
+        Ok(())
+    }
+}
+
 pub fn init(game: &mut Game) {
     game.world.register::<ChunkComponent>();
     game.world.register::<ModifiedChunk>();
@@ -101,10 +174,14 @@ pub fn init(game: &mut Game) {
 
     game.world.create_entity().with(ChunkComponent { chunkpos: [0, 0, 0] }).with(ModifiedChunk).build();
 
+    let view_distance = ViewDistance::default();
+    let r = view_distance.0;
+    game.world.insert(view_distance);
+
     let mut worldgen = WorldGen::new(374437);
-    for x in -10..11 {
-        for z in -10..11 {
-            if x * x + z * z <= 100 {
+    for x in -r..=r {
+        for z in -r..=r {
+            if x * x + z * z <= r * r {
                 for y in 0..3 {
                     worldgen.queue_chunk([x, y, z]);
                 }
@@ -114,33 +191,49 @@ pub fn init(game: &mut Game) {
 
     game.world.insert(Mutex::new(worldgen));
 
-    game.insert_frame_task(Box::new(|w, d| {
-        d.add_thread_local(ClearModified {});
-
-        let chunks = w.fetch_mut::<Mutex<WorldGen>>().lock().unwrap().receive_chunks();
-        for c in &chunks {
-            w.create_entity().with(ChunkComponent { chunkpos: c.pos }).with(ModifiedChunk).build();
-        }
+    game.add_system(Stage::Simulation, ReceiveChunks, "receive chunks", &[]);
+    game.add_thread_local_system(ClearModified {});
+}
 
-        let mut vw = w.fetch_mut::<VoxelWorld>();
-        for c in chunks {
-            vw.register_chunk(&c.pos, c.voxels);
+struct ReceiveChunks;
+
+impl<'a> System<'a> for ReceiveChunks {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, ChunkComponent>,
+        WriteStorage<'a, ModifiedChunk>,
+        WriteExpect<'a, VoxelWorld>,
+        ReadExpect<'a, Mutex<WorldGen>>,
+    );
+
+    fn run(&mut self, (entities, mut chunks, mut modified, mut vworld, worldgen): Self::SystemData) {
+        for c in worldgen.lock().unwrap().receive_chunks() {
+            entities
+                .build_entity()
+                .with(ChunkComponent { chunkpos: c.pos }, &mut chunks)
+                .with(ModifiedChunk, &mut modified)
+                .build();
+            vworld.register_chunk(&c.pos, c.voxels);
         }
-    }));
+    }
 }
 
 struct ClearModified;
 
 impl<'a> System<'a> for ClearModified {
-    type SystemData = WriteStorage<'a, ModifiedChunk>;
+    type SystemData = (WriteStorage<'a, ModifiedChunk>, WriteExpect<'a, VoxelWorld>);
 
-    fn run(&mut self, mut data: Self::SystemData) { data.clear(); }
+    fn run(&mut self, (mut modified, mut vworld): Self::SystemData) {
+        modified.clear();
+        vworld.clear_edits();
+    }
 }
 
 pub struct ChunkView<'a> {
     chunks: Vec<ChunkRef<'a>>,
     grid_size_x: u32,
     grid_size_xy: u32, //grid size x * y
+    origin: [i32; 3], //chunk position of the first chunk
     pub offsets: [i32; 3],
 }
 
@@ -159,6 +252,38 @@ impl<'a> ChunkView<'a> {
         let chunk = &self.chunks[chunk_index];
         chunk.get_block(x % CHUNK_SIZE, y % CHUNK_SIZE, z % CHUNK_SIZE)
     }
+
+    //inclusive range of chunk positions covered by the view
+    pub fn chunk_range(&self) -> ([i32; 3], [i32; 3]) {
+        let size = [
+            self.grid_size_x,
+            self.grid_size_xy / self.grid_size_x,
+            self.chunks.len() as u32 / self.grid_size_xy,
+        ];
+        (self.origin, [0, 1, 2].map(|i| self.origin[i] + size[i] as i32 - 1))
+    }
+
+    //same as VoxelWorld::get_tile, ignores offsets and returns none outside the view or for unloaded chunks
+    pub fn get_world_tile(&self, pos: [i32; 3]) -> Option<Tile> {
+        let cs = CHUNK_SIZE as i32;
+        let (start, end) = self.chunk_range();
+        let cpos = pos.map(|n| n.div_euclid(cs));
+
+        if (0..3).any(|i| cpos[i] < start[i] || cpos[i] > end[i]) {
+            return None;
+        }
+
+        let [gx, gy, gz] = [0, 1, 2].map(|i| (cpos[i] - start[i]) as usize);
+        let chunk = &self.chunks[gx + gy * self.grid_size_x as usize + gz * self.grid_size_xy as usize];
+
+        //empty placeholder chunks don't have a valid position
+        if chunk.cpos != cpos {
+            return None;
+        }
+
+        let [x, y, z] = pos.map(|n| n.rem_euclid(cs) as usize);
+        Some(chunk.get_block(x, y, z))
+    }
 }
 
 pub fn world_pos_to_chunkpos(worldpos: [i32; 3]) -> [i32; 3] {
@@ -196,6 +321,6 @@ impl VoxelWorld {
             }
         }
 
-        ChunkView { chunks, grid_size_x, grid_size_xy, offsets: [0; 3] }
+        ChunkView { chunks, grid_size_x, grid_size_xy, origin: [start_x, start_y, start_z], offsets: [0; 3] }
     }
 }
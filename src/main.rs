@@ -6,16 +6,29 @@ mod game;
 mod render;
 mod util;
 
+const SAVE_DIR: &str = "saves/world";
+//beside the world so loading doesn't find a save before the world was saved once
+const MESH_CACHE_DIR: &str = "saves/mesh_cache";
 
 
 fn main() -> eyre::Result<()>{
     // magma_renderer::engine::material::foo();
     // return Ok(());
 
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(i) = args.iter().position(|a| a == "--headless") {
+        let steps = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(600);
+        return run_headless(steps);
+    }
+
     let mut window = window::Window::new()?;
     let core = window.core.clone();
 
-    let mut game = game::Game::new(&core, &window.renderpass);
+    let mut game = game::Game::new(&core, &window.renderpass)?;
+    if std::path::Path::new(SAVE_DIR).exists() {
+        game.load_world(SAVE_DIR)?;
+    }
+    render::chunk_render::enable_mesh_cache(&game.world, MESH_CACHE_DIR);
     // window.lock_cursor();
     while window.prepare_and_poll_events()? {
         let mut cmd = CommandBuffer::new(&core);
@@ -27,9 +40,20 @@ fn main() -> eyre::Result<()>{
     }
 
     println!("exiting");
+    game.save_world(SAVE_DIR)?;
 
     let a = &game.world as &dyn Any;
     
 
     Ok(())
-}
\ No newline at end of file
+}
+
+fn run_headless(steps: u64) -> eyre::Result<()> {
+    let mut runner = game::headless::HeadlessRunner::new(1.0 / 60.0)?;
+    runner.run(steps);
+
+    let chunk_count = runner.world().fetch::<game::voxels::VoxelWorld>().chunk_positions().count();
+    println!("simulated {} steps, {} chunks loaded", runner.steps(), chunk_count);
+
+    Ok(())
+}
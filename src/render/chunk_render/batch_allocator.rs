@@ -0,0 +1,768 @@
+use std::collections::{HashMap, HashSet};
+
+/*
+    Cpu side bookkeeping of batches of primatives in pools of fixed size reigons, without any gpu
+    resources. Every operation appends what has to happen to the buffers to AllocatorOps, which
+    the PrimativeManager records into a command buffer. Offsets and counts of batches are in
+    primatives, the emitted copies are in bytes.
+*/
+
+//batches store their pool id in 8 bits and the cull shader has this many draw counters per primative type
+pub const MAX_POOLS: u32 = 256;
+
+#[derive(Clone, Copy)]
+struct Batch {
+    count: u32,
+    offset: u32,
+    pool_id: u32,
+}
+
+impl Batch {
+    fn compact(&self) -> [u32; 2] { [self.offset, (self.pool_id << 24) | (self.count & 0xFF_FFFF)] }
+}
+
+struct Reigon {
+    primative_offset: u32,
+    cap: u32,
+    top: u32, //relative to offset
+    usage: u32,
+    batch_ids: HashSet<u32>,
+    is_written: bool,
+}
+
+struct Pool {
+    pool_id: u32,
+    reigons: Vec<Reigon>,
+}
+
+#[derive(Clone, Copy)]
+pub struct BatchUpload {
+    pub byte_offset: u32,
+    pub primative_count: u32,
+    pub id: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CopySource {
+    //the upload buffer the BatchUpload offsets point into
+    Upload,
+    Pool(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CopyOp {
+    pub src: CopySource,
+    pub src_offset: u64,
+    pub dst_pool: u32,
+    pub dst_offset: u64,
+    pub size: u64,
+}
+
+//buffers of created pools have to exist before the copies are recorded, released ones are only dropped after
+#[derive(Debug, Default)]
+pub struct AllocatorOps {
+    //pool id and size in bytes
+    pub created_pools: Vec<(u32, u64)>,
+    pub copies: Vec<CopyOp>,
+    pub released_pools: Vec<u32>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PrimativeStats {
+    pub pool_count: usize,
+    pub batch_count: usize,
+    pub used_bytes: u64,
+    //bytes that can still be allocated at the top of the reigons
+    pub free_bytes: u64,
+    //bytes below the top of the reigons that belonged to removed batches
+    pub fragmented_bytes: u64,
+    //reigons counted by fragmentation score in steps of 0.1
+    pub fragmentation_histogram: [u32; 10],
+    pub released_pools: u64,
+}
+
+pub struct BatchAllocator {
+    primative_size: u32,
+    reigon_size: u32,
+    //released pools leave a hole, batches store the pool id so the other pools can't move
+    pools: Vec<Option<Pool>>,
+    batches: HashMap<u32, Batch>,
+    updated_batches: HashSet<u32>,
+    released_pools: u64,
+}
+
+impl BatchAllocator {
+    pub fn new(primative_size: u32, reigon_size: u32) -> BatchAllocator {
+        Self {
+            primative_size,
+            reigon_size,
+            pools: Vec::new(),
+            batches: HashMap::new(),
+            updated_batches: HashSet::new(),
+            released_pools: 0,
+        }
+    }
+
+    pub fn batch_count(&self) -> u32 { self.batches.len() as u32 }
+    pub fn pool_batch_count(&self, pool_id: u32) -> u32 {
+        self.pool(pool_id).reigons.iter().map(|r| r.batch_ids.len() as u32).sum()
+    }
+
+    fn live_pools(&self) -> impl Iterator<Item = &Pool> { self.pools.iter().flatten() }
+    fn pool(&self, pool_id: u32) -> &Pool { self.pools[pool_id as usize].as_ref().unwrap() }
+    fn pool_mut(&mut self, pool_id: u32) -> &mut Pool { self.pools[pool_id as usize].as_mut().unwrap() }
+
+    pub fn reigon_size(&self) -> u32 { self.reigon_size }
+
+    /*
+        A batch with an id that is already allocated replaces it. Fails without changing anything if a
+        batch is larger than a reigon, and if a new pool would be needed past MAX_POOLS, the batches
+        placed and pools created before that are rolled back.
+    */
+    pub fn insert(&mut self, uploads: &[BatchUpload], ops: &mut AllocatorOps) -> eyre::Result<()> {
+        if let Some(upload) = uploads.iter().find(|u| u.primative_count > self.reigon_size) {
+            eyre::bail!(
+                "batch {} with {} primatives doesn't fit into a reigon of {}",
+                upload.id,
+                upload.primative_count,
+                self.reigon_size
+            );
+        }
+
+        let undo = InsertUndo::new(self, uploads, ops);
+        if let Err(e) = self.insert_batches(uploads, ops) {
+            undo.restore(self, uploads, ops);
+            return Err(e);
+        }
+
+        uploads.iter().for_each(|u| {
+            self.updated_batches.insert(u.id);
+        });
+        Ok(())
+    }
+
+    fn insert_batches(&mut self, uploads: &[BatchUpload], ops: &mut AllocatorOps) -> eyre::Result<()> {
+        uploads.iter().for_each(|u| self.remove_from_reigon(u.id));
+        let mut i = 0;
+
+        while i < uploads.len() {
+            let mut reigons: Vec<_> = self
+                .live_pools()
+                .flat_map(|p| p.reigons.iter().enumerate().map(|(rid, r)| (p.pool_id, rid as u32, r.score())))
+                .filter(|(_, _, score)| *score > 0.5)
+                .collect();
+
+            reigons.sort_unstable_by(|a, b| a.2.total_cmp(&b.2).reverse());
+
+            let inserted_before = i;
+            self.insert_into_reigons(uploads, &reigons, &mut i, ops);
+
+            //none of the reigons had room for the next batch
+            if i == inserted_before {
+                self.new_pool(ops)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, ids: &[u32]) {
+        for id in ids {
+            self.remove_from_reigon(*id);
+            self.batches.remove(id);
+            self.updated_batches.insert(*id);
+        }
+    }
+
+    //should be called once per frame after the last insert, reigons written this frame aren't swept
+    pub fn end_frame(&mut self) {
+        for pool in self.pools.iter_mut().flatten() {
+            for reigon in &mut pool.reigons {
+                reigon.is_written = false; //set it back to false for the next frame
+            }
+        }
+    }
+
+    //batches whose gpu description changed and their compacted description, removed batches are zeroed
+    pub fn take_updated_batches(&mut self) -> Vec<(u32, [u32; 2])> {
+        let updated = self.updated_batches.drain().map(|id| (id, self.batches.get(&id).map_or([0, 0], |b| b.compact())));
+        updated.collect()
+    }
+
+    pub fn sweep(&mut self, ops: &mut AllocatorOps) -> eyre::Result<()> {
+        let mut fragmented_reigons: Vec<_> = self
+            .live_pools()
+            .flat_map(|p| p.reigons.iter().enumerate().map(|(rid, r)| (p.pool_id, rid as u32, r.fragmentation_score())))
+            .filter(|(_, _, score)| *score > 0.15)
+            .filter(|(p, r, _)| self.pool(*p).reigons[*r as usize].is_written == false) //check if it is written this frame
+            .collect();
+
+        if fragmented_reigons.len() == 0 {
+            return Ok(());
+        }
+
+        fragmented_reigons.sort_unstable_by(|a, b| a.2.total_cmp(&b.2).reverse());
+
+        fragmented_reigons.truncate(4); //we want to sweep at max 4 reigon
+
+        let mut dst_reigons: Vec<_> = self
+            .live_pools()
+            .flat_map(|p| p.reigons.iter().enumerate().map(|(rid, r)| (p.pool_id, rid as u32, r.fragmentation_score())))
+            .filter(|(_, _, score)| *score < 0.08)
+            .collect();
+
+        if dst_reigons.len() == 0 {
+            return Ok(());
+        }
+
+        dst_reigons.sort_unstable_by(|a, b| a.2.total_cmp(&b.2));
+
+        let mut dst_reigon_index = 0;
+
+        for (poolid, reigonid, _) in &fragmented_reigons {
+            let mut batches = self.pool_mut(*poolid).reigons[*reigonid as usize].reset_and_get_batches();
+
+            while dst_reigon_index < dst_reigons.len() {
+                let (dst_pool_id, dst_reigon_id, _) = dst_reigons[dst_reigon_index];
+                self.sweep_batches_to_reigon(*poolid, &mut batches, dst_pool_id, dst_reigon_id, ops);
+                if batches.len() > 0 {
+                    dst_reigon_index += 1;
+                } else {
+                    break;
+                }
+            }
+
+            //if we are out of clean reigons handle the remaining batches and break.
+            if dst_reigon_index >= dst_reigons.len() {
+                self.handle_remaining_batches(*poolid, *reigonid, batches, ops);
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    //releases pools without batches, one empty pool is kept so alternating inserts and removals don't recreate it
+    pub fn release_empty_pools(&mut self, ops: &mut AllocatorOps) {
+        let mut kept_empty = false;
+
+        for slot in self.pools.iter_mut().rev() {
+            if !slot.as_ref().map_or(false, |p| p.is_empty()) {
+                continue;
+            }
+            if !kept_empty {
+                kept_empty = true;
+                continue;
+            }
+
+            ops.released_pools.push(slot.take().unwrap().pool_id);
+            self.released_pools += 1;
+        }
+    }
+
+    pub fn stats(&self) -> PrimativeStats {
+        let mut stats = PrimativeStats {
+            pool_count: self.live_pools().count(),
+            batch_count: self.batches.len(),
+            released_pools: self.released_pools,
+            ..Default::default()
+        };
+
+        for reigon in self.live_pools().flat_map(|p| p.reigons.iter()) {
+            stats.used_bytes += reigon.usage as u64 * self.primative_size as u64;
+            stats.free_bytes += (reigon.cap - reigon.top) as u64 * self.primative_size as u64;
+            stats.fragmented_bytes += (reigon.top - reigon.usage) as u64 * self.primative_size as u64;
+            stats.fragmentation_histogram[((reigon.fragmentation_score() * 10.0) as usize).min(9)] += 1;
+        }
+
+        stats
+    }
+
+    /*
+        Checks that the bookkeeping is consistent, every batch lies inside the reigon that lists it,
+        reigons only list batches they hold and their usage is the sum of their batches.
+    */
+    pub fn validate(&self) -> eyre::Result<()> {
+        for (id, batch) in &self.batches {
+            let Some(Some(pool)) = self.pools.get(batch.pool_id as usize) else {
+                eyre::bail!("batch {id} is in missing pool {}", batch.pool_id)
+            };
+            let reigon = &pool.reigons[(batch.offset / self.reigon_size) as usize];
+            if !reigon.batch_ids.contains(id) {
+                eyre::bail!("batch {id} is not listed by its reigon");
+            }
+            if batch.offset + batch.count > reigon.primative_offset + reigon.top {
+                eyre::bail!("batch {id} ends above the top of its reigon");
+            }
+        }
+
+        for pool in self.live_pools() {
+            for (rid, reigon) in pool.reigons.iter().enumerate() {
+                let mut ranges = Vec::new();
+                for id in &reigon.batch_ids {
+                    match self.batches.get(id) {
+                        Some(b) if b.pool_id == pool.pool_id && b.offset / self.reigon_size == rid as u32 => {
+                            ranges.push((b.offset, b.offset + b.count))
+                        }
+                        _ => eyre::bail!("reigon {rid} of pool {} lists batch {id} it doesn't hold", pool.pool_id),
+                    }
+                }
+
+                let usage: u32 = ranges.iter().map(|(start, end)| end - start).sum();
+                if usage != reigon.usage || reigon.usage > reigon.top || reigon.top > reigon.cap {
+                    eyre::bail!("reigon {rid} of pool {} has usage {} top {} but holds {usage}", pool.pool_id, reigon.usage, reigon.top);
+                }
+
+                ranges.sort_unstable();
+                if ranges.windows(2).any(|w| w[0].1 > w[1].0) {
+                    eyre::bail!("reigon {rid} of pool {} has overlapping batches", pool.pool_id);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    //without a free pool slot the batches stay in the reigon they were swept from, it stays fragmented
+    fn handle_remaining_batches(
+        &mut self,
+        src_pool_id: u32,
+        src_reigon_id: u32,
+        mut batches: Vec<u32>,
+        ops: &mut AllocatorOps,
+    ) {
+        let Ok(dst_pool_id) = self.new_pool(ops) else {
+            self.keep_in_reigon(src_pool_id, src_reigon_id, batches);
+            return;
+        };
+        for dst_reigon_id in 0..self.pool(dst_pool_id).reigons.len() as u32 {
+            if batches.is_empty() {
+                break;
+            }
+            self.sweep_batches_to_reigon(src_pool_id, &mut batches, dst_pool_id, dst_reigon_id, ops);
+        }
+    }
+
+    //lists batches that weren't moved out of a reset reigon again, at the offsets they still have
+    fn keep_in_reigon(&mut self, pool_id: u32, reigon_id: u32, batches: Vec<u32>) {
+        let reigon = &mut self.pools[pool_id as usize].as_mut().unwrap().reigons[reigon_id as usize];
+        for id in batches {
+            let Some(batch) = self.batches.get(&id) else { continue };
+            reigon.batch_ids.insert(id);
+            reigon.usage += batch.count;
+            reigon.top = reigon.top.max(batch.offset + batch.count - reigon.primative_offset);
+        }
+    }
+
+    fn sweep_batches_to_reigon(
+        &mut self,
+        src_pool_id: u32,
+        batches: &mut Vec<u32>,
+        dst_pool_id: u32,
+        dst_reigon_id: u32,
+        ops: &mut AllocatorOps,
+    ) {
+        let dst_pool = self.pools[dst_pool_id as usize].as_mut().unwrap();
+        let dst_reigon = &mut dst_pool.reigons[dst_reigon_id as usize];
+
+        while let Some(batch_id) = batches.pop() {
+            let Some(batch) = self.batches.get_mut(&batch_id) else {
+                eprintln!("batch_id: {batch_id} is not found. skipping it in sweep");
+                continue;
+            };
+
+            let Some(new_offset) = dst_reigon.allocate(batch.count) else {
+                batches.push(batch_id);//push batch id back in order to prevent leakage
+                break;
+            };
+
+            dst_reigon.batch_ids.insert(batch_id);
+            ops.copies.push(CopyOp {
+                src: CopySource::Pool(src_pool_id),
+                src_offset: batch.offset as u64 * self.primative_size as u64,
+                dst_pool: dst_pool_id,
+                dst_offset: new_offset as u64 * self.primative_size as u64,
+                size: batch.count as u64 * self.primative_size as u64,
+            });
+
+            batch.pool_id = dst_pool_id;
+            batch.offset = new_offset;
+
+            // set batch to updated
+            self.updated_batches.insert(batch_id);
+        }
+    }
+
+    fn remove_from_reigon(&mut self, id: u32) {
+        if let Some(batch) = self.batches.get(&id) {
+            let pool = self.pools[batch.pool_id as usize].as_mut().unwrap();
+            let reigon = &mut pool.reigons[(batch.offset / self.reigon_size) as usize];
+            if reigon.batch_ids.remove(&id) {
+                reigon.usage -= batch.count;
+            }
+            //nothing is left below the top so the whole reigon can be reused
+            if reigon.batch_ids.is_empty() {
+                reigon.top = 0;
+            }
+        }
+    }
+
+    fn insert_into_reigons(&mut self, uploads: &[BatchUpload], reigons: &[(u32, u32, f32)], i: &mut usize, ops: &mut AllocatorOps) {
+        for (pid, rid, _) in reigons {
+            if *i >= uploads.len() {
+                break;
+            }
+
+            let pool = self.pools[*pid as usize].as_mut().unwrap();
+            let reigon = &mut pool.reigons[*rid as usize];
+            reigon.is_written = true;
+
+            while let Some(upload) = uploads.get(*i) {
+                let Some(alloc_offset) = reigon.allocate(upload.primative_count) else {break};
+
+                ops.copies.push(CopyOp {
+                    src: CopySource::Upload,
+                    src_offset: upload.byte_offset as u64,
+                    dst_pool: *pid,
+                    dst_offset: alloc_offset as u64 * self.primative_size as u64,
+                    size: upload.primative_count as u64 * self.primative_size as u64,
+                });
+                self.batches.insert(upload.id, Batch { count: upload.primative_count, offset: alloc_offset, pool_id: *pid });
+                reigon.batch_ids.insert(upload.id);
+
+                *i += 1;
+            }
+        }
+    }
+
+    //reuses the slot of a released pool if there is one, returns the id of the new pool
+    fn new_pool(&mut self, ops: &mut AllocatorOps) -> eyre::Result<u32> {
+        let reigon_count = self.live_pools().last().map(|p| p.reigons.len() as u32).unwrap_or(4);
+        let pool_id = self.pools.iter().position(|p| p.is_none()).unwrap_or(self.pools.len());
+        if pool_id as u32 >= MAX_POOLS {
+            eyre::bail!("all {MAX_POOLS} primative pools are in use, a batch id can't address more");
+        }
+        if pool_id == self.pools.len() {
+            self.pools.push(None);
+        }
+
+        self.pools[pool_id] = Some(Pool {
+            pool_id: pool_id as u32,
+            reigons: (0..reigon_count)
+                .map(|i| Reigon {
+                    primative_offset: i * self.reigon_size,
+                    cap: self.reigon_size,
+                    top: 0,
+                    batch_ids: HashSet::new(),
+                    usage: 0,
+                    is_written: false,
+                })
+                .collect(),
+        });
+
+        let byte_size = self.primative_size as u64 * self.reigon_size as u64 * reigon_count as u64;
+        ops.created_pools.push((pool_id as u32, byte_size));
+        Ok(pool_id as u32)
+    }
+}
+
+//what an insert changes before it can fail, a failed insert leaves the allocator and the ops as they were
+struct InsertUndo {
+    replaced: Vec<(u32, Option<Batch>)>,
+    //top, usage and is_written of every reigon, indexed by pool slot
+    reigons: Vec<Vec<(u32, u32, bool)>>,
+    copy_count: usize,
+    created_pool_count: usize,
+}
+
+impl InsertUndo {
+    fn new(alloc: &BatchAllocator, uploads: &[BatchUpload], ops: &AllocatorOps) -> InsertUndo {
+        Self {
+            replaced: uploads.iter().map(|u| (u.id, alloc.batches.get(&u.id).copied())).collect(),
+            reigons: alloc
+                .pools
+                .iter()
+                .map(|p| p.iter().flat_map(|p| &p.reigons).map(|r| (r.top, r.usage, r.is_written)).collect())
+                .collect(),
+            copy_count: ops.copies.len(),
+            created_pool_count: ops.created_pools.len(),
+        }
+    }
+
+    fn restore(self, alloc: &mut BatchAllocator, uploads: &[BatchUpload], ops: &mut AllocatorOps) {
+        for upload in uploads {
+            alloc.remove_from_reigon(upload.id);
+            alloc.batches.remove(&upload.id);
+        }
+
+        for (pool_id, _) in ops.created_pools.drain(self.created_pool_count..) {
+            alloc.pools[pool_id as usize] = None;
+        }
+        alloc.pools.truncate(self.reigons.len());
+        ops.copies.truncate(self.copy_count);
+
+        for (id, batch) in self.replaced {
+            let Some(batch) = batch else { continue };
+            let reigon_id = (batch.offset / alloc.reigon_size) as usize;
+            alloc.pool_mut(batch.pool_id).reigons[reigon_id].batch_ids.insert(id);
+            alloc.batches.insert(id, batch);
+        }
+
+        for (pool, reigons) in alloc.pools.iter_mut().zip(self.reigons) {
+            for (reigon, (top, usage, is_written)) in pool.iter_mut().flat_map(|p| &mut p.reigons).zip(reigons) {
+                reigon.top = top;
+                reigon.usage = usage;
+                reigon.is_written = is_written;
+            }
+        }
+    }
+}
+
+impl Pool {
+    fn is_empty(&self) -> bool { self.reigons.iter().all(|r| r.batch_ids.is_empty()) }
+}
+
+impl Reigon {
+    fn score(&self) -> f32 {
+        let top = self.top as f32;
+        let cap = self.cap as f32;
+        let usage = self.usage as f32;
+
+        let empty_percent = 1.0 - (top / cap);
+        if usage == top {
+            return empty_percent;
+        }
+
+        (usage / top) * empty_percent
+    }
+
+    fn fragmentation_score(&self) -> f32 { (self.top - self.usage) as f32 / self.cap as f32 }
+
+    fn allocate(&mut self, batch_size: u32) -> Option<u32> {
+        if batch_size + self.top <= self.cap {
+            let allocation = self.primative_offset + self.top;
+            self.top += batch_size;
+            self.usage += batch_size;
+            Some(allocation)
+        } else {
+            None
+        }
+    }
+
+    fn reset_and_get_batches(&mut self) -> Vec<u32> {
+        self.top = 0;
+        self.usage = 0;
+
+        let mut vec = Vec::new();
+        vec.extend(self.batch_ids.drain());
+        vec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRIMATIVE_SIZE: u32 = 8;
+    const REIGON_SIZE: u32 = 64;
+
+    //xorshift, the simulation has to run the same way every time
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u32) -> u32 { (self.next() % n as u64) as u32 }
+    }
+
+    fn check(alloc: &mut BatchAllocator, batches: &HashMap<u32, u32>, step: usize) {
+        if let Err(e) = alloc.validate() {
+            panic!("step {step}: {e}");
+        }
+
+        let stats = alloc.stats();
+        let used: u64 = batches.values().map(|c| *c as u64 * PRIMATIVE_SIZE as u64).sum();
+        let reigon_count: u32 = alloc.live_pools().map(|p| p.reigons.len() as u32).sum();
+        assert_eq!(stats.batch_count, batches.len(), "step {step}");
+        assert_eq!(stats.used_bytes, used, "step {step}");
+        assert_eq!(
+            stats.used_bytes + stats.free_bytes + stats.fragmented_bytes,
+            (reigon_count * REIGON_SIZE * PRIMATIVE_SIZE) as u64,
+            "step {step}"
+        );
+        assert_eq!(stats.fragmentation_histogram.iter().sum::<u32>(), reigon_count, "step {step}");
+
+        for (id, [_, packed]) in alloc.take_updated_batches() {
+            assert_eq!(packed & 0xFF_FFFF, batches.get(&id).copied().unwrap_or(0), "step {step} batch {id}");
+        }
+    }
+
+    #[test]
+    fn oversized_batch_is_rejected() {
+        let mut alloc = BatchAllocator::new(PRIMATIVE_SIZE, REIGON_SIZE);
+        let mut ops = AllocatorOps::default();
+        alloc.insert(&[BatchUpload { byte_offset: 0, primative_count: 10, id: 0 }], &mut ops).unwrap();
+        alloc.take_updated_batches();
+
+        let mut ops = AllocatorOps::default();
+        let uploads = [
+            BatchUpload { byte_offset: 0, primative_count: 20, id: 0 },
+            BatchUpload { byte_offset: 0, primative_count: REIGON_SIZE + 1, id: 1 },
+        ];
+        assert!(alloc.insert(&uploads, &mut ops).is_err());
+
+        //the batch that would have been replaced is left as it was
+        assert!(ops.copies.is_empty() && ops.created_pools.is_empty());
+        assert!(alloc.take_updated_batches().is_empty());
+        alloc.validate().unwrap();
+        assert_eq!(alloc.stats().used_bytes, 10 * PRIMATIVE_SIZE as u64);
+    }
+
+    #[test]
+    fn pools_past_max_pools_fail() {
+        //every batch fills a reigon so each pool holds 4 of them
+        let mut alloc = BatchAllocator::new(PRIMATIVE_SIZE, 4);
+        let mut ops = AllocatorOps::default();
+        let uploads: Vec<_> = (0..MAX_POOLS * 4).map(|id| BatchUpload { byte_offset: 0, primative_count: 4, id }).collect();
+        alloc.insert(&uploads, &mut ops).unwrap();
+        assert_eq!(alloc.stats().pool_count, MAX_POOLS as usize);
+        assert_eq!(ops.created_pools.last().map(|p| p.0), Some(MAX_POOLS - 1));
+
+        let mut ops = AllocatorOps::default();
+        let last = BatchUpload { byte_offset: 0, primative_count: 1, id: MAX_POOLS * 4 };
+        assert!(alloc.insert(&[last], &mut ops).is_err());
+        assert!(ops.created_pools.is_empty());
+        assert_eq!(alloc.stats().pool_count, MAX_POOLS as usize);
+        alloc.validate().unwrap();
+
+        //the pool emptied by removals is kept and filled again
+        alloc.remove(&(0..4).collect::<Vec<_>>());
+        alloc.release_empty_pools(&mut ops);
+        assert!(ops.released_pools.is_empty());
+        alloc.insert(&[last], &mut ops).unwrap();
+        assert_eq!(alloc.stats().pool_count, MAX_POOLS as usize);
+        alloc.validate().unwrap();
+    }
+
+    #[test]
+    fn failed_insert_rolls_back_replaced_batches() {
+        let mut alloc = BatchAllocator::new(PRIMATIVE_SIZE, 4);
+        let mut ops = AllocatorOps::default();
+        let uploads: Vec<_> = (0..MAX_POOLS * 4).map(|id| BatchUpload { byte_offset: 0, primative_count: 4, id }).collect();
+        alloc.insert(&uploads, &mut ops).unwrap();
+        alloc.take_updated_batches();
+        let before = alloc.stats();
+
+        //the replaced batches free two reigons and are placed into them, the new batch needs a third
+        let mut ops = AllocatorOps::default();
+        let uploads = [
+            BatchUpload { byte_offset: 0, primative_count: 2, id: 0 },
+            BatchUpload { byte_offset: 0, primative_count: 4, id: 1 },
+            BatchUpload { byte_offset: 0, primative_count: 4, id: MAX_POOLS * 4 },
+        ];
+        assert!(alloc.insert(&uploads, &mut ops).is_err());
+
+        assert!(ops.copies.is_empty() && ops.created_pools.is_empty());
+        assert!(alloc.take_updated_batches().is_empty());
+        alloc.validate().unwrap();
+        assert_eq!(alloc.stats(), before);
+        assert_eq!(alloc.batches[&0].count, 4);
+
+        //the replacements alone fit into the reigons they free
+        alloc.insert(&uploads[..2], &mut ops).unwrap();
+        alloc.validate().unwrap();
+        assert_eq!(alloc.stats().used_bytes, before.used_bytes - 2 * PRIMATIVE_SIZE as u64);
+        assert_eq!(alloc.stats().pool_count, MAX_POOLS as usize);
+    }
+
+    #[test]
+    fn sweep_without_a_free_pool_keeps_the_batches() {
+        let mut alloc = BatchAllocator::new(PRIMATIVE_SIZE, 4);
+        let mut ops = AllocatorOps::default();
+        //one reigon holds three batches, every other reigon of every pool one that fills it
+        let small = [(0, 1), (1, 1), (2, 2)].map(|(id, primative_count)| BatchUpload { byte_offset: 0, primative_count, id });
+        alloc.insert(&small, &mut ops).unwrap();
+        let full: Vec<_> =
+            (3..MAX_POOLS * 4 + 2).map(|id| BatchUpload { byte_offset: 0, primative_count: 4, id }).collect();
+        alloc.insert(&full, &mut ops).unwrap();
+        assert_eq!(alloc.stats().pool_count, MAX_POOLS as usize);
+
+        //the reigon is fragmented and the clean reigons it could be swept into are full
+        alloc.remove(&[0]);
+        alloc.end_frame();
+        alloc.take_updated_batches();
+        let before = alloc.stats();
+        assert_eq!(before.fragmented_bytes, PRIMATIVE_SIZE as u64);
+
+        let mut ops = AllocatorOps::default();
+        alloc.sweep(&mut ops).unwrap();
+
+        assert!(ops.copies.is_empty() && ops.created_pools.is_empty());
+        assert!(alloc.take_updated_batches().is_empty());
+        alloc.validate().unwrap();
+        assert_eq!(alloc.stats(), before);
+        assert_eq!(alloc.batch_count(), MAX_POOLS * 4 + 1);
+    }
+
+    #[test]
+    fn random_operations_keep_bookkeeping_consistent() {
+        let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+        let mut alloc = BatchAllocator::new(PRIMATIVE_SIZE, REIGON_SIZE);
+        let mut batches: HashMap<u32, u32> = HashMap::new();
+        let mut pool_bytes: HashMap<u32, u64> = HashMap::new();
+
+        for step in 0..2000 {
+            let mut ops = AllocatorOps::default();
+
+            match rng.below(10) {
+                0..=3 => {
+                    //ids are unique within one insert like the chunk mesh manager hands them out
+                    let mut uploads: Vec<BatchUpload> = Vec::new();
+                    for _ in 0..1 + rng.below(6) {
+                        let id = rng.below(96);
+                        let primative_count = 1 + rng.below(REIGON_SIZE);
+                        if uploads.iter().all(|u| u.id != id) {
+                            uploads.push(BatchUpload { byte_offset: 0, primative_count, id });
+                        }
+                    }
+
+                    alloc.insert(&uploads, &mut ops).unwrap();
+                    uploads.iter().for_each(|u| {
+                        batches.insert(u.id, u.primative_count);
+                    });
+                }
+                4..=6 => {
+                    let ids: Vec<_> = (0..1 + rng.below(6)).map(|_| rng.below(96)).collect();
+                    alloc.remove(&ids);
+                    ids.iter().for_each(|id| {
+                        batches.remove(id);
+                    });
+                }
+                7 | 8 => alloc.sweep(&mut ops).unwrap(),
+                _ => {
+                    alloc.end_frame();
+                    alloc.release_empty_pools(&mut ops);
+                }
+            }
+
+            for (pool_id, size) in &ops.created_pools {
+                assert!(pool_bytes.insert(*pool_id, *size).is_none(), "step {step}: pool {pool_id} created twice");
+            }
+            for copy in &ops.copies {
+                let size = pool_bytes.get(&copy.dst_pool).copied().unwrap_or(0);
+                assert!(copy.dst_offset + copy.size <= size, "step {step}: copy past the end of pool {}", copy.dst_pool);
+            }
+            for pool_id in &ops.released_pools {
+                assert!(pool_bytes.remove(pool_id).is_some(), "step {step}: released missing pool {pool_id}");
+            }
+
+            check(&mut alloc, &batches, step);
+            assert_eq!(alloc.stats().pool_count, pool_bytes.len(), "step {step}");
+        }
+    }
+}
@@ -1,19 +1,29 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use ash::vk;
-use bytemuck::{bytes_of, Pod, Zeroable};
+use bytemuck::{Pod, Zeroable};
 use glam::Mat4;
 use magma_renderer::core::*;
 use magma_renderer::engine::material::*;
 
 use crate::{
-    game::{CameraData, Game},
-    render::{renderpassmanager::RenderPassManager, VkDrawIndexedIndirectCommand},
+    game::{voxels::SECTION_COUNT, CameraData, Game},
+    render::{
+        renderpassmanager::RenderPassManager,
+        resource_state::{BufferAccess, BufferUsage},
+        VkDrawIndexedIndirectCommand,
+    },
 };
 
 use super::{
-    primative_manager::{BatchUpload, PrimativeManager},
+    mesh_queue::{mesh_bytes, MeshQueue, MeshUploadStats, UploadBudget},
+    primative_manager::{BatchUpload, PrimativeManager, PrimativeStats, MAX_POOLS},
     stencil_buffer::StencilBuffer,
+    lod::MAX_LOD,
+    mesher::ALL_SECTIONS,
     ChunkMesh, Quad,
 };
 
@@ -36,22 +46,107 @@ impl IDManager {
     pub fn new() -> IDManager { Self { id_counter: 0, free_ids: Vec::new() } }
 }
 
+//every lod of a chunk has its own mesh and id, only the active one is drawn
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ChunkMeshKey {
+    pub pos: [i32; 3],
+    pub lod: u8,
+}
+
+//every non empty section is its own batch with its own id, empty meshes are kept so the lod isn't meshed again
+struct ResidentMesh {
+    ids: [Option<u32>; SECTION_COUNT],
+    seams: u8,
+    //flush the mesh was uploaded or stopped being the active lod in
+    inactive_since: u64,
+}
+
+impl ResidentMesh {
+    fn ids(&self) -> impl Iterator<Item = u32> + '_ { self.ids.iter().flatten().copied() }
+}
+
+#[derive(Clone, Copy)]
 enum ChunkUpdate {
     Removed,
-    Inserted,
+    Inserted(ChunkMeshKey),
 }
 
 pub struct ChunkMeshManager {
-    chunk_ids: HashMap<[i32; 3], u32>,
+    meshes: HashMap<ChunkMeshKey, ResidentMesh>,
+    active_lods: HashMap<[i32; 3], u8>,
+    //modified chunks whose active mesh is outdated and dropped once the new one replaces it
+    stale_chunks: HashSet<[i32; 3]>,
     id_man: IDManager,
+    //ids the buffers indexed by chunk id can hold, grows with reserve_ids
     id_cap: u32,
     chunk_buffer: Buffer<ChunkGPUBufferData>,
+    //written by the late hi-z cull for the early cull of the next frame and marked by the early cull for
+    //the late one, indexed like chunk_buffer. entries of new ids are garbage until the first late cull,
+    //any value is safe to read
+    hiz_visibility_buffer: Buffer<u32>,
     opaque_meshes: PrimativeManager,
     stencil_buffers: Box<[StencilBuffer]>,
-    queued_meshes: Vec<ChunkMesh>,
-    updated_chunks: HashMap<[i32; 3], ChunkUpdate>,
+    queued_meshes: MeshQueue,
+    //section patches from tile edits, applied in order and outside of the upload budget
+    queued_patches: Vec<ChunkMesh>,
+    pub upload_budget: UploadBudget,
+    upload_stats: MeshUploadStats,
+    //pool buffers released by the last flush, still alive until its command buffer is done
+    released_buffers: Vec<vk::Buffer>,
+    //keyed by chunk id
+    updated_chunks: HashMap<u32, ChunkUpdate>,
+    //chunks dropped by a failed flush, they have to be meshed again
+    dropped_chunks: Vec<[i32; 3]>,
+    //chunks the cave culling found to be hidden from the camera
+    occluded_chunks: HashSet<[i32; 3]>,
+    //counts flushes, meshes of lods that aren't drawn are evicted by it
+    frame: u64,
+    //flushes a lod that isn't drawn stays resident in case the chunk switches back to it
+    pub inactive_lod_frames: u64,
+    core: Arc<Core>,
+}
+
+//quads of the largest chunk mesh, the quad index buffer is sized for it
+pub const QUADS_PER_REIGON: u32 = 128_000;
+
+//capacity of the buffers indexed by chunk id once ids below id_count have to fit, doubles so growing stays rare
+fn grown_id_cap(cap: u32, id_count: u32) -> u32 {
+    if id_count <= cap {
+        return cap;
+    }
+    id_count.max(cap.saturating_mul(2))
+}
+
+//with this many pools every lod that isn't drawn is evicted right away
+const LOD_EVICTION_POOLS: usize = MAX_POOLS as usize * 3 / 4;
+
+/*
+    Meshes of lods that aren't drawn, keyed by the flush they became inactive in. They are evicted
+    once they were inactive for max_frames flushes or all at once under pool pressure.
+*/
+fn lods_to_evict(
+    inactive: impl Iterator<Item = (ChunkMeshKey, u64)>,
+    frame: u64,
+    max_frames: u64,
+    pool_pressure: bool,
+) -> Vec<ChunkMeshKey> {
+    inactive.filter(|(_, since)| pool_pressure || frame.saturating_sub(*since) >= max_frames).map(|(key, _)| key).collect()
 }
 
+//the flushed chunks and the chunks owning any of the batches whose copies weren't recorded
+fn unflushed_chunks(
+    meshes: &HashMap<ChunkMeshKey, ResidentMesh>,
+    flushed: &[[i32; 3]],
+    batches: &HashSet<u32>,
+) -> HashSet<[i32; 3]> {
+    let moved = meshes.iter().filter(|(_, mesh)| mesh.ids().any(|id| batches.contains(&id))).map(|(key, _)| key.pos);
+    flushed.iter().copied().chain(moved).collect()
+}
+
+//bits of ChunkGPUBufferData::flags, matches chunk_cull.comp
+pub const CHUNK_FLAG_LOADED: u32 = 0x1;
+pub const CHUNK_FLAG_VISIBLE: u32 = 0x2;
+
 #[repr(C)]
 #[derive(Clone, Copy, Zeroable, Pod, Debug, Default)]
 pub struct ChunkGPUBufferData {
@@ -59,99 +154,519 @@ pub struct ChunkGPUBufferData {
     flags: u32,
 }
 
+const CHUNK_GPU_SIZE: u64 = std::mem::size_of::<ChunkGPUBufferData>() as u64;
+
 impl ChunkMeshManager {
     //gettres
     pub fn get_max_chunk_id(&self) -> u32 {self.id_man.id_counter}
     pub fn get_chunk_buffer(&self) -> &Buffer<ChunkGPUBufferData> {&self.chunk_buffer}
+    pub fn get_hiz_visibility_buffer(&self) -> &Buffer<u32> {&self.hiz_visibility_buffer}
     pub fn get_opaque_meshes(&self) -> &PrimativeManager {&self.opaque_meshes}
+    pub fn get_upload_stats(&self) -> MeshUploadStats {self.upload_stats}
+    pub fn get_primative_stats(&self) -> PrimativeStats {self.opaque_meshes.stats()}
+
+    //released buffers have to be forgotten by the descriptor cache and the state tracker
+    pub fn take_released_buffers(&mut self) -> Vec<vk::Buffer> { std::mem::take(&mut self.released_buffers) }
+
+    pub fn take_dropped_chunks(&mut self) -> Vec<[i32; 3]> { std::mem::take(&mut self.dropped_chunks) }
+
+    //replaces meshes of the same chunks that are still queued, they were built for an older state
+    pub fn submit_meshes(&mut self, meshes: Vec<ChunkMesh>) { meshes.into_iter().for_each(|mesh| self.queue(mesh)); }
+
+    //lods that aren't drawn are dropped right away for patches, they could be activated before the patch is applied
+    fn queue(&mut self, mesh: ChunkMesh) {
+        if mesh.is_patch() {
+            self.discard_other_lods(ChunkMeshKey { pos: mesh.pos, lod: mesh.lod });
+            self.queued_patches.push(mesh);
+        } else {
+            self.queued_meshes.push(mesh);
+        }
+    }
+
+    fn requeue(&mut self, mesh: ChunkMesh) {
+        if mesh.is_patch() {
+            self.queued_patches.push(mesh);
+        } else {
+            self.queued_meshes.requeue(mesh);
+        }
+    }
+
+    //the lod is drawn with these seams, a patch built for it can be swapped in
+    fn is_resident(&self, pos: [i32; 3], lod: u8, seams: u8) -> bool {
+        let key = ChunkMeshKey { pos, lod };
+        self.active_lods.get(&pos) == Some(&lod) && matches!(self.meshes.get(&key), Some(mesh) if mesh.seams == seams)
+    }
+
+    //sections of the chunk can be remeshed on their own, no newer mesh of the whole chunk is on its way
+    pub fn can_patch(&self, pos: [i32; 3], lod: u8, seams: u8) -> bool {
+        self.is_resident(pos, lod, seams) && !self.stale_chunks.contains(&pos) && !self.queued_meshes.contains(&pos)
+    }
+
+    //makes an already meshed lod the drawn one, returns false if it has to be meshed first
+    pub fn activate_lod(&mut self, pos: [i32; 3], lod: u8, seams: u8) -> bool {
+        let key = ChunkMeshKey { pos, lod };
+        if self.stale_chunks.contains(&pos) {
+            return false;
+        }
+        match self.meshes.get(&key) {
+            Some(mesh) if mesh.seams == seams => {
+                self.set_active(key);
+                true
+            }
+            Some(_) => {
+                self.discard_mesh(key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    //drops every lod of a chunk whose tiles changed, the old active mesh stays drawn until the new one is flushed
+    pub fn invalidate_chunk(&mut self, pos: [i32; 3]) {
+        let active = self.active_lods.get(&pos).copied();
+        let stale: Vec<_> = self.meshes.keys().filter(|k| k.pos == pos && Some(k.lod) != active).copied().collect();
+        for key in stale {
+            self.discard_mesh(key);
+        }
+        self.stale_chunks.insert(pos);
+    }
+
+    fn discard_mesh(&mut self, key: ChunkMeshKey) {
+        let Some(mesh) = self.meshes.remove(&key) else { return };
+        mesh.ids().for_each(|id| self.remove_batch(id));
+    }
+
+    fn remove_batch(&mut self, id: u32) {
+        self.opaque_meshes.remove_batches(&[id]);
+        self.id_man.free_id(id);
+        self.updated_chunks.insert(id, ChunkUpdate::Removed);
+    }
 
-    pub fn submit_meshes(&mut self, mut meshes: Vec<ChunkMesh>) { self.queued_meshes.append(&mut meshes); }
+    //the batches are removed with the next sweep, the lod is meshed again if it is activated later
+    fn evict_inactive_lods(&mut self) {
+        let pool_pressure = self.opaque_meshes.get_pools().count() >= LOD_EVICTION_POOLS;
+        let inactive = self
+            .meshes
+            .iter()
+            .filter(|(key, _)| self.active_lods.get(&key.pos) != Some(&key.lod))
+            .map(|(key, mesh)| (*key, mesh.inactive_since));
 
-    pub fn flush_stencil(&mut self, cmd: &mut CommandBuffer, frame_index: usize) {
+        for key in lods_to_evict(inactive, self.frame, self.inactive_lod_frames, pool_pressure) {
+            self.discard_mesh(key);
+        }
+    }
+
+    //the other lods of a chunk whose tiles changed were built from its old tiles
+    fn discard_other_lods(&mut self, key: ChunkMeshKey) {
+        let outdated: Vec<_> = self.meshes.keys().filter(|k| k.pos == key.pos && k.lod != key.lod).copied().collect();
+        outdated.into_iter().for_each(|k| self.discard_mesh(k));
+    }
+
+    fn set_active(&mut self, key: ChunkMeshKey) {
+        let previous = self.active_lods.insert(key.pos, key.lod);
+        if let Some(previous) = previous.filter(|lod| *lod != key.lod) {
+            if let Some(mesh) = self.meshes.get_mut(&ChunkMeshKey { pos: key.pos, lod: previous }) {
+                mesh.inactive_since = self.frame;
+            }
+        }
+        for lod in [previous, Some(key.lod)].into_iter().flatten() {
+            let key = ChunkMeshKey { pos: key.pos, lod };
+            let ids: Vec<u32> = self.meshes.get(&key).map(|m| m.ids().collect()).unwrap_or_default();
+            for id in ids {
+                self.updated_chunks.insert(id, ChunkUpdate::Inserted(key));
+            }
+        }
+    }
+
+    //swaps the batches of the patched sections, the sections that weren't edited keep theirs
+    fn apply_patch(&mut self, key: ChunkMeshKey, sections: u8, patch: ResidentMesh) {
+        let resident = self.meshes.get_mut(&key).unwrap();
+        let mut replaced = Vec::new();
+        for section in (0..SECTION_COUNT).filter(|s| sections & (1 << s) != 0) {
+            replaced.extend(std::mem::replace(&mut resident.ids[section], patch.ids[section]));
+        }
+
+        replaced.into_iter().for_each(|id| self.remove_batch(id));
+        for id in patch.ids() {
+            self.updated_chunks.insert(id, ChunkUpdate::Inserted(key));
+        }
+    }
+
+    //chunk updates flushing the mesh can cause, its new ids and the resident ones it replaces or deactivates
+    fn chunk_update_bound(&self, mesh: &ChunkMesh) -> usize {
+        let new_ids = (0..SECTION_COUNT).filter(|s| !mesh.section_range(*s).is_empty()).count();
+        let resident = (0..=MAX_LOD).filter_map(|lod| self.meshes.get(&ChunkMeshKey { pos: mesh.pos, lod }));
+        new_ids + resident.map(|m| m.ids().count()).sum::<usize>()
+    }
+
+    fn chunk_flags(&self, key: &ChunkMeshKey) -> u32 {
+        if self.active_lods.get(&key.pos) != Some(&key.lod) {
+            0x0
+        } else if self.occluded_chunks.contains(&key.pos) {
+            CHUNK_FLAG_LOADED
+        } else {
+            CHUNK_FLAG_LOADED | CHUNK_FLAG_VISIBLE
+        }
+    }
+
+    //chunks whose visibility changed get their flags uploaded with the next flush
+    pub fn update_occlusion(&mut self, is_visible: impl Fn(&[i32; 3]) -> bool) {
+        for (pos, lod) in &self.active_lods {
+            let occluded = !is_visible(pos);
+            if occluded == self.occluded_chunks.contains(pos) {
+                continue;
+            }
+
+            if occluded {
+                self.occluded_chunks.insert(*pos);
+            } else {
+                self.occluded_chunks.remove(pos);
+            }
+
+            let key = ChunkMeshKey { pos: *pos, lod: *lod };
+            for id in self.meshes.get(&key).into_iter().flat_map(|m| m.ids()) {
+                self.updated_chunks.entry(id).or_insert(ChunkUpdate::Inserted(key));
+            }
+        }
+    }
+
+    /*
+        Grows the buffers indexed by chunk id so ids below id_count fit, doubling the capacity. The
+        chunk data and batch descriptions are copied over, the visibility of the late cull isn't
+        since any value is safe to read. Replaced buffers end up in released_buffers.
+    */
+    fn reserve_ids(&mut self, cmd: &mut CommandBuffer, id_count: u32, usage: &mut BufferUsage) -> eyre::Result<()> {
+        if id_count <= self.id_cap {
+            return Ok(());
+        }
+
+        let cap = grown_id_cap(self.id_cap, id_count);
+
+        let chunk_buffer = self.core.create_buffer(self.chunk_buffer.get_usage(), cap, false)?;
+        let copy = vk::BufferCopy { src_offset: 0, dst_offset: 0, size: self.chunk_buffer.byte_size() };
+        unsafe {
+            cmd.copy_buffer_reigons(self.chunk_buffer.inner(), chunk_buffer.inner(), &[copy]);
+        }
+        usage.add(chunk_buffer.inner(), BufferAccess::TransferWrite);
+        let old_chunk_buffer = std::mem::replace(&mut self.chunk_buffer, chunk_buffer);
+        self.released_buffers.push(old_chunk_buffer.inner());
+        cmd.add_dependency(old_chunk_buffer);
+
+        let visibility_buffer = self.core.create_buffer(self.hiz_visibility_buffer.get_usage(), cap, false)?;
+        let old_visibility_buffer = std::mem::replace(&mut self.hiz_visibility_buffer, visibility_buffer);
+        self.released_buffers.push(old_visibility_buffer.inner());
+        cmd.add_dependency(old_visibility_buffer);
+
+        self.released_buffers.extend(self.opaque_meshes.grow_ids(cmd, cap, usage)?);
+        self.id_cap = cap;
+        Ok(())
+    }
+
+    /*
+        Uploads the queued patches and the queued meshes closest to the camera within the upload
+        budget, returns the buffers the recorded copies use. Patches go first so edits show up in
+        the frame they were made, unless the stencil buffer is full. If recording fails the chunks it
+        touched are dropped and returned by take_dropped_chunks.
+    */
+    pub fn flush_stencil(
+        &mut self,
+        cmd: &mut CommandBuffer,
+        frame_index: usize,
+        camera_chunk: [i32; 3],
+    ) -> eyre::Result<BufferUsage> {
         let mut quad_mesh_uploads = Vec::new();
+        let mut flushed = Vec::new();
 
-        let stencil = &mut self.stencil_buffers[frame_index];
-        stencil.reset();
+        self.frame += 1;
+        self.evict_inactive_lods();
+        self.stencil_buffers[frame_index].reset();
 
-        let mut remaining_chunks = 100;
+        let mut uploaded_bytes = 0;
+        let mut stencil_full = false;
+        //the chunk data is uploaded after the quads, room for it is kept free. one more for the allignment
+        let mut chunk_updates = self.updated_chunks.len() + 1;
 
-        while let Some(mesh) = self.queued_meshes.pop() {
-            let chunk_id = *self.chunk_ids.entry(mesh.pos).or_insert_with(|| {
-                self.updated_chunks.insert(mesh.pos, ChunkUpdate::Inserted);
-                self.id_man.new_id()
-            });
+        let patches = std::mem::take(&mut self.queued_patches);
+        let meshes = self.queued_meshes.take(camera_chunk, &self.upload_budget);
+        for mesh in patches.into_iter().chain(meshes) {
+            if stencil_full {
+                self.requeue(mesh);
+                continue;
+            }
 
-            let Some(byte_offset) = stencil.upload(bytemuck::cast_slice(mesh.quads.as_slice())) else {
-                self.queued_meshes.push(mesh);
-                break
-            };
+            //the chunk was remeshed as a whole since the patch was built
+            if mesh.is_patch() && !self.is_resident(mesh.pos, mesh.lod, mesh.seams) {
+                continue;
+            }
 
-            quad_mesh_uploads.push(BatchUpload {
-                byte_offset: byte_offset as u32,
-                primative_count: mesh.quads.len() as u32,
-                id: chunk_id,
-            });
+            let updates = chunk_updates + self.chunk_update_bound(&mesh);
+            let quad_bytes: &[u8] = bytemuck::cast_slice(mesh.quads.as_slice());
+            let stencil = &mut self.stencil_buffers[frame_index];
+            if stencil.free_bytes() < quad_bytes.len() as u64 + updates as u64 * CHUNK_GPU_SIZE {
+                self.requeue(mesh);
+                stencil_full = true;
+                continue;
+            }
+            chunk_updates = updates;
+            let byte_offset = if mesh.empty() { 0 } else { stencil.upload(quad_bytes).unwrap() };
+            uploaded_bytes += mesh_bytes(&mesh);
 
-            remaining_chunks -= 1;
-            if remaining_chunks <= 0 {
-                break;
+            let mut ids = [None; SECTION_COUNT];
+            for section in 0..SECTION_COUNT {
+                let quads = mesh.section_range(section);
+                if quads.is_empty() {
+                    continue;
+                }
+
+                let chunk_id = self.id_man.new_id();
+                quad_mesh_uploads.push(BatchUpload {
+                    byte_offset: (byte_offset + (quads.start * std::mem::size_of::<Quad>()) as u64) as u32,
+                    primative_count: quads.len() as u32,
+                    id: chunk_id,
+                });
+                ids[section] = Some(chunk_id);
             }
+
+            let key = ChunkMeshKey { pos: mesh.pos, lod: mesh.lod };
+            flushed.push((key, mesh.sections, ResidentMesh { ids, seams: mesh.seams, inactive_since: self.frame }));
         }
 
-        self.opaque_meshes.insert_batches(cmd, &stencil.buffer, quad_mesh_uploads);
-        self.opaque_meshes.sweep_and_flush(cmd, stencil);
+        self.upload_stats = MeshUploadStats {
+            queued: self.queued_meshes.len() + self.queued_patches.len(),
+            uploaded: flushed.len(),
+            uploaded_bytes,
+            deferred_bytes: self.queued_meshes.queued_bytes() + self.queued_patches.iter().map(mesh_bytes).sum::<u64>(),
+        };
+
+        let flushed_chunks: Vec<_> = flushed.iter().map(|(key, ..)| key.pos).collect();
+
+        //a new mesh replaces the active lod of its chunk only once it is uploaded
+        for (key, sections, mesh) in flushed {
+            if sections != ALL_SECTIONS {
+                self.apply_patch(key, sections, mesh);
+                continue;
+            }
 
-        let mut copy_commands = Vec::new();
+            //a mesh for the same lod may still be resident when the seams changed
+            self.discard_mesh(key);
+            self.meshes.insert(key, mesh);
+            self.set_active(key);
 
-        for (pos, update) in &self.updated_chunks {
-            let chunk_id = *self.chunk_ids.get(pos).unwrap();
-            let gpu_chunk = ChunkGPUBufferData {
-                pos: *pos,
-                flags: match update {
-                    ChunkUpdate::Removed => 0x0,
-                    ChunkUpdate::Inserted => 0x1,
-                },
-            };
-            const CHUNK_GPU_SIZE: u64 = std::mem::size_of::<ChunkGPUBufferData>() as u64;
-            let offset = stencil.upload(bytes_of(&gpu_chunk)).unwrap();
+            if self.stale_chunks.remove(&key.pos) {
+                self.discard_other_lods(key);
+            }
+        }
+
+        //the recorded copies are dropped with the command buffer on failure
+        let pending_updates = self.updated_chunks.clone();
+        let result = self.record_flush(cmd, frame_index, quad_mesh_uploads);
+        if result.is_err() {
+            self.updated_chunks.extend(pending_updates);
+            self.drop_unflushed_chunks(&flushed_chunks);
+        }
+        result
+    }
+
+    fn record_flush(
+        &mut self,
+        cmd: &mut CommandBuffer,
+        frame_index: usize,
+        quad_mesh_uploads: Vec<BatchUpload>,
+    ) -> eyre::Result<BufferUsage> {
+        //only the buffers of recorded copies are added
+        let mut usage = BufferUsage::new();
+
+        //has to be recorded before anything is written to the buffers indexed by chunk id
+        self.reserve_ids(cmd, self.id_man.id_counter, &mut usage)?;
+
+        //before the batch descriptions take the rest of the stencil buffer
+        self.upload_chunk_data(cmd, frame_index, &mut usage)?;
+
+        let stencil = &mut self.stencil_buffers[frame_index];
+        self.opaque_meshes.insert_batches(cmd, &stencil.buffer, quad_mesh_uploads, &mut usage)?;
+        self.opaque_meshes.sweep_and_flush(cmd, stencil, &mut usage)?;
+        let mut released = self.opaque_meshes.release_empty_pools(cmd);
+        self.released_buffers.append(&mut released);
+
+        Ok(usage)
+    }
+
+    /*
+        Drops the chunks flushed by a failed flush and the ones with batches moved by its sweep, their
+        batches may hold anything. They are drawn again once they are meshed again.
+    */
+    fn drop_unflushed_chunks(&mut self, flushed: &[[i32; 3]]) {
+        let batches: HashSet<u32> = self.opaque_meshes.take_unflushed_batches().into_iter().collect();
+        for pos in unflushed_chunks(&self.meshes, flushed, &batches) {
+            self.active_lods.remove(&pos);
+            self.invalidate_chunk(pos);
+            self.dropped_chunks.push(pos);
+        }
+    }
+
+    fn upload_chunk_data(
+        &mut self,
+        cmd: &mut CommandBuffer,
+        frame_index: usize,
+        usage: &mut BufferUsage,
+    ) -> eyre::Result<()> {
+        let chunk_data: Vec<(u32, ChunkGPUBufferData)> = self
+            .updated_chunks
+            .iter()
+            .map(|(chunk_id, update)| {
+                let gpu_chunk = match update {
+                    ChunkUpdate::Removed => ChunkGPUBufferData { pos: [0; 3], flags: 0x0 },
+                    ChunkUpdate::Inserted(key) => ChunkGPUBufferData { pos: key.pos, flags: self.chunk_flags(key) },
+                };
+                (*chunk_id, gpu_chunk)
+            })
+            .collect();
+        self.updated_chunks.clear();
+
+        if chunk_data.is_empty() {
+            return Ok(());
+        }
+
+        let stencil = &mut self.stencil_buffers[frame_index];
+        let (gpu_chunks, src_offset) = stencil
+            .allocate_items::<ChunkGPUBufferData>(chunk_data.len() as u64)
+            .ok_or_else(|| eyre::eyre!("no room left in the stencil buffer for {} chunk updates", chunk_data.len()))?;
+
+        let mut copy_commands = Vec::with_capacity(chunk_data.len());
+        for (i, (chunk_id, data)) in chunk_data.into_iter().enumerate() {
+            gpu_chunks[i] = data;
             copy_commands.push(vk::BufferCopy {
-                src_offset: offset,
+                src_offset: src_offset + i as u64 * CHUNK_GPU_SIZE,
                 dst_offset: chunk_id as u64 * CHUNK_GPU_SIZE,
                 size: CHUNK_GPU_SIZE,
             });
         }
 
-        self.updated_chunks.clear();
-
         unsafe {
             cmd.copy_buffer_reigons(stencil.buffer.inner(), self.chunk_buffer.inner(), &copy_commands);
         }
+        usage.add(stencil.buffer.inner(), BufferAccess::TransferRead);
+        usage.add(self.chunk_buffer.inner(), BufferAccess::TransferWrite);
+        Ok(())
     }
 
     pub fn total_batch_count(&self) -> u32 { self.opaque_meshes.batch_count() }
 
     pub fn new(core: &Arc<Core>) -> eyre::Result<Self> {
-        let cap = 100_000;
+        let cap = 16_384;
 
         Ok(Self {
-            chunk_ids: HashMap::new(),
+            meshes: HashMap::new(),
+            active_lods: HashMap::new(),
+            stale_chunks: HashSet::new(),
             id_man: IDManager::new(),
             id_cap: cap,
             chunk_buffer: core.create_buffer(
-                vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+                vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::TRANSFER_SRC,
                 cap,
                 false,
             )?,
+            hiz_visibility_buffer: core.create_buffer(vk::BufferUsageFlags::STORAGE_BUFFER, cap, false)?,
             opaque_meshes: PrimativeManager::new(
                 core,
                 std::mem::size_of::<Quad>() as u32,
-                128_000,
+                QUADS_PER_REIGON,
                 cap,
-                vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::STORAGE_BUFFER,
+                //sweeps copy batches between pools
+                vk::BufferUsageFlags::TRANSFER_DST
+                    | vk::BufferUsageFlags::TRANSFER_SRC
+                    | vk::BufferUsageFlags::VERTEX_BUFFER
+                    | vk::BufferUsageFlags::STORAGE_BUFFER,
             )?,
             stencil_buffers: (0..2).map(|_| StencilBuffer::new(core, 10_000_000)).collect::<eyre::Result<_>>()?,
-            queued_meshes: Vec::new(),
+            queued_meshes: MeshQueue::new(),
+            queued_patches: Vec::new(),
+            upload_budget: UploadBudget::default(),
+            upload_stats: MeshUploadStats::default(),
+            released_buffers: Vec::new(),
             updated_chunks: HashMap::new(),
+            dropped_chunks: Vec::new(),
+            occluded_chunks: HashSet::new(),
+            frame: 0,
+            inactive_lod_frames: 600,
+            core: core.clone(),
         })
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::chunk_render::batch_allocator::{AllocatorOps, BatchAllocator};
+
+    fn key(x: i32, lod: u8) -> ChunkMeshKey { ChunkMeshKey { pos: [x, 0, 0], lod } }
+
+    #[test]
+    fn inactive_lods_are_evicted_after_max_frames() {
+        let inactive = [(key(0, 1), 10), (key(1, 2), 50), (key(2, 0), 0)];
+        let mut evicted = lods_to_evict(inactive.into_iter(), 100, 60, false);
+        evicted.sort_by_key(|k| k.pos);
+        assert_eq!(evicted, vec![key(0, 1), key(2, 0)]);
+
+        assert!(lods_to_evict(inactive.into_iter(), 50, 60, false).is_empty());
+        //a lod that just became inactive stays with any limit above zero
+        assert!(lods_to_evict([(key(0, 1), 5)].into_iter(), 5, 1, false).is_empty());
+    }
+
+    #[test]
+    fn pool_pressure_evicts_every_inactive_lod() {
+        let inactive = [(key(0, 1), 99), (key(1, 2), 100)];
+        assert_eq!(lods_to_evict(inactive.into_iter(), 100, 600, true).len(), 2);
+    }
+
+    #[test]
+    fn unflushed_chunks_include_moved_batches() {
+        let mesh = |ids: [Option<u32>; SECTION_COUNT]| ResidentMesh { ids, seams: 0, inactive_since: 0 };
+        let mut ids = [None; SECTION_COUNT];
+        ids[0] = Some(3);
+        ids[SECTION_COUNT - 1] = Some(4);
+        let meshes = HashMap::from([(key(0, 0), mesh([None; SECTION_COUNT])), (key(1, 0), mesh(ids)), (key(2, 1), mesh(ids))]);
+
+        let chunks = unflushed_chunks(&meshes, &[[0, 0, 0], [5, 0, 0]], &HashSet::from([4, 9]));
+        assert_eq!(chunks, HashSet::from([[0, 0, 0], [5, 0, 0], [1, 0, 0], [2, 0, 0]]));
+        assert!(unflushed_chunks(&meshes, &[], &HashSet::new()).is_empty());
+    }
+
+    #[test]
+    fn id_cap_grows_past_the_last_id() {
+        let cap = 16_384;
+        let mut id_man = IDManager::new();
+        for _ in 0..cap {
+            id_man.new_id();
+        }
+        assert_eq!(grown_id_cap(cap, id_man.id_counter), cap);
+
+        //the first id past the cap doubles it
+        assert_eq!(id_man.new_id(), cap);
+        assert_eq!(grown_id_cap(cap, id_man.id_counter), cap * 2);
+
+        //freed ids are reused before the counter grows
+        id_man.free_id(7);
+        assert_eq!(id_man.new_id(), 7);
+        assert_eq!(id_man.id_counter, cap + 1);
+
+        //more ids than double the cap at once and no overflow near the end of the id range
+        assert_eq!(grown_id_cap(cap, cap * 5), cap * 5);
+        assert_eq!(grown_id_cap(u32::MAX / 2 + 1, u32::MAX), u32::MAX);
+    }
+
+    #[test]
+    fn batch_of_a_whole_reigon_fits() {
+        let mut alloc = BatchAllocator::new(std::mem::size_of::<Quad>() as u32, QUADS_PER_REIGON);
+        let mut ops = AllocatorOps::default();
+
+        let full = BatchUpload { byte_offset: 0, primative_count: QUADS_PER_REIGON, id: 0 };
+        alloc.insert(&[full], &mut ops).unwrap();
+        assert_eq!(alloc.stats().free_bytes, 3 * QUADS_PER_REIGON as u64 * std::mem::size_of::<Quad>() as u64);
+        alloc.validate().unwrap();
+
+        let oversized = BatchUpload { byte_offset: 0, primative_count: QUADS_PER_REIGON + 1, id: 1 };
+        assert!(alloc.insert(&[oversized], &mut ops).is_err());
+        assert_eq!(alloc.batch_count(), 1);
+    }
+}
@@ -1,4 +1,7 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
 use ash::vk;
 use bytemuck::{bytes_of, Pod, Zeroable};
@@ -7,17 +10,42 @@ use magma_renderer::core::*;
 use magma_renderer::engine::material::*;
 
 use crate::{
-    game::{CameraData, Game},
-    render::{renderpassmanager::RenderPassManager, VkDrawIndexedIndirectCommand},
+    game::{Game, Stage},
+    render::{
+        descriptor_cache::{DescriptorCache, DescriptorKey},
+        frustum::Frustum,
+        hiz::HiZ,
+        hot_reload::HotReload,
+        renderpassmanager::RenderPassManager,
+        resource_state::{BufferAccess, BufferBarrier, BufferUsage},
+        VkDrawIndexedIndirectCommand,
+    },
 };
 
 use super::{
-    chunk_mesh_manager::ChunkMeshManager,
-    primative_manager::{BatchUpload, PrimativeManager},
+    chunk_mesh_manager::{ChunkMeshManager, QUADS_PER_REIGON},
+    primative_manager::{BatchUpload, PrimativeManager, PrimativePool, MAX_POOLS},
     stencil_buffer::StencilBuffer,
     ChunkMesh, Quad,
 };
 
+const CULL_SHADER: &str = "res/chunk_cull.comp";
+
+//bits of the cull_flags push constant of the cull shader
+const CULL_FLAG_CAVE: u32 = 0x1;
+const CULL_FLAG_HIZ_EARLY: u32 = 0x2;
+const CULL_FLAG_HIZ_LATE: u32 = 0x4;
+
+#[repr(C)]
+#[derive(Debug, Pod, Clone, Copy, Zeroable)]
+struct CullPush {
+    frustum_planes: [[f32; 4]; 6],
+    chunk_count: u32,
+    cull_flags: u32,
+    late_draw_base: u32,
+    _padding: u32,
+}
+
 //cpu side
 struct FramelyData {
     draw_offset_buffer: Buffer<u32>,
@@ -31,6 +59,10 @@ pub struct ChunkRenderManager {
     proj_view: Mat4,
     standart_opaque_material: MaterialID,
     shared_data: Arc<ChunkRenderSharedData>,
+    //skip chunks the cave culling marked as hidden, only valid for views from the camera
+    pub occlusion_culling: bool,
+    //test chunks against the depth pyramid of the camera, only valid for views from the camera
+    pub hiz_culling: bool,
     core: Arc<Core>,
 }
 
@@ -46,10 +78,12 @@ struct PushConstant {
 }
 
 pub struct ChunkRenderSharedData {
-    quad_index_buffer: Buffer<u16>,
+    //indices of QUADS_PER_REIGON quads, enough for the largest batch
+    quad_index_buffer: Buffer<u32>,
     // chunk_data_set_layout: vk::DescriptorSetLayout,
     // quad_buffer_set_layout: vk::DescriptorSetLayout,
-    cull_pipeline: Arc<Pipeline>,
+    //swapped when res/chunk_cull.comp is reloaded
+    cull_pipeline: Mutex<Arc<Pipeline>>,
 }
 
 impl ChunkRenderManager {
@@ -57,40 +91,44 @@ impl ChunkRenderManager {
         self.standart_opaque_material = material_id;
     }
 
+    pub fn remap_material(&mut self, hot_reload: &HotReload) {
+        self.standart_opaque_material = hot_reload.remap(self.standart_opaque_material);
+    }
+
     pub fn cull_and_draw_chunks(
         &mut self,
         mesh_manager: &ChunkMeshManager,
         material_manager: &MaterialManager,
         draw_cmd: &mut CommandBuffer,
         compute_cmd: &mut CommandBuffer,
-        descriptor_pool: &mut DescriptorPool,
+        descriptor_cache: &mut DescriptorCache,
+        rp_man: &RenderPassManager,
         frame_index: usize,
-        cam_data: &CameraData,
+        proj_view: Mat4,
+        camera_set: vk::DescriptorSet,
+        hiz_set: vk::DescriptorSet,
     ) -> eyre::Result<()> {
-        if mesh_manager.total_batch_count() > self.indirect_draw_buffer.size() {
+        //the late draws of the hi-z test are stored after the early ones
+        let draw_capacity = mesh_manager.total_batch_count() * if self.hiz_culling { 2 } else { 1 };
+        if draw_capacity > self.indirect_draw_buffer.size() {
             //extend the capcacity of indirect draw buffers and parameter buffers
             let new_buffer = self.core.create_buffer(
                 self.indirect_draw_buffer.get_usage(),
-                (self.indirect_draw_buffer.size() * 2).max(mesh_manager.total_batch_count()),
+                (self.indirect_draw_buffer.size() * 2).max(draw_capacity),
                 false,
             )?;
 
-            compute_cmd.add_dependency(std::mem::replace(&mut self.indirect_draw_buffer, new_buffer));
+            let old_buffer = std::mem::replace(&mut self.indirect_draw_buffer, new_buffer);
+            descriptor_cache.forget_buffer(old_buffer.inner());
+            rp_man.forget_buffer(old_buffer.inner());
+            compute_cmd.add_dependency(old_buffer);
 
             // println!("resized indirect draw buffer to {}",self.indirect_draw_buffer.size());
         }
-        self.proj_view = cam_data.proj_view;
+        self.proj_view = proj_view;
 
         let material = material_manager.get_material(self.standart_opaque_material).unwrap();
-        draw_cmd.bind_material(&material);
-
-        draw_cmd.bind_descriptor_set(0, cam_data.dset);
-        draw_cmd.bind_index_buffer(self.shared_data.quad_index_buffer.as_slice());
-
-        let chunk_data_set = DescriptorSetBuilder::new()
-            .add_ssbo(&[mesh_manager.get_chunk_buffer()])
-            .build(material.pipeline().get_descriptor_set_layout(1).unwrap(), descriptor_pool)?;
-        draw_cmd.bind_descriptor_set(1, chunk_data_set);
+        self.bind_draw_state(mesh_manager, &material, draw_cmd, descriptor_cache, camera_set)?;
 
         let mut draw_counter = 0;
         self.draw_and_cull_mesh_type(
@@ -100,30 +138,31 @@ impl ChunkRenderManager {
             0,
             frame_index,
             &mut draw_counter,
-            descriptor_pool,
+            descriptor_cache,
         )?;
 
         //culling
-        compute_cmd.bind_pipeline(&self.shared_data.cull_pipeline);
-
-        let cull_set = DescriptorSetBuilder::new()
-            .add_ssbo(&[mesh_manager.get_chunk_buffer()])
-            .add_ssbo(&[mesh_manager.get_opaque_meshes().get_batch_descriptions()])
-            .add_ssbo(&[&self.indirect_draw_buffer])
-            // .add_ssbo(&[&self.draw_parameter_buffer])
-            .add_ssbo(&[&self.draw_count_buffer])
-            .add_ssbo(&[&self.framely_data[frame_index].draw_offset_buffer])
-            .build(self.shared_data.cull_pipeline.get_descriptor_set_layout(0).unwrap(), descriptor_pool)?;
-        compute_cmd.bind_descriptor_set(0, cull_set);
-
-        #[repr(C)]
-        #[derive(Debug, Pod, Clone, Copy, Zeroable)]
-        struct CullPush {
-            chunk_count: u32,
+        let cull_pipeline = self.cull_pipeline();
+        compute_cmd.bind_pipeline(&cull_pipeline);
+        compute_cmd.bind_descriptor_set(0, self.cull_set(mesh_manager, &cull_pipeline, descriptor_cache, frame_index)?);
+        compute_cmd.bind_descriptor_set(1, hiz_set);
+
+        let mut cull_flags = 0;
+        if self.occlusion_culling {
+            cull_flags |= CULL_FLAG_CAVE;
+        }
+        if self.hiz_culling {
+            cull_flags |= CULL_FLAG_HIZ_EARLY;
         }
 
         compute_cmd.push_constant(
-            &CullPush { chunk_count: mesh_manager.get_max_chunk_id() },
+            &CullPush {
+                frustum_planes: Frustum::from_proj_view(self.proj_view).to_gpu(),
+                chunk_count: mesh_manager.get_max_chunk_id(),
+                cull_flags,
+                late_draw_base: 0,
+                _padding: 0,
+            },
             vk::ShaderStageFlags::COMPUTE,
             0,
         );
@@ -136,41 +175,238 @@ impl ChunkRenderManager {
         }
 
         unsafe {
+            //only the counters of the early draws, the late cull clears its own
             compute_cmd.device().cmd_fill_buffer(
                 compute_cmd.inner(),
                 self.draw_count_buffer.inner(),
                 0,
-                self.draw_count_buffer.byte_size(),
+                MAX_POOLS as u64 * 4,
                 0,
             );
+            //the cull shader counts draws on top of the cleared count
+            BufferBarrier::record(
+                compute_cmd,
+                &[BufferBarrier::between(
+                    self.draw_count_buffer.inner(),
+                    BufferAccess::TransferWrite,
+                    BufferAccess::ComputeWrite,
+                )],
+            );
             compute_cmd.dispatch((chunk_count - 1) / group_size + 1, 1, 1);
+        }
 
-            compute_cmd.pipeline_barrier(
-                vk::PipelineStageFlags::COMPUTE_SHADER,
-                vk::PipelineStageFlags::DRAW_INDIRECT,
-                vk::DependencyFlags::DEVICE_GROUP,
-                &[],
-                &[
-                    vk::BufferMemoryBarrier::builder()
-                        .src_access_mask(vk::AccessFlags::SHADER_WRITE)
-                        .dst_access_mask(vk::AccessFlags::INDIRECT_COMMAND_READ)
-                        .buffer(self.indirect_draw_buffer.inner())
-                        .size(vk::WHOLE_SIZE)
-                        .build(),
-                    vk::BufferMemoryBarrier::builder()
-                        .src_access_mask(vk::AccessFlags::SHADER_WRITE)
-                        .dst_access_mask(vk::AccessFlags::INDIRECT_COMMAND_READ)
-                        .buffer(self.draw_count_buffer.inner())
-                        .size(vk::WHOLE_SIZE)
-                        .build(),
-                ],
-                &[],
+        Ok(())
+    }
+
+    pub fn cull_pipeline(&self) -> Arc<Pipeline> { self.shared_data.cull_pipeline.lock().unwrap().clone() }
+
+    fn cull_set(
+        &self,
+        mesh_manager: &ChunkMeshManager,
+        cull_pipeline: &Pipeline,
+        descriptor_cache: &mut DescriptorCache,
+        frame_index: usize,
+    ) -> eyre::Result<vk::DescriptorSet> {
+        let cull_layout = cull_pipeline.get_descriptor_set_layout(0).unwrap();
+        let draw_offset_buffer = &self.framely_data[frame_index].draw_offset_buffer;
+        let cull_key = DescriptorKey::new(cull_layout)
+            .buffer(mesh_manager.get_chunk_buffer().inner())
+            .buffer(mesh_manager.get_opaque_meshes().get_batch_descriptions().inner())
+            .buffer(self.indirect_draw_buffer.inner())
+            .buffer(self.draw_count_buffer.inner())
+            .buffer(draw_offset_buffer.inner())
+            .buffer(mesh_manager.get_hiz_visibility_buffer().inner());
+        descriptor_cache.get_or_build(cull_key, |pool| {
+            Ok(DescriptorSetBuilder::new()
+                .add_ssbo(&[mesh_manager.get_chunk_buffer()])
+                .add_ssbo(&[mesh_manager.get_opaque_meshes().get_batch_descriptions()])
+                .add_ssbo(&[&self.indirect_draw_buffer])
+                // .add_ssbo(&[&self.draw_parameter_buffer])
+                .add_ssbo(&[&self.draw_count_buffer])
+                .add_ssbo(&[draw_offset_buffer])
+                .add_ssbo(&[mesh_manager.get_hiz_visibility_buffer()])
+                .build(cull_layout, pool)?)
+        })
+    }
+
+    /*
+        Tests every chunk against the pyramid built from this frame's depth, runs after "hiz_build".
+        Chunks the early cull rejected that pass are written to the late draws, drawn by draw_late_chunks.
+    */
+    pub fn record_late_cull(
+        &self,
+        mesh_manager: &ChunkMeshManager,
+        compute_cmd: &mut CommandBuffer,
+        descriptor_cache: &mut DescriptorCache,
+        frame_index: usize,
+        hiz_set: vk::DescriptorSet,
+    ) -> eyre::Result<()> {
+        let chunk_count = mesh_manager.get_max_chunk_id();
+        if chunk_count == 0 {
+            return Ok(());
+        }
+
+        let cull_pipeline = self.cull_pipeline();
+        compute_cmd.bind_pipeline(&cull_pipeline);
+        compute_cmd.bind_descriptor_set(0, self.cull_set(mesh_manager, &cull_pipeline, descriptor_cache, frame_index)?);
+        compute_cmd.bind_descriptor_set(1, hiz_set);
+        compute_cmd.push_constant(
+            &CullPush {
+                frustum_planes: Frustum::from_proj_view(self.proj_view).to_gpu(),
+                chunk_count,
+                cull_flags: CULL_FLAG_HIZ_LATE,
+                late_draw_base: mesh_manager.total_batch_count(),
+                _padding: 0,
+            },
+            vk::ShaderStageFlags::COMPUTE,
+            0,
+        );
+
+        let group_size = 128;
+        unsafe {
+            compute_cmd.device().cmd_fill_buffer(
+                compute_cmd.inner(),
+                self.draw_count_buffer.inner(),
+                MAX_POOLS as u64 * 4,
+                MAX_POOLS as u64 * 4,
+                0,
+            );
+            BufferBarrier::record(
+                compute_cmd,
+                &[BufferBarrier::between(
+                    self.draw_count_buffer.inner(),
+                    BufferAccess::TransferWrite,
+                    BufferAccess::ComputeWrite,
+                )],
             );
+            compute_cmd.dispatch((chunk_count - 1) / group_size + 1, 1, 1);
+        }
+
+        Ok(())
+    }
+
+    //draws the chunks found by record_late_cull on top of the gbuffer of the early draws
+    pub fn draw_late_chunks(
+        &self,
+        mesh_manager: &ChunkMeshManager,
+        material_manager: &MaterialManager,
+        draw_cmd: &mut CommandBuffer,
+        descriptor_cache: &mut DescriptorCache,
+        camera_set: vk::DescriptorSet,
+    ) -> eyre::Result<()> {
+        let material = material_manager.get_material(self.standart_opaque_material).unwrap();
+        self.bind_draw_state(mesh_manager, &material, draw_cmd, descriptor_cache, camera_set)?;
+
+        //same layout as the early draws, offset by the late draw base
+        let opaque_meshes = mesh_manager.get_opaque_meshes();
+        let mut draw_counter = mesh_manager.total_batch_count();
+        for pool in opaque_meshes.get_pools() {
+            let multi_draw_count = opaque_meshes.pool_batch_count(pool.pool_id());
+            self.bind_pool(&material, pool, draw_cmd, descriptor_cache)?;
+            self.draw_indirect(draw_cmd, draw_counter, MAX_POOLS + pool.pool_id(), multi_draw_count);
+            draw_counter += multi_draw_count;
+        }
+
+        Ok(())
+    }
+
+    //material, camera, index buffer and chunk data shared by the early and late draws
+    fn bind_draw_state(
+        &self,
+        mesh_manager: &ChunkMeshManager,
+        material: &Material,
+        draw_cmd: &mut CommandBuffer,
+        descriptor_cache: &mut DescriptorCache,
+        camera_set: vk::DescriptorSet,
+    ) -> eyre::Result<()> {
+        draw_cmd.bind_material(material);
+
+        draw_cmd.bind_descriptor_set(0, camera_set);
+        unsafe {
+            let index_buffer = self.shared_data.quad_index_buffer.inner();
+            draw_cmd.device().cmd_bind_index_buffer(draw_cmd.inner(), index_buffer, 0, vk::IndexType::UINT32);
         }
 
+        let chunk_data_layout = material.pipeline().get_descriptor_set_layout(1).unwrap();
+        let chunk_data_key = DescriptorKey::new(chunk_data_layout).buffer(mesh_manager.get_chunk_buffer().inner());
+        let chunk_data_set = descriptor_cache.get_or_build(chunk_data_key, |pool| {
+            Ok(DescriptorSetBuilder::new().add_ssbo(&[mesh_manager.get_chunk_buffer()]).build(chunk_data_layout, pool)?)
+        })?;
+        draw_cmd.bind_descriptor_set(1, chunk_data_set);
+        Ok(())
+    }
+
+    fn bind_pool(
+        &self,
+        material: &Material,
+        pool: &PrimativePool,
+        draw_cmd: &mut CommandBuffer,
+        descriptor_cache: &mut DescriptorCache,
+    ) -> eyre::Result<()> {
+        let layout = material.pipeline().get_descriptor_set_layout(3).unwrap();
+        let key = DescriptorKey::new(layout).buffer(pool.get_primative_buffer().inner());
+        let set = descriptor_cache.get_or_build(key, |descriptor_pool| {
+            Ok(DescriptorSetBuilder::new().add_ssbo(&[pool.get_primative_buffer()]).build(layout, descriptor_pool)?)
+        })?;
+        draw_cmd.bind_descriptor_set(3, set);
         Ok(())
     }
 
+    //draws up to max_count draws starting at first_draw, the count is read from the counter
+    fn draw_indirect(&self, draw_cmd: &mut CommandBuffer, first_draw: u32, counter_index: u32, max_count: u32) {
+        let stride = 20;
+        unsafe {
+            draw_cmd.draw_indexed_indirect_count(
+                self.indirect_draw_buffer.inner(),
+                first_draw as u64 * stride as u64, //byte offset
+                self.draw_count_buffer.inner(),
+                counter_index as u64 * 4 as u64, //byte offset
+                max_count,
+                stride,
+            );
+        }
+    }
+
+    //buffers used by the cull dispatch and by the draws of cull_and_draw_chunks
+    pub fn add_buffer_usage(&self, mesh_manager: &ChunkMeshManager, compute: &mut BufferUsage, graphics: &mut BufferUsage) {
+        let opaque_meshes = mesh_manager.get_opaque_meshes();
+
+        compute.add(mesh_manager.get_chunk_buffer().inner(), BufferAccess::ComputeRead);
+        compute.add(opaque_meshes.get_batch_descriptions().inner(), BufferAccess::ComputeRead);
+        //the early hi-z test marks the chunks it rejects
+        let hiz_access = if self.hiz_culling { BufferAccess::ComputeWrite } else { BufferAccess::ComputeRead };
+        compute.add(mesh_manager.get_hiz_visibility_buffer().inner(), hiz_access);
+        compute.add(self.indirect_draw_buffer.inner(), BufferAccess::ComputeWrite);
+        compute.add(self.draw_count_buffer.inner(), BufferAccess::TransferWrite);
+        compute.add(self.draw_count_buffer.inner(), BufferAccess::ComputeWrite);
+
+        graphics.add(self.indirect_draw_buffer.inner(), BufferAccess::IndirectRead);
+        graphics.add(self.draw_count_buffer.inner(), BufferAccess::IndirectRead);
+        graphics.add(mesh_manager.get_chunk_buffer().inner(), BufferAccess::VertexRead);
+        for buffer in opaque_meshes.pool_buffers() {
+            graphics.add(buffer, BufferAccess::VertexRead);
+        }
+    }
+
+    //buffers used by record_late_cull and by the draws of draw_late_chunks
+    pub fn add_late_buffer_usage(&self, mesh_manager: &ChunkMeshManager, compute: &mut BufferUsage, graphics: &mut BufferUsage) {
+        let opaque_meshes = mesh_manager.get_opaque_meshes();
+
+        compute.add(mesh_manager.get_chunk_buffer().inner(), BufferAccess::ComputeRead);
+        compute.add(opaque_meshes.get_batch_descriptions().inner(), BufferAccess::ComputeRead);
+        compute.add(mesh_manager.get_hiz_visibility_buffer().inner(), BufferAccess::ComputeWrite);
+        compute.add(self.indirect_draw_buffer.inner(), BufferAccess::ComputeWrite);
+        compute.add(self.draw_count_buffer.inner(), BufferAccess::TransferWrite);
+        compute.add(self.draw_count_buffer.inner(), BufferAccess::ComputeWrite);
+
+        graphics.add(self.indirect_draw_buffer.inner(), BufferAccess::IndirectRead);
+        graphics.add(self.draw_count_buffer.inner(), BufferAccess::IndirectRead);
+        graphics.add(mesh_manager.get_chunk_buffer().inner(), BufferAccess::VertexRead);
+        for buffer in opaque_meshes.pool_buffers() {
+            graphics.add(buffer, BufferAccess::VertexRead);
+        }
+    }
+
     pub fn new_shared_data(
         core: &Arc<Core>,
         material_manager: &mut MaterialManager,
@@ -182,11 +418,11 @@ impl ChunkRenderManager {
         let shared_data = ChunkRenderSharedData {
             quad_index_buffer: cmd.gpu_buffer_from_slice(
                 vk::BufferUsageFlags::INDEX_BUFFER,
-                &(0..16384)
+                &(0..QUADS_PER_REIGON)
                     .flat_map(|i| [i * 4 + 0, i * 4 + 1, i * 4 + 2, i * 4 + 2, i * 4 + 1, i * 4 + 3])
-                    .collect::<Vec<u16>>(),
+                    .collect::<Vec<u32>>(),
             )?,
-            cull_pipeline: material_manager.compile_compute_shader("res/chunk_cull.comp")?.0,
+            cull_pipeline: Mutex::new(material_manager.compile_compute_shader(CULL_SHADER)?.0),
         };
 
         cmd.end()?;
@@ -203,14 +439,14 @@ impl ChunkRenderManager {
         primative_id: u32,
         frame_index: usize,
         draw_counter: &mut u32,
-        descriptor_pool: &mut DescriptorPool,
+        descriptor_cache: &mut DescriptorCache,
     ) -> eyre::Result<()> {
         //bind materials
         draw_cmd.bind_material(material);
 
-        for (i, pool) in primative_man.get_pools().iter().enumerate() {
-            let multi_draw_index = primative_id * 256 + i as u32;
-            let multi_draw_count = pool.get_batch_count();
+        for pool in primative_man.get_pools() {
+            let multi_draw_index = primative_id * MAX_POOLS + pool.pool_id();
+            let multi_draw_count = primative_man.pool_batch_count(pool.pool_id());
             let multi_draw_offset = *draw_counter;
             *draw_counter += multi_draw_count;
 
@@ -222,26 +458,13 @@ impl ChunkRenderManager {
             // draw_cmd.push_constant(&push, vk::ShaderStageFlags::VERTEX, 0);
 
             if true  {
-                let set = DescriptorSetBuilder::new()
-                    .add_ssbo(&[pool.get_primative_buffer()])
-                    .build(material.pipeline().get_descriptor_set_layout(3).unwrap(), descriptor_pool)?;
-                draw_cmd.bind_descriptor_set(3, set);
+                self.bind_pool(material, pool, draw_cmd, descriptor_cache)?;
             } else {
                 draw_cmd.bind_vertex_buffers(&[pool.get_primative_buffer()])
             }
 
             //draw
-            unsafe {
-                let stride = 20;
-                draw_cmd.draw_indexed_indirect_count(
-                    self.indirect_draw_buffer.inner(),
-                    multi_draw_offset as u64 * stride as u64, //byte offset
-                    self.draw_count_buffer.inner(),
-                    multi_draw_index as u64 * 4 as u64, //byte offset
-                    multi_draw_count,
-                    stride,
-                );
-            }
+            self.draw_indirect(draw_cmd, multi_draw_offset, multi_draw_index, multi_draw_count);
         }
 
         Ok(())
@@ -249,7 +472,15 @@ impl ChunkRenderManager {
 
     pub(crate) fn new(core: &Arc<Core>, material_manager: &mut MaterialManager) -> eyre::Result<ChunkRenderManager> {
         let shared_data = Self::new_shared_data(core, material_manager)?;
-        let draw_counter_count = 1 * 256;
+        Self::with_shared_data(core, shared_data)
+    }
+
+    //managers drawing the same chunks from different views share the index buffer and cull pipeline
+    pub(crate) fn with_shared_data(
+        core: &Arc<Core>,
+        shared_data: Arc<ChunkRenderSharedData>,
+    ) -> eyre::Result<ChunkRenderManager> {
+        let draw_counter_count = 1 * MAX_POOLS;
 
         Ok(Self {
             core: core.clone(),
@@ -262,7 +493,8 @@ impl ChunkRenderManager {
                 vk::BufferUsageFlags::STORAGE_BUFFER
                     | vk::BufferUsageFlags::TRANSFER_DST
                     | vk::BufferUsageFlags::INDIRECT_BUFFER,
-                draw_counter_count,
+                //the counters of the early draws followed by the ones of the late draws
+                draw_counter_count * 2,
                 false,
             )?,
             // draw_parameter_buffer: core.create_buffer(
@@ -284,18 +516,27 @@ impl ChunkRenderManager {
             proj_view: glam::Mat4::IDENTITY,
             shared_data,
             standart_opaque_material: MaterialID::NULL,
+            occlusion_culling: false,
+            hiz_culling: false,
         })
     }
 }
 
 mod render_system {
-    use std::{borrow::BorrowMut, ops::Deref};
+    use std::ops::Deref;
 
     use crate::{
         game::FrameIndex,
-        render::{chunk_render::ChunkVertex, renderpassmanager::RenderPassManager},
+        render::{
+            chunk_render::ChunkVertex,
+            renderpasses::DeferedPass,
+            renderpassmanager::RenderPassManager,
+            shadows::{ShadowCascades, MAX_CASCADES},
+        },
     };
 
+    const SHADOW_CASCADE_NAMES: [&str; MAX_CASCADES] = ["shadow0", "shadow1", "shadow2", "shadow3"];
+
     use super::*;
     use specs::prelude::*;
 
@@ -308,24 +549,37 @@ mod render_system {
             core: &Arc<Core>,
             renderpass_manager: &RenderPassManager,
             material_manager: &mut MaterialManager,
+            hot_reload: &mut HotReload,
         ) -> eyre::Result<ChunkRendererData> {
             let mut d = Self { render_managers: HashMap::new() };
 
+            for subpass in ["gpass", "shadow"] {
+                renderpass_manager
+                    .get_subpass(subpass)
+                    .ok_or_else(|| eyre::eyre!("subpass \"{}\" is not registered", subpass))?;
+            }
+
             material_manager.set_vertex_layout("chunk_vertex".into(), ChunkVertex::get_desciption());
 
-            let mut render_manager = ChunkRenderManager::new(core, material_manager).unwrap();
+            let mut render_manager = ChunkRenderManager::new(core, material_manager)?;
+            render_manager.occlusion_culling = true;
+            render_manager.hiz_culling = true;
 
             let mut cmd = core.new_cmd();
             cmd.begin()?;
-            let gpass = renderpass_manager.get_subpass("gpass").unwrap();
 
             render_manager.set_material(
                 0,
-                material_manager.load_material(
-                    &mut cmd,
-                    "res/chunk.mat.yaml".into(),
-                )?,
+                hot_reload.load_material(material_manager, &mut cmd, "res/chunk.mat.yaml")?,
             );
+            hot_reload.watch(CULL_SHADER);
+
+            let shadow_material = hot_reload.load_material(material_manager, &mut cmd, "res/chunk_shadow.mat.yaml")?;
+            for name in SHADOW_CASCADE_NAMES {
+                let mut shadow_manager = ChunkRenderManager::with_shared_data(core, render_manager.shared_data.clone())?;
+                shadow_manager.set_material(0, shadow_material);
+                d.render_managers.insert(name, shadow_manager);
+            }
 
             cmd.end()?;
             cmd.immediate_submit()?;
@@ -347,18 +601,25 @@ mod render_system {
             ReadExpect<'a, ChunkMeshManager>,
             ReadExpect<'a, MaterialManager>,
             ReadExpect<'a, FrameIndex>,
+            ReadExpect<'a, ShadowCascades>,
+            WriteExpect<'a, HiZ>,
         );
 
         fn run(
             &mut self,
-            (mut render_data, rp_man, gloabls, cam_data, mesh_manager, mat_man, frame_index): Self::SystemData,
+            (mut render_data, rp_man, gloabls, cam_data, mesh_manager, mat_man, frame_index, cascades, mut hiz): Self::SystemData,
         ) {
             let gpass = rp_man.get_subpass("gpass").unwrap();
             let mut draw_cmd = gpass.new_cmd().unwrap();
             let mut ccmd = gloabls.core().new_secondry_cmd();
             ccmd.begin_secondry(None).unwrap();
 
-            let mut descriptor_pool = gloabls.frame_data().descriptor_pool.lock().unwrap();
+            let mut descriptor_cache = gloabls.descriptor_cache();
+
+            //every manager shares the cull pipeline and with it the layout of the hi-z set
+            hiz.update_gpu_data(frame_index.index(), cam_data.proj_view, rp_man.extent());
+            let hiz_layout = render_data.render_managers["gpass"].cull_pipeline().get_descriptor_set_layout(1).unwrap();
+            let hiz_set = hiz.cull_set(hiz_layout, &rp_man, &mut descriptor_cache, frame_index.index()).unwrap();
 
             let chunk_render_manager = render_data.render_managers.get_mut("gpass").unwrap();
             chunk_render_manager
@@ -367,34 +628,127 @@ mod render_system {
                     &mat_man,
                     &mut draw_cmd,
                     &mut ccmd,
-                    descriptor_pool.borrow_mut(),
+                    &mut descriptor_cache,
+                    &rp_man,
                     frame_index.index(),
-                    &cam_data,
+                    cam_data.proj_view,
+                    cam_data.dset,
+                    hiz_set,
                 )
                 .unwrap();
 
+            let mut late_cmd = gloabls.core().new_secondry_cmd();
+            late_cmd.begin_secondry(None).unwrap();
+            chunk_render_manager
+                .record_late_cull(&mesh_manager, &mut late_cmd, &mut descriptor_cache, frame_index.index(), hiz_set)
+                .unwrap();
+            late_cmd.end().unwrap();
+
+            //recorded for the gpass subpass, "late_gpass" executes it in the gbuffer renderpass again
+            let mut late_draw_cmd = gpass.new_cmd().unwrap();
+            chunk_render_manager
+                .draw_late_chunks(&mesh_manager, &mat_man, &mut late_draw_cmd, &mut descriptor_cache, cam_data.dset)
+                .unwrap();
+            late_draw_cmd.end().unwrap();
+            rp_man.get_renderpass_ref::<DeferedPass>("deferred_render").unwrap().submit_late_cmd(late_draw_cmd);
+
+            let mut late_usage = BufferUsage::new();
+            let mut late_graphics_usage = BufferUsage::new();
+            chunk_render_manager.add_late_buffer_usage(&mesh_manager, &mut late_usage, &mut late_graphics_usage);
+
+            //every cascade is culled with its own frustum and drawn into its region of the shadow map
+            let shadow_subpass = rp_man.get_subpass("shadow").unwrap();
+            let mut shadow_cmd = shadow_subpass.new_cmd().unwrap();
+
+            for (i, camera) in cascades.cameras.iter().enumerate() {
+                let viewport = cascades.viewport(i);
+                let scissor = vk::Rect2D {
+                    offset: vk::Offset2D { x: viewport.x as i32, y: viewport.y as i32 },
+                    extent: vk::Extent2D { width: viewport.width as u32, height: viewport.height as u32 },
+                };
+                unsafe {
+                    shadow_cmd.device().cmd_set_viewport(shadow_cmd.inner(), 0, &[viewport]);
+                    shadow_cmd.device().cmd_set_scissor(shadow_cmd.inner(), 0, &[scissor]);
+                }
+
+                let shadow_manager = render_data.render_managers.get_mut(SHADOW_CASCADE_NAMES[i]).unwrap();
+                shadow_manager
+                    .cull_and_draw_chunks(
+                        &mesh_manager,
+                        &mat_man,
+                        &mut shadow_cmd,
+                        &mut ccmd,
+                        &mut descriptor_cache,
+                        &rp_man,
+                        frame_index.index(),
+                        camera.proj_view,
+                        camera.dset,
+                        hiz_set,
+                    )
+                    .unwrap();
+            }
+
             ccmd.end().unwrap();
             // draw_cmd.end().unwrap();
 
+            let mut compute_usage = BufferUsage::new();
+            let mut graphics_usage = BufferUsage::new();
+            for manager in render_data.render_managers.values() {
+                manager.add_buffer_usage(&mesh_manager, &mut compute_usage, &mut graphics_usage);
+            }
+
             gpass.submit_cmd(draw_cmd).unwrap();
-            rp_man.submit_compute(ccmd);
+            shadow_subpass.submit_cmd(shadow_cmd).unwrap();
+            rp_man.submit_compute(ccmd, compute_usage);
+
+            rp_man.submit_compute_to("chunk_cull_late", late_cmd, late_usage);
+            rp_man.use_in_graphics(graphics_usage);
+            rp_man.use_in_graphics_after("chunk_cull_late", late_graphics_usage);
+        }
+    }
+
+    //swaps in reloaded chunk materials and recompiles the cull shader when it changes
+    pub struct ReloadChunkPipelines;
+
+    impl<'a> System<'a> for ReloadChunkPipelines {
+        type SystemData = (WriteExpect<'a, ChunkRendererData>, WriteExpect<'a, MaterialManager>, ReadExpect<'a, HotReload>);
+
+        fn run(&mut self, (mut render_data, mut mat_man, hot_reload): Self::SystemData) {
+            for manager in render_data.render_managers.values_mut() {
+                manager.remap_material(&hot_reload);
+            }
+
+            if !hot_reload.is_changed(CULL_SHADER) {
+                return;
+            }
+
+            //every manager shares the same data
+            let Some(manager) = render_data.render_managers.values().next() else { return };
+            match mat_man.compile_compute_shader(CULL_SHADER) {
+                Ok((pipeline, ..)) => {
+                    *manager.shared_data.cull_pipeline.lock().unwrap() = pipeline;
+                    eprintln!("reloaded \"{CULL_SHADER}\"");
+                }
+                Err(e) => eprintln!("failed to reload \"{CULL_SHADER}\", keeping the old pipeline: {e}"),
+            }
         }
     }
 }
 
 pub fn register_render_data(game: &mut Game) -> eyre::Result<()> {
+    let core = game.core()?;
     let chunkrender_data = render_system::ChunkRendererData::new(
-        &game.core, //
+        &core, //
         &game.world.fetch::<RenderPassManager>(),
         &mut game.world.fetch_mut::<MaterialManager>(),
+        &mut game.world.fetch_mut::<HotReload>(),
     )?;
 
     game.world.insert(chunkrender_data);
-    game.world.insert(ChunkMeshManager::new(&game.core)?);
+    game.world.insert(ChunkMeshManager::new(&core)?);
 
-    game.insert_frame_task(Box::new(|w, d| {
-        d.add(render_system::ChunkRenderer, "chunk render", &["chunk mesh"]);
-    }));
+    game.add_system(Stage::RenderPrep, render_system::ChunkRenderer, "chunk render", &["shadow cascades"]);
+    game.add_system(Stage::Input, render_system::ReloadChunkPipelines, "reload chunk pipelines", &["hot reload"]);
 
     Ok(())
 }
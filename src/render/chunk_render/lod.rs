@@ -0,0 +1,280 @@
+use std::collections::{HashMap, HashSet};
+
+use specs::prelude::*;
+
+use crate::game::{
+    voxels::{world_pos_to_chunkpos, ChunkComponent, Tile, AIR, CHUNK_SIZE},
+    CameraData,
+};
+
+use super::{mesher::TileGrid, visibility::FACE_OFFSETS};
+
+//cells of lod l are 2^l voxels wide, quads store the lod in 2 bits
+pub const MAX_LOD: u8 = 3;
+
+#[derive(Clone, Copy, Debug)]
+pub struct LodSettings {
+    //chunk distance from the camera at which lod 1, 2 and 3 start
+    pub distances: [i32; MAX_LOD as usize],
+    pub enabled: bool,
+}
+
+impl Default for LodSettings {
+    fn default() -> Self { Self { distances: [8, 16, 24], enabled: true } }
+}
+
+impl LodSettings {
+    //distance is measured in chunks along the furthest axis
+    pub fn select_lod(&self, chunk: [i32; 3], camera_chunk: [i32; 3]) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+
+        let distance = (0..3).map(|i| (chunk[i] - camera_chunk[i]).abs()).max().unwrap();
+        self.distances.iter().filter(|d| distance >= **d).count() as u8
+    }
+}
+
+/*
+    Coarse voxel grid of a chunk with a border of one cell from the neighbouring chunks. A cell is
+    solid if at least half of its voxels aren't air and takes the tile of its highest voxel, so
+    the surface keeps its top tiles.
+*/
+pub struct LodGrid {
+    size: i32,
+    cells: Vec<Tile>,
+}
+
+impl LodGrid {
+    pub fn downsample(grid: &impl TileGrid, lod: u8) -> LodGrid {
+        let scale = 1 << lod;
+        let size = CHUNK_SIZE as i32 / scale;
+        let padded = (size + 2) as usize;
+        let mut cells = Vec::with_capacity(padded * padded * padded);
+
+        for z in -1..=size {
+            for y in -1..=size {
+                for x in -1..=size {
+                    let mut solid = 0;
+                    let mut top = None;
+                    for vy in (y * scale..(y + 1) * scale).rev() {
+                        for vz in z * scale..(z + 1) * scale {
+                            for vx in x * scale..(x + 1) * scale {
+                                let tile = grid.get_tile(vx, vy, vz);
+                                if tile != AIR {
+                                    solid += 1;
+                                    top.get_or_insert(tile);
+                                }
+                            }
+                        }
+                    }
+
+                    let is_solid = solid * 2 >= scale * scale * scale;
+                    cells.push(if is_solid { top.unwrap() } else { AIR });
+                }
+            }
+        }
+
+        Self { size, cells }
+    }
+
+    pub fn size(&self) -> i32 { self.size }
+}
+
+impl TileGrid for LodGrid {
+    fn get_tile(&self, x: i32, y: i32, z: i32) -> Tile {
+        let padded = self.size + 2;
+        self.cells[((x + 1) + (y + 1) * padded + (z + 1) * padded * padded) as usize]
+    }
+}
+
+/*
+    Cells outside of the chunk on a seam side read as air, so every face on the border to a
+    neighbour with another lod is meshed. The coarser side may not cover what the finer side
+    culled against, the extra faces close the holes.
+*/
+pub struct SeamGrid<'a, G: TileGrid> {
+    pub grid: &'a G,
+    pub size: i32,
+    //bits are faces in FACE_OFFSETS order
+    pub seams: u8,
+}
+
+impl<'a, G: TileGrid> TileGrid for SeamGrid<'a, G> {
+    fn get_tile(&self, x: i32, y: i32, z: i32) -> Tile {
+        let pos = [x, y, z];
+        let on_seam = (0..3).any(|axis| {
+            (pos[axis] >= self.size && self.seams & (1 << (axis * 2)) != 0)
+                || (pos[axis] < 0 && self.seams & (1 << (axis * 2 + 1)) != 0)
+        });
+
+        if on_seam {
+            AIR
+        } else {
+            self.grid.get_tile(x, y, z)
+        }
+    }
+}
+
+//lod every loaded chunk should be meshed at and the chunks that need a new mesh because of it
+#[derive(Default)]
+pub struct ChunkLods {
+    lods: HashMap<[i32; 3], u8>,
+    dirty: HashSet<[i32; 3]>,
+}
+
+impl ChunkLods {
+    pub fn new() -> ChunkLods { Self::default() }
+
+    pub fn lod(&self, pos: &[i32; 3]) -> u8 { self.lods.get(pos).copied().unwrap_or(0) }
+
+    //faces whose neighbour is loaded at another lod
+    pub fn seam_mask(&self, pos: &[i32; 3]) -> u8 {
+        let lod = self.lod(pos);
+        FACE_OFFSETS.iter().enumerate().fold(0, |mask, (face, offset)| {
+            let neighbour = [0, 1, 2].map(|i| pos[i] + offset[i]);
+            match self.lods.get(&neighbour) {
+                Some(l) if *l != lod => mask | (1 << face),
+                _ => mask,
+            }
+        })
+    }
+
+    //a chunk whose lod changes is remeshed together with its neighbours, their seams change with it
+    pub fn set_lod(&mut self, pos: [i32; 3], lod: u8) {
+        if self.lods.insert(pos, lod) == Some(lod) {
+            return;
+        }
+
+        self.dirty.insert(pos);
+        for offset in FACE_OFFSETS {
+            self.dirty.insert([0, 1, 2].map(|i| pos[i] + offset[i]));
+        }
+    }
+
+    pub fn take_dirty(&mut self) -> HashSet<[i32; 3]> { std::mem::take(&mut self.dirty) }
+}
+
+pub struct UpdateChunkLods;
+
+impl<'a> System<'a> for UpdateChunkLods {
+    type SystemData = (
+        WriteExpect<'a, ChunkLods>,
+        ReadStorage<'a, ChunkComponent>,
+        ReadExpect<'a, CameraData>,
+        ReadExpect<'a, LodSettings>,
+    );
+
+    fn run(&mut self, (mut lods, chunks, cam_data, settings): Self::SystemData) {
+        let camera_chunk = world_pos_to_chunkpos(cam_data.position.floor().as_ivec3().to_array());
+        for chunk in chunks.join() {
+            lods.set_lod(chunk.chunkpos, settings.select_lod(chunk.chunkpos, camera_chunk));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::game::voxels::{GRASS, STONE};
+
+    use super::*;
+
+    struct FnGrid<F: Fn(i32, i32, i32) -> Tile>(F);
+
+    impl<F: Fn(i32, i32, i32) -> Tile> TileGrid for FnGrid<F> {
+        fn get_tile(&self, x: i32, y: i32, z: i32) -> Tile { (self.0)(x, y, z) }
+    }
+
+    #[test]
+    fn lod_grows_with_the_furthest_axis() {
+        let settings = LodSettings::default();
+        assert_eq!(settings.select_lod([7, 0, -7], [0, 0, 0]), 0);
+        assert_eq!(settings.select_lod([8, 0, 0], [0, 0, 0]), 1);
+        assert_eq!(settings.select_lod([1, -16, 3], [0, 0, 0]), 2);
+        assert_eq!(settings.select_lod([0, 0, 100], [0, 0, 0]), MAX_LOD);
+        assert_eq!(settings.select_lod([30, 2, 0], [25, 0, 0]), 0);
+
+        let disabled = LodSettings { enabled: false, ..Default::default() };
+        assert_eq!(disabled.select_lod([100, 0, 0], [0, 0, 0]), 0);
+    }
+
+    #[test]
+    fn cells_are_solid_when_half_their_voxels_are() {
+        //lod 1 cells are 2 voxels wide, the chunk on -x is all stone
+        let grid = FnGrid(|x, y, z| match (x, y, z) {
+            (x, ..) if x < 0 => STONE,
+            (1, 1, 1) => GRASS,
+            (0..=1, 0, 0..=1) => STONE,
+            (3, 0, 1) => AIR,
+            (2..=5, 0, 0..=1) => STONE,
+            _ => AIR,
+        });
+        let lod_grid = LodGrid::downsample(&grid, 1);
+
+        assert_eq!(lod_grid.size(), CHUNK_SIZE as i32 / 2);
+        //5 of 8 voxels, the grass on top wins over the stone below it
+        assert_eq!(lod_grid.get_tile(0, 0, 0), GRASS);
+        //3 of 8
+        assert_eq!(lod_grid.get_tile(1, 0, 0), AIR);
+        //4 of 8
+        assert_eq!(lod_grid.get_tile(2, 0, 0), STONE);
+        assert_eq!(lod_grid.get_tile(0, 1, 0), AIR);
+        //the border cells come from the neighbours
+        assert_eq!(lod_grid.get_tile(-1, 5, 7), STONE);
+        assert_eq!(lod_grid.get_tile(lod_grid.size(), 0, 0), AIR);
+
+        let full = LodGrid::downsample(&grid, 0);
+        assert_eq!(full.size(), CHUNK_SIZE as i32);
+        assert_eq!(full.get_tile(1, 1, 1), GRASS);
+        assert_eq!(full.get_tile(3, 0, 1), AIR);
+    }
+
+    #[test]
+    fn seam_bits_follow_face_offsets() {
+        let grid = FnGrid(|_, _, _| STONE);
+        let size = 4;
+        //the cell just outside of the chunk on every face
+        let outside = FACE_OFFSETS.map(|offset| offset.map(|o| if o > 0 { size } else if o < 0 { -1 } else { 1 }));
+
+        for face in 0..6 {
+            let seam_grid = SeamGrid { grid: &grid, size, seams: 1 << face };
+            for (other, [x, y, z]) in outside.iter().enumerate() {
+                let expected = if other == face { AIR } else { STONE };
+                assert_eq!(seam_grid.get_tile(*x, *y, *z), expected, "seam {face}, cell {other}");
+            }
+            assert_eq!(seam_grid.get_tile(0, 0, 0), STONE);
+            assert_eq!(seam_grid.get_tile(size - 1, size - 1, size - 1), STONE);
+        }
+    }
+
+    #[test]
+    fn seams_face_neighbours_with_another_lod() {
+        let mut lods = ChunkLods::new();
+        lods.set_lod([0, 0, 0], 0);
+        lods.set_lod([1, 0, 0], 1);
+        lods.set_lod([0, 1, 0], 0);
+
+        assert_eq!(lods.seam_mask(&[0, 0, 0]), 1 << 0);
+        assert_eq!(lods.seam_mask(&[1, 0, 0]), 1 << 1);
+        //unloaded neighbours are no seam
+        assert_eq!(lods.seam_mask(&[0, 1, 0]), 0);
+        assert_eq!(lods.lod(&[5, 5, 5]), 0);
+    }
+
+    #[test]
+    fn lod_changes_dirty_the_neighbours() {
+        let mut lods = ChunkLods::new();
+        lods.set_lod([0, 0, 0], 0);
+        lods.set_lod([1, 0, 0], 1);
+        assert!(lods.take_dirty().contains(&[2, 0, 0]));
+
+        lods.set_lod([1, 0, 0], 1);
+        assert!(lods.take_dirty().is_empty());
+
+        lods.set_lod([1, 0, 0], 2);
+        let mut expected: HashSet<[i32; 3]> = FACE_OFFSETS.map(|o| [1 + o[0], o[1], o[2]]).into_iter().collect();
+        expected.insert([1, 0, 0]);
+        assert_eq!(lods.take_dirty(), expected);
+        assert_eq!(lods.seam_mask(&[0, 0, 0]), 1 << 0);
+    }
+}
@@ -0,0 +1,133 @@
+use std::path::{Path, PathBuf};
+
+use eyre::WrapErr;
+
+use super::{mesher::ALL_SECTIONS, visibility::ChunkConnectivity, ChunkMesh, Quad};
+
+const MAGIC: [u8; 4] = *b"CMSH";
+//bump when the quad layout or the meshing changes, older files are treated as misses
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 4 + 4 + 8 + 6 + 4;
+
+//64 bit fnv-1a, unlike the std hashers it is stable between builds
+pub fn content_hash(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes.into_iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/*
+    Meshes stored as one file per chunk, lod and seam mask, next to the saved world. A file is only
+    used if the hash of the tiles it was meshed from matches, so changed chunks or neighbours just
+    miss. Layout: magic, version, tile hash, connectivity, quad count and the quads. The directory
+    is only created by the first store.
+*/
+pub struct MeshCache {
+    dir: PathBuf,
+}
+
+impl MeshCache {
+    pub fn new(dir: impl AsRef<Path>) -> MeshCache { Self { dir: dir.as_ref().to_path_buf() } }
+
+    fn path(&self, pos: [i32; 3], lod: u8, seams: u8) -> PathBuf {
+        self.dir.join(format!("{}_{}_{}_{lod}_{seams}.mesh", pos[0], pos[1], pos[2]))
+    }
+
+    pub fn load(&self, pos: [i32; 3], lod: u8, seams: u8, hash: u64) -> Option<ChunkMesh> {
+        let bytes = std::fs::read(self.path(pos, lod, seams)).ok()?;
+        let (quads, connectivity) = decode(&bytes, hash)?;
+        Some(ChunkMesh::new(pos, lod, seams, ALL_SECTIONS, quads, connectivity))
+    }
+
+    //written to a temporary file first so a crash can't leave a truncated mesh behind
+    pub fn store(&self, mesh: &ChunkMesh, hash: u64) -> eyre::Result<()> {
+        let path = self.path(mesh.pos, mesh.lod, mesh.seams);
+        let tmp = path.with_extension("tmp");
+        std::fs::create_dir_all(&self.dir).wrap_err_with(|| format!("couldn't create mesh cache {}", self.dir.display()))?;
+        std::fs::write(&tmp, encode(mesh, hash))?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
+
+fn encode(mesh: &ChunkMesh, hash: u64) -> Vec<u8> {
+    let quads: &[u8] = bytemuck::cast_slice(&mesh.quads);
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + quads.len());
+    bytes.extend(MAGIC);
+    bytes.extend(VERSION.to_le_bytes());
+    bytes.extend(hash.to_le_bytes());
+    bytes.extend(mesh.connectivity.to_bits());
+    bytes.extend((mesh.quads.len() as u32).to_le_bytes());
+    bytes.extend(quads);
+    bytes
+}
+
+//none if the file is from another version, for other tiles or truncated
+fn decode(bytes: &[u8], hash: u64) -> Option<(Vec<Quad>, ChunkConnectivity)> {
+    let header = bytes.get(..HEADER_SIZE)?;
+    let u32_at = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+
+    if header[..4] != MAGIC || u32_at(4) != VERSION || u64::from_le_bytes(header[8..16].try_into().unwrap()) != hash {
+        return None;
+    }
+
+    let connectivity = ChunkConnectivity::from_bits(header[16..22].try_into().unwrap());
+    let quad_count = u32_at(22) as usize;
+    let quad_bytes = bytes.get(HEADER_SIZE..)?;
+    if quad_bytes.len() != quad_count * std::mem::size_of::<Quad>() {
+        return None;
+    }
+
+    //the file contents aren't aligned for Quad
+    let quads = quad_bytes.chunks_exact(std::mem::size_of::<Quad>()).map(bytemuck::pod_read_unaligned).collect();
+    Some((quads, connectivity))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mesh(pos: [i32; 3], quads: u32) -> ChunkMesh {
+        let quads = (0..quads).map(|i| Quad { data: [0, i] }).collect();
+        ChunkMesh::new(pos, 1, 0b10, ALL_SECTIONS, quads, ChunkConnectivity::from_bits([1, 2, 3, 4, 5, 6]))
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mesh_cache_{name}_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let mesh = mesh([1, -2, 3], 5);
+        let (quads, connectivity) = decode(&encode(&mesh, 42), 42).unwrap();
+        assert_eq!(quads.iter().map(|q| q.data).collect::<Vec<_>>(), mesh.quads.iter().map(|q| q.data).collect::<Vec<_>>());
+        assert_eq!(connectivity.to_bits(), [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn other_tiles_or_truncated_files_miss() {
+        let bytes = encode(&mesh([0; 3], 5), 42);
+        assert!(decode(&bytes, 43).is_none());
+        assert!(decode(&bytes[..bytes.len() - 1], 42).is_none());
+        assert!(decode(&bytes[..HEADER_SIZE - 1], 42).is_none());
+        assert_eq!(decode(&encode(&mesh([0; 3], 0), 42), 42).map(|(q, _)| q.len()), Some(0));
+    }
+
+    #[test]
+    fn directory_is_created_by_the_first_store() {
+        let dir = temp_dir("lazy");
+        let cache = MeshCache::new(&dir);
+        assert!(!dir.exists());
+        assert!(cache.load([0; 3], 1, 0b10, 42).is_none());
+        assert!(!dir.exists());
+
+        cache.store(&mesh([0; 3], 3), 42).unwrap();
+        assert!(dir.exists());
+        assert_eq!(cache.load([0; 3], 1, 0b10, 42).map(|m| m.quads.len()), Some(3));
+        assert!(cache.load([0; 3], 1, 0b10, 7).is_none());
+        //other lods and seams are separate files
+        assert!(cache.load([0; 3], 0, 0b10, 42).is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
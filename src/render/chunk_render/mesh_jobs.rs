@@ -0,0 +1,227 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+};
+
+use rayon::prelude::*;
+
+use crate::game::voxels::{Tile, VoxelWorld, CHUNK_SIZE, SECTION_HEIGHT};
+
+use super::{
+    mesh_cache::{content_hash, MeshCache},
+    mesher::{ChunkMesher, TileGrid},
+    ChunkMesh,
+};
+
+/*
+    Copy of the tiles a chunk is meshed from, the chunk itself and a border from its neighbours.
+    Lods downsample whole cells so the border has to be as wide as a cell, 2^lod tiles. Edits
+    only copy the section they remesh.
+*/
+pub struct ChunkSnapshot {
+    pos: [i32; 3],
+    //local coordinates of the first tile and the size of the copied box
+    min: [i32; 3],
+    size: [i32; 3],
+    tiles: Vec<Tile>,
+}
+
+impl ChunkSnapshot {
+    pub fn new(voxelworld: &VoxelWorld, pos: [i32; 3], lod: u8) -> ChunkSnapshot {
+        let padding = 1 << lod;
+        Self::copy(voxelworld, pos, [-padding; 3], [CHUNK_SIZE as i32 + padding * 2; 3])
+    }
+
+    //one section with a border of one tile, enough to mesh it at full resolution
+    pub fn section(voxelworld: &VoxelWorld, pos: [i32; 3], section: usize) -> ChunkSnapshot {
+        let (y, cs) = ((section * SECTION_HEIGHT) as i32, CHUNK_SIZE as i32);
+        Self::copy(voxelworld, pos, [-1, y - 1, -1], [cs + 2, SECTION_HEIGHT as i32 + 2, cs + 2])
+    }
+
+    fn copy(voxelworld: &VoxelWorld, pos: [i32; 3], min: [i32; 3], size: [i32; 3]) -> ChunkSnapshot {
+        let [cx, cy, cz] = pos;
+        let mut view = voxelworld.get_chunk_view([cx - 1, cy - 1, cz - 1], [cx + 1, cy + 1, cz + 1]);
+        view.offsets.iter_mut().for_each(|n| *n += CHUNK_SIZE as i32);
+
+        let mut tiles = Vec::with_capacity(size.iter().product::<i32>() as usize);
+        for z in min[2]..min[2] + size[2] {
+            for y in min[1]..min[1] + size[1] {
+                for x in min[0]..min[0] + size[0] {
+                    tiles.push(view.get_tile(x, y, z));
+                }
+            }
+        }
+
+        Self { pos, min, size, tiles }
+    }
+
+    pub fn pos(&self) -> [i32; 3] { self.pos }
+
+    //covers the neighbour borders too, the mesh of a chunk changes with them
+    pub fn content_hash(&self) -> u64 { content_hash(self.tiles.iter().flat_map(|t| t.0.to_le_bytes())) }
+
+    //loads the mesh from the cache if it was stored for the same tiles, stores it otherwise
+    fn mesh_cached(&self, cache: Option<&MeshCache>, lod: u8, seams: u8) -> ChunkMesh {
+        let Some(cache) = cache else {
+            return ChunkMesher {}.mesh_chunk(self, lod, seams);
+        };
+
+        let hash = self.content_hash();
+        if let Some(mesh) = cache.load(self.pos, lod, seams, hash) {
+            return mesh;
+        }
+
+        let mesh = ChunkMesher {}.mesh_chunk(self, lod, seams);
+        if let Err(err) = cache.store(&mesh, hash) {
+            eprintln!("couldn't cache mesh of chunk {:?}: {err}", self.pos);
+        }
+        mesh
+    }
+}
+
+impl TileGrid for ChunkSnapshot {
+    fn get_tile(&self, x: i32, y: i32, z: i32) -> Tile {
+        let [x, y, z] = [x - self.min[0], y - self.min[1], z - self.min[2]];
+        self.tiles[(x + y * self.size[0] + z * self.size[0] * self.size[1]) as usize]
+    }
+}
+
+struct MeshResult {
+    mesh: ChunkMesh,
+    version: u64,
+}
+
+/*
+    Meshes chunks on the rayon pool like WorldGen generates them. Every request bumps the version
+    of its chunk, results of an older version were built from outdated tiles or for another lod
+    and are dropped. Chunks wait for a free job slot before their snapshot is taken, this bounds
+    the memory held by snapshots when a lot of chunks are generated at once.
+*/
+pub struct MeshJobs {
+    versions: HashMap<[i32; 3], u64>,
+    waiting: HashSet<[i32; 3]>,
+    //jobs running per chunk, results of cancelled ones are still on their way
+    in_flight: HashMap<[i32; 3], u32>,
+    pub max_in_flight: usize,
+    cache: Option<Arc<MeshCache>>,
+    result_send: Sender<MeshResult>,
+    result_recv: Receiver<MeshResult>,
+}
+
+impl MeshJobs {
+    pub fn new() -> MeshJobs {
+        let (result_send, result_recv) = channel();
+        Self { versions: HashMap::new(), waiting: HashSet::new(), in_flight: HashMap::new(), max_in_flight: 64, cache: None, result_send, result_recv }
+    }
+
+    //the chunk will be meshed again, results still in flight for it are outdated
+    pub fn request(&mut self, pos: [i32; 3]) {
+        *self.versions.entry(pos).or_insert(0) += 1;
+        self.waiting.insert(pos);
+    }
+
+    //the chunk doesn't need a new mesh anymore, like when a fitting lod was still resident
+    pub fn cancel(&mut self, pos: [i32; 3]) {
+        self.waiting.remove(&pos);
+        if let Some(version) = self.versions.get_mut(&pos) {
+            *version += 1;
+        }
+    }
+
+    pub fn set_cache(&mut self, cache: Option<MeshCache>) { self.cache = cache.map(Arc::new); }
+
+    pub fn waiting_count(&self) -> usize { self.waiting.len() }
+    pub fn in_flight_count(&self) -> usize { self.in_flight.values().sum::<u32>() as usize }
+
+    //a mesh of the chunk is still to be built or received
+    pub fn is_pending(&self, pos: &[i32; 3]) -> bool { self.waiting.contains(pos) || self.in_flight.contains_key(pos) }
+
+    //takes snapshots of the waiting chunks closest to the camera and starts meshing them
+    pub fn start_jobs(&mut self, voxelworld: &VoxelWorld, camera_chunk: [i32; 3], lod_of: impl Fn(&[i32; 3]) -> (u8, u8)) {
+        let slots = self.max_in_flight.saturating_sub(self.in_flight_count());
+        let distance = |pos: &[i32; 3]| (0..3).map(|i| (pos[i] - camera_chunk[i]).pow(2)).sum::<i32>();
+
+        let mut next: Vec<[i32; 3]> = self.waiting.iter().copied().collect();
+        next.sort_unstable_by_key(|pos| (distance(pos), *pos));
+        next.truncate(slots);
+
+        let jobs: Vec<_> = next.iter().map(|pos| (*pos, lod_of(pos), self.versions[pos])).collect();
+        let snapshots: Vec<ChunkSnapshot> =
+            jobs.par_iter().map(|(pos, (lod, _), _)| ChunkSnapshot::new(voxelworld, *pos, *lod)).collect();
+
+        for (snapshot, (pos, (lod, seams), version)) in snapshots.into_iter().zip(jobs) {
+            self.waiting.remove(&pos);
+            *self.in_flight.entry(pos).or_insert(0) += 1;
+
+            let channel = self.result_send.clone();
+            let cache = self.cache.clone();
+            rayon::spawn(move || {
+                let mesh = snapshot.mesh_cached(cache.as_deref(), lod, seams);
+                //the receiver is gone once the jobs are dropped, like when the world is unloaded
+                let _ = channel.send(MeshResult { mesh, version });
+            });
+        }
+    }
+
+    pub fn receive_meshes(&mut self) -> Vec<ChunkMesh> {
+        let results: Vec<MeshResult> = self.result_recv.try_iter().collect();
+        for result in &results {
+            let count = self.in_flight.get_mut(&result.mesh.pos).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                self.in_flight.remove(&result.mesh.pos);
+            }
+        }
+
+        results.into_iter().filter(|r| self.versions.get(&r.mesh.pos) == Some(&r.version)).map(|r| r.mesh).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::game::voxels::{AIR, CHUNK_VOLUME, STONE};
+
+    use super::*;
+
+    //results of the jobs in flight, the jobs run on the rayon pool
+    fn receive_all(jobs: &mut MeshJobs) -> Vec<ChunkMesh> {
+        let start = Instant::now();
+        let mut meshes = vec![];
+        while jobs.in_flight_count() > 0 {
+            assert!(start.elapsed() < Duration::from_secs(10), "mesh jobs didn't finish");
+            meshes.extend(jobs.receive_meshes());
+            std::thread::yield_now();
+        }
+        meshes
+    }
+
+    #[test]
+    fn results_of_older_requests_are_dropped() {
+        let pos = [0, 0, 0];
+        let mut world = VoxelWorld::new();
+        world.register_chunk(&pos, Box::new([AIR; CHUNK_VOLUME]));
+
+        let mut jobs = MeshJobs::new();
+        jobs.request(pos);
+        jobs.start_jobs(&world, pos, |_| (0, 0));
+        assert_eq!(jobs.in_flight_count(), 1);
+
+        //the edit is made while the empty chunk is meshed
+        world.set_tile([4, 4, 4], STONE);
+        jobs.request(pos);
+        assert!(receive_all(&mut jobs).is_empty());
+        assert!(jobs.is_pending(&pos));
+
+        jobs.start_jobs(&world, pos, |_| (0, 0));
+        let meshes = receive_all(&mut jobs);
+        assert_eq!(meshes.len(), 1);
+        assert_eq!(meshes[0].pos, pos);
+        assert!(!meshes[0].empty());
+        assert!(!jobs.is_pending(&pos));
+    }
+}
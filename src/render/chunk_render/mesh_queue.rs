@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+
+use super::{ChunkMesh, Quad};
+
+#[derive(Clone, Copy, Debug)]
+pub struct UploadBudget {
+    //quad bytes uploaded per frame, should leave room in the stencil buffer for the chunk data and sweeps
+    pub max_bytes: u64,
+    pub max_meshes: u32,
+}
+
+impl Default for UploadBudget {
+    fn default() -> Self { Self { max_bytes: 8_000_000, max_meshes: 100 } }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MeshUploadStats {
+    pub queued: usize,
+    pub uploaded: usize,
+    pub uploaded_bytes: u64,
+    //bytes left in the queue after the last flush
+    pub deferred_bytes: u64,
+}
+
+pub fn mesh_bytes(mesh: &ChunkMesh) -> u64 { (mesh.quads.len() * std::mem::size_of::<Quad>()) as u64 }
+
+/*
+    Meshes waiting for upload, one per chunk so a newer mesh always replaces the queued one. The
+    meshes closest to the camera are taken first, the first one is taken even if it is larger than
+    the whole budget so a huge mesh can't block the queue.
+*/
+#[derive(Default)]
+pub struct MeshQueue {
+    meshes: HashMap<[i32; 3], ChunkMesh>,
+}
+
+impl MeshQueue {
+    pub fn new() -> MeshQueue { Self::default() }
+
+    pub fn push(&mut self, mesh: ChunkMesh) { self.meshes.insert(mesh.pos, mesh); }
+
+    //puts back a mesh that couldn't be uploaded unless a newer one was queued in the meantime
+    pub fn requeue(&mut self, mesh: ChunkMesh) { self.meshes.entry(mesh.pos).or_insert(mesh); }
+
+    pub fn contains(&self, pos: &[i32; 3]) -> bool { self.meshes.contains_key(pos) }
+    pub fn len(&self) -> usize { self.meshes.len() }
+    pub fn queued_bytes(&self) -> u64 { self.meshes.values().map(mesh_bytes).sum() }
+
+    //nearest first, empty meshes don't count against the budget
+    pub fn take(&mut self, camera_chunk: [i32; 3], budget: &UploadBudget) -> Vec<ChunkMesh> {
+        let distance = |pos: &[i32; 3]| (0..3).map(|i| (pos[i] - camera_chunk[i]).pow(2)).sum::<i32>();
+
+        let mut order: Vec<([i32; 3], u64)> = self.meshes.iter().map(|(pos, mesh)| (*pos, mesh_bytes(mesh))).collect();
+        order.sort_unstable_by_key(|(pos, _)| (distance(pos), *pos));
+
+        let mut taken = Vec::new();
+        let (mut bytes, mut count) = (0, 0);
+        for (pos, size) in order {
+            if size > 0 {
+                if count >= budget.max_meshes || (count > 0 && bytes + size > budget.max_bytes) {
+                    continue;
+                }
+                bytes += size;
+                count += 1;
+            }
+            taken.push(self.meshes.remove(&pos).unwrap());
+        }
+
+        taken
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::chunk_render::{mesher::ALL_SECTIONS, visibility::ChunkConnectivity};
+    use bytemuck::Zeroable;
+
+    const QUAD_BYTES: u64 = std::mem::size_of::<Quad>() as u64;
+
+    fn mesh(pos: [i32; 3], quads: usize) -> ChunkMesh {
+        ChunkMesh::new(pos, 0, 0, ALL_SECTIONS, vec![Quad::zeroed(); quads], ChunkConnectivity::ALL)
+    }
+
+    fn positions(meshes: &[ChunkMesh]) -> Vec<[i32; 3]> { meshes.iter().map(|m| m.pos).collect() }
+
+    fn unlimited() -> UploadBudget { UploadBudget { max_bytes: u64::MAX, max_meshes: u32::MAX } }
+
+    #[test]
+    fn latest_mesh_wins() {
+        let mut queue = MeshQueue::new();
+        queue.push(mesh([1, 2, 3], 1));
+        queue.push(mesh([1, 2, 3], 3));
+
+        assert_eq!(queue.len(), 1);
+        assert!(queue.contains(&[1, 2, 3]));
+        assert_eq!(queue.queued_bytes(), 3 * QUAD_BYTES);
+
+        let taken = queue.take([0; 3], &unlimited());
+        assert_eq!(taken.len(), 1);
+        assert_eq!(taken[0].quads.len(), 3);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn nearest_meshes_are_taken_first() {
+        let mut queue = MeshQueue::new();
+        for pos in [[5, 0, 0], [1, 0, 0], [0, 0, 3], [-2, 0, 0]] {
+            queue.push(mesh(pos, 1));
+        }
+
+        let taken = queue.take([0; 3], &unlimited());
+        assert_eq!(positions(&taken), vec![[1, 0, 0], [-2, 0, 0], [0, 0, 3], [5, 0, 0]]);
+
+        //distances are relative to the camera chunk
+        for pos in [[5, 0, 0], [1, 0, 0], [0, 0, 3]] {
+            queue.push(mesh(pos, 1));
+        }
+        let taken = queue.take([4, 0, 0], &unlimited());
+        assert_eq!(positions(&taken), vec![[5, 0, 0], [1, 0, 0], [0, 0, 3]]);
+    }
+
+    #[test]
+    fn budget_limits_bytes_and_meshes() {
+        let mut queue = MeshQueue::new();
+        for x in 0..5 {
+            queue.push(mesh([x, 0, 0], 10));
+        }
+
+        let taken = queue.take([0; 3], &UploadBudget { max_bytes: 25 * QUAD_BYTES, max_meshes: 100 });
+        assert_eq!(positions(&taken), vec![[0, 0, 0], [1, 0, 0]]);
+
+        let taken = queue.take([0; 3], &UploadBudget { max_bytes: u64::MAX, max_meshes: 1 });
+        assert_eq!(positions(&taken), vec![[2, 0, 0]]);
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.queued_bytes(), 20 * QUAD_BYTES);
+    }
+
+    #[test]
+    fn empty_meshes_ignore_the_budget() {
+        let mut queue = MeshQueue::new();
+        queue.push(mesh([0, 0, 0], 10));
+        queue.push(mesh([1, 0, 0], 10));
+        queue.push(mesh([2, 0, 0], 0));
+
+        let taken = queue.take([0; 3], &UploadBudget { max_bytes: u64::MAX, max_meshes: 1 });
+        assert_eq!(positions(&taken), vec![[0, 0, 0], [2, 0, 0]]);
+        assert!(queue.contains(&[1, 0, 0]));
+    }
+
+    #[test]
+    fn first_oversized_mesh_is_taken() {
+        let mut queue = MeshQueue::new();
+        queue.push(mesh([0, 0, 0], 100));
+        queue.push(mesh([1, 0, 0], 1));
+
+        let budget = UploadBudget { max_bytes: 10 * QUAD_BYTES, max_meshes: 100 };
+        let taken = queue.take([0; 3], &budget);
+        assert_eq!(positions(&taken), vec![[0, 0, 0]]);
+
+        //the smaller one fits the next frame
+        let taken = queue.take([0; 3], &budget);
+        assert_eq!(positions(&taken), vec![[1, 0, 0]]);
+    }
+
+    #[test]
+    fn requeue_keeps_newer_mesh() {
+        let mut queue = MeshQueue::new();
+        queue.push(mesh([0, 0, 0], 5));
+        let old = queue.take([0; 3], &unlimited()).pop().unwrap();
+
+        queue.push(mesh([0, 0, 0], 2));
+        queue.requeue(old);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.queued_bytes(), 2 * QUAD_BYTES);
+
+        //without a newer one the mesh is put back
+        let newer = queue.take([0; 3], &unlimited()).pop().unwrap();
+        queue.requeue(newer);
+        assert_eq!(queue.queued_bytes(), 2 * QUAD_BYTES);
+    }
+}
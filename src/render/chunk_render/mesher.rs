@@ -1,11 +1,20 @@
-use std::sync::Arc;
+use std::{
+    ops::Range,
+    sync::{Arc, Mutex},
+};
 
-use crate::game::FrameIndex;
+use crate::game::{CameraData, FrameIndex, RenderGlobals};
 
-use super::*;
+use super::{
+    lod::{ChunkLods, LodGrid, SeamGrid},
+    mesh_jobs::{ChunkSnapshot, MeshJobs},
+    visibility::{ChunkConnectivity, ChunkVisibility},
+    *,
+};
 
 use ash::vk;
 use magma_renderer::core::CommandBuffer;
+use rayon::prelude::*;
 use specs::prelude::*;
 
 /* Quad Storage 2x32 bits
@@ -16,7 +25,9 @@ use specs::prelude::*;
     material 14 bits 18-32
 
     // u32-1
-    // reserved for future
+    ambient occlusion 4x2 bits 0-8
+    lod 2 bits 8-10, the quad is scaled by 2^lod
+    ambient occlusion flip flag 1 bit 31
 
 */
 
@@ -27,6 +38,32 @@ pub struct Quad {
     pub data: [u32; 2],
 }
 
+pub const ALL_SECTIONS: u8 = (1 << SECTION_COUNT) - 1;
+
+impl Quad {
+    //section of the tile the quad belongs to, lod quads store cell coordinates
+    pub fn section(&self, lod: u8) -> usize { (((self.data[0] >> 5) & 0x1F) << lod) as usize / SECTION_HEIGHT }
+}
+
+//anything tiles can be meshed from, coordinates are local to the chunk and reach one tile outside of it
+pub trait TileGrid {
+    fn get_tile(&self, x: i32, y: i32, z: i32) -> Tile;
+}
+
+impl<'a> TileGrid for ChunkView<'a> {
+    fn get_tile(&self, x: i32, y: i32, z: i32) -> Tile { ChunkView::get_tile(self, x, y, z) }
+}
+
+//only reaches the tiles inside the chunk
+impl<'a> TileGrid for ChunkRef<'a> {
+    fn get_tile(&self, x: i32, y: i32, z: i32) -> Tile { self.get_block(x as usize, y as usize, z as usize) }
+}
+
+//always from the full resolution tiles of the chunk, a coarse lod cell may close a tunnel
+fn chunk_connectivity(grid: &impl TileGrid) -> ChunkConnectivity {
+    ChunkConnectivity::compute(|x, y, z| grid.get_tile(x, y, z).transparent())
+}
+
 pub struct ChunkMesher {}
 
 impl ChunkMesher {
@@ -41,7 +78,7 @@ impl ChunkMesher {
         return side0 as u32 + side1 as u32 + corner as u32;
     }
 
-    fn ambient_occulusion_face(&self, x: i32, y: i32, z: i32, direction: Direction, view: &ChunkView) -> (u32, bool) {
+    fn ambient_occulusion_face(&self, x: i32, y: i32, z: i32, direction: Direction, view: &impl TileGrid) -> (u32, bool) {
         let mut block_n_ = false;
         let mut block_ne = false;
         let mut block_nw = false;
@@ -122,7 +159,7 @@ impl ChunkMesher {
         (corner_0 | (corner_1 << 2) | (corner_2 << 4) | (corner_3 << 6), corner_0 + corner_3 > corner_1 + corner_2)
     }
 
-    fn new_quad(&self, tile: Tile, x: i32, y: i32, z: i32, direction: Direction, view: &ChunkView) -> Quad {
+    fn new_quad(&self, tile: Tile, x: i32, y: i32, z: i32, lod: u8, direction: Direction, view: &impl TileGrid) -> Quad {
         let mut data_0 = 0u32;
 
         assert!(x < 32);
@@ -148,51 +185,87 @@ impl ChunkMesher {
 
         let (ao_bits, ao_flip_flag) = self.ambient_occulusion_face(x, y, z, direction, view);
         data_1 |= ao_bits;
+        data_1 |= (lod as u32) << 8;
         data_1 |= (ao_flip_flag as u32) << 31;
 
         Quad { data: [data_0, data_1] }
     }
 
-    pub fn mesh_chunk(&self, voxelworld: &VoxelWorld, chunkpos: &[i32; 3]) -> ChunkMesh {
-        let [cx, cy, cz] = *chunkpos;
-
-        let mut view = voxelworld.get_chunk_view([cx - 1, cy - 1, cz - 1], [cx + 1, cy + 1, cz + 1]);
-        view.offsets.iter_mut().for_each(|n| *n += CHUNK_SIZE as i32);
-
+    //rows are meshed bottom to top, so the quads end up sorted by section
+    fn mesh_grid(&self, grid: &impl TileGrid, size: i32, rows: Range<i32>, lod: u8) -> Vec<Quad> {
         let mut quads: Vec<Quad> = Vec::new();
 
-        for y in 0..32 {
-            for z in 0..32 {
-                for x in 0..32 {
-                    let tile = view.get_tile(x, y, z);
+        for y in rows {
+            for z in 0..size {
+                for x in 0..size {
+                    let tile = grid.get_tile(x, y, z);
 
                     if tile == Tile(0) {
                         continue;
                     }
 
-                    let ypt = view.get_tile(x, y + 1, z);
-                    let ynt = view.get_tile(x, y - 1, z);
-                    let xpt = view.get_tile(x + 1, y, z);
-                    let xnt = view.get_tile(x - 1, y, z);
-                    let zpt = view.get_tile(x, y, z + 1);
-                    let znt = view.get_tile(x, y, z - 1);
-
-                    #[rustfmt::skip] if ypt.transparent() { quads.push(self.new_quad(tile, x, y, z, Direction::YP,&view))};
-                    #[rustfmt::skip] if ynt.transparent() { quads.push(self.new_quad(tile, x, y, z, Direction::YN,&view))};
-                    #[rustfmt::skip] if xpt.transparent() { quads.push(self.new_quad(tile, x, y, z, Direction::XP,&view))};
-                    #[rustfmt::skip] if xnt.transparent() { quads.push(self.new_quad(tile, x, y, z, Direction::XN,&view))};
-                    #[rustfmt::skip] if zpt.transparent() { quads.push(self.new_quad(tile, x, y, z, Direction::ZP,&view))};
-                    #[rustfmt::skip] if znt.transparent() { quads.push(self.new_quad(tile, x, y, z, Direction::ZN,&view))};
+                    let ypt = grid.get_tile(x, y + 1, z);
+                    let ynt = grid.get_tile(x, y - 1, z);
+                    let xpt = grid.get_tile(x + 1, y, z);
+                    let xnt = grid.get_tile(x - 1, y, z);
+                    let zpt = grid.get_tile(x, y, z + 1);
+                    let znt = grid.get_tile(x, y, z - 1);
+
+                    #[rustfmt::skip] if ypt.transparent() { quads.push(self.new_quad(tile, x, y, z, lod, Direction::YP, grid))};
+                    #[rustfmt::skip] if ynt.transparent() { quads.push(self.new_quad(tile, x, y, z, lod, Direction::YN, grid))};
+                    #[rustfmt::skip] if xpt.transparent() { quads.push(self.new_quad(tile, x, y, z, lod, Direction::XP, grid))};
+                    #[rustfmt::skip] if xnt.transparent() { quads.push(self.new_quad(tile, x, y, z, lod, Direction::XN, grid))};
+                    #[rustfmt::skip] if zpt.transparent() { quads.push(self.new_quad(tile, x, y, z, lod, Direction::ZP, grid))};
+                    #[rustfmt::skip] if znt.transparent() { quads.push(self.new_quad(tile, x, y, z, lod, Direction::ZN, grid))};
                 }
             }
         }
 
-        ChunkMesh { pos: *chunkpos, quads }
+        quads
+    }
+
+    //seams are the faces bordering chunks of another lod, see SeamGrid
+    pub fn mesh_chunk(&self, snapshot: &ChunkSnapshot, lod: u8, seams: u8) -> ChunkMesh {
+        let size = CHUNK_SIZE as i32;
+        let quads = if lod == 0 {
+            self.mesh_grid(&SeamGrid { grid: snapshot, size, seams }, size, 0..size, 0)
+        } else {
+            let grid = LodGrid::downsample(snapshot, lod);
+            self.mesh_grid(&SeamGrid { grid: &grid, size: grid.size(), seams }, grid.size(), 0..grid.size(), lod)
+        };
+
+        ChunkMesh::new(snapshot.pos(), lod, seams, ALL_SECTIONS, quads, chunk_connectivity(snapshot))
+    }
+
+    //remeshes the given sections of a chunk at full resolution after tile edits, only reads the tiles around them
+    pub fn mesh_sections(&self, voxelworld: &VoxelWorld, pos: [i32; 3], sections: u8, seams: u8) -> ChunkMesh {
+        let size = CHUNK_SIZE as i32;
+        let mut quads = Vec::new();
+        for section in (0..SECTION_COUNT).filter(|s| sections & (1 << s) != 0) {
+            let snapshot = ChunkSnapshot::section(voxelworld, pos, section);
+            let rows = (section * SECTION_HEIGHT) as i32..((section + 1) * SECTION_HEIGHT) as i32;
+            quads.extend(self.mesh_grid(&SeamGrid { grid: &snapshot, size, seams }, size, rows, 0));
+        }
+
+        //the flood fill stays inside the chunk, so its tiles are read directly
+        let chunk = voxelworld.get_chunk(&pos).unwrap_or_else(|| ChunkRef::empty());
+        ChunkMesh::new(pos, 0, seams, sections, quads, chunk_connectivity(&chunk))
     }
 }
 
 impl ChunkMesh {
-    fn empty(&self) -> bool { self.quads.len() == 0 }
+    //quads have to be sorted by section like mesh_grid emits them
+    pub fn new(pos: [i32; 3], lod: u8, seams: u8, sections: u8, quads: Vec<Quad>, connectivity: ChunkConnectivity) -> ChunkMesh {
+        let section_offsets = std::array::from_fn(|s| quads.partition_point(|q| q.section(lod) < s) as u32);
+        Self { pos, lod, seams, sections, quads, section_offsets, connectivity }
+    }
+
+    pub fn empty(&self) -> bool { self.quads.len() == 0 }
+    pub fn is_patch(&self) -> bool { self.sections != ALL_SECTIONS }
+
+    pub fn section_range(&self, section: usize) -> Range<usize> {
+        self.section_offsets[section] as usize..self.section_offsets[section + 1] as usize
+    }
 }
 
 impl<'a> System<'a> for ChunkMesher {
@@ -202,31 +275,191 @@ impl<'a> System<'a> for ChunkMesher {
         ReadStorage<'a, ChunkComponent>,
         ReadStorage<'a, ModifiedChunk>,
         WriteExpect<'a, super::chunk_mesh_manager::ChunkMeshManager>,
+        WriteExpect<'a, ChunkVisibility>,
+        WriteExpect<'a, ChunkLods>,
+        ReadExpect<'a, Mutex<MeshJobs>>,
         ReadExpect<'a, FrameIndex>,
-        Entities<'a>,
+        ReadExpect<'a, CameraData>,
+        ReadExpect<'a, RenderGlobals>,
     );
 
-    fn run(&mut self, (vworld, rpman, chunk, modifiedf, mut mesh_man, frame_index, entities): Self::SystemData) {
+    fn run(
+        &mut self,
+        (
+            vworld,
+            rpman,
+            chunk,
+            modifiedf,
+            mut mesh_man,
+            mut visibility,
+            mut lods,
+            mesh_jobs,
+            frame_index,
+            cam_data,
+            globals,
+        ): Self::SystemData,
+    ) {
         // let mut expired_meshes = Vec::new();
         let mut cmd = CommandBuffer::new_secondry(rpman.core());
         cmd.begin_secondry(None).unwrap();
 
-        let meshes = (&chunk, &modifiedf, &entities)
-            .par_join()
-            .filter_map(|(chunk, _, _entity)| {
-                let mesh = self.mesh_chunk(&*vworld, &chunk.chunkpos);
-                if mesh.empty() {
-                    return None;
-                }
-                Some(mesh)
-            })
+        let mut jobs = mesh_jobs.lock().unwrap();
+
+        //modified chunks drop all their meshes, chunks whose lod or seams changed may still have a fitting one
+        let dirty = lods.take_dirty();
+        for (chunk, modified) in (&chunk, modifiedf.maybe()).join() {
+            let pos = chunk.chunkpos;
+            if modified.is_some() {
+                mesh_man.invalidate_chunk(pos);
+            } else if !dirty.contains(&pos) {
+                continue;
+            } else if mesh_man.activate_lod(pos, lods.lod(&pos), lods.seam_mask(&pos)) {
+                jobs.cancel(pos);
+                continue;
+            }
+            jobs.request(pos);
+        }
+
+        /*
+            Edited sections of chunks whose drawn mesh is up to date are remeshed right away and swapped
+            in with this frame's flush. Anything else, like an edit to a chunk still being meshed or
+            drawn at a coarser lod, remeshes the whole chunk.
+        */
+        let mut patched = Vec::new();
+        for (pos, sections) in vworld.edited_sections() {
+            let (lod, seams) = (lods.lod(pos), lods.seam_mask(pos));
+            if lod == 0 && !jobs.is_pending(pos) && mesh_man.can_patch(*pos, lod, seams) {
+                patched.push((*pos, *sections, seams));
+            } else {
+                mesh_man.invalidate_chunk(*pos);
+                jobs.request(*pos);
+            }
+        }
+        let voxelworld: &VoxelWorld = &vworld;
+        let patches: Vec<ChunkMesh> = patched
+            .par_iter()
+            .map(|(pos, sections, seams)| ChunkMesher {}.mesh_sections(voxelworld, *pos, *sections, *seams))
             .collect();
 
+        let camera_chunk = world_pos_to_chunkpos(cam_data.position.floor().as_ivec3().to_array());
+        jobs.start_jobs(&vworld, camera_chunk, |pos| (lods.lod(pos), lods.seam_mask(pos)));
+        let mut meshes = jobs.receive_meshes();
+        meshes.extend(patches);
+
+        //empty chunks still need their connectivity for the cave culling
+        for mesh in &meshes {
+            visibility.set_connectivity(mesh.pos, mesh.connectivity);
+        }
+
         mesh_man.submit_meshes(meshes);
-        mesh_man.flush_stencil(&mut cmd, frame_index.index());
+        let usage = mesh_man.flush_stencil(&mut cmd, frame_index.index(), camera_chunk);
+        for buffer in mesh_man.take_released_buffers() {
+            globals.descriptor_cache().forget_buffer(buffer);
+            rpman.forget_buffer(buffer);
+        }
+
+        //the copies recorded before the failure are dropped with the command buffer, their chunks are meshed again
+        let usage = match usage {
+            Ok(usage) => usage,
+            Err(e) => {
+                eprintln!("failed to upload chunk meshes, meshing them again: {e}");
+                mesh_man.take_dropped_chunks().into_iter().for_each(|pos| jobs.request(pos));
+                return;
+            }
+        };
 
         // cmd.add_dependency(&Arc::new(expired_meshes));
         cmd.end().unwrap();
-        rpman.submit_compute(cmd);
+        rpman.submit_compute(cmd, usage);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section_quads(mesh: &ChunkMesh, section: usize) -> Vec<[u32; 2]> {
+        mesh.quads[mesh.section_range(section)].iter().map(|q| q.data).collect()
+    }
+
+    fn mesh_full(world: &VoxelWorld, pos: [i32; 3]) -> ChunkMesh {
+        ChunkMesher {}.mesh_chunk(&ChunkSnapshot::new(world, pos, 0), 0, 0)
+    }
+
+    //stone below y 12 with a one tile hole at the bottom
+    fn world() -> VoxelWorld {
+        let mut world = VoxelWorld::new();
+        world.register_chunk(&[0, 0, 0], Box::new([AIR; CHUNK_VOLUME]));
+        for z in 0..CHUNK_SIZE as i32 {
+            for x in 0..CHUNK_SIZE as i32 {
+                for y in 0..12 {
+                    world.set_tile([x, y, z], STONE);
+                }
+            }
+        }
+        world.set_tile([3, 0, 3], AIR);
+        world.clear_edits();
+        world
+    }
+
+    #[test]
+    fn edit_marks_the_sections_around_the_tile() {
+        let mut world = world();
+        world.set_tile([5, 20, 5], STONE);
+        assert_eq!(world.edited_sections().get(&[0, 0, 0]), Some(&0b0100));
+
+        //the tile below the section is a neighbour too
+        world.clear_edits();
+        world.set_tile([5, 16, 5], STONE);
+        assert_eq!(world.edited_sections().get(&[0, 0, 0]), Some(&0b0110));
+
+        //setting the same tile again changes nothing
+        world.clear_edits();
+        world.set_tile([5, 16, 5], STONE);
+        assert!(world.edited_sections().is_empty());
+    }
+
+    #[test]
+    fn edit_only_remeshes_its_sections() {
+        let mut world = world();
+        let before = mesh_full(&world, [0, 0, 0]);
+
+        //the tile below sits in the section under it
+        world.set_tile([5, 8, 5], AIR);
+        let sections = world.edited_sections()[&[0, 0, 0]];
+        assert_eq!(sections, 0b0011);
+
+        let patch = ChunkMesher {}.mesh_sections(&world, [0, 0, 0], sections, 0);
+        let after = mesh_full(&world, [0, 0, 0]);
+        assert!(patch.is_patch());
+        assert_eq!(patch.sections, sections);
+
+        for section in 0..SECTION_COUNT {
+            if sections & (1 << section) != 0 {
+                assert_eq!(section_quads(&patch, section), section_quads(&after, section), "section {section}");
+            } else {
+                assert!(patch.section_range(section).is_empty(), "section {section}");
+                assert_eq!(section_quads(&before, section), section_quads(&after, section), "section {section}");
+            }
+        }
+        assert_ne!(section_quads(&before, 1), section_quads(&after, 1));
+    }
+
+    #[test]
+    fn patch_and_full_mesh_share_connectivity() {
+        let mut world = world();
+        assert!(!mesh_full(&world, [0, 0, 0]).connectivity.connects(2, 3));
+
+        //a shaft from the top of the stone down to the hole at the bottom
+        for y in 1..12 {
+            world.set_tile([3, y, 3], AIR);
+        }
+        let sections = world.edited_sections()[&[0, 0, 0]];
+
+        let patch = ChunkMesher {}.mesh_sections(&world, [0, 0, 0], sections, 0);
+        let full = mesh_full(&world, [0, 0, 0]);
+        assert_eq!(patch.connectivity.to_bits(), full.connectivity.to_bits());
+        //the bottom face is only reached through the shaft
+        assert!(full.connectivity.connects(2, 3));
     }
 }
@@ -1,13 +1,19 @@
-use crate::game::{voxels::*, Game};
+use crate::game::{plugin::Plugin, voxels::*, Game, Stage};
 use bytemuck::{Pod, Zeroable};
-use magma_renderer::{auto_description, core::Renderpass};
+use magma_renderer::auto_description;
 use specs::prelude::*;
 
+mod batch_allocator;
 mod chunk_renderer;
+pub mod lod;
 pub mod mesher;
+mod mesh_cache;
+mod mesh_jobs;
+mod mesh_queue;
 mod primative_manager;
 mod stencil_buffer;
 mod chunk_mesh_manager;
+pub mod visibility;
 
 auto_description!(
     #[repr(C)]
@@ -29,15 +35,41 @@ pub enum Direction {
     ZN,
 }
 
-pub fn init(game: &mut Game, renderpass: &dyn Renderpass) {
-    game.insert_frame_task(Box::new(move |_w, d| {
-        d.add(mesher::ChunkMesher {}, "chunk mesh", &[]);
-    }));
+pub struct ChunkRenderPlugin;
 
-    chunk_renderer::register_render_data(game).unwrap();
+impl Plugin for ChunkRenderPlugin {
+    fn name(&self) -> &'static str { "chunk_render" }
+    fn provides(&self) -> &'static [&'static str] { &["chunk_mesh_manager"] }
+    fn requires(&self) -> &'static [&'static str] { &["gpass", "material_manager", "render_pass_manager", "voxel_world"] }
+    fn build(&self, game: &mut Game) -> eyre::Result<()> { init(game) }
+}
+
+pub fn init(game: &mut Game) -> eyre::Result<()> {
+    game.world.insert(visibility::ChunkVisibility::new());
+    game.world.insert(lod::LodSettings::default());
+    game.world.insert(lod::ChunkLods::new());
+    game.world.insert(std::sync::Mutex::new(mesh_jobs::MeshJobs::new()));
+    game.add_system(Stage::Meshing, visibility::UpdateChunkVisibility, "chunk visibility", &[]);
+    game.add_system(Stage::Meshing, lod::UpdateChunkLods, "chunk lod", &[]);
+    game.add_system(Stage::Meshing, mesher::ChunkMesher {}, "chunk mesh", &["chunk visibility", "chunk lod"]);
+
+    chunk_renderer::register_render_data(game)
+}
+
+//meshes are loaded from and stored into dir when the tiles they were built from didn't change
+pub fn enable_mesh_cache(world: &World, dir: impl AsRef<std::path::Path>) {
+    let cache = mesh_cache::MeshCache::new(dir);
+    world.fetch::<std::sync::Mutex<mesh_jobs::MeshJobs>>().lock().unwrap().set_cache(Some(cache));
 }
 
 pub struct ChunkMesh {
     pos: [i32; 3],
+    lod: u8,
+    seams: u8,
+    //sections the quads were meshed for, a mesh without all of them patches the resident one
+    sections: u8,
+    //sorted by section, section s spans section_offsets[s]..section_offsets[s + 1]
     quads: Vec<Quad>,
+    section_offsets: [u32; SECTION_COUNT + 1],
+    connectivity: visibility::ChunkConnectivity,
 }
@@ -1,68 +1,46 @@
 use ash::vk;
 use magma_renderer::core::*;
-use std::{
-    collections::{HashMap, HashSet},
-    sync::Arc,
-};
-
-use super::stencil_buffer::StencilBuffer;
-struct PrimativeBatch {
-    count: u32,
-    offset: u32,
-    id: u32,
-    pool_id: u32,
-}
+use std::sync::Arc;
 
-impl PrimativeBatch {
-    pub fn compact(&self) -> [u32; 2] { [self.offset, (self.pool_id << 24) | (self.count & 0xFF_FFFF)] }
-}
-
-struct PrimativeReigon {
-    primative_offset: u32,
-    cap: u32,
-    top: u32, //relative to offset
-    usage: u32,
-    batch_ids: HashSet<u32>,
-    is_written: bool,
-}
+pub use super::batch_allocator::{BatchUpload, PrimativeStats, MAX_POOLS};
+use super::{
+    batch_allocator::{AllocatorOps, BatchAllocator, CopySource},
+    stencil_buffer::StencilBuffer,
+};
+use crate::render::resource_state::{BufferAccess, BufferUsage};
 
 pub struct PrimativePool {
-    primative_size: u32,
     pool_id: u32,
     primative_buffer: Buffer<u8>,
-    reigons: Vec<PrimativeReigon>,
-    reigon_size: u32,
 }
 
 impl PrimativePool {
     pub fn get_primative_buffer(&self) -> &Buffer<u8> { &self.primative_buffer }
-    pub fn get_batch_count(&self) -> u32 { self.reigons.iter().map(|r| r.batch_ids.len() as u32).sum() }
+    pub fn pool_id(&self) -> u32 { self.pool_id }
 }
 
+//records the buffer operations of a BatchAllocator, owns the pool buffers and the batch descriptions read by the cull shader
 pub struct PrimativeManager {
-    primative_size: u32,
-    pools: Vec<PrimativePool>,
+    allocator: BatchAllocator,
+    //indexed by pool id like the allocator's pools
+    pools: Vec<Option<PrimativePool>>,
     batch_description_buffer: Buffer<u8>,
-    batches: HashMap<u32, PrimativeBatch>,
     max_id: u32,
-    reigon_size: u32,
     core: Arc<Core>,
     buffer_usage: vk::BufferUsageFlags,
-    updated_batches: HashSet<u32>,
-}
-
-pub struct BatchUpload {
-    pub byte_offset: u32,
-    pub primative_count: u32,
-    pub id: u32,
 }
 
 impl PrimativeManager {
     // getters
     pub fn get_batch_descriptions(&self) -> &Buffer<u8> { &self.batch_description_buffer }
-    pub fn batch_count(&self) -> u32 { self.batches.len() as u32 }
-    pub fn get_pools(&self) -> &[PrimativePool] { &self.pools }
-    
+    pub fn batch_count(&self) -> u32 { self.allocator.batch_count() }
+    pub fn get_pools(&self) -> impl Iterator<Item = &PrimativePool> { self.pools.iter().flatten() }
+    pub fn pool_buffers(&self) -> impl Iterator<Item = vk::Buffer> + '_ { self.get_pools().map(|p| p.primative_buffer.inner()) }
+    pub fn pool_batch_count(&self, pool_id: u32) -> u32 { self.allocator.pool_batch_count(pool_id) }
+    pub fn stats(&self) -> PrimativeStats { self.allocator.stats() }
+    //largest batch in primatives
+    pub fn max_batch_size(&self) -> u32 { self.allocator.reigon_size() }
+
     pub fn new(
         core: &Arc<Core>,
         primative_size: u32,
@@ -71,193 +49,175 @@ impl PrimativeManager {
         buffer_usage: vk::BufferUsageFlags,
     ) -> eyre::Result<PrimativeManager> {
         Ok(Self {
-            primative_size,
+            allocator: BatchAllocator::new(primative_size, reigon_size),
             pools: Vec::new(),
             batch_description_buffer: core.create_buffer(
-                vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+                vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::TRANSFER_SRC,
                 max_id * 8,
                 false,
             )?,
-            batches: HashMap::new(),
             max_id,
-            reigon_size,
             core: core.clone(),
             buffer_usage,
-            updated_batches: HashSet::new(),
         })
     }
 
-    pub fn insert_batches(&mut self, cmd: &mut CommandBuffer, stencil_buffer: &Buffer<u8>, uploads: Vec<BatchUpload>) {
-        uploads.iter().for_each(|u| self.remove_batch_from_reigon(u.id));
-        let mut i = 0;
-
-        while i < uploads.len() {
-            let mut reigons: Vec<_> = self
-                .pools
-                .iter()
-                .flat_map(|p| p.reigons.iter().enumerate().map(|(rid, r)| (p.pool_id, rid as u32, r.score())))
-                .filter(|(_, _, score)| *score > 0.5)
-                .collect();
-
-            if reigons.len() == 0 {
-                self.new_pool().unwrap();
-                continue;
-            };
-
-            reigons.sort_unstable_by(|a, b| a.2.total_cmp(&b.2).reverse());
-
-            self.insert_into_reigons(cmd, &stencil_buffer, &uploads, &reigons, &mut i);
-        }
-
-        // self.update_gpu_batches(cmd, ids, stencil_buffer)
-
-        uploads.iter().for_each(|u| self.update_batch_gpu(u.id));
-    }
-
-    pub fn remove_batches(&mut self, ids: &[u32]) {
-        for id in ids {
-            self.remove_batch_from_reigon(*id);
-            self.batches.remove(id);
-            self.update_batch_gpu(*id);
+    pub fn insert_batches(
+        &mut self,
+        cmd: &mut CommandBuffer,
+        stencil_buffer: &Buffer<u8>,
+        uploads: Vec<BatchUpload>,
+        usage: &mut BufferUsage,
+    ) -> eyre::Result<()> {
+        if let Some(upload) = uploads.iter().find(|u| u.id >= self.max_id) {
+            eyre::bail!("batch id {} is past the {} batch descriptions, grow_ids has to be called first", upload.id, self.max_id);
         }
-    }
-
-    pub fn sweep_and_flush(&mut self, cmd: &mut CommandBuffer, stencil_buffer: &mut StencilBuffer) {
-        self.sweep(cmd);
 
-        self.update_gpu_batches(cmd, stencil_buffer);
-
-        for pool in &mut self.pools {
-            for reigon in &mut pool.reigons {
-                reigon.is_written = false; //set it back to false for the next frame
-            }
-        }
+        let mut ops = AllocatorOps::default();
+        let result = self.allocator.insert(&uploads, &mut ops);
+        //the batches allocated before a failure still have to be copied
+        self.execute(cmd, stencil_buffer, ops, usage)?;
+        result
     }
 
-    fn sweep(&mut self, cmd: &mut CommandBuffer) {
-        let mut fragmented_reigons: Vec<_> = self
-            .pools
-            .iter()
-            .flat_map(|p| p.reigons.iter().enumerate().map(|(rid, r)| (p.pool_id, rid as u32, r.fragmentation_score())))
-            .filter(|(_, _, score)| *score > 0.15)
-            .filter(|(p, r, _)| self.pools[*p as usize].reigons[*r as usize].is_written == false) //check if it is written this frame
-            .collect();
-
-        if fragmented_reigons.len() == 0 {
-            return;
+    /*
+        Grows the batch descriptions to hold ids up to max_id, the old descriptions are copied over.
+        Returns the replaced buffer, the command buffer keeps it alive until it is done.
+    */
+    pub fn grow_ids(
+        &mut self,
+        cmd: &mut CommandBuffer,
+        max_id: u32,
+        usage: &mut BufferUsage,
+    ) -> eyre::Result<Option<vk::Buffer>> {
+        if max_id <= self.max_id {
+            return Ok(None);
         }
 
-        fragmented_reigons.sort_unstable_by(|a, b| a.2.total_cmp(&b.2).reverse());
-
-        fragmented_reigons.shrink_to(4); //we want to sweep at max 4 reigon
-
-        let mut dst_reigons: Vec<_> = self
-            .pools
-            .iter()
-            .flat_map(|p| p.reigons.iter().enumerate().map(|(rid, r)| (p.pool_id, rid as u32, r.fragmentation_score())))
-            .filter(|(_, _, score)| *score < 0.08)
-            .collect();
-
-        if dst_reigons.len() == 0 {
-            return;
+        let buffer = self.core.create_buffer(self.batch_description_buffer.get_usage(), max_id * 8, false)?;
+        let copy = vk::BufferCopy { src_offset: 0, dst_offset: 0, size: self.batch_description_buffer.byte_size() };
+        unsafe {
+            cmd.copy_buffer_reigons(self.batch_description_buffer.inner(), buffer.inner(), &[copy]);
         }
+        usage.add(buffer.inner(), BufferAccess::TransferWrite);
 
-        dst_reigons.sort_unstable_by(|a, b| a.2.total_cmp(&b.2));
-
-        let mut dst_reigon_index = 0;
+        let old = std::mem::replace(&mut self.batch_description_buffer, buffer);
+        self.max_id = max_id;
+        let handle = old.inner();
+        cmd.add_dependency(old);
+        Ok(Some(handle))
+    }
 
-        for (poolid, reigonid, _) in &dst_reigons {
-            let mut batches = self.pools[*poolid as usize].reigons[*reigonid as usize].reset_and_get_batches();
+    pub fn remove_batches(&mut self, ids: &[u32]) { self.allocator.remove(ids); }
 
-            while dst_reigon_index < dst_reigons.len() {
-                let (dst_pool_id, dst_reigon_id, _) = dst_reigons[dst_reigon_index];
-                self.sweep_batches_to_reigon(cmd, *poolid, &mut batches, dst_pool_id, dst_reigon_id);
-                if batches.len() > 0 {
-                    dst_reigon_index += 1;
-                } else {
-                    break;
-                }
-            }
+    //batches inserted, moved or removed since the last flush, their copies and descriptions weren't recorded
+    pub fn take_unflushed_batches(&mut self) -> Vec<u32> {
+        self.allocator.take_updated_batches().into_iter().map(|(id, _)| id).collect()
+    }
 
-            //if we are out of clean reigons handle the remaining batches and break.
-            if dst_reigon_index >= dst_reigons.len() {
-                self.handle_remaining_batches(cmd, *poolid, batches);
-                break;
+    pub fn sweep_and_flush(
+        &mut self,
+        cmd: &mut CommandBuffer,
+        stencil_buffer: &mut StencilBuffer,
+        usage: &mut BufferUsage,
+    ) -> eyre::Result<()> {
+        let mut ops = AllocatorOps::default();
+        let result = self.allocator.sweep(&mut ops);
+        self.execute(cmd, &stencil_buffer.buffer, ops, usage)?;
+        result?;
+
+        self.update_gpu_batches(cmd, stencil_buffer, usage);
+        self.allocator.end_frame();
+
+        if cfg!(debug_assertions) {
+            if let Err(err) = self.allocator.validate() {
+                eprintln!("primative manager bookkeeping is inconsistent: {err}");
             }
         }
+
+        Ok(())
     }
 
-    fn handle_remaining_batches(&mut self, cmd: &mut CommandBuffer, src_pool_id: u32, mut batches: Vec<u32>) {
-        self.new_pool().unwrap();
-        self.sweep_batches_to_reigon(cmd, src_pool_id, &mut batches, (self.pools.len() - 1) as u32, 0);
+    /*
+        Releases pools without batches, the command buffer keeps their buffers alive until it is done.
+        Returns the released buffers so they can be forgotten by the descriptor cache and the state
+        tracker.
+    */
+    pub fn release_empty_pools(&mut self, cmd: &mut CommandBuffer) -> Vec<vk::Buffer> {
+        let mut ops = AllocatorOps::default();
+        self.allocator.release_empty_pools(&mut ops);
+
+        let mut released = Vec::new();
+        for pool_id in ops.released_pools {
+            let pool = self.pools[pool_id as usize].take().unwrap();
+            released.push(pool.primative_buffer.inner());
+            cmd.add_dependency(pool.primative_buffer);
+        }
+        released
     }
 
-    fn sweep_batches_to_reigon(
+    /*
+        Creates the buffers of new pools and records the copies, the upload buffer is the source of
+        CopySource::Upload copies. Only the buffers of recorded copies are added to the usage.
+    */
+    fn execute(
         &mut self,
         cmd: &mut CommandBuffer,
-        src_pool_id: u32,
-        batches: &mut Vec<u32>,
-        dst_pool_id: u32,
-        dst_reigon_id: u32,
-    ) {
-        let dst_pool = self.pools.last_mut().unwrap();
-        let dst_reigon = &mut dst_pool.reigons[dst_reigon_id as usize];
-
-        let mut copy_commands = Vec::new();
+        upload_buffer: &Buffer<u8>,
+        ops: AllocatorOps,
+        usage: &mut BufferUsage,
+    ) -> eyre::Result<()> {
+        for (pool_id, byte_size) in ops.created_pools {
+            if pool_id as usize >= self.pools.len() {
+                self.pools.resize_with(pool_id as usize + 1, || None);
+            }
+            self.pools[pool_id as usize] =
+                Some(PrimativePool { pool_id, primative_buffer: self.core.create_buffer(self.buffer_usage, byte_size as u32, false)? });
+        }
 
-        while let Some(batch_id) = batches.pop() {
-            let Some(batch) = self.batches.get_mut(&batch_id) else {
-                eprintln!("batch_id: {batch_id} is not found. skipping it in sweep");
-                continue;
-            };
+        //consecutive copies between the same buffers are recorded together
+        let mut start = 0;
+        while start < ops.copies.len() {
+            let (src, dst_pool) = (ops.copies[start].src, ops.copies[start].dst_pool);
+            let group_len = ops.copies[start..].iter().take_while(|c| (c.src, c.dst_pool) == (src, dst_pool)).count();
+            let end = start + group_len;
 
-            let Some(new_offset) = dst_reigon.allocate(batch.count) else {
-                batches.push(batch_id);//push batch id back in order to prevent leakage
-                break;
+            let src = match src {
+                CopySource::Upload => upload_buffer.inner(),
+                CopySource::Pool(pool_id) => self.pools[pool_id as usize].as_ref().unwrap().primative_buffer.inner(),
             };
+            let dst = self.pools[dst_pool as usize].as_ref().unwrap().primative_buffer.inner();
+            let copies: Vec<_> = ops.copies[start..end]
+                .iter()
+                .map(|c| vk::BufferCopy { src_offset: c.src_offset, dst_offset: c.dst_offset, size: c.size })
+                .collect();
 
-            dst_reigon.batch_ids.insert(batch_id);
-            copy_commands.push(vk::BufferCopy {
-                src_offset: batch.offset as u64 * self.primative_size as u64,
-                dst_offset: new_offset as u64 * self.primative_size as u64,
-                size: new_offset as u64 * self.primative_size as u64,
-            });
-
-            batch.pool_id = dst_pool.pool_id;
-            batch.offset = new_offset;
-
-            // set batch to updated
-            self.updated_batches.insert(batch_id);
+            unsafe {
+                cmd.copy_buffer_reigons(src, dst, &copies);
+            }
+            usage.add(src, BufferAccess::TransferRead);
+            usage.add(dst, BufferAccess::TransferWrite);
+            start = end;
         }
 
-        unsafe {
-            cmd.copy_buffer_reigons(
-                self.pools[src_pool_id as usize].primative_buffer.inner(),
-                self.pools[dst_pool_id as usize].primative_buffer.inner(),
-                &copy_commands,
-            );
-        }
+        Ok(())
     }
 
-    fn remove_batch_from_reigon(&mut self, id: u32) {
-        if let Some(batch) = self.batches.get(&id) {
-            let pool = &mut self.pools[batch.pool_id as usize];
-            let reigon = &mut pool.reigons[(batch.offset / pool.reigon_size) as usize];
-            reigon.batch_ids.remove(&batch.id);
+    fn update_gpu_batches(&mut self, cmd: &mut CommandBuffer, stencil_buffer: &mut StencilBuffer, usage: &mut BufferUsage) {
+        let updated = self.allocator.take_updated_batches();
+        if updated.is_empty() {
+            return;
         }
-    }
-
-    fn update_batch_gpu(&mut self, id: u32) { self.updated_batches.insert(id); }
 
-    fn update_gpu_batches(&mut self, cmd: &mut CommandBuffer, stencil_buffer: &mut StencilBuffer) {
-        let (gpu_data, byte_offset) = stencil_buffer.allocate_items(self.updated_batches.len() as u64).unwrap();
+        let (gpu_data, byte_offset) = stencil_buffer.allocate_items(updated.len() as u64).unwrap();
 
-        let mut copies = Vec::with_capacity(self.updated_batches.len());
+        let mut copies = Vec::with_capacity(updated.len());
 
         let compacted_size = 8;
 
-        for (i, id) in self.updated_batches.iter().enumerate() {
-            gpu_data[i] = self.batches.get(id).map_or([0, 0], |b| b.compact());
+        for (i, (id, compact)) in updated.iter().enumerate() {
+            debug_assert!(*id < self.max_id);
+            gpu_data[i] = *compact;
             copies.push(vk::BufferCopy {
                 src_offset: i as u64 * compacted_size + byte_offset,
                 dst_offset: *id as u64 * compacted_size,
@@ -268,113 +228,7 @@ impl PrimativeManager {
         unsafe {
             cmd.copy_buffer_reigons(stencil_buffer.buffer.inner(), self.batch_description_buffer.inner(), &copies);
         }
-
-        self.updated_batches.clear();
-    }
-
-    fn insert_into_reigons(
-        &mut self,
-        cmd: &mut CommandBuffer,
-        stencil_buffer: &Buffer<u8>,
-        uploads: &[BatchUpload],
-        reigons: &[(u32, u32, f32)],
-        i: &mut usize,
-    ) {
-        for (pid, rid, _) in reigons {
-            if *i >= uploads.len() {
-                break;
-            }
-
-            let pool = &mut self.pools[*pid as usize];
-            let reigon = &mut pool.reigons[*rid as usize];
-            reigon.is_written = true;
-
-            let mut copy_cmds: Vec<vk::BufferCopy> = Vec::new();
-
-            while let Some(upload) = uploads.get(*i) {
-                let Some(alloc_offset) = reigon.allocate(upload.primative_count) else {break};
-
-                copy_cmds.push(vk::BufferCopy {
-                    src_offset: upload.byte_offset as u64,
-                    dst_offset: (alloc_offset * self.primative_size) as u64,
-                    size: (upload.primative_count * self.primative_size) as u64,
-                });
-                self.batches.insert(
-                    upload.id,
-                    PrimativeBatch { count: upload.primative_count, offset: alloc_offset, id: upload.id, pool_id: *pid },
-                );
-                reigon.batch_ids.insert(upload.id);
-
-                *i += 1;
-            }
-
-            unsafe {
-                cmd.copy_buffer_reigons(stencil_buffer.inner(), pool.primative_buffer.inner(), &copy_cmds);
-            }
-        }
-    }
-
-    fn new_pool(&mut self) -> eyre::Result<()> {
-        let reigon_count = self.pools.last().map(|p| p.reigons.len() as u32).unwrap_or(4);
-
-        self.pools.push(PrimativePool {
-            primative_size: self.primative_size,
-            pool_id: self.pools.len() as u32,
-            primative_buffer: self.core.create_buffer(
-                self.buffer_usage,
-                self.primative_size * self.reigon_size * reigon_count,
-                false,
-            )?,
-            reigons: (0..reigon_count)
-                .map(|i| PrimativeReigon {
-                    primative_offset: i * self.reigon_size,
-                    cap: self.reigon_size,
-                    top: 0,
-                    batch_ids: HashSet::new(),
-                    usage: 0,
-                    is_written: false,
-                })
-                .collect(),
-            reigon_size: self.reigon_size,
-        });
-
-        Ok(())
-    }
-}
-
-impl PrimativeReigon {
-    fn score(&self) -> f32 {
-        let top = self.top as f32;
-        let cap = self.cap as f32;
-        let usage = self.usage as f32;
-
-        let empty_percent = 1.0 - (top / cap);
-        if usage == top {
-            return empty_percent;
-        }
-
-        (usage / top) * empty_percent
-    }
-
-    fn fragmentation_score(&self) -> f32 { (self.top - self.usage) as f32 / self.cap as f32 }
-
-    fn allocate(&mut self, batch_size: u32) -> Option<u32> {
-        if batch_size + self.top <= self.cap {
-            let allocation = self.primative_offset + self.top;
-            self.top += batch_size;
-            self.usage += batch_size;
-            Some(allocation)
-        } else {
-            None
-        }
-    }
-
-    fn reset_and_get_batches(&mut self) -> Vec<u32> {
-        self.top = 0;
-        self.usage = 0;
-
-        let mut vec = Vec::new();
-        vec.extend(self.batch_ids.drain());
-        vec
+        usage.add(stencil_buffer.buffer.inner(), BufferAccess::TransferRead);
+        usage.add(self.batch_description_buffer.inner(), BufferAccess::TransferWrite);
     }
 }
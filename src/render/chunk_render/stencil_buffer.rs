@@ -50,5 +50,7 @@ impl StencilBuffer {
             .map(|(s,o)| (bytemuck::cast_slice_mut(s),o))
     }
 
+    pub fn free_bytes(&self) -> u64 { self.buffer.byte_size() - self.top }
+
     pub fn reset(&mut self) { self.top = 0; }
 }
@@ -0,0 +1,293 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use specs::prelude::*;
+
+use crate::game::{
+    voxels::{world_pos_to_chunkpos, ViewDistance, CHUNK_SIZE},
+    CameraData,
+};
+
+use super::chunk_mesh_manager::ChunkMeshManager;
+
+//faces are ordered like Direction, x+ x- y+ y- z+ z-, the opposite of a face is face ^ 1
+pub const FACE_OFFSETS: [[i32; 3]; 6] = [[1, 0, 0], [-1, 0, 0], [0, 1, 0], [0, -1, 0], [0, 0, 1], [0, 0, -1]];
+
+//which faces of a chunk can see each other through its transparent cells, bit b of faces[a] connects a and b
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChunkConnectivity {
+    faces: [u8; 6],
+}
+
+impl ChunkConnectivity {
+    pub const NONE: ChunkConnectivity = ChunkConnectivity { faces: [0; 6] };
+    pub const ALL: ChunkConnectivity = ChunkConnectivity { faces: [0x3F; 6] };
+
+    pub fn connects(&self, a: usize, b: usize) -> bool { self.faces[a] & (1 << b) != 0 }
+
+    pub fn to_bits(&self) -> [u8; 6] { self.faces }
+    pub fn from_bits(faces: [u8; 6]) -> ChunkConnectivity { Self { faces } }
+
+    fn connect_all(&mut self, face_mask: u8) {
+        for a in (0..6).filter(|a| face_mask & (1 << a) != 0) {
+            self.faces[a] |= face_mask;
+        }
+    }
+
+    /*
+        Flood fills every region of transparent cells and connects all chunk faces a region
+        touches. is_transparent takes local coordinates from 0 to CHUNK_SIZE.
+    */
+    pub fn compute(is_transparent: impl Fn(i32, i32, i32) -> bool) -> ChunkConnectivity {
+        let size = CHUNK_SIZE as i32;
+        let index = |x: i32, y: i32, z: i32| (x + y * size + z * size * size) as usize;
+
+        let mut visited = vec![false; CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE];
+        let mut connectivity = ChunkConnectivity::NONE;
+        let mut stack = vec![];
+
+        for z in 0..size {
+            for y in 0..size {
+                for x in 0..size {
+                    if visited[index(x, y, z)] || !is_transparent(x, y, z) {
+                        continue;
+                    }
+
+                    let mut face_mask = 0u8;
+                    visited[index(x, y, z)] = true;
+                    stack.push([x, y, z]);
+
+                    while let Some(pos) = stack.pop() {
+                        for (face, offset) in FACE_OFFSETS.iter().enumerate() {
+                            let next = [0, 1, 2].map(|i| pos[i] + offset[i]);
+                            if next.iter().any(|n| *n < 0 || *n >= size) {
+                                face_mask |= 1 << face;
+                                continue;
+                            }
+
+                            let [nx, ny, nz] = next;
+                            if !visited[index(nx, ny, nz)] && is_transparent(nx, ny, nz) {
+                                visited[index(nx, ny, nz)] = true;
+                                stack.push(next);
+                            }
+                        }
+                    }
+
+                    connectivity.connect_all(face_mask);
+                }
+            }
+        }
+
+        connectivity
+    }
+}
+
+/*
+    Breadth first search over chunks from the camera's chunk. A chunk is entered through the face
+    opposite of the step and left through another face only if the two faces are connected, steps
+    never go back against a direction already taken so the search can't wrap around walls.
+    Chunks without connectivity, like unloaded or never meshed ones, are treated as open.
+*/
+pub fn visible_chunks(
+    start: [i32; 3],
+    max_distance: i32,
+    connectivity: impl Fn(&[i32; 3]) -> Option<ChunkConnectivity>,
+) -> HashSet<[i32; 3]> {
+    let mut visible = HashSet::from([start]);
+    //chunk, face it was entered from and the directions taken to reach it
+    let mut queue = VecDeque::from([(start, None::<usize>, 0u8)]);
+
+    while let Some((pos, entry_face, directions)) = queue.pop_front() {
+        let chunk = connectivity(&pos).unwrap_or(ChunkConnectivity::ALL);
+
+        for (face, offset) in FACE_OFFSETS.iter().enumerate() {
+            if directions & (1 << (face ^ 1)) != 0 {
+                continue;
+            }
+            if entry_face.map_or(false, |entry| !chunk.connects(entry, face)) {
+                continue;
+            }
+
+            let next = [0, 1, 2].map(|i| pos[i] + offset[i]);
+            if (0..3).any(|i| (next[i] - start[i]).abs() > max_distance) || !visible.insert(next) {
+                continue;
+            }
+
+            queue.push_back((next, Some(face ^ 1), directions | (1 << face)));
+        }
+    }
+
+    visible
+}
+
+//connectivity of meshed chunks and the chunks found visible from the camera last frame
+pub struct ChunkVisibility {
+    connectivity: HashMap<[i32; 3], ChunkConnectivity>,
+    visible: HashSet<[i32; 3]>,
+    pub enabled: bool,
+}
+
+impl ChunkVisibility {
+    pub fn new() -> ChunkVisibility { Self { connectivity: HashMap::new(), visible: HashSet::new(), enabled: true } }
+
+    pub fn set_connectivity(&mut self, pos: [i32; 3], connectivity: ChunkConnectivity) {
+        self.connectivity.insert(pos, connectivity);
+    }
+
+
+    pub fn is_visible(&self, pos: &[i32; 3]) -> bool { !self.enabled || self.visible.contains(pos) }
+    pub fn visible_count(&self) -> usize { self.visible.len() }
+}
+
+pub struct UpdateChunkVisibility;
+
+impl<'a> System<'a> for UpdateChunkVisibility {
+    type SystemData = (
+        WriteExpect<'a, ChunkVisibility>,
+        WriteExpect<'a, ChunkMeshManager>,
+        ReadExpect<'a, CameraData>,
+        ReadExpect<'a, ViewDistance>,
+    );
+
+    fn run(&mut self, (mut visibility, mut mesh_man, cam_data, view_distance): Self::SystemData) {
+        if visibility.enabled {
+            let camera_chunk = world_pos_to_chunkpos(cam_data.position.floor().as_ivec3().to_array());
+            let visible = visible_chunks(camera_chunk, view_distance.0 + 1, |pos| visibility.connectivity.get(pos).copied());
+            visibility.visible = visible;
+        }
+
+        mesh_man.update_occlusion(|pos| visibility.is_visible(pos));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const X_POS: usize = 0;
+    const X_NEG: usize = 1;
+    const Y_POS: usize = 2;
+
+    fn connected_faces(connectivity: &ChunkConnectivity) -> Vec<(usize, usize)> {
+        let mut pairs = vec![];
+        for a in 0..6 {
+            for b in a + 1..6 {
+                if connectivity.connects(a, b) {
+                    pairs.push((a, b));
+                }
+            }
+        }
+        pairs
+    }
+
+    #[test]
+    fn solid_chunk_connects_nothing() {
+        assert_eq!(ChunkConnectivity::compute(|_, _, _| false), ChunkConnectivity::NONE);
+    }
+
+    #[test]
+    fn empty_chunk_connects_everything() {
+        assert_eq!(ChunkConnectivity::compute(|_, _, _| true), ChunkConnectivity::ALL);
+    }
+
+    #[test]
+    fn hollow_chunk_connects_nothing() {
+        let max = CHUNK_SIZE as i32 - 1;
+        let shell = |x: i32, y: i32, z: i32| [x, y, z].iter().any(|c| *c == 0 || *c == max);
+        assert_eq!(ChunkConnectivity::compute(|x, y, z| !shell(x, y, z)), ChunkConnectivity::NONE);
+    }
+
+    #[test]
+    fn tunnel_connects_its_ends() {
+        let connectivity = ChunkConnectivity::compute(|_, y, z| y == 5 && z == 5);
+        assert_eq!(connected_faces(&connectivity), vec![(X_POS, X_NEG)]);
+        assert!(connectivity.connects(X_NEG, X_POS));
+        assert!(!connectivity.connects(X_POS, Y_POS));
+    }
+
+    #[test]
+    fn separate_tunnels_stay_separate() {
+        //a tunnel along x at y 2 and one along y at x 10, they don't cross
+        let connectivity = ChunkConnectivity::compute(|x, y, z| (y == 2 && z == 5) || (x == 10 && z == 9));
+        assert_eq!(connected_faces(&connectivity), vec![(X_POS, X_NEG), (Y_POS, Y_POS + 1)]);
+    }
+
+    #[test]
+    fn bent_tunnel_connects_both_faces() {
+        //along x from the x- face to the middle and up to the y+ face from there
+        let connectivity = ChunkConnectivity::compute(|x, y, z| z == 3 && ((y == 4 && x <= 8) || (x == 8 && y >= 4)));
+        assert_eq!(connected_faces(&connectivity), vec![(X_NEG, Y_POS)]);
+    }
+
+    #[test]
+    fn bits_round_trip() {
+        let connectivity = ChunkConnectivity::compute(|_, y, z| y == 5 && z == 5);
+        assert_eq!(ChunkConnectivity::from_bits(connectivity.to_bits()), connectivity);
+    }
+
+    #[test]
+    fn open_chunks_are_all_visible() {
+        let visible = visible_chunks([0, 0, 0], 1, |_| None);
+        assert_eq!(visible.len(), 27);
+
+        let visible = visible_chunks([4, -2, 7], 2, |_| Some(ChunkConnectivity::ALL));
+        assert_eq!(visible.len(), 125);
+        assert!(visible.contains(&[6, 0, 9]));
+        assert!(!visible.contains(&[7, -2, 7]));
+    }
+
+    #[test]
+    fn sealed_chunks_only_show_the_neighbours() {
+        //the camera's chunk is left through every face, its neighbours can't be crossed
+        let visible = visible_chunks([0, 0, 0], 4, |_| Some(ChunkConnectivity::NONE));
+        let mut expected: HashSet<[i32; 3]> = FACE_OFFSETS.into_iter().collect();
+        expected.insert([0, 0, 0]);
+        assert_eq!(visible, expected);
+    }
+
+    #[test]
+    fn search_follows_connected_faces() {
+        let tunnel = ChunkConnectivity::compute(|_, y, z| y == 5 && z == 5);
+        //a tunnel along x through two chunks, ending at a sealed chunk
+        let connectivity = |pos: &[i32; 3]| match pos {
+            [1 | 2, 0, 0] => Some(tunnel),
+            _ => Some(ChunkConnectivity::NONE),
+        };
+
+        let visible = visible_chunks([0, 0, 0], 8, connectivity);
+        assert!(visible.contains(&[2, 0, 0]));
+        assert!(visible.contains(&[3, 0, 0]));
+        assert!(!visible.contains(&[4, 0, 0]));
+        //the tunnel doesn't open to the sides
+        assert!(!visible.contains(&[1, 1, 0]));
+        assert!(!visible.contains(&[2, 0, -1]));
+        //7 around the camera and 2 more along the tunnel
+        assert_eq!(visible.len(), 9);
+    }
+
+    #[test]
+    fn search_turns_through_bent_tunnels() {
+        let bent = ChunkConnectivity::compute(|x, y, z| z == 3 && ((y == 4 && x <= 8) || (x == 8 && y >= 4)));
+        //entered from the x- face at [1, 0, 0], left upwards
+        let connectivity = |pos: &[i32; 3]| match pos {
+            [1, 0, 0] => Some(bent),
+            [1, 1, 0] => None,
+            _ => Some(ChunkConnectivity::NONE),
+        };
+
+        let visible = visible_chunks([0, 0, 0], 8, connectivity);
+        assert!(visible.contains(&[1, 1, 0]));
+        assert!(!visible.contains(&[2, 0, 0]));
+        //the open chunk above is left through every face except back down
+        assert!(visible.contains(&[1, 2, 0]));
+        assert!(visible.contains(&[2, 1, 0]));
+        //going back against x from there isn't allowed
+        assert!(!visible.contains(&[0, 2, 0]));
+    }
+
+    #[test]
+    fn search_stops_at_the_max_distance() {
+        let visible = visible_chunks([0, 0, 0], 3, |_| None);
+        assert!(visible.iter().all(|pos| pos.iter().all(|c| c.abs() <= 3)));
+        assert!(visible.contains(&[3, -3, 3]));
+    }
+}
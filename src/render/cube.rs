@@ -1,15 +1,16 @@
 use ash::vk;
 use magma_renderer::engine::material::*;
-use magma_renderer::{core::*, engine::mesh_manager::MeshManager};
+use magma_renderer::core::*;
 use std::sync::Arc;
 
 use specs::prelude::*;
 
-use crate::game::{self, CameraData, Game};
+use crate::game::{self, plugin::Plugin, CameraData, Game};
 
 use super::{
+    hot_reload::HotReload,
+    model::{EntityMaterial, ModelData, ModelManager, RenderModel},
     renderpassmanager::{self, RenderPassManager},
-    RenderAble,
 };
 
 
@@ -44,31 +45,42 @@ pub fn create_cube_mesh(core: &Arc<Core>, cmd: &mut CommandBuffer) -> eyre::Resu
     // })
 }
 
-pub struct CubePrefab(pub RenderAble);
+pub struct CubePrefab(pub RenderModel, pub EntityMaterial);
+
+pub struct CubePlugin;
+
+impl Plugin for CubePlugin {
+    fn name(&self) -> &'static str { "cube" }
+    fn provides(&self) -> &'static [&'static str] { &["cube_prefab"] }
+    fn requires(&self) -> &'static [&'static str] { &["gpass", "material_manager", "model_manager"] }
+    fn build(&self, game: &mut Game) -> eyre::Result<()> { init_cube(game) }
+}
 
 pub fn init_cube(game: &mut Game) -> eyre::Result<()> {
     let cube_prefab = {
-        let core = &game.core;
+        let core = game.core()?;
 
         let mut cmd = core.new_cmd();
         cmd.begin()?;
 
         let rp_man = game.world.fetch::<RenderPassManager>();
-        let rp = rp_man.get_subpass("gpass").unwrap();
+        let rp = rp_man.get_subpass("gpass").ok_or_else(|| eyre::eyre!("subpass \"gpass\" is not registered"))?;
 
         let mut material_system = game.world.fetch_mut::<MaterialManager>();
         // material_system.set_vertex_layout("cube".into(), MeshVertex::get_desciption());
 
-        let materialid = material_system.load_material(&mut cmd, "res/cube.mat.yaml".into())?;
+        let materialid =
+            game.world.fetch_mut::<HotReload>().load_material(&mut material_system, &mut cmd, "res/model.mat.yaml")?;
 
-        let mut mesh_man = game.world.fetch_mut::<MeshManager>();
+        let mut model_man = game.world.fetch_mut::<ModelManager>();
 
-        let meshid = mesh_man.register_mesh(create_cube_mesh(&game.core, &mut cmd)?);
+        let modelid = model_man.upload(&mut cmd, &ModelData::cube())?;
 
         cmd.end()?;
         cmd.immediate_submit()?;
 
-        CubePrefab(RenderAble { meshid, materialid })
+        //first tile of the voxel tilemap
+        CubePrefab(RenderModel { modelid, materialid }, EntityMaterial::atlas_tile(0, 16))
     };
 
     game.world.insert(cube_prefab);
@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+
+use ash::vk;
+use magma_renderer::core::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BoundResource {
+    Buffer(vk::Buffer),
+    //attachment of a renderpass registered to the RenderPassManager, its image changes when the renderpass is resized
+    Attachment { renderpass: &'static str, name: &'static str },
+    Sampler(vk::Sampler),
+}
+
+//a descriptor set is identified by its layout and everything bound to it in binding order
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DescriptorKey {
+    layout: vk::DescriptorSetLayout,
+    resources: Vec<BoundResource>,
+}
+
+impl DescriptorKey {
+    pub fn new(layout: vk::DescriptorSetLayout) -> DescriptorKey { Self { layout, resources: vec![] } }
+
+    pub fn buffer(mut self, buffer: vk::Buffer) -> DescriptorKey {
+        self.resources.push(BoundResource::Buffer(buffer));
+        self
+    }
+
+    pub fn attachment(mut self, renderpass: &'static str, name: &'static str, sampler: vk::Sampler) -> DescriptorKey {
+        self.resources.push(BoundResource::Attachment { renderpass, name });
+        self.resources.push(BoundResource::Sampler(sampler));
+        self
+    }
+
+    pub fn uses_renderpass(&self, renderpass: &str) -> bool {
+        self.resources.iter().any(|r| matches!(r, BoundResource::Attachment { renderpass: rp, .. } if *rp == renderpass))
+    }
+
+    pub fn uses_buffer(&self, buffer: vk::Buffer) -> bool { self.resources.contains(&BoundResource::Buffer(buffer)) }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DescriptorCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub live_sets: usize,
+    //sets that were invalidated but are still allocated in the pool
+    pub orphaned_sets: usize,
+}
+
+//the pool the cached sets are allocated from, only resetting it as a whole is needed by the cache
+pub trait SetPool {
+    fn reset_sets(&mut self) -> eyre::Result<()>;
+}
+
+impl SetPool for DescriptorPool {
+    fn reset_sets(&mut self) -> eyre::Result<()> {
+        self.reset()?;
+        Ok(())
+    }
+}
+
+/*
+    Descriptor sets that live across frames, built once for every combination of layout and bound
+    resources instead of every frame from the per frame pools. Sets referencing an attachment are
+    invalidated when its renderpass is resized and sets referencing a buffer when it is destroyed.
+    The pool can't free single sets so invalidated ones are only reclaimed by resetting the whole
+    pool, which happens on resize once they outnumber the live ones.
+*/
+pub struct DescriptorCache<P: SetPool = DescriptorPool> {
+    sets: HashMap<DescriptorKey, vk::DescriptorSet>,
+    pool: P,
+    stats: DescriptorCacheStats,
+}
+
+impl DescriptorCache {
+    pub fn new(core: &std::sync::Arc<Core>) -> DescriptorCache { Self::with_pool(DescriptorPool::new(core)) }
+}
+
+impl<P: SetPool> DescriptorCache<P> {
+    pub fn with_pool(pool: P) -> DescriptorCache<P> { Self { sets: HashMap::new(), pool, stats: Default::default() } }
+
+    //the gpu must be idle, invalidated sets may be in use by frames in flight otherwise
+    pub fn invalidate_renderpass(&mut self, renderpass: &str) {
+        self.remove_where(|key| key.uses_renderpass(renderpass));
+        if self.stats.orphaned_sets > self.sets.len() {
+            self.sets.clear();
+            self.stats.orphaned_sets = 0;
+            self.pool.reset_sets().unwrap();
+        }
+    }
+
+    pub fn get_or_build(
+        &mut self,
+        key: DescriptorKey,
+        build: impl FnOnce(&mut P) -> eyre::Result<vk::DescriptorSet>,
+    ) -> eyre::Result<vk::DescriptorSet> {
+        if let Some(set) = self.sets.get(&key) {
+            self.stats.hits += 1;
+            return Ok(*set);
+        }
+
+        self.stats.misses += 1;
+        let set = build(&mut self.pool)?;
+        self.sets.insert(key, set);
+        Ok(set)
+    }
+
+    //should be called before a buffer bound to cached sets is destroyed, its handle may be reused
+    pub fn forget_buffer(&mut self, buffer: vk::Buffer) { self.remove_where(|key| key.uses_buffer(buffer)); }
+
+    fn remove_where(&mut self, f: impl Fn(&DescriptorKey) -> bool) {
+        let before = self.sets.len();
+        self.sets.retain(|key, _| !f(key));
+        self.stats.orphaned_sets += before - self.sets.len();
+    }
+
+    pub fn stats(&self) -> DescriptorCacheStats { DescriptorCacheStats { live_sets: self.sets.len(), ..self.stats } }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ash::vk::Handle;
+
+    //hands out increasing set handles and counts resets
+    #[derive(Default)]
+    struct FakePool {
+        allocated: u64,
+        resets: u32,
+    }
+
+    impl SetPool for FakePool {
+        fn reset_sets(&mut self) -> eyre::Result<()> {
+            self.allocated = 0;
+            self.resets += 1;
+            Ok(())
+        }
+    }
+
+    fn build(pool: &mut FakePool) -> eyre::Result<vk::DescriptorSet> {
+        pool.allocated += 1;
+        Ok(vk::DescriptorSet::from_raw(pool.allocated))
+    }
+
+    fn layout(raw: u64) -> vk::DescriptorSetLayout { vk::DescriptorSetLayout::from_raw(raw) }
+    fn buffer(raw: u64) -> vk::Buffer { vk::Buffer::from_raw(raw) }
+    fn sampler() -> vk::Sampler { vk::Sampler::from_raw(1) }
+
+    fn cache() -> DescriptorCache<FakePool> { DescriptorCache::with_pool(FakePool::default()) }
+
+    #[test]
+    fn hits_and_misses() {
+        let mut cache = cache();
+        let a = cache.get_or_build(DescriptorKey::new(layout(1)).buffer(buffer(1)), build).unwrap();
+        let b = cache.get_or_build(DescriptorKey::new(layout(1)).buffer(buffer(2)), build).unwrap();
+        assert_ne!(a, b);
+
+        assert_eq!(cache.get_or_build(DescriptorKey::new(layout(1)).buffer(buffer(1)), build).unwrap(), a);
+        //the layout is part of the key
+        cache.get_or_build(DescriptorKey::new(layout(2)).buffer(buffer(1)), build).unwrap();
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.live_sets, stats.orphaned_sets), (1, 3, 3, 0));
+        assert_eq!(cache.pool.allocated, 3);
+    }
+
+    #[test]
+    fn failed_build_is_not_cached() {
+        let mut cache = cache();
+        let key = DescriptorKey::new(layout(1)).buffer(buffer(1));
+        assert!(cache.get_or_build(key.clone(), |_| Err(eyre::eyre!("pool is full"))).is_err());
+
+        cache.get_or_build(key, build).unwrap();
+        assert_eq!(cache.stats().misses, 2);
+        assert_eq!(cache.stats().live_sets, 1);
+    }
+
+    #[test]
+    fn invalidate_renderpass_only_drops_its_sets() {
+        let mut cache = cache();
+        let gbuffer = DescriptorKey::new(layout(1)).attachment("deferred_render", "albedo_spec", sampler());
+        let hdr = DescriptorKey::new(layout(1)).attachment("hdr", "color", sampler());
+        let mixed = DescriptorKey::new(layout(2)).buffer(buffer(1)).attachment("deferred_render", "depth", sampler());
+        let plain = DescriptorKey::new(layout(3)).buffer(buffer(1));
+        for key in [&gbuffer, &hdr, &mixed, &plain] {
+            cache.get_or_build(key.clone(), build).unwrap();
+        }
+
+        cache.invalidate_renderpass("deferred_render");
+        assert_eq!(cache.stats().live_sets, 2);
+        assert_eq!(cache.stats().orphaned_sets, 2);
+        assert_eq!(cache.pool.resets, 0);
+
+        //the sets of the other keys are still hits
+        cache.get_or_build(hdr, build).unwrap();
+        cache.get_or_build(plain, build).unwrap();
+        assert_eq!(cache.stats().hits, 2);
+        cache.get_or_build(gbuffer, build).unwrap();
+        assert_eq!(cache.stats().misses, 5);
+    }
+
+    #[test]
+    fn forget_buffer() {
+        let mut cache = cache();
+        let first = DescriptorKey::new(layout(1)).buffer(buffer(1));
+        let both = DescriptorKey::new(layout(2)).buffer(buffer(2)).buffer(buffer(1));
+        let second = DescriptorKey::new(layout(1)).buffer(buffer(2));
+        for key in [&first, &both, &second] {
+            cache.get_or_build(key.clone(), build).unwrap();
+        }
+
+        cache.forget_buffer(buffer(1));
+        assert_eq!(cache.stats().live_sets, 1);
+        assert_eq!(cache.stats().orphaned_sets, 2);
+
+        cache.get_or_build(second, build).unwrap();
+        assert_eq!(cache.stats().hits, 1);
+        cache.get_or_build(first, build).unwrap();
+        assert_eq!(cache.stats().misses, 4);
+    }
+
+    #[test]
+    fn orphans_reset_the_pool_once_they_outnumber_live_sets() {
+        let mut cache = cache();
+        let live = DescriptorKey::new(layout(1)).buffer(buffer(1));
+        cache.get_or_build(live.clone(), build).unwrap();
+
+        //one orphan for one live set doesn't reset yet
+        cache.get_or_build(DescriptorKey::new(layout(1)).attachment("hdr", "color", sampler()), build).unwrap();
+        cache.invalidate_renderpass("hdr");
+        assert_eq!(cache.pool.resets, 0);
+        assert_eq!(cache.stats().orphaned_sets, 1);
+
+        cache.get_or_build(DescriptorKey::new(layout(1)).attachment("hdr", "color", sampler()), build).unwrap();
+        cache.invalidate_renderpass("hdr");
+        assert_eq!(cache.pool.resets, 1);
+
+        //every set was freed with the pool, including the live one
+        let stats = cache.stats();
+        assert_eq!((stats.live_sets, stats.orphaned_sets), (0, 0));
+        cache.get_or_build(live, build).unwrap();
+        assert_eq!(cache.stats().misses, 4);
+        assert_eq!(cache.pool.allocated, 1);
+    }
+
+    #[test]
+    fn forgetting_buffers_doesnt_reset_the_pool() {
+        let mut cache = cache();
+        for raw in 1..=3 {
+            cache.get_or_build(DescriptorKey::new(layout(1)).buffer(buffer(raw)), build).unwrap();
+            cache.forget_buffer(buffer(raw));
+        }
+        assert_eq!(cache.stats().orphaned_sets, 3);
+        assert_eq!(cache.pool.resets, 0);
+    }
+}
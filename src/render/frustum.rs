@@ -0,0 +1,32 @@
+use glam::*;
+
+//planes of a view frustum, xyz is the normal pointing inside and w the distance
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frustum {
+    pub planes: [Vec4; 6],
+}
+
+impl Frustum {
+    //extracts the planes from a projection with vulkan's 0 to 1 depth range
+    pub fn from_proj_view(proj_view: Mat4) -> Frustum {
+        let [r0, r1, r2, r3] = [proj_view.row(0), proj_view.row(1), proj_view.row(2), proj_view.row(3)];
+
+        let planes = [r3 + r0, r3 - r0, r3 + r1, r3 - r1, r2, r3 - r2].map(|p| p / p.truncate().length());
+
+        Frustum { planes }
+    }
+
+    pub fn intersects_aabb(&self, min: Vec3, max: Vec3) -> bool {
+        self.planes.iter().all(|plane| {
+            //corner of the box furthest along the plane normal
+            let corner = vec3(
+                if plane.x >= 0.0 { max.x } else { min.x },
+                if plane.y >= 0.0 { max.y } else { min.y },
+                if plane.z >= 0.0 { max.z } else { min.z },
+            );
+            plane.truncate().dot(corner) + plane.w >= 0.0
+        })
+    }
+
+    pub fn to_gpu(&self) -> [[f32; 4]; 6] { self.planes.map(|p| p.to_array()) }
+}
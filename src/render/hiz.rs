@@ -0,0 +1,550 @@
+use std::sync::Arc;
+
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use glam::*;
+use magma_renderer::core::*;
+
+use crate::{
+    game::{CameraData, Game, RenderGlobals},
+    include_glsl,
+};
+
+use super::{
+    descriptor_cache::{DescriptorCache, DescriptorKey},
+    render_graph::{PassDesc, ResourceKind},
+    renderpasses::DeferedPass,
+    renderpassmanager::*,
+};
+
+pub const HIZ_LEVELS: usize = 8;
+//every level is a renderpass and subpass with this name, level 0 is half the size of the depth buffer
+pub const HIZ_LEVEL_NAMES: [&str; HIZ_LEVELS] = ["hiz0", "hiz1", "hiz2", "hiz3", "hiz4", "hiz5", "hiz6", "hiz7"];
+pub const HIZ_FORMAT: vk::Format = vk::Format::R32_SFLOAT;
+
+pub fn level_extent((width, height): (u32, u32), level: usize) -> (u32, u32) {
+    ((width >> (level + 1)).max(1), (height >> (level + 1)).max(1))
+}
+
+//source texels, end exclusive, reduced into a texel of the next level. with odd sizes a texel covers three
+pub fn source_range(texel: u32, src_size: u32, dst_size: u32) -> (u32, u32) {
+    let start = texel * src_size / dst_size;
+    let end = ((texel + 1) * src_size + dst_size - 1) / dst_size;
+    (start, end.min(src_size))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DepthLevel {
+    pub width: u32,
+    pub height: u32,
+    pub depth: Vec<f32>,
+}
+
+impl DepthLevel {
+    pub fn new(width: u32, height: u32, depth: Vec<f32>) -> DepthLevel {
+        assert_eq!(depth.len(), (width * height) as usize);
+        Self { width, height, depth }
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> f32 { self.depth[(x + y * self.width) as usize] }
+
+    //every texel keeps the furthest depth of the texels it covers, like res/hiz_reduce.frag
+    pub fn reduce(&self, width: u32, height: u32) -> DepthLevel {
+        let mut depth = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let (x0, x1) = source_range(x, self.width, width);
+                let (y0, y1) = source_range(y, self.height, height);
+                let max = (y0..y1).flat_map(|sy| (x0..x1).map(move |sx| (sx, sy))).fold(0.0f32, |m, (sx, sy)| {
+                    m.max(self.get(sx, sy))
+                });
+                depth.push(max);
+            }
+        }
+        DepthLevel { width, height, depth }
+    }
+}
+
+//screen space bounds of a box, uv from 0 to 1 with y down and the depth of its nearest point
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScreenRect {
+    pub min: Vec2,
+    pub max: Vec2,
+    pub depth: f32,
+}
+
+//none if the box reaches behind the camera, it can't be occluded then
+pub fn project_aabb(proj_view: Mat4, aabb_min: Vec3, aabb_max: Vec3) -> Option<ScreenRect> {
+    let mut rect = ScreenRect { min: Vec2::ONE, max: Vec2::ZERO, depth: 1.0 };
+
+    for i in 0..8 {
+        let corner = vec3(
+            if i & 1 != 0 { aabb_max.x } else { aabb_min.x },
+            if i & 2 != 0 { aabb_max.y } else { aabb_min.y },
+            if i & 4 != 0 { aabb_max.z } else { aabb_min.z },
+        );
+        let clip = proj_view * corner.extend(1.0);
+        if clip.w <= 0.0 {
+            return None;
+        }
+
+        let ndc = clip.truncate() / clip.w;
+        let uv = ndc.truncate() * 0.5 + 0.5;
+        rect.min = rect.min.min(uv);
+        rect.max = rect.max.max(uv);
+        rect.depth = rect.depth.min(ndc.z);
+    }
+
+    rect.min = rect.min.clamp(Vec2::ZERO, Vec2::ONE);
+    rect.max = rect.max.clamp(Vec2::ZERO, Vec2::ONE);
+    Some(rect)
+}
+
+/*
+    Cpu version of the test in res/chunk_cull.comp. The level is chosen so the rect covers at
+    most 2x2 texels, boxes that would need a level coarser than the last one are never occluded.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct HiZPyramid {
+    pub levels: Vec<DepthLevel>,
+}
+
+impl HiZPyramid {
+    pub fn from_depth(depth: &DepthLevel, level_count: usize) -> HiZPyramid {
+        let mut levels: Vec<DepthLevel> = Vec::with_capacity(level_count);
+        for level in 0..level_count {
+            let (width, height) = level_extent((depth.width, depth.height), level);
+            let src = levels.last().unwrap_or(depth);
+            levels.push(src.reduce(width, height));
+        }
+        Self { levels }
+    }
+
+    pub fn select_level(&self, rect: &ScreenRect) -> Option<usize> {
+        let base = &self.levels[0];
+        let size = (rect.max - rect.min) * vec2(base.width as f32, base.height as f32);
+        let level = size.max_element().max(1.0).log2().ceil() as usize;
+        (level < self.levels.len()).then_some(level)
+    }
+
+    pub fn is_occluded(&self, proj_view: Mat4, aabb_min: Vec3, aabb_max: Vec3) -> bool {
+        let Some(rect) = project_aabb(proj_view, aabb_min, aabb_max) else { return false };
+        let Some(level) = self.select_level(&rect) else { return false };
+
+        let level = &self.levels[level];
+        let texel = |uv: Vec2| {
+            let size = vec2(level.width as f32, level.height as f32);
+            let p = (uv * size).floor();
+            (p.x.clamp(0.0, size.x - 1.0) as u32, p.y.clamp(0.0, size.y - 1.0) as u32)
+        };
+        let (x0, y0) = texel(rect.min);
+        let (x1, y1) = texel(rect.max);
+
+        let mut max_depth = 0.0f32;
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                max_depth = max_depth.max(level.get(x, y));
+            }
+        }
+
+        rect.depth > max_depth
+    }
+}
+
+//one level of the pyramid on the gpu
+pub struct HiZLevel {
+    pub renderpass: MultiPassRenderPass,
+    pub color: AttachmentIndex,
+    level: usize,
+}
+
+impl HasRenderPass for HiZLevel {
+    fn renderpass(&self) -> &dyn Renderpass { &self.renderpass }
+    fn resize(&mut self, width: u32, height: u32) -> eyre::Result<()> {
+        let (width, height) = level_extent((width, height), self.level);
+        self.renderpass.resize(width, height)?;
+        Ok(())
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+struct ReducePush {
+    src_size: [i32; 2],
+    dst_size: [i32; 2],
+}
+
+//matches HiZData in res/chunk_cull.comp, set 1 binding 0
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+struct HiZGpuData {
+    early_proj_view: [[f32; 4]; 4],
+    late_proj_view: [[f32; 4]; 4],
+    depth_size: [u32; 2],
+    valid: u32,
+    _padding: u32,
+}
+
+/*
+    Depth pyramid reduced from DeferedPass::depth after the gbuffer is drawn. The cull pass of the
+    next frame tests chunks that weren't visible against it with the matrix it was built with
+    (early), a compute pass after the reduction tests every chunk against the current one and
+    writes which are visible for the next frame (late). Chunks the early test rejected that pass
+    the late one are drawn into the gbuffer again by "late_gpass" in the same frame, so chunks
+    uncovered by camera movement don't show up a frame late.
+*/
+pub struct HiZ {
+    pipeline: Arc<Pipeline>,
+    set_layout: vk::DescriptorSetLayout,
+    sampler: Handle<vk::Sampler>,
+    gpu_data: Box<[Buffer<HiZGpuData>]>,
+    //matrix of the frame the pyramid was built from, none until it is built after a resize
+    pyramid_proj_view: Option<Mat4>,
+    pub enabled: bool,
+}
+
+impl HiZ {
+    pub fn new(core: &Arc<Core>, man: &mut RenderPassManager) -> eyre::Result<HiZ> {
+        let mut levels = Vec::with_capacity(HIZ_LEVELS);
+        for (level, name) in HIZ_LEVEL_NAMES.iter().enumerate() {
+            let (width, height) = level_extent(man.extent(), level);
+            let mut builder = RenderPassBuilder::new();
+            let color = builder.add_attachment(HIZ_FORMAT, None, true);
+            builder.add_subpass(&[color], None, &[]);
+            levels.push((*name, HiZLevel { renderpass: builder.build(core, width, height)?, color, level }));
+        }
+
+        let set_layout = DescriptorSetLayoutBuilder::new().add_sampler(vk::ShaderStageFlags::FRAGMENT, 1).build(core)?;
+        let layout = PipelineLayoutBuilder::new()
+            .add_set(set_layout)
+            .add_push::<ReducePush>(vk::ShaderStageFlags::FRAGMENT, 0)
+            .build(core)?;
+
+        //every level has the same format so the pipeline works with all of them
+        let pipeline = GPipelineBuilder::new()
+            .set_depth_testing(false)
+            .set_rasterization(vk::PolygonMode::FILL, vk::CullModeFlags::NONE)
+            .set_topology(vk::PrimitiveTopology::TRIANGLE_LIST)
+            .set_pipeline_layout(layout)
+            .add_shader_stage(
+                vk::ShaderStageFlags::VERTEX,
+                &ShaderModule::new(core, include_glsl!("res/screen_quad.vert"))?.module(),
+            )
+            .add_shader_stage(
+                vk::ShaderStageFlags::FRAGMENT,
+                &ShaderModule::new(core, include_glsl!("res/hiz_reduce.frag"))?.module(),
+            )
+            .set_render_target(levels[0].1.renderpass.get_subpasses()[0].get_render_target())
+            .build(core)?;
+
+        for (name, level) in levels {
+            man.register_renderpass(Box::new(level), name, vec![SubpassAction::Secondry(name)]);
+        }
+
+        Ok(Self {
+            pipeline,
+            set_layout,
+            sampler: core.create_sampler(vk::Filter::NEAREST, None),
+            gpu_data: (0..2)
+                .map(|_| core.create_buffer(vk::BufferUsageFlags::UNIFORM_BUFFER, 1, true))
+                .collect::<eyre::Result<_>>()?,
+            pyramid_proj_view: None,
+            enabled: true,
+        })
+    }
+
+    //adds the reduction and the late cull to the render graph, the late draws are read by "late_gpass"
+    pub fn register(man: &mut RenderPassManager) {
+        man.add_resource("hiz", ResourceKind::Attachment);
+        man.add_resource("late_draws", ResourceKind::Buffer);
+        man.add_pass(
+            PassDesc::graphics("hiz_build").reads(&["gbuffer.depth"]).writes(&["hiz"]),
+            PassExec::Record(Box::new(record_hiz)),
+        );
+        man.add_pass(PassDesc::compute("chunk_cull_late").reads(&["hiz"]).writes(&["late_draws"]), PassExec::Compute);
+    }
+
+    //the levels are recreated with undefined contents on resize
+    pub fn invalidate(&mut self) { self.pyramid_proj_view = None; }
+
+    pub fn is_valid(&self) -> bool { self.enabled && self.pyramid_proj_view.is_some() }
+
+    pub fn update_gpu_data(&mut self, frame_index: usize, proj_view: Mat4, depth_size: (u32, u32)) {
+        let early_proj_view = self.pyramid_proj_view.unwrap_or(proj_view);
+        self.gpu_data[frame_index].get_data_mut().unwrap()[0] = HiZGpuData {
+            early_proj_view: early_proj_view.to_cols_array_2d(),
+            late_proj_view: proj_view.to_cols_array_2d(),
+            depth_size: [depth_size.0, depth_size.1],
+            valid: self.is_valid() as u32,
+            _padding: 0,
+        };
+    }
+
+    //set 1 of the cull shader, the data buffer followed by every level
+    pub fn cull_set(
+        &self,
+        layout: vk::DescriptorSetLayout,
+        man: &RenderPassManager,
+        descriptor_cache: &mut DescriptorCache,
+        frame_index: usize,
+    ) -> eyre::Result<vk::DescriptorSet> {
+        let sampler = *self.sampler;
+        let gpu_data = &self.gpu_data[frame_index];
+        let key = HIZ_LEVEL_NAMES
+            .iter()
+            .fold(DescriptorKey::new(layout).buffer(gpu_data.inner()), |key, name| key.attachment(name, "color", sampler));
+
+        descriptor_cache.get_or_build(key, |pool| {
+            let mut builder = DescriptorSetBuilder::new().add_ubo(&[gpu_data]);
+            for name in HIZ_LEVEL_NAMES {
+                let level = man.get_renderpass_ref::<HiZLevel>(name).unwrap();
+                builder = builder.add_sampled_image(level.renderpass.get_attachment(level.color), sampler);
+            }
+            Ok(builder.build(layout, pool)?)
+        })
+    }
+
+    fn record_level(
+        &self,
+        cmd: &mut CommandBuffer,
+        man: &RenderPassManager,
+        descriptor_cache: &mut DescriptorCache,
+        level: usize,
+    ) -> eyre::Result<()> {
+        let sampler = *self.sampler;
+        let deferred = man.get_renderpass_ref::<DeferedPass>("deferred_render").unwrap();
+
+        let (key, src, src_size) = match level {
+            0 => (
+                DescriptorKey::new(self.set_layout).attachment("deferred_render", "depth", sampler),
+                deferred.renderpass.get_attachment(deferred.depth),
+                deferred.renderpass.extends(),
+            ),
+            _ => {
+                let src = man.get_renderpass_ref::<HiZLevel>(HIZ_LEVEL_NAMES[level - 1]).unwrap();
+                (
+                    DescriptorKey::new(self.set_layout).attachment(HIZ_LEVEL_NAMES[level - 1], "color", sampler),
+                    src.renderpass.get_attachment(src.color),
+                    src.renderpass.extends(),
+                )
+            }
+        };
+        let dset = descriptor_cache.get_or_build(key, |pool| {
+            Ok(DescriptorSetBuilder::new().add_sampled_image(src, sampler).build(self.set_layout, pool)?)
+        })?;
+
+        let (width, height) = man.get_renderpass_ref::<HiZLevel>(HIZ_LEVEL_NAMES[level]).unwrap().renderpass.extends();
+        let viewport = vk::Viewport { x: 0.0, y: 0.0, width: width as f32, height: height as f32, min_depth: 0.0, max_depth: 1.0 };
+        let scissor = vk::Rect2D { offset: vk::Offset2D { x: 0, y: 0 }, extent: vk::Extent2D { width, height } };
+
+        cmd.bind_pipeline(&self.pipeline);
+        cmd.bind_descriptor_set(0, dset);
+        cmd.push_constant(
+            &ReducePush {
+                src_size: [src_size.0 as i32, src_size.1 as i32],
+                dst_size: [width as i32, height as i32],
+            },
+            vk::ShaderStageFlags::FRAGMENT,
+            0,
+        );
+        unsafe {
+            cmd.device().cmd_set_viewport(cmd.inner(), 0, &[viewport]);
+            cmd.device().cmd_set_scissor(cmd.inner(), 0, &[scissor]);
+            cmd.draw(3, 1, 0, 0);
+        }
+
+        Ok(())
+    }
+}
+
+//reduces the depth of this frame level by level, every level samples the one written before it
+fn record_hiz(cmd: &mut CommandBuffer, ctx: &PassContext) -> eyre::Result<()> {
+    let mut hiz = ctx.game.world.fetch_mut::<HiZ>();
+    if !hiz.enabled {
+        hiz.invalidate();
+        return Ok(());
+    }
+
+    let man = ctx.manager;
+    let globals = ctx.game.world.fetch::<RenderGlobals>();
+    let mut descriptor_cache = globals.descriptor_cache();
+
+    for (level, name) in HIZ_LEVEL_NAMES.iter().enumerate() {
+        if level > 0 {
+            let barrier = vk::MemoryBarrier::builder()
+                .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                .build();
+            unsafe {
+                cmd.pipeline_barrier(
+                    vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                    vk::PipelineStageFlags::FRAGMENT_SHADER,
+                    vk::DependencyFlags::empty(),
+                    &[barrier],
+                    &[],
+                    &[],
+                );
+            }
+        }
+
+        let subpass = man.get_subpass(name).unwrap();
+        let mut level_cmd = subpass.new_cmd()?;
+        hiz.record_level(&mut level_cmd, man, &mut descriptor_cache, level)?;
+        subpass.submit_cmd(level_cmd)?;
+        man.execute_renderpass(cmd, name);
+    }
+
+    //the early cull of the next frames reads the pyramid, the late cull of this frame waits through the graph
+    let barrier = vk::MemoryBarrier::builder()
+        .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+        .dst_access_mask(vk::AccessFlags::SHADER_READ)
+        .build();
+    unsafe {
+        cmd.pipeline_barrier(
+            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::DependencyFlags::empty(),
+            &[barrier],
+            &[],
+            &[],
+        );
+    }
+
+    hiz.pyramid_proj_view = Some(ctx.game.world.fetch::<CameraData>().proj_view);
+    Ok(())
+}
+
+pub fn init(game: &mut Game, man: &mut RenderPassManager) -> eyre::Result<()> {
+    let hiz = HiZ::new(&game.core()?, man)?;
+    HiZ::register(man);
+    game.world.insert(hiz);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proj_view() -> Mat4 { Mat4::perspective_rh(std::f32::consts::FRAC_PI_2, 1.0, 0.1, 100.0) }
+
+    fn ndc_depth(z: f32) -> f32 {
+        let clip = proj_view() * vec4(0.0, 0.0, z, 1.0);
+        clip.z / clip.w
+    }
+
+    //depth of a wall 10 blocks in front of the camera, with a hole in the middle if hole is set
+    fn wall(size: u32, hole: bool) -> DepthLevel {
+        let wall_depth = ndc_depth(-10.0);
+        let in_hole = |c: u32| c >= size / 4 && c < size * 3 / 4;
+        let depth = (0..size * size)
+            .map(|i| if hole && in_hole(i % size) && in_hole(i / size) { 1.0 } else { wall_depth })
+            .collect();
+        DepthLevel::new(size, size, depth)
+    }
+
+    #[test]
+    fn level_extents_halve_down_to_one() {
+        assert_eq!(level_extent((1920, 1080), 0), (960, 540));
+        assert_eq!(level_extent((1920, 1080), 3), (120, 67));
+        assert_eq!(level_extent((5, 3), 0), (2, 1));
+        assert_eq!(level_extent((5, 3), 4), (1, 1));
+    }
+
+    #[test]
+    fn odd_sizes_overlap_the_middle_texel() {
+        assert_eq!(source_range(0, 5, 2), (0, 3));
+        assert_eq!(source_range(1, 5, 2), (2, 5));
+        assert_eq!(source_range(0, 3, 1), (0, 3));
+        //even sizes don't overlap
+        assert_eq!(source_range(0, 4, 2), (0, 2));
+        assert_eq!(source_range(1, 4, 2), (2, 4));
+    }
+
+    #[test]
+    fn reduce_keeps_the_furthest_depth() {
+        #[rustfmt::skip]
+        let depth = DepthLevel::new(5, 3, vec![
+            0.1, 0.9, 0.2, 0.3, 0.4,
+            0.1, 0.1, 0.6, 0.1, 0.5,
+            0.2, 0.1, 0.1, 0.7, 0.1,
+        ]);
+
+        let reduced = depth.reduce(2, 1);
+        assert_eq!(reduced, DepthLevel::new(2, 1, vec![0.9, 0.7]));
+
+        //the middle column is covered by both texels
+        let mut middle = DepthLevel::new(5, 3, vec![0.0; 15]);
+        middle.depth[2 + 5] = 0.8;
+        assert_eq!(middle.reduce(2, 1).depth, vec![0.8, 0.8]);
+
+        let pyramid = HiZPyramid::from_depth(&depth, 3);
+        let extents: Vec<_> = pyramid.levels.iter().map(|l| (l.width, l.height)).collect();
+        assert_eq!(extents, vec![(2, 1), (1, 1), (1, 1)]);
+        assert_eq!(pyramid.levels[2].depth, vec![0.9]);
+    }
+
+    #[test]
+    fn select_level_covers_the_rect_with_two_texels() {
+        let pyramid = HiZPyramid::from_depth(&wall(64, false), HIZ_LEVELS);
+        let rect = |size: f32| ScreenRect { min: Vec2::splat(0.25), max: Vec2::splat(0.25 + size), depth: 0.5 };
+
+        //level 0 is 32x32
+        assert_eq!(pyramid.select_level(&rect(0.0)), Some(0));
+        assert_eq!(pyramid.select_level(&rect(1.0 / 32.0)), Some(0));
+        assert_eq!(pyramid.select_level(&rect(1.5 / 32.0)), Some(1));
+        assert_eq!(pyramid.select_level(&rect(0.25)), Some(3));
+        assert_eq!(pyramid.select_level(&rect(0.75)), Some(5));
+
+        //rects needing a level past the last one are never tested
+        let shallow = HiZPyramid::from_depth(&wall(64, false), 2);
+        assert_eq!(shallow.select_level(&rect(0.25)), None);
+    }
+
+    #[test]
+    fn project_aabb_bounds_the_box() {
+        let rect = project_aabb(proj_view(), vec3(-1.0, -1.0, -21.0), vec3(1.0, 1.0, -20.0)).unwrap();
+        //the near face is 20 blocks away with a 90 degree fov
+        assert!((rect.min - Vec2::splat(0.475)).abs().max_element() < 1e-4);
+        assert!((rect.max - Vec2::splat(0.525)).abs().max_element() < 1e-4);
+        assert!((rect.depth - ndc_depth(-20.0)).abs() < 1e-6);
+
+        //boxes partly outside the screen are clamped to it
+        let rect = project_aabb(proj_view(), vec3(5.0, -1.0, -6.0), vec3(50.0, 1.0, -5.0)).unwrap();
+        assert_eq!(rect.max.x, 1.0);
+    }
+
+    #[test]
+    fn box_behind_a_wall_is_occluded() {
+        let pyramid = HiZPyramid::from_depth(&wall(64, false), HIZ_LEVELS);
+        assert!(pyramid.is_occluded(proj_view(), vec3(-1.0, -1.0, -21.0), vec3(1.0, 1.0, -20.0)));
+    }
+
+    #[test]
+    fn box_in_front_of_a_wall_is_visible() {
+        let pyramid = HiZPyramid::from_depth(&wall(64, false), HIZ_LEVELS);
+        assert!(!pyramid.is_occluded(proj_view(), vec3(-1.0, -1.0, -6.0), vec3(1.0, 1.0, -5.0)));
+    }
+
+    #[test]
+    fn box_seen_through_a_hole_is_visible() {
+        let pyramid = HiZPyramid::from_depth(&wall(64, true), HIZ_LEVELS);
+        assert!(!pyramid.is_occluded(proj_view(), vec3(-1.0, -1.0, -21.0), vec3(1.0, 1.0, -20.0)));
+        //off to the side it is still behind the wall
+        assert!(pyramid.is_occluded(proj_view(), vec3(14.0, 14.0, -21.0), vec3(16.0, 16.0, -20.0)));
+    }
+
+    #[test]
+    fn boxes_reaching_behind_the_camera_are_never_occluded() {
+        let pyramid = HiZPyramid::from_depth(&wall(64, false), HIZ_LEVELS);
+        assert_eq!(project_aabb(proj_view(), vec3(-1.0, -1.0, -1.0), vec3(1.0, 1.0, 1.0)), None);
+        assert!(!pyramid.is_occluded(proj_view(), vec3(-1.0, -1.0, -1.0), vec3(1.0, 1.0, 1.0)));
+        assert!(!pyramid.is_occluded(proj_view(), vec3(-1.0, -1.0, 5.0), vec3(1.0, 1.0, 6.0)));
+    }
+
+    #[test]
+    fn large_boxes_are_never_occluded() {
+        //needs level 4 of the 32x32 base level
+        let pyramid = HiZPyramid::from_depth(&wall(64, false), 2);
+        assert!(!pyramid.is_occluded(proj_view(), vec3(-10.0, -10.0, -21.0), vec3(10.0, 10.0, -20.0)));
+    }
+}
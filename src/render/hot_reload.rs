@@ -0,0 +1,358 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, Instant, SystemTime},
+};
+
+use magma_renderer::core::*;
+use magma_renderer::engine::material::{MaterialID, MaterialManager};
+use specs::prelude::*;
+
+use crate::game::RenderGlobals;
+
+//remembers the modification time of files and reports the ones that changed since the last poll
+#[derive(Default)]
+pub struct FileWatcher {
+    files: HashMap<PathBuf, Option<SystemTime>>,
+}
+
+impl FileWatcher {
+    pub fn new() -> FileWatcher { Self::default() }
+
+    pub fn watch(&mut self, path: impl AsRef<Path>) {
+        let path = path.as_ref().to_path_buf();
+        let modified = modified_time(&path);
+        self.files.entry(path).or_insert(modified);
+    }
+
+    pub fn is_watched(&self, path: &Path) -> bool { self.files.contains_key(path) }
+
+    //files that are missing are skipped until they appear again, editors may replace files while saving
+    pub fn poll(&mut self) -> Vec<PathBuf> {
+        let mut changed = vec![];
+        for (path, last_modified) in &mut self.files {
+            let Some(modified) = modified_time(path) else { continue };
+            if *last_modified != Some(modified) {
+                *last_modified = Some(modified);
+                changed.push(path.clone());
+            }
+        }
+        changed.sort();
+        changed
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> { std::fs::metadata(path).and_then(|m| m.modified()).ok() }
+
+/*
+    Shader paths listed under "shaders:" in a material yaml, either as a block list or a flow list
+    like "shaders: [a.vert, b.frag]". The key may be indented, block items belong to it as long as
+    they aren't indented less than the key.
+*/
+pub fn material_shaders(yaml: &str) -> Vec<String> {
+    let unquote = |s: &str| s.trim().trim_matches(|c| c == '"' || c == '\'').to_string();
+    let mut shaders = vec![];
+    //indentation of the "shaders:" key while its block list is read
+    let mut block_indent: Option<usize> = None;
+
+    for line in yaml.lines() {
+        let line = line.split(" #").next().unwrap();
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let indent = line.len() - line.trim_start().len();
+
+        if let Some(key_indent) = block_indent {
+            match trimmed.strip_prefix('-') {
+                Some(item) if indent >= key_indent => {
+                    shaders.push(unquote(item));
+                    continue;
+                }
+                _ => block_indent = None,
+            }
+        }
+
+        if let Some(value) = trimmed.strip_prefix("shaders:").map(str::trim) {
+            if value.is_empty() {
+                block_indent = Some(indent);
+            } else if let Some(list) = value.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+                shaders.extend(list.split(',').map(unquote).filter(|s| !s.is_empty()));
+            }
+        }
+    }
+
+    shaders
+}
+
+/*
+    Shaders that are baked in with include_glsl! are compiled with glslc from the vulkan sdk when
+    they are reloaded, it has to be in the PATH.
+*/
+pub fn compile_glsl(path: &str) -> eyre::Result<Vec<u32>> {
+    let output = Command::new("glslc")
+        .args([path, "-o", "-"])
+        .output()
+        .map_err(|e| eyre::eyre!("couldn't run glslc: {e}"))?;
+
+    if !output.status.success() {
+        return Err(eyre::eyre!("{}", String::from_utf8_lossy(&output.stderr).trim_end()));
+    }
+    if output.stdout.len() % 4 != 0 {
+        return Err(eyre::eyre!("glslc output for \"{path}\" is not spir-v"));
+    }
+
+    Ok(output.stdout.chunks_exact(4).map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]])).collect())
+}
+
+struct WatchedMaterial {
+    id: MaterialID,
+    shaders: Vec<String>,
+}
+
+/*
+    Watches shader and material files. Materials loaded through it are reloaded from their yaml
+    when the yaml or one of its shaders changes, other systems look at changed_files and
+    remapped_materials in Stage::Input after "hot reload" to swap their own pipelines. A failed
+    reload keeps the old pipeline and logs the error.
+*/
+pub struct HotReload {
+    watcher: FileWatcher,
+    materials: HashMap<String, WatchedMaterial>,
+    changed_files: Vec<PathBuf>,
+    remapped_materials: Vec<(MaterialID, MaterialID)>,
+    last_poll: Instant,
+    pub poll_interval: Duration,
+    pub enabled: bool,
+}
+
+impl HotReload {
+    pub fn new() -> HotReload {
+        Self {
+            watcher: FileWatcher::new(),
+            materials: HashMap::new(),
+            changed_files: vec![],
+            remapped_materials: vec![],
+            last_poll: Instant::now(),
+            poll_interval: Duration::from_millis(500),
+            enabled: true,
+        }
+    }
+
+    pub fn watch(&mut self, path: &str) { self.watcher.watch(path); }
+
+    //loads the material and reloads it whenever its yaml or shaders change
+    pub fn load_material(
+        &mut self,
+        mat_man: &mut MaterialManager,
+        cmd: &mut CommandBuffer,
+        path: &str,
+    ) -> eyre::Result<MaterialID> {
+        if let Some(material) = self.materials.get(path) {
+            return Ok(material.id);
+        }
+
+        let id = mat_man.load_material(cmd, path.into())?;
+        let shaders = std::fs::read_to_string(path).map(|yaml| material_shaders(&yaml)).unwrap_or_default();
+
+        self.watcher.watch(path);
+        for shader in &shaders {
+            self.watcher.watch(shader);
+        }
+        self.materials.insert(path.to_string(), WatchedMaterial { id, shaders });
+
+        Ok(id)
+    }
+
+    //files that changed this frame
+    pub fn changed_files(&self) -> &[PathBuf] { &self.changed_files }
+    pub fn is_changed(&self, path: &str) -> bool { self.changed_files.iter().any(|p| p == Path::new(path)) }
+
+    //(old, new) ids of the materials reloaded this frame
+    pub fn remapped_materials(&self) -> &[(MaterialID, MaterialID)] { &self.remapped_materials }
+
+    pub fn remap(&self, id: MaterialID) -> MaterialID {
+        self.remapped_materials.iter().find(|(old, _)| *old == id).map_or(id, |(_, new)| *new)
+    }
+
+    /*
+        The systems after "hot reload" remapped their ids in the frame the materials were replaced
+        and the frames recorded before were waited for, so the old materials are unused by now.
+    */
+    fn release_replaced_materials(&mut self, mat_man: &mut MaterialManager) {
+        for (old, _) in self.remapped_materials.drain(..) {
+            mat_man.remove_material(old);
+        }
+    }
+
+    fn poll(&mut self) -> bool {
+        self.changed_files.clear();
+        self.remapped_materials.clear();
+
+        if !self.enabled || self.last_poll.elapsed() < self.poll_interval {
+            return false;
+        }
+        self.last_poll = Instant::now();
+
+        self.changed_files = self.watcher.poll();
+        !self.changed_files.is_empty()
+    }
+
+    fn reload_materials(&mut self, mat_man: &mut MaterialManager, core: &std::sync::Arc<Core>) -> eyre::Result<()> {
+        let changed: Vec<String> = self
+            .materials
+            .iter()
+            .filter(|(path, m)| self.is_changed(path) || m.shaders.iter().any(|s| self.is_changed(s)))
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        if changed.is_empty() {
+            return Ok(());
+        }
+
+        let mut cmd = core.new_cmd();
+        cmd.begin()?;
+
+        for path in changed {
+            match mat_man.load_material(&mut cmd, path.clone().into()) {
+                Ok(id) => {
+                    eprintln!("reloaded material \"{path}\"");
+                    let material = self.materials.get_mut(&path).unwrap();
+                    self.remapped_materials.push((material.id, id));
+                    material.id = id;
+                    //shaders may have been added to or removed from the yaml
+                    if let Ok(yaml) = std::fs::read_to_string(&path) {
+                        material.shaders = material_shaders(&yaml);
+                        for shader in &material.shaders {
+                            self.watcher.watch(shader);
+                        }
+                    }
+                }
+                Err(e) => eprintln!("failed to reload material \"{path}\", keeping the old one: {e}"),
+            }
+        }
+
+        cmd.end()?;
+        cmd.immediate_submit()?;
+
+        Ok(())
+    }
+}
+
+//polls the watched files, waits for the gpu and reloads the changed materials
+pub struct WatchShaders;
+
+impl<'a> System<'a> for WatchShaders {
+    type SystemData = (WriteExpect<'a, HotReload>, WriteExpect<'a, MaterialManager>, ReadExpect<'a, RenderGlobals>);
+
+    fn run(&mut self, (mut hot_reload, mut mat_man, globals): Self::SystemData) {
+        hot_reload.release_replaced_materials(&mut mat_man);
+        if !hot_reload.poll() {
+            return;
+        }
+
+        //pipelines of the frames in flight are replaced below and in the systems after this one
+        unsafe {
+            globals.core().device().device_wait_idle().unwrap();
+        }
+
+        if let Err(e) = hot_reload.reload_materials(&mut mat_man, globals.core()) {
+            eprintln!("failed to reload materials: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+
+    #[test]
+    fn block_list_shaders() {
+        let yaml = std::fs::read_to_string("res/chunk.mat.yaml").unwrap();
+        assert_eq!(material_shaders(&yaml), vec!["res/chunk2.frag", "res/chunk2.vert"]);
+
+        //items at the indentation of the key, quotes and comments
+        let yaml = "textures:\n- a.png\nshaders: # compiled at load\n- \"a.vert\"\n- 'a.frag' # lit\nsubpass: gpass\n";
+        assert_eq!(material_shaders(yaml), vec!["a.vert", "a.frag"]);
+    }
+
+    #[test]
+    fn flow_list_shaders() {
+        assert_eq!(material_shaders("shaders: [a.vert, \"a.frag\"]\nsubpass: gpass\n"), vec!["a.vert", "a.frag"]);
+        assert_eq!(material_shaders("shaders: []\n"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn indented_shaders_key() {
+        let yaml = "material:\n  textures:\n    - a.png\n  shaders:\n    - a.vert\n    - a.frag\n  subpass: gpass\n";
+        assert_eq!(material_shaders(yaml), vec!["a.vert", "a.frag"]);
+
+        //a less indented item belongs to the parent, not to the shaders
+        let yaml = "material:\n  shaders:\n    - a.vert\n- other\n";
+        assert_eq!(material_shaders(yaml), vec!["a.vert"]);
+    }
+
+    #[test]
+    fn lists_of_other_keys_are_ignored() {
+        assert_eq!(material_shaders("textures:\n  - a.png\nmy_shaders:\n  - b.vert\n"), Vec::<String>::new());
+    }
+
+    fn touch(path: &Path, secs: u64) {
+        let file = File::options().create(true).append(true).open(path).unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)).unwrap();
+    }
+
+    #[test]
+    fn watcher_reports_modified_files_once() {
+        let dir = std::env::temp_dir().join(format!("file_watcher_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.frag"), dir.join("b.frag"));
+        touch(&a, 100);
+        touch(&b, 100);
+
+        let mut watcher = FileWatcher::new();
+        watcher.watch(&b);
+        watcher.watch(&a);
+        assert!(watcher.is_watched(&a));
+        assert!(watcher.poll().is_empty());
+
+        touch(&b, 200);
+        touch(&a, 200);
+        assert_eq!(watcher.poll(), vec![a.clone(), b.clone()]);
+        assert!(watcher.poll().is_empty());
+
+        //going back in time is a change too, editors may restore backups
+        touch(&a, 150);
+        assert_eq!(watcher.poll(), vec![a.clone()]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn watcher_skips_missing_files_until_they_appear() {
+        let dir = std::env::temp_dir().join(format!("file_watcher_missing_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.frag");
+
+        let mut watcher = FileWatcher::new();
+        watcher.watch(&path);
+        assert!(watcher.poll().is_empty());
+
+        touch(&path, 100);
+        assert_eq!(watcher.poll(), vec![path.clone()]);
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(watcher.poll().is_empty());
+        touch(&path, 100);
+        assert!(watcher.poll().is_empty());
+        touch(&path, 300);
+        assert_eq!(watcher.poll(), vec![path.clone()]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
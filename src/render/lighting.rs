@@ -0,0 +1,254 @@
+use bytemuck::{Pod, Zeroable};
+use glam::*;
+use specs::prelude::*;
+
+use crate::game::time::WorldTime;
+
+use super::shadows::MAX_CASCADES;
+
+pub const MAX_POINT_LIGHTS: usize = 256;
+
+pub struct Light {
+    pub color: Vec3,
+    pub intensity: f32,
+    pub radius: f32,
+}
+
+impl Component for Light {
+    type Storage = DenseVecStorage<Self>;
+}
+
+pub struct LightingSettings {
+    //direction pointing towards the sun
+    pub sun_direction: Vec3,
+    pub sun_color: Vec3,
+    //hemispheric ambient, normals facing up get the sky colour and facing down get the ground colour
+    pub sky_ambient: Vec3,
+    pub ground_ambient: Vec3,
+    //sky gradient drawn behind the world, the horizon colour is also used for fog
+    pub sky_zenith: Vec3,
+    pub sky_horizon: Vec3,
+}
+
+impl Default for LightingSettings {
+    fn default() -> Self {
+        Self {
+            sun_direction: vec3(0.3, 1.0, 0.2).normalize(),
+            sun_color: vec3(1.0, 0.95, 0.85),
+            sky_ambient: vec3(0.35, 0.4, 0.5),
+            ground_ambient: vec3(0.15, 0.13, 0.1),
+            sky_zenith: vec3(0.25, 0.45, 0.85),
+            sky_horizon: vec3(0.7, 0.8, 0.9),
+        }
+    }
+}
+
+impl LightingSettings {
+    pub fn from_world_time(time: &WorldTime) -> Self {
+        let day = LightingSettings::default();
+        let daylight = time.daylight();
+
+        //the sun turns orange close to the horizon
+        let sunset = 1.0 - (time.sun_direction().y.abs() / 0.3).clamp(0.0, 1.0);
+        let sun_color = day.sun_color.lerp(vec3(1.0, 0.5, 0.25), sunset) * daylight;
+        let sky_horizon = vec3(0.02, 0.03, 0.06).lerp(day.sky_horizon.lerp(vec3(0.9, 0.5, 0.3), sunset), daylight);
+
+        Self {
+            sun_direction: time.sun_direction(),
+            sun_color,
+            sky_ambient: vec3(0.04, 0.05, 0.08).lerp(day.sky_ambient, daylight),
+            ground_ambient: vec3(0.02, 0.02, 0.02).lerp(day.ground_ambient, daylight),
+            sky_zenith: vec3(0.0, 0.0, 0.02).lerp(day.sky_zenith, daylight),
+            sky_horizon,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FogMode {
+    Off,
+    Linear,
+    Exponential,
+}
+
+/*
+    Fog is blended towards the sky colour. It ends at the view distance so chunks at the edge
+    of the loaded area fade out instead of popping, start is given as a fraction of that distance.
+*/
+#[derive(Clone, Copy, Debug)]
+pub struct FogSettings {
+    pub mode: FogMode,
+    pub start: f32,
+    //exponential fog reaches 1 - e^-density at the view distance
+    pub density: f32,
+}
+
+impl Default for FogSettings {
+    fn default() -> Self { Self { mode: FogMode::Linear, start: 0.6, density: 4.0 } }
+}
+
+impl FogSettings {
+    //(mode, start distance, end distance, density)
+    pub fn to_gpu(&self, view_distance: f32) -> [f32; 4] {
+        let mode = match self.mode {
+            FogMode::Off => 0.0,
+            FogMode::Linear => 1.0,
+            FogMode::Exponential => 2.0,
+        };
+        [mode, self.start * view_distance, view_distance, self.density]
+    }
+}
+
+//keeps the lighting in sync with the time of day
+pub struct UpdateLighting;
+
+impl<'a> System<'a> for UpdateLighting {
+    type SystemData = (ReadExpect<'a, WorldTime>, WriteExpect<'a, LightingSettings>);
+
+    fn run(&mut self, (time, mut settings): Self::SystemData) { *settings = LightingSettings::from_world_time(&time); }
+}
+
+//matches LightingData in final.frag, std140
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug)]
+pub struct GpuLightingData {
+    pub inv_proj_view: [[f32; 4]; 4],
+    pub camera_pos: [f32; 4],
+    pub sun_direction: [f32; 4],
+    pub sun_color: [f32; 4],
+    pub sky_ambient: [f32; 4],
+    pub ground_ambient: [f32; 4],
+    pub sky_zenith: [f32; 4],
+    pub sky_horizon: [f32; 4],
+    pub fog: [f32; 4],
+    //point light count, shadow cascade count
+    pub point_light_count: [u32; 4],
+    pub shadow_matrices: [[[f32; 4]; 4]; MAX_CASCADES],
+    pub cascade_splits: [f32; MAX_CASCADES],
+}
+
+//matches PointLight in final.frag, std430
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug, PartialEq)]
+pub struct GpuPointLight {
+    pub position_radius: [f32; 4],
+    pub color_intensity: [f32; 4],
+}
+
+//packs the lighting data for the gpu, if there are more than max_lights lights the closest ones to the camera are kept
+pub fn pack_lights<'a>(
+    settings: &LightingSettings,
+    proj_view: Mat4,
+    camera_pos: Vec3,
+    lights: impl Iterator<Item = (Vec3, &'a Light)>,
+    max_lights: usize,
+) -> (GpuLightingData, Vec<GpuPointLight>) {
+    let mut sorted_lights: Vec<_> = lights
+        .map(|(pos, light)| {
+            let gpu_light = GpuPointLight {
+                position_radius: pos.extend(light.radius).to_array(),
+                color_intensity: light.color.extend(light.intensity).to_array(),
+            };
+            (pos.distance_squared(camera_pos), gpu_light)
+        })
+        .collect();
+
+    sorted_lights.sort_by(|a, b| a.0.total_cmp(&b.0));
+    sorted_lights.truncate(max_lights);
+
+    let point_lights: Vec<_> = sorted_lights.into_iter().map(|(_, l)| l).collect();
+
+    let data = GpuLightingData {
+        inv_proj_view: proj_view.inverse().to_cols_array_2d(),
+        camera_pos: camera_pos.extend(1.0).to_array(),
+        sun_direction: settings.sun_direction.normalize_or_zero().extend(0.0).to_array(),
+        sun_color: settings.sun_color.extend(1.0).to_array(),
+        sky_ambient: settings.sky_ambient.extend(1.0).to_array(),
+        ground_ambient: settings.ground_ambient.extend(1.0).to_array(),
+        sky_zenith: settings.sky_zenith.extend(1.0).to_array(),
+        sky_horizon: settings.sky_horizon.extend(1.0).to_array(),
+        fog: [0.0; 4],
+        point_light_count: [point_lights.len() as u32, 0, 0, 0],
+        shadow_matrices: [Mat4::IDENTITY.to_cols_array_2d(); MAX_CASCADES],
+        cascade_splits: [0.0; MAX_CASCADES],
+    };
+
+    (data, point_lights)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn light(intensity: f32) -> Light { Light { color: Vec3::ONE, intensity, radius: 8.0 } }
+
+    fn pack(lights: &[(Vec3, Light)], max_lights: usize) -> (GpuLightingData, Vec<GpuPointLight>) {
+        let settings = LightingSettings::default();
+        pack_lights(&settings, Mat4::IDENTITY, Vec3::ZERO, lights.iter().map(|(p, l)| (*p, l)), max_lights)
+    }
+
+    #[test]
+    fn lights_are_sorted_by_camera_distance() {
+        let lights = [(vec3(0.0, 0.0, 9.0), light(1.0)), (vec3(1.0, 0.0, 0.0), light(2.0)), (vec3(-4.0, 0.0, 0.0), light(3.0))];
+        let (data, packed) = pack(&lights, MAX_POINT_LIGHTS);
+
+        assert_eq!(data.point_light_count[0], 3);
+        assert_eq!(packed.iter().map(|l| l.color_intensity[3]).collect::<Vec<_>>(), vec![2.0, 3.0, 1.0]);
+        assert_eq!(packed[0].position_radius, [1.0, 0.0, 0.0, 8.0]);
+    }
+
+    #[test]
+    fn lights_beyond_max_are_dropped_furthest_first() {
+        let lights: Vec<_> = (0..MAX_POINT_LIGHTS + 10).rev().map(|i| (vec3(i as f32, 0.0, 0.0), light(i as f32))).collect();
+        let (data, packed) = pack(&lights, MAX_POINT_LIGHTS);
+
+        assert_eq!(packed.len(), MAX_POINT_LIGHTS);
+        assert_eq!(data.point_light_count[0], MAX_POINT_LIGHTS as u32);
+        assert!(packed.iter().enumerate().all(|(i, l)| l.color_intensity[3] == i as f32));
+
+        let (_, few) = pack(&lights, 2);
+        assert_eq!(few.len(), 2);
+    }
+
+    #[test]
+    fn no_lights() {
+        let (data, packed) = pack(&[], MAX_POINT_LIGHTS);
+        assert!(packed.is_empty());
+        assert_eq!(data.point_light_count, [0; 4]);
+        assert_eq!(data.inv_proj_view, Mat4::IDENTITY.to_cols_array_2d());
+    }
+
+    #[test]
+    fn fog_ends_at_view_distance() {
+        //final.frag fades from start to end, exponential fog reaches 1 - e^-density at the end
+        let linear = FogSettings { mode: FogMode::Linear, start: 0.5, density: 4.0 };
+        assert_eq!(linear.to_gpu(320.0), [1.0, 160.0, 320.0, 4.0]);
+
+        let exponential = FogSettings { mode: FogMode::Exponential, start: 0.0, density: 2.5 };
+        assert_eq!(exponential.to_gpu(200.0), [2.0, 0.0, 200.0, 2.5]);
+
+        let off = FogSettings { mode: FogMode::Off, ..Default::default() };
+        assert_eq!(off.to_gpu(320.0)[0], 0.0);
+    }
+
+    #[test]
+    fn gpu_layouts_match_shader() {
+        //offsets of LightingData in final.frag under std140, every member is 16 byte aligned
+        let data = GpuLightingData::zeroed();
+        let base = &data as *const _ as usize;
+        let offset = |field: *const u8| field as usize - base;
+
+        assert_eq!(offset(data.camera_pos.as_ptr().cast()), 64);
+        assert_eq!(offset(data.sun_direction.as_ptr().cast()), 80);
+        assert_eq!(offset(data.sky_horizon.as_ptr().cast()), 160);
+        assert_eq!(offset(data.fog.as_ptr().cast()), 176);
+        assert_eq!(offset(data.point_light_count.as_ptr().cast()), 192);
+        assert_eq!(offset(data.shadow_matrices.as_ptr().cast()), 208);
+        assert_eq!(offset(data.cascade_splits.as_ptr().cast()), 208 + 64 * MAX_CASCADES);
+        assert_eq!(std::mem::size_of::<GpuLightingData>(), 208 + 64 * MAX_CASCADES + 16);
+
+        //PointLight is two vec4s under std430
+        assert_eq!(std::mem::size_of::<GpuPointLight>(), 32);
+        assert_eq!(std::mem::align_of::<GpuPointLight>(), 4);
+    }
+}
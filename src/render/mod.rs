@@ -1,7 +1,17 @@
 pub mod chunk_render;
 mod cube;
+pub mod descriptor_cache;
+pub mod frustum;
+pub mod hiz;
+pub mod hot_reload;
+pub mod lighting;
+pub mod model;
+pub mod postprocess;
+pub mod render_graph;
 pub mod renderpasses;
 pub mod renderpassmanager;
+pub mod resource_state;
+pub mod shadows;
 
 pub use cube::*;
 use magma_renderer::engine::mesh_manager::MeshManager;
@@ -25,8 +35,10 @@ use magma_renderer::engine::mesh_manager::MeshID;
 use specs::Component;
 use specs::System;
 use specs::VecStorage;
+use specs::WriteStorage;
 
 use crate::game;
+use crate::game::plugin::Plugin;
 use crate::game::CameraData;
 use crate::game::Game;
 use crate::game::Transform;
@@ -102,6 +114,34 @@ impl<'a> System<'a> for Renderer {
     }
 }
 
+//points entities at the reloaded versions of their materials
+pub struct RemapEntityMaterials;
+
+impl<'a> System<'a> for RemapEntityMaterials {
+    type SystemData = (
+        WriteStorage<'a, RenderAble>,
+        WriteStorage<'a, model::RenderModel>,
+        Option<specs::Write<'a, CubePrefab>>,
+        ReadExpect<'a, hot_reload::HotReload>,
+    );
+
+    fn run(&mut self, (mut renderables, mut render_models, cube_prefab, hot_reload): Self::SystemData) {
+        if hot_reload.remapped_materials().is_empty() {
+            return;
+        }
+
+        for renderable in (&mut renderables).join() {
+            renderable.materialid = hot_reload.remap(renderable.materialid);
+        }
+        for render_model in (&mut render_models).join() {
+            render_model.materialid = hot_reload.remap(render_model.materialid);
+        }
+        if let Some(mut cube_prefab) = cube_prefab {
+            cube_prefab.0.materialid = hot_reload.remap(cube_prefab.0.materialid);
+        }
+    }
+}
+
 // fn create_pipeline(core: &Arc<Core>, renderpass: &dyn Renderpass) -> eyre::Result<Arc<Pipeline>> {
 //     let vert_code = ShaderModule::new(core, include_glsl!("res/cube.vert"))?;
 //     let frag_code = ShaderModule::new(core, include_glsl!("res/cube.frag"))?;
@@ -161,22 +201,37 @@ unsafe fn foo(core: &Arc<Core>) {
     // core.device().cmd_draw_indexed_indirect_count(command_buffer, buffer, offset, count_buffer, count_buffer_offset, max_draw_count, stride)
 }
 
-pub fn init_material_system(game: &mut Game) {
+pub struct MaterialPlugin;
+
+impl Plugin for MaterialPlugin {
+    fn name(&self) -> &'static str { "materials" }
+    fn provides(&self) -> &'static [&'static str] { &["material_manager", "mesh_manager", "model_manager"] }
+    fn build(&self, game: &mut Game) -> eyre::Result<()> { init_material_system(game) }
+}
+
+pub fn init_material_system(game: &mut Game) -> eyre::Result<()> {
     use magma_renderer::engine::material::*;
 
-    let core = &game.core;
-    let material_system = MaterialManager::new(core).unwrap();
+    let core = game.core()?;
+    let material_system = MaterialManager::new(&core)?;
 
     let mesh_manager = MeshManager::new();
     game.world.insert(mesh_manager);
 
     game.world.insert(material_system);
 
+    game.world.insert(model::ModelManager::new());
+    game.world.insert(hot_reload::HotReload::new());
+
     game.world.register::<RenderAble>();
+    game.world.register::<model::RenderModel>();
+    game.world.register::<model::EntityMaterial>();
 
-    game.insert_frame_task(Box::new(move |_world, d| {
-        d.add(Renderer {}, "render_meshes", &[]);
-        //
-        // d.add(RenderSys { cube_cube: mesh.clone() }, "render cubes", &[]);
-    }));
+    game.add_system(game::Stage::RenderPrep, Renderer {}, "render_meshes", &[]);
+    game.add_system(game::Stage::RenderPrep, model::ModelRenderer, "render_models", &[]);
+
+    game.add_system(game::Stage::Input, hot_reload::WatchShaders, "hot reload", &[]);
+    game.add_system(game::Stage::Input, RemapEntityMaterials, "remap entity materials", &["hot reload"]);
+
+    Ok(())
 }
@@ -0,0 +1,427 @@
+use std::collections::HashMap;
+
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use glam::*;
+use magma_renderer::core::*;
+use magma_renderer::engine::material::{MaterialID, MaterialManager};
+use specs::prelude::*;
+
+use crate::game::{CameraData, RenderGlobals, Transform};
+
+use super::{descriptor_cache::DescriptorKey, renderpassmanager::RenderPassManager};
+
+//matches Vertex in res/model.vert, uv is packed into the w components to keep the std430 layout tight
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Zeroable, Pod)]
+pub struct ModelVertex {
+    pub pos_u: [f32; 4],
+    pub normal_v: [f32; 4],
+}
+
+impl ModelVertex {
+    pub fn new(pos: Vec3, normal: Vec3, uv: Vec2) -> ModelVertex {
+        Self { pos_u: pos.extend(uv.x).to_array(), normal_v: normal.extend(uv.y).to_array() }
+    }
+
+    pub fn pos(&self) -> Vec3 { Vec4::from(self.pos_u).truncate() }
+    pub fn normal(&self) -> Vec3 { Vec4::from(self.normal_v).truncate() }
+    pub fn uv(&self) -> Vec2 { vec2(self.pos_u[3], self.normal_v[3]) }
+}
+
+//cpu side mesh, triangle list
+#[derive(Clone, Debug, Default)]
+pub struct ModelData {
+    pub vertices: Vec<ModelVertex>,
+    pub indices: Vec<u32>,
+}
+
+impl ModelData {
+    //unit cube from 0 to 1 like the collider of spawned cubes, every face maps the whole uv range
+    pub fn cube() -> ModelData {
+        let faces = [
+            (Vec3::X, Vec3::Z, Vec3::Y),
+            (Vec3::NEG_X, Vec3::NEG_Z, Vec3::Y),
+            (Vec3::Y, Vec3::X, Vec3::Z),
+            (Vec3::NEG_Y, Vec3::X, Vec3::NEG_Z),
+            (Vec3::Z, Vec3::NEG_X, Vec3::Y),
+            (Vec3::NEG_Z, Vec3::X, Vec3::Y),
+        ];
+
+        let mut data = ModelData::default();
+        for (normal, right, up) in faces {
+            let base = data.vertices.len() as u32;
+            let center = Vec3::splat(0.5) + normal * 0.5;
+            for (u, v) in [(0.0, 1.0), (1.0, 1.0), (0.0, 0.0), (1.0, 0.0)] {
+                let pos = center + right * (u - 0.5) + up * (0.5 - v);
+                data.vertices.push(ModelVertex::new(pos, normal, vec2(u, v)));
+            }
+            data.indices.extend_from_slice(&[base, base + 1, base + 2, base + 2, base + 1, base + 3]);
+        }
+        data
+    }
+
+    /*
+        Parses the v, vt, vn and f statements of a wavefront obj, everything else is ignored.
+        Polygons are triangulated as fans and vertices are shared between faces using the same
+        position, uv and normal. Faces without normals get smooth normals from the face normals.
+    */
+    pub fn from_obj(src: &str) -> eyre::Result<ModelData> {
+        let mut positions: Vec<Vec3> = vec![];
+        let mut uvs: Vec<Vec2> = vec![];
+        let mut normals: Vec<Vec3> = vec![];
+
+        let mut data = ModelData::default();
+        let mut vertex_ids: HashMap<(usize, Option<usize>, Option<usize>), u32> = HashMap::new();
+        //vertices without a normal, they accumulate the normals of the faces using them
+        let mut smooth: Vec<bool> = vec![];
+
+        for (line_number, line) in src.lines().enumerate() {
+            let line_number = line_number + 1;
+            let mut words = line.split_whitespace();
+
+            match words.next() {
+                Some("v") => positions.push(Vec3::from_slice(&parse_floats(words, 3, line_number)?)),
+                Some("vt") => {
+                    let uv = parse_floats(words, 2, line_number)?;
+                    uvs.push(vec2(uv[0], 1.0 - uv[1])); //obj uvs start at the bottom left
+                }
+                Some("vn") => normals.push(Vec3::from_slice(&parse_floats(words, 3, line_number)?).normalize_or_zero()),
+                Some("f") => {
+                    let mut polygon = vec![];
+                    for word in words {
+                        let mut parts = word.split('/');
+                        let mut index = |len: usize| -> eyre::Result<Option<usize>> {
+                            match parts.next() {
+                                None | Some("") => Ok(None),
+                                Some(s) => resolve_obj_index(s, len)
+                                    .map(Some)
+                                    .ok_or_else(|| eyre::eyre!("line {line_number}: invalid index \"{s}\"")),
+                            }
+                        };
+
+                        let key = (
+                            index(positions.len())?.ok_or_else(|| eyre::eyre!("line {line_number}: face without position"))?,
+                            index(uvs.len())?,
+                            index(normals.len())?,
+                        );
+
+                        let id = *vertex_ids.entry(key).or_insert_with(|| {
+                            data.vertices.push(ModelVertex::new(
+                                positions[key.0],
+                                key.2.map_or(Vec3::ZERO, |n| normals[n]),
+                                key.1.map_or(Vec2::ZERO, |t| uvs[t]),
+                            ));
+                            smooth.push(key.2.is_none());
+                            (data.vertices.len() - 1) as u32
+                        });
+                        polygon.push(id);
+                    }
+
+                    if polygon.len() < 3 {
+                        return Err(eyre::eyre!("line {line_number}: face with less than 3 vertices"));
+                    }
+
+                    for i in 1..polygon.len() - 1 {
+                        let triangle = [polygon[0], polygon[i], polygon[i + 1]];
+                        data.indices.extend_from_slice(&triangle);
+
+                        let [a, b, c] = triangle.map(|id| data.vertices[id as usize].pos());
+                        //not normalized so bigger faces weigh more
+                        let face_normal = (b - a).cross(c - a);
+                        for id in triangle.into_iter().filter(|id| smooth[*id as usize]) {
+                            let vertex = &mut data.vertices[id as usize];
+                            vertex.normal_v = (vertex.normal() + face_normal).extend(vertex.normal_v[3]).to_array();
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for (vertex, _) in data.vertices.iter_mut().zip(&smooth).filter(|(_, smooth)| **smooth) {
+            vertex.normal_v = vertex.normal().normalize_or_zero().extend(vertex.normal_v[3]).to_array();
+        }
+
+        Ok(data)
+    }
+}
+
+//missing components are filled with zeros, extra ones like the w of positions are dropped
+fn parse_floats<'a>(words: impl Iterator<Item = &'a str>, len: usize, line_number: usize) -> eyre::Result<Vec<f32>> {
+    let mut values = words
+        .map(|w| w.parse::<f32>().map_err(|e| eyre::eyre!("line {line_number}: {e}")))
+        .collect::<eyre::Result<Vec<f32>>>()?;
+
+    if values.is_empty() {
+        return Err(eyre::eyre!("line {line_number}: missing components"));
+    }
+    values.resize(len, 0.0);
+    Ok(values)
+}
+
+//obj indices start from 1, negative indices count back from the last element
+fn resolve_obj_index(s: &str, len: usize) -> Option<usize> {
+    let i: i64 = s.parse().ok()?;
+    let index = if i < 0 { len as i64 + i } else { i - 1 };
+    (0..len as i64).contains(&index).then_some(index as usize)
+}
+
+pub struct Model {
+    vertex_buffer: Buffer<ModelVertex>,
+    index_buffer: Buffer<u32>,
+    index_count: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ModelID(u32);
+
+pub struct ModelManager {
+    models: Vec<Model>,
+    names: HashMap<String, ModelID>,
+}
+
+impl ModelManager {
+    pub fn new() -> ModelManager { Self { models: vec![], names: HashMap::new() } }
+
+    pub fn upload(&mut self, cmd: &mut CommandBuffer, data: &ModelData) -> eyre::Result<ModelID> {
+        if data.indices.is_empty() {
+            return Err(eyre::eyre!("model has no triangles"));
+        }
+
+        self.models.push(Model {
+            vertex_buffer: cmd.gpu_buffer_from_slice(vk::BufferUsageFlags::STORAGE_BUFFER, &data.vertices)?,
+            index_buffer: cmd.gpu_buffer_from_slice(vk::BufferUsageFlags::INDEX_BUFFER, &data.indices)?,
+            index_count: data.indices.len() as u32,
+        });
+        Ok(ModelID(self.models.len() as u32 - 1))
+    }
+
+    //loading the same path twice returns the model loaded first
+    pub fn load_obj(&mut self, cmd: &mut CommandBuffer, path: &str) -> eyre::Result<ModelID> {
+        if let Some(id) = self.names.get(path) {
+            return Ok(*id);
+        }
+
+        let src = std::fs::read_to_string(path).map_err(|e| eyre::eyre!("couldn't read model \"{path}\": {e}"))?;
+        let data = ModelData::from_obj(&src).map_err(|e| eyre::eyre!("couldn't parse model \"{path}\": {e}"))?;
+        let id = self.upload(cmd, &data)?;
+        self.names.insert(path.to_string(), id);
+        Ok(id)
+    }
+
+    pub fn get_model(&self, id: ModelID) -> Option<&Model> { self.models.get(id.0 as usize) }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RenderModel {
+    pub modelid: ModelID,
+    pub materialid: MaterialID,
+}
+
+impl Component for RenderModel {
+    type Storage = VecStorage<Self>;
+}
+
+/*
+    Per entity overrides on top of the material. The colour multiplies the texture and uv_rect
+    (offset xy, scale zw) selects the part of the material's texture the model's uvs map to, so
+    entities sharing a material can use different tiles of an atlas like res/voxel_tilemap.png.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EntityMaterial {
+    pub color: Vec4,
+    pub uv_rect: Vec4,
+}
+
+impl Default for EntityMaterial {
+    fn default() -> Self { Self { color: Vec4::ONE, uv_rect: vec4(0.0, 0.0, 1.0, 1.0) } }
+}
+
+impl EntityMaterial {
+    pub fn with_color(color: Vec4) -> EntityMaterial { Self { color, ..Default::default() } }
+
+    //tile of an atlas with a single row of tiles
+    pub fn atlas_tile(tile: u32, tile_count: u32) -> EntityMaterial {
+        let width = 1.0 / tile_count as f32;
+        Self { uv_rect: vec4(tile as f32 * width, 0.0, width, 1.0), ..Default::default() }
+    }
+}
+
+impl Component for EntityMaterial {
+    type Storage = VecStorage<Self>;
+}
+
+//matches Push in res/model.vert
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Pod)]
+struct ModelPush {
+    model: [[f32; 4]; 4],
+    color: [f32; 4],
+    uv_rect: [f32; 4],
+}
+
+//draws every RenderModel into the gpass, entities are batched by material and model to save rebinds
+pub struct ModelRenderer;
+
+impl<'a> System<'a> for ModelRenderer {
+    type SystemData = (
+        ReadStorage<'a, Transform>,
+        ReadStorage<'a, RenderModel>,
+        ReadStorage<'a, EntityMaterial>,
+        ReadExpect<'a, CameraData>,
+        ReadExpect<'a, RenderGlobals>,
+        ReadExpect<'a, RenderPassManager>,
+        ReadExpect<'a, MaterialManager>,
+        ReadExpect<'a, ModelManager>,
+    );
+
+    fn run(
+        &mut self,
+        (transforms, render_models, entity_materials, cam_data, globals, rp_man, mat_man, model_man): Self::SystemData,
+    ) {
+        let mut batches: HashMap<(MaterialID, ModelID), Vec<ModelPush>> = HashMap::new();
+        for (transform, render_model, entity_material) in (&transforms, &render_models, entity_materials.maybe()).join() {
+            let entity_material = entity_material.copied().unwrap_or_default();
+            batches.entry((render_model.materialid, render_model.modelid)).or_default().push(ModelPush {
+                model: transform.matrix().to_cols_array_2d(),
+                color: entity_material.color.to_array(),
+                uv_rect: entity_material.uv_rect.to_array(),
+            });
+        }
+
+        if batches.is_empty() {
+            return;
+        }
+
+        let Some(gpass) = rp_man.get_subpass("gpass") else {
+            eprintln!("subpass \"gpass\" is not registered, skipping the models");
+            return;
+        };
+        let mut cmd = match gpass.new_cmd() {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                eprintln!("failed to begin the model command buffer, skipping the models: {e}");
+                return;
+            }
+        };
+        let mut descriptor_cache = globals.descriptor_cache();
+
+        for ((materialid, modelid), pushes) in batches {
+            let Some(material) = mat_man.get_material(materialid) else { continue };
+            let Some(model) = model_man.get_model(modelid) else { continue };
+
+            cmd.bind_material(&material);
+            cmd.bind_descriptor_set(0, cam_data.dset);
+
+            let layout = material.pipeline().get_descriptor_set_layout(2).unwrap();
+            let vertex_set = descriptor_cache
+                .get_or_build(DescriptorKey::new(layout).buffer(model.vertex_buffer.inner()), |pool| {
+                    Ok(DescriptorSetBuilder::new().add_ssbo(&[&model.vertex_buffer]).build(layout, pool)?)
+                });
+            let vertex_set = match vertex_set {
+                Ok(set) => set,
+                Err(e) => {
+                    eprintln!("couldn't build the vertex set of model {modelid:?}, skipping it: {e}");
+                    continue;
+                }
+            };
+            cmd.bind_descriptor_set(2, vertex_set);
+            unsafe {
+                cmd.device().cmd_bind_index_buffer(cmd.inner(), model.index_buffer.inner(), 0, vk::IndexType::UINT32);
+            }
+
+            for push in pushes {
+                cmd.push_constant(&push, vk::ShaderStageFlags::VERTEX, 0);
+                unsafe {
+                    cmd.device().cmd_draw_indexed(cmd.inner(), model.index_count, 1, 0, 0, 0);
+                }
+            }
+        }
+
+        if let Err(e) = gpass.submit_cmd(cmd) {
+            eprintln!("failed to submit the model command buffer: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUAD: &str = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n";
+
+    fn positions(data: &ModelData) -> Vec<Vec3> {
+        data.indices.iter().map(|i| data.vertices[*i as usize].pos()).collect()
+    }
+
+    #[test]
+    fn polygons_are_triangulated_as_fans() {
+        let data = ModelData::from_obj(&format!("{QUAD}f 1 2 3 4\n")).unwrap();
+        assert_eq!(data.vertices.len(), 4);
+        assert_eq!(data.indices, vec![0, 1, 2, 0, 2, 3]);
+        assert!(data.vertices.iter().all(|v| v.normal() == Vec3::Z));
+    }
+
+    #[test]
+    fn negative_indices_count_back_from_the_last_vertex() {
+        let absolute = ModelData::from_obj(&format!("{QUAD}f 1 2 3 4\n")).unwrap();
+        let relative = ModelData::from_obj(&format!("{QUAD}f -4 -3 -2 -1\n")).unwrap();
+        assert_eq!(relative.indices, absolute.indices);
+        assert_eq!(relative.vertices, absolute.vertices);
+
+        //relative to the vertices declared before the face, not the whole file
+        let src = "v 0 0 0\nv 1 0 0\nv 0 1 0\nf -3 -2 -1\nv 0 0 5\nv 1 0 5\nv 0 1 5\nf -3 -2 -1\n";
+        let data = ModelData::from_obj(src).unwrap();
+        assert_eq!(data.vertices.len(), 6);
+        assert_eq!(positions(&data)[3..], [vec3(0.0, 0.0, 5.0), vec3(1.0, 0.0, 5.0), vec3(0.0, 1.0, 5.0)]);
+    }
+
+    #[test]
+    fn vertices_are_shared_only_with_the_same_attributes() {
+        let data = ModelData::from_obj(&format!("{QUAD}f 1 2 3\nf 1 3 4\n")).unwrap();
+        assert_eq!(data.vertices.len(), 4);
+        assert_eq!(data.indices, vec![0, 1, 2, 0, 2, 3]);
+
+        //the first corner of the second face uses another uv so it gets its own vertex
+        let src = format!("{QUAD}vt 0.25 0.75\nvt 1 1\nvn 0 0 2\nf 1/1/1 2/1/1 3/1/1\nf 1/2/1 3/1/1 4/1/1\n");
+        let data = ModelData::from_obj(&src).unwrap();
+        assert_eq!(data.vertices.len(), 5);
+        assert_eq!(data.indices, vec![0, 1, 2, 3, 2, 4]);
+        assert_eq!(data.vertices[0].uv(), vec2(0.25, 0.25));
+        assert_eq!(data.vertices[3].uv(), vec2(1.0, 0.0));
+        assert!(data.vertices.iter().all(|v| v.normal() == Vec3::Z));
+    }
+
+    #[test]
+    fn missing_normals_are_smoothed_over_the_faces() {
+        //a floor and a wall triangle of the same size meeting at the first and third vertex
+        let data = ModelData::from_obj("v 0 0 0\nv 0 0 1\nv 1 0 0\nv 0 1 0\nf 1 2 3\nf 1 3 4\n").unwrap();
+        let shared = vec3(0.0, 1.0, 1.0).normalize();
+
+        assert!(data.vertices[0].normal().abs_diff_eq(shared, 1e-6));
+        assert_eq!(data.vertices[1].normal(), Vec3::Y);
+        assert!(data.vertices[2].normal().abs_diff_eq(shared, 1e-6));
+        assert_eq!(data.vertices[3].normal(), Vec3::Z);
+    }
+
+    #[test]
+    fn errors_name_their_line() {
+        let err = |src: &str| ModelData::from_obj(src).unwrap_err().to_string();
+
+        assert_eq!(err(&format!("{QUAD}f 1 2\n")), "line 5: face with less than 3 vertices");
+        assert_eq!(err(&format!("{QUAD}f 1 2 5\n")), "line 5: invalid index \"5\"");
+        assert_eq!(err(&format!("{QUAD}f 0 1 2\n")), "line 5: invalid index \"0\"");
+        assert_eq!(err(&format!("{QUAD}f 1 2 -5\n")), "line 5: invalid index \"-5\"");
+        assert_eq!(err(&format!("{QUAD}f 1 2/1 3\n")), "line 5: invalid index \"1\"");
+        assert_eq!(err(&format!("{QUAD}f 1 /1 3\n")), "line 5: face without position");
+        assert_eq!(err("v 0 0 0\nvt\n"), "line 2: missing components");
+        assert_eq!(err("v 0 x 0\n"), "line 1: invalid float literal");
+    }
+
+    #[test]
+    fn comments_and_other_statements_are_ignored() {
+        let data = ModelData::from_obj(&format!("# quad\no quad\ns off\n\n{QUAD}usemtl none\nf 1 2 3 4\n")).unwrap();
+        assert_eq!(data.indices.len(), 6);
+    }
+}
@@ -0,0 +1,416 @@
+use std::{collections::HashMap, sync::Arc};
+
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use magma_renderer::core::*;
+
+use crate::{
+    game::{CameraData, FrameIndex, Game, RenderGlobals},
+    include_glsl,
+};
+
+use super::{
+    descriptor_cache::{DescriptorCache, DescriptorKey},
+    hot_reload::{compile_glsl, HotReload},
+    render_graph::{PassDesc, ResourceKind},
+    renderpasses::DeferedPass,
+    renderpassmanager::*,
+};
+
+//output name of the node that draws into the swapchain
+pub const SWAPCHAIN: &str = "swapchain";
+//target the lighting resolve draws into, the first input of the chain
+pub const HDR: &str = "hdr";
+pub const HDR_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+//vertex shader of every node, the lighting resolve uses it too
+const SCREEN_QUAD_SHADER: &str = "res/screen_quad.vert";
+
+//offscreen colour target, registered to the RenderPassManager as a renderpass and subpass with the same name
+pub struct PostTarget {
+    pub renderpass: MultiPassRenderPass,
+    pub color: AttachmentIndex,
+}
+
+impl HasRenderPass for PostTarget {
+    fn renderpass(&self) -> &dyn Renderpass { &self.renderpass }
+    fn resize(&mut self, width: u32, height: u32) -> eyre::Result<()> {
+        self.renderpass.resize(width, height)?;
+        Ok(())
+    }
+}
+
+impl PostTarget {
+    pub fn new(core: &Arc<Core>, format: vk::Format, (width, height): (u32, u32)) -> eyre::Result<PostTarget> {
+        let mut builder = RenderPassBuilder::new();
+        let color = builder.add_attachment(format, None, true);
+        builder.add_subpass(&[color], None, &[]);
+
+        Ok(Self { renderpass: builder.build(core, width, height)?, color })
+    }
+
+    pub fn register(self, man: &mut RenderPassManager, name: &'static str) {
+        man.register_renderpass(Box::new(self), name, vec![SubpassAction::Secondry(name)]);
+    }
+}
+
+/*
+    A fullscreen effect. Inputs are sampled at set 0 in the declared order, they name either an
+    earlier output or a G-buffer attachment ("gbuffer.albedo", "gbuffer.normal", "gbuffer.depth").
+    Disabled nodes still run with the enabled push constant set to 0 and pass their first input
+    through, so the nodes after them don't need to know about it. The shader is baked in, shader_path
+    is the source it is compiled from again when it changes.
+*/
+pub struct PostNodeDesc {
+    pub name: &'static str,
+    pub shader: &'static [u32],
+    pub shader_path: &'static str,
+    pub inputs: &'static [&'static str],
+    pub output: &'static str,
+    pub format: vk::Format,
+}
+
+pub struct PostNode {
+    pub name: &'static str,
+    pub shader_path: &'static str,
+    pub inputs: &'static [&'static str],
+    pub output: &'static str,
+    //registered name of the PostTarget the output is drawn into, shared with other outputs of its slot
+    pub target: &'static str,
+    pub enabled: bool,
+    pipeline: Arc<Pipeline>,
+    set_layout: vk::DescriptorSetLayout,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+struct PostPush {
+    enabled: u32,
+}
+
+//matches PostGlobals in the post shaders, set 1
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+struct PostGlobals {
+    proj_view: [[f32; 4]; 4],
+    inv_proj_view: [[f32; 4]; 4],
+}
+
+pub fn default_nodes() -> Vec<PostNodeDesc> {
+    vec![
+        PostNodeDesc {
+            name: "ssao",
+            shader: include_glsl!("res/post/ssao.frag"),
+            shader_path: "res/post/ssao.frag",
+            inputs: &[HDR, "gbuffer.depth", "gbuffer.normal"],
+            output: "hdr_ao",
+            format: HDR_FORMAT,
+        },
+        PostNodeDesc {
+            name: "bloom",
+            shader: include_glsl!("res/post/bloom.frag"),
+            shader_path: "res/post/bloom.frag",
+            inputs: &["hdr_ao"],
+            output: "bloom",
+            format: HDR_FORMAT,
+        },
+        PostNodeDesc {
+            name: "tonemap",
+            shader: include_glsl!("res/post/tonemap.frag"),
+            shader_path: "res/post/tonemap.frag",
+            inputs: &["hdr_ao", "bloom"],
+            output: "tonemapped",
+            format: HDR_FORMAT,
+        },
+        PostNodeDesc {
+            name: "gamma",
+            shader: include_glsl!("res/post/gamma.frag"),
+            shader_path: "res/post/gamma.frag",
+            inputs: &["tonemapped"],
+            output: "ldr",
+            format: vk::Format::R8G8B8A8_UNORM,
+        },
+        PostNodeDesc {
+            name: "fxaa",
+            shader: include_glsl!("res/post/fxaa.frag"),
+            shader_path: "res/post/fxaa.frag",
+            inputs: &["ldr"],
+            output: SWAPCHAIN,
+            format: vk::Format::UNDEFINED,
+        },
+    ]
+}
+
+pub struct PostProcessChain {
+    nodes: Vec<PostNode>,
+    //target every output draws into, outputs whose lifetimes don't overlap share one
+    targets: HashMap<&'static str, &'static str>,
+    globals_layout: vk::DescriptorSetLayout,
+    globals_buffers: Box<[Buffer<PostGlobals>]>,
+    sampler: Handle<vk::Sampler>,
+}
+
+impl PostProcessChain {
+    //creates the output targets of the nodes and adds them to the render graph of the manager
+    pub fn new(
+        core: &Arc<Core>,
+        man: &mut RenderPassManager,
+        swapchain: &dyn Renderpass,
+        descs: Vec<PostNodeDesc>,
+    ) -> eyre::Result<PostProcessChain> {
+        let globals_layout = DescriptorSetLayoutBuilder::new().add_ubo(vk::ShaderStageFlags::FRAGMENT, 1).build(core)?;
+
+        let mut outputs = vec![HDR];
+        let mut declared = Vec::with_capacity(descs.len());
+
+        for (i, desc) in descs.into_iter().enumerate() {
+            for input in desc.inputs {
+                if !input.starts_with("gbuffer.") && !outputs.contains(input) {
+                    return Err(eyre::eyre!("post node \"{}\" reads \"{}\" before it is written", desc.name, input));
+                }
+            }
+
+            let set_layout = desc
+                .inputs
+                .iter()
+                .fold(DescriptorSetLayoutBuilder::new(), |b, _| b.add_sampler(vk::ShaderStageFlags::FRAGMENT, 1))
+                .build(core)?;
+
+            if desc.output != SWAPCHAIN {
+                man.add_transient(desc.output, ResourceKind::Attachment, desc.format);
+            }
+            man.add_pass(
+                PassDesc::graphics(desc.name).reads(desc.inputs).writes(&[desc.output]),
+                PassExec::Record(Box::new(move |cmd, ctx| {
+                    ctx.game.world.fetch::<PostProcessChain>().execute_node(i, cmd, ctx)
+                })),
+            );
+
+            outputs.push(desc.output);
+            declared.push((desc, set_layout));
+        }
+
+        //outputs in the same slot are never alive at once, they draw into the target of the first one
+        let slots = man.compiled_graph()?.transient_slots.clone();
+        let mut slot_targets: HashMap<usize, &'static str> = HashMap::new();
+        let mut targets = HashMap::from([(HDR, HDR)]);
+        let mut nodes = Vec::with_capacity(declared.len());
+
+        for (desc, set_layout) in declared {
+            let vert = include_glsl!("res/screen_quad.vert");
+            let (target, pipeline) = if desc.output == SWAPCHAIN {
+                (SWAPCHAIN, Self::create_pipeline(core, swapchain, vert, desc.shader, set_layout, globals_layout)?)
+            } else {
+                let target = match slot_targets.get(&slots[desc.output]) {
+                    Some(&target) => target,
+                    None => {
+                        PostTarget::new(core, desc.format, swapchain.extends())?.register(man, desc.output);
+                        slot_targets.insert(slots[desc.output], desc.output);
+                        desc.output
+                    }
+                };
+                let rp = &man.get_renderpass_ref::<PostTarget>(target).unwrap().renderpass;
+                (target, Self::create_pipeline(core, rp, vert, desc.shader, set_layout, globals_layout)?)
+            };
+
+            targets.insert(desc.output, target);
+            nodes.push(PostNode {
+                name: desc.name,
+                shader_path: desc.shader_path,
+                inputs: desc.inputs,
+                output: desc.output,
+                target,
+                enabled: true,
+                pipeline,
+                set_layout,
+            });
+        }
+
+        if nodes.last().map(|n| n.output) != Some(SWAPCHAIN) {
+            return Err(eyre::eyre!("the last post node has to output to the swapchain"));
+        }
+
+        Ok(Self {
+            nodes,
+            targets,
+            globals_layout,
+            globals_buffers: (0..2)
+                .map(|_| core.create_buffer(vk::BufferUsageFlags::UNIFORM_BUFFER, 1, true))
+                .collect::<eyre::Result<_>>()?,
+            sampler: core.create_sampler(vk::Filter::LINEAR, None),
+        })
+    }
+
+    fn create_pipeline(
+        core: &Arc<Core>,
+        rp: &dyn Renderpass,
+        vert: &[u32],
+        frag: &[u32],
+        set_layout: vk::DescriptorSetLayout,
+        globals_layout: vk::DescriptorSetLayout,
+    ) -> eyre::Result<Arc<Pipeline>> {
+        let layout = PipelineLayoutBuilder::new()
+            .add_set(set_layout)
+            .add_set(globals_layout)
+            .add_push::<PostPush>(vk::ShaderStageFlags::FRAGMENT, 0)
+            .build(core)?;
+
+        let pipeline = GPipelineBuilder::new()
+            .set_depth_testing(false)
+            .set_rasterization(vk::PolygonMode::FILL, vk::CullModeFlags::NONE)
+            .set_topology(vk::PrimitiveTopology::TRIANGLE_LIST)
+            .set_pipeline_layout(layout)
+            .add_shader_stage(vk::ShaderStageFlags::VERTEX, &ShaderModule::new(core, vert)?.module())
+            .add_shader_stage(vk::ShaderStageFlags::FRAGMENT, &ShaderModule::new(core, frag)?.module())
+            .set_render_target(rp.get_subpasses()[0].get_render_target())
+            .build(core)?;
+
+        Ok(pipeline)
+    }
+
+    pub fn nodes(&self) -> &[PostNode] { &self.nodes }
+
+    //shaders to watch for reload_pipelines
+    pub fn shader_paths(&self) -> impl Iterator<Item = &'static str> + '_ {
+        std::iter::once(SCREEN_QUAD_SHADER).chain(self.nodes.iter().map(|n| n.shader_path))
+    }
+
+    /*
+        Compiles the shaders of the nodes whose fragment shader or the shared vertex shader changed
+        this frame and swaps their pipelines. A failed reload keeps the old pipeline and logs the error.
+    */
+    pub fn reload_pipelines(&mut self, man: &RenderPassManager, swapchain: &dyn Renderpass, hot_reload: &HotReload) {
+        let vert_changed = hot_reload.is_changed(SCREEN_QUAD_SHADER);
+        if !vert_changed && !self.nodes.iter().any(|n| hot_reload.is_changed(n.shader_path)) {
+            return;
+        }
+
+        let globals_layout = self.globals_layout;
+        for node in &mut self.nodes {
+            if !vert_changed && !hot_reload.is_changed(node.shader_path) {
+                continue;
+            }
+
+            let rp: &dyn Renderpass = if node.output == SWAPCHAIN {
+                swapchain
+            } else {
+                &man.get_renderpass_ref::<PostTarget>(node.target).unwrap().renderpass
+            };
+            let pipeline = compile_glsl(SCREEN_QUAD_SHADER).and_then(|vert| {
+                let frag = compile_glsl(node.shader_path)?;
+                Self::create_pipeline(man.core(), rp, &vert, &frag, node.set_layout, globals_layout)
+            });
+
+            match pipeline {
+                Ok(pipeline) => {
+                    node.pipeline = pipeline;
+                    eprintln!("reloaded the \"{}\" post pipeline", node.name);
+                }
+                Err(e) => eprintln!("failed to reload the \"{}\" post pipeline, keeping the old one: {e}", node.name),
+            }
+        }
+    }
+
+    //returns false if there is no node with the name
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        match self.nodes.iter_mut().find(|n| n.name == name) {
+            Some(node) => {
+                node.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_enabled(&self, name: &str) -> Option<bool> { self.nodes.iter().find(|n| n.name == name).map(|n| n.enabled) }
+
+    //renderpass and attachment an input samples, used as the descriptor cache key
+    fn input_attachment(&self, input: &'static str) -> (&'static str, &'static str) {
+        match input {
+            "gbuffer.albedo" => ("deferred_render", "albedo_spec"),
+            "gbuffer.normal" => ("deferred_render", "normal"),
+            "gbuffer.depth" => ("deferred_render", "depth"),
+            _ => (self.targets[input], "color"),
+        }
+    }
+
+    fn record_node(
+        &self,
+        node: &PostNode,
+        cmd: &mut CommandBuffer,
+        man: &RenderPassManager,
+        descriptor_cache: &mut DescriptorCache,
+        globals_set: vk::DescriptorSet,
+    ) -> eyre::Result<()> {
+        let deferred = man.get_renderpass_ref::<DeferedPass>("deferred_render").unwrap();
+        let attachment = |name: &str| match name {
+            "gbuffer.albedo" => deferred.renderpass.get_attachment(deferred.albedo_spec),
+            "gbuffer.normal" => deferred.renderpass.get_attachment(deferred.normal),
+            "gbuffer.depth" => deferred.renderpass.get_attachment(deferred.depth),
+            _ => {
+                let target = man.get_renderpass_ref::<PostTarget>(self.targets[name]).unwrap();
+                target.renderpass.get_attachment(target.color)
+            }
+        };
+
+        let sampler = *self.sampler;
+        let key = node.inputs.iter().fold(DescriptorKey::new(node.set_layout), |key, input| {
+            let (renderpass, name) = self.input_attachment(*input);
+            key.attachment(renderpass, name, sampler)
+        });
+        let dset = descriptor_cache.get_or_build(key, |pool| {
+            let mut builder = DescriptorSetBuilder::new();
+            for input in node.inputs {
+                builder = builder.add_sampled_image(attachment(input), sampler);
+            }
+            Ok(builder.build(node.set_layout, pool)?)
+        })?;
+
+        cmd.bind_pipeline(&node.pipeline);
+        cmd.bind_descriptor_set(0, dset);
+        cmd.bind_descriptor_set(1, globals_set);
+        cmd.push_constant(&PostPush { enabled: node.enabled as u32 }, vk::ShaderStageFlags::FRAGMENT, 0);
+        unsafe {
+            cmd.draw(3, 1, 0, 0);
+        }
+
+        Ok(())
+    }
+
+    pub fn update_globals(&mut self, game: &Game) {
+        let frame_index = game.world.fetch::<FrameIndex>().index();
+        let cam_data = game.world.fetch::<CameraData>();
+        self.globals_buffers[frame_index].get_data_mut().unwrap()[0] = PostGlobals {
+            proj_view: cam_data.proj_view.to_cols_array_2d(),
+            inv_proj_view: cam_data.proj_view.inverse().to_cols_array_2d(),
+        };
+    }
+
+    fn execute_node(&self, index: usize, cmd: &mut CommandBuffer, ctx: &PassContext) -> eyre::Result<()> {
+        let node = &self.nodes[index];
+        let man = ctx.manager;
+        let frame_index = ctx.game.world.fetch::<FrameIndex>().index();
+
+        let globals = ctx.game.world.fetch::<RenderGlobals>();
+        let mut descriptor_cache = globals.descriptor_cache();
+        let globals_buffer = &self.globals_buffers[frame_index];
+        let globals_set =
+            descriptor_cache.get_or_build(DescriptorKey::new(self.globals_layout).buffer(globals_buffer.inner()), |pool| {
+                Ok(DescriptorSetBuilder::new().add_ubo(&[globals_buffer]).build(self.globals_layout, pool)?)
+            })?;
+
+        if node.output == SWAPCHAIN {
+            ctx.swapchain.begin(cmd.inner(), true);
+            self.record_node(node, cmd, man, &mut descriptor_cache, globals_set)?;
+            ctx.swapchain.end(cmd.inner());
+        } else {
+            let subpass = man.get_subpass(node.target).unwrap();
+            let mut node_cmd = subpass.new_cmd()?;
+            self.record_node(node, &mut node_cmd, man, &mut descriptor_cache, globals_set)?;
+            subpass.submit_cmd(node_cmd)?;
+
+            man.execute_renderpass(cmd, node.target);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,504 @@
+use std::collections::HashMap;
+
+use ash::vk;
+use eyre::{eyre, Result};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResourceKind {
+    Attachment,
+    Buffer,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PassKind {
+    Graphics,
+    Compute,
+}
+
+#[derive(Clone, Debug)]
+pub struct ResourceDesc {
+    pub name: &'static str,
+    pub kind: ResourceKind,
+    //only used inside the frame, its memory may be shared with other transients
+    pub transient: bool,
+    //transients only share memory with ones of the same kind and format, undefined for buffers
+    pub format: vk::Format,
+    //owned outside of the graph like the swapchain, it can be read without being written first
+    pub imported: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct PassDesc {
+    pub name: &'static str,
+    pub kind: PassKind,
+    pub reads: Vec<&'static str>,
+    pub writes: Vec<&'static str>,
+}
+
+impl PassDesc {
+    pub fn graphics(name: &'static str) -> PassDesc { Self { name, kind: PassKind::Graphics, reads: vec![], writes: vec![] } }
+    pub fn compute(name: &'static str) -> PassDesc { Self { name, kind: PassKind::Compute, reads: vec![], writes: vec![] } }
+
+    pub fn reads(mut self, resources: &[&'static str]) -> PassDesc {
+        self.reads.extend_from_slice(resources);
+        self
+    }
+
+    pub fn writes(mut self, resources: &[&'static str]) -> PassDesc {
+        self.writes.extend_from_slice(resources);
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Barrier {
+    pub resource: &'static str,
+    pub kind: ResourceKind,
+    pub src_pass: &'static str,
+    pub src: PassKind,
+    pub dst: PassKind,
+}
+
+impl Barrier {
+    pub fn src_stage(&self) -> vk::PipelineStageFlags {
+        match self.src {
+            PassKind::Graphics => {
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS
+            }
+            PassKind::Compute => vk::PipelineStageFlags::COMPUTE_SHADER,
+        }
+    }
+
+    pub fn dst_stage(&self) -> vk::PipelineStageFlags {
+        match (self.dst, self.kind) {
+            (PassKind::Graphics, ResourceKind::Attachment) => vk::PipelineStageFlags::FRAGMENT_SHADER,
+            (PassKind::Graphics, ResourceKind::Buffer) => {
+                vk::PipelineStageFlags::DRAW_INDIRECT | vk::PipelineStageFlags::VERTEX_SHADER
+            }
+            (PassKind::Compute, _) => vk::PipelineStageFlags::COMPUTE_SHADER,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CompiledGraph {
+    pub order: Vec<&'static str>,
+    //barriers to record before every pass, indexed like order
+    pub barriers: Vec<Vec<Barrier>>,
+    //first and last position in order using each transient resource
+    pub lifetimes: HashMap<&'static str, (usize, usize)>,
+    //transients sharing a slot are never alive at the same time so their memory can be aliased
+    pub transient_slots: HashMap<&'static str, usize>,
+}
+
+impl CompiledGraph {
+    //memory barrier covering every barrier of a pass, none if the pass doesn't need to wait
+    pub fn memory_barrier(&self, pass_index: usize) -> Option<(vk::PipelineStageFlags, vk::PipelineStageFlags)> {
+        self.barriers[pass_index]
+            .iter()
+            .map(|b| (b.src_stage(), b.dst_stage()))
+            .reduce(|(src_a, dst_a), (src_b, dst_b)| (src_a | src_b, dst_a | dst_b))
+    }
+}
+
+/*
+    Passes are declared with the resources they read and write. Compiling orders them by data flow,
+    a reader runs after the writer declared before it (or the first writer if there is none) and
+    writers of the same resource keep their declaration order. Ties are broken by declaration order.
+*/
+#[derive(Clone, Debug, Default)]
+pub struct RenderGraph {
+    resources: Vec<ResourceDesc>,
+    passes: Vec<PassDesc>,
+}
+
+impl RenderGraph {
+    pub fn new() -> RenderGraph { Self::default() }
+
+    pub fn add_resource(&mut self, name: &'static str, kind: ResourceKind) {
+        self.resources.push(ResourceDesc { name, kind, transient: false, format: vk::Format::UNDEFINED, imported: false });
+    }
+
+    pub fn add_transient(&mut self, name: &'static str, kind: ResourceKind, format: vk::Format) {
+        self.resources.push(ResourceDesc { name, kind, transient: true, format, imported: false });
+    }
+
+    pub fn import_resource(&mut self, name: &'static str, kind: ResourceKind) {
+        self.resources.push(ResourceDesc { name, kind, transient: false, format: vk::Format::UNDEFINED, imported: true });
+    }
+
+    pub fn add_pass(&mut self, pass: PassDesc) { self.passes.push(pass); }
+
+    pub fn passes(&self) -> &[PassDesc] { &self.passes }
+    pub fn resources(&self) -> &[ResourceDesc] { &self.resources }
+
+    fn resource(&self, name: &str) -> Option<&ResourceDesc> { self.resources.iter().find(|r| r.name == name) }
+
+    fn validate(&self) -> Result<()> {
+        for (i, r) in self.resources.iter().enumerate() {
+            if self.resources[..i].iter().any(|o| o.name == r.name) {
+                return Err(eyre!("resource \"{}\" is declared twice", r.name));
+            }
+        }
+
+        for (i, p) in self.passes.iter().enumerate() {
+            if self.passes[..i].iter().any(|o| o.name == p.name) {
+                return Err(eyre!("pass \"{}\" is declared twice", p.name));
+            }
+
+            for r in p.reads.iter().chain(&p.writes) {
+                if self.resource(r).is_none() {
+                    return Err(eyre!("pass \"{}\" uses undeclared resource \"{}\"", p.name, r));
+                }
+            }
+
+            for r in &p.reads {
+                let written = self.passes.iter().any(|o| o.writes.contains(r));
+                if !written && !self.resource(r).unwrap().imported {
+                    return Err(eyre!("pass \"{}\" reads \"{}\" but no pass writes it", p.name, r));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    //edges between pass indices, from -> to
+    fn dependencies(&self) -> Vec<(usize, usize)> {
+        let mut edges = vec![];
+
+        for resource in &self.resources {
+            let writers: Vec<usize> = (0..self.passes.len()).filter(|&i| self.passes[i].writes.contains(&resource.name)).collect();
+
+            for w in writers.windows(2) {
+                edges.push((w[0], w[1]));
+            }
+
+            for (reader, pass) in self.passes.iter().enumerate() {
+                if !pass.reads.contains(&resource.name) || pass.writes.contains(&resource.name) {
+                    continue;
+                }
+
+                //the value a reader sees comes from the last writer declared before it
+                let producer = writers.iter().rposition(|&w| w < reader);
+                match producer {
+                    Some(p) => {
+                        edges.push((writers[p], reader));
+                        if let Some(&next) = writers.get(p + 1) {
+                            edges.push((reader, next));
+                        }
+                    }
+                    None => {
+                        if let Some(&first) = writers.first() {
+                            edges.push((first, reader));
+                        }
+                    }
+                }
+            }
+        }
+
+        edges
+    }
+
+    fn sort(&self) -> Result<Vec<usize>> {
+        let edges = self.dependencies();
+        let mut in_degree = vec![0; self.passes.len()];
+        for (_, to) in &edges {
+            in_degree[*to] += 1;
+        }
+
+        let mut order = Vec::with_capacity(self.passes.len());
+        let mut done = vec![false; self.passes.len()];
+
+        while order.len() < self.passes.len() {
+            let next = (0..self.passes.len()).find(|&i| !done[i] && in_degree[i] == 0).ok_or_else(|| {
+                let remaining: Vec<_> = (0..self.passes.len()).filter(|&i| !done[i]).map(|i| self.passes[i].name).collect();
+                eyre!("render graph has a cycle between {:?}", remaining)
+            })?;
+
+            done[next] = true;
+            order.push(next);
+            for (_, to) in edges.iter().filter(|(from, _)| *from == next) {
+                in_degree[*to] -= 1;
+            }
+        }
+
+        Ok(order)
+    }
+
+    pub fn compile(&self) -> Result<CompiledGraph> {
+        self.validate()?;
+        let order = self.sort()?;
+
+        struct State {
+            last_writer: Option<usize>,
+            readers: Vec<usize>,
+        }
+
+        let mut states: HashMap<&str, State> =
+            self.resources.iter().map(|r| (r.name, State { last_writer: None, readers: vec![] })).collect();
+        let mut barriers = vec![];
+
+        for &p in &order {
+            let pass = &self.passes[p];
+            let mut pass_barriers: Vec<Barrier> = vec![];
+            let mut add_barrier = |resource: &'static str, src: usize| {
+                let barrier = Barrier {
+                    resource,
+                    kind: self.resource(resource).unwrap().kind,
+                    src_pass: self.passes[src].name,
+                    src: self.passes[src].kind,
+                    dst: pass.kind,
+                };
+                if src != p && !pass_barriers.contains(&barrier) {
+                    pass_barriers.push(barrier);
+                }
+            };
+
+            for &r in &pass.reads {
+                if let Some(w) = states[r].last_writer {
+                    add_barrier(r, w); //read after write
+                }
+            }
+
+            for &r in &pass.writes {
+                let state = &states[r];
+                if !state.readers.is_empty() {
+                    for &reader in &state.readers {
+                        add_barrier(r, reader); //write after read
+                    }
+                } else if let Some(w) = state.last_writer {
+                    add_barrier(r, w); //write after write
+                }
+            }
+
+            for r in &pass.reads {
+                states.get_mut(r).unwrap().readers.push(p);
+            }
+            for r in &pass.writes {
+                let state = states.get_mut(r).unwrap();
+                state.last_writer = Some(p);
+                state.readers.clear();
+            }
+
+            barriers.push(pass_barriers);
+        }
+
+        let mut lifetimes = HashMap::new();
+        for (position, &p) in order.iter().enumerate() {
+            let pass = &self.passes[p];
+            for r in pass.reads.iter().chain(&pass.writes) {
+                if self.resource(r).unwrap().transient {
+                    let lifetime = lifetimes.entry(*r).or_insert((position, position));
+                    lifetime.1 = position;
+                }
+            }
+        }
+
+        Ok(CompiledGraph {
+            order: order.iter().map(|&p| self.passes[p].name).collect(),
+            barriers,
+            transient_slots: self.assign_slots(&lifetimes),
+            lifetimes,
+        })
+    }
+
+    //greedy interval colouring, a slot is reused once the previous resource in it is dead
+    fn assign_slots(&self, lifetimes: &HashMap<&'static str, (usize, usize)>) -> HashMap<&'static str, usize> {
+        let mut transients: Vec<_> = lifetimes.iter().map(|(name, lifetime)| (*name, *lifetime)).collect();
+        transients.sort_by_key(|(name, (first, _))| (*first, *name));
+
+        //(kind, format, last use) of every slot
+        let mut slots: Vec<(ResourceKind, vk::Format, usize)> = vec![];
+        let mut assignment = HashMap::new();
+
+        for (name, (first, last)) in transients {
+            let resource = self.resource(name).unwrap();
+            let free = |(kind, format, end): &(ResourceKind, vk::Format, usize)| {
+                *kind == resource.kind && *format == resource.format && *end < first
+            };
+            let slot = match slots.iter().position(free) {
+                Some(slot) => slot,
+                None => {
+                    slots.push((resource.kind, resource.format, last));
+                    slots.len() - 1
+                }
+            };
+            slots[slot].2 = last;
+            assignment.insert(name, slot);
+        }
+
+        assignment
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(resources: &[(&'static str, ResourceKind)], passes: Vec<PassDesc>) -> RenderGraph {
+        let mut graph = RenderGraph::new();
+        graph.import_resource("swapchain", ResourceKind::Attachment);
+        resources.iter().for_each(|(name, kind)| graph.add_resource(name, *kind));
+        passes.into_iter().for_each(|p| graph.add_pass(p));
+        graph
+    }
+
+    fn frame_graph() -> RenderGraph {
+        graph(
+            &[("draws", ResourceKind::Buffer), ("color", ResourceKind::Attachment)],
+            vec![
+                PassDesc::graphics("post").reads(&["color"]).writes(&["swapchain"]),
+                PassDesc::graphics("draw").reads(&["draws"]).writes(&["color"]),
+                PassDesc::compute("cull").writes(&["draws"]),
+            ],
+        )
+    }
+
+    #[test]
+    fn passes_are_ordered_by_data_flow() {
+        assert_eq!(frame_graph().compile().unwrap().order, vec!["cull", "draw", "post"]);
+    }
+
+    #[test]
+    fn independent_passes_keep_declaration_order() {
+        let graph = graph(
+            &[("a", ResourceKind::Buffer), ("b", ResourceKind::Buffer)],
+            vec![PassDesc::compute("second").writes(&["b"]), PassDesc::compute("first").writes(&["a"])],
+        );
+        assert_eq!(graph.compile().unwrap().order, vec!["second", "first"]);
+    }
+
+    #[test]
+    fn cycles_are_reported() {
+        let graph = graph(
+            &[("x", ResourceKind::Buffer), ("y", ResourceKind::Buffer)],
+            vec![PassDesc::compute("a").reads(&["x"]).writes(&["y"]), PassDesc::compute("b").reads(&["y"]).writes(&["x"])],
+        );
+        let err = graph.compile().unwrap_err().to_string();
+        assert!(err.contains("cycle"), "{err}");
+    }
+
+    #[test]
+    fn reads_need_a_writer_unless_imported() {
+        let graph = graph(&[("depth", ResourceKind::Attachment)], vec![PassDesc::graphics("ssao").reads(&["depth"])]);
+        let err = graph.compile().unwrap_err().to_string();
+        assert!(err.contains("no pass writes"), "{err}");
+
+        let graph = self::graph(&[], vec![PassDesc::graphics("copy").reads(&["swapchain"])]);
+        assert!(graph.compile().is_ok());
+
+        let graph = self::graph(&[], vec![PassDesc::graphics("copy").writes(&["missing"])]);
+        assert!(graph.compile().unwrap_err().to_string().contains("undeclared"));
+    }
+
+    #[test]
+    fn read_after_write_barriers() {
+        let compiled = frame_graph().compile().unwrap();
+
+        assert!(compiled.barriers[0].is_empty());
+        assert!(compiled.memory_barrier(0).is_none());
+        assert_eq!(
+            compiled.barriers[1],
+            vec![Barrier {
+                resource: "draws",
+                kind: ResourceKind::Buffer,
+                src_pass: "cull",
+                src: PassKind::Compute,
+                dst: PassKind::Graphics,
+            }]
+        );
+        assert_eq!(
+            compiled.memory_barrier(1),
+            Some((
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::DRAW_INDIRECT | vk::PipelineStageFlags::VERTEX_SHADER
+            ))
+        );
+        assert_eq!(compiled.barriers[2].len(), 1);
+        assert_eq!(compiled.barriers[2][0].src_pass, "draw");
+        assert_eq!(compiled.barriers[2][0].dst_stage(), vk::PipelineStageFlags::FRAGMENT_SHADER);
+    }
+
+    #[test]
+    fn write_after_read_and_write_after_write_barriers() {
+        let graph = graph(
+            &[("draws", ResourceKind::Buffer), ("color", ResourceKind::Attachment)],
+            vec![
+                PassDesc::compute("cull").writes(&["draws"]),
+                PassDesc::graphics("draw").reads(&["draws"]).writes(&["color"]),
+                PassDesc::compute("recull").writes(&["draws"]),
+                PassDesc::graphics("overlay").writes(&["color"]),
+            ],
+        );
+        let compiled = graph.compile().unwrap();
+        assert_eq!(compiled.order, vec!["cull", "draw", "recull", "overlay"]);
+
+        //recull has to wait for draw to finish reading, not for cull
+        assert_eq!(compiled.barriers[2].iter().map(|b| b.src_pass).collect::<Vec<_>>(), vec!["draw"]);
+        assert_eq!(compiled.barriers[3].iter().map(|b| b.src_pass).collect::<Vec<_>>(), vec!["draw"]);
+        assert_eq!(compiled.barriers[3][0].resource, "color");
+    }
+
+    #[test]
+    fn transient_lifetimes_span_their_first_and_last_use() {
+        let hdr = vk::Format::R16G16B16A16_SFLOAT;
+        let mut graph = graph(
+            &[("hdr", ResourceKind::Attachment)],
+            vec![
+                PassDesc::graphics("lighting").writes(&["hdr"]),
+                PassDesc::graphics("ssao").reads(&["hdr"]).writes(&["hdr_ao"]),
+                PassDesc::graphics("bloom").reads(&["hdr_ao"]).writes(&["bloom"]),
+                PassDesc::graphics("tonemap").reads(&["hdr_ao", "bloom"]).writes(&["tonemapped"]),
+                PassDesc::graphics("gamma").reads(&["tonemapped"]).writes(&["ldr"]),
+                PassDesc::graphics("fxaa").reads(&["ldr"]).writes(&["swapchain"]),
+            ],
+        );
+        for name in ["hdr_ao", "bloom", "tonemapped"] {
+            graph.add_transient(name, ResourceKind::Attachment, hdr);
+        }
+        graph.add_transient("ldr", ResourceKind::Attachment, vk::Format::R8G8B8A8_UNORM);
+        let compiled = graph.compile().unwrap();
+
+        assert_eq!(compiled.lifetimes.len(), 4);
+        assert_eq!(compiled.lifetimes["hdr_ao"], (1, 3));
+        assert_eq!(compiled.lifetimes["bloom"], (2, 3));
+        assert_eq!(compiled.lifetimes["tonemapped"], (3, 4));
+        assert_eq!(compiled.lifetimes["ldr"], (4, 5));
+
+        //every hdr target is still read by tonemap, ldr could take the slot of hdr_ao but has another format
+        assert_eq!(compiled.transient_slots["hdr_ao"], 0);
+        assert_eq!(compiled.transient_slots["bloom"], 1);
+        assert_eq!(compiled.transient_slots["tonemapped"], 2);
+        assert_eq!(compiled.transient_slots["ldr"], 3);
+        assert!(!compiled.transient_slots.contains_key("hdr"));
+    }
+
+    #[test]
+    fn dead_transients_share_slots() {
+        let format = vk::Format::R8G8B8A8_UNORM;
+        let mut graph = graph(
+            &[],
+            vec![
+                PassDesc::graphics("p0").writes(&["t0"]),
+                PassDesc::graphics("p1").reads(&["t0"]).writes(&["t1"]),
+                PassDesc::graphics("p2").reads(&["t1"]).writes(&["t2", "scratch"]),
+                PassDesc::graphics("p3").reads(&["t2", "scratch"]).writes(&["t3"]),
+                PassDesc::graphics("p4").reads(&["t3"]).writes(&["swapchain"]),
+            ],
+        );
+        for name in ["t0", "t1", "t2", "t3"] {
+            graph.add_transient(name, ResourceKind::Attachment, format);
+        }
+        graph.add_transient("scratch", ResourceKind::Buffer, vk::Format::UNDEFINED);
+        let compiled = graph.compile().unwrap();
+
+        assert_eq!(compiled.lifetimes["t0"], (0, 1));
+        assert_eq!(compiled.lifetimes["t2"], (2, 3));
+        assert_eq!(compiled.lifetimes["scratch"], (2, 3));
+
+        //t2 starts after t0 is last read and t3 after t1, a buffer never takes the slot of an attachment
+        let slots: Vec<_> = ["t0", "t1", "t2", "t3", "scratch"].iter().map(|t| compiled.transient_slots[t]).collect();
+        assert_eq!(slots, vec![0, 1, 0, 1, 2]);
+    }
+}
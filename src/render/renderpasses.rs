@@ -1,73 +1,192 @@
 use ash::vk;
 use magma_renderer::{core::*, engine::material::MaterialManager};
-use std::{ops::DerefMut, sync::Arc};
+use std::sync::{Arc, Mutex};
+
+use specs::prelude::*;
 
 use crate::{
-    game::{Game, RenderGlobals},
+    game::{plugin::Plugin, voxels::ViewDistance, CameraData, FrameIndex, Game, RenderGlobals, Stage, Transform},
     include_glsl,
 };
 
-use super::renderpassmanager::*;
+use super::{
+    descriptor_cache::DescriptorKey,
+    hiz::{self, HiZ},
+    hot_reload::{compile_glsl, HotReload},
+    lighting::{
+        pack_lights, FogSettings, GpuLightingData, GpuPointLight, Light, LightingSettings, UpdateLighting,
+        MAX_POINT_LIGHTS,
+    },
+    postprocess::{default_nodes, PostProcessChain, PostTarget, HDR, HDR_FORMAT, SWAPCHAIN},
+    render_graph::{PassDesc, ResourceKind},
+    renderpassmanager::*,
+    shadows::{ShadowCascades, ShadowPass, ShadowSettings, UpdateShadowCascades},
+};
 
 const CLEAR_ZERO: vk::ClearValue = vk::ClearValue { color: vk::ClearColorValue { float32: [0.0, 0.0, 0.0, 0.0] } };
+const CLEAR_DEPTH: vk::ClearValue =
+    vk::ClearValue { depth_stencil: vk::ClearDepthStencilValue { depth: 1.0, stencil: 0 } };
 
+/*
+    The gbuffer is drawn twice a frame, "deferred_render" draws the chunks that passed the early
+    hi-z test and "late_gpass" the ones only the late test found visible. Its attachments have no
+    clear value so the second renderpass keeps the first one's contents, clear_gbuffer clears them
+    at the start of the first one instead.
+*/
 pub struct DeferedPass {
     pub renderpass: MultiPassRenderPass,
     pub depth: AttachmentIndex,
     pub normal: AttachmentIndex,
     pub albedo_spec: AttachmentIndex,
     dset_layout: vk::DescriptorSetLayout,
+    light_set_layout: vk::DescriptorSetLayout,
+    shadow_set_layout: vk::DescriptorSetLayout,
     pipeline: Arc<Pipeline>,
     sampler: Handle<vk::Sampler>,
+    light_buffers: Box<[LightBuffers]>,
+    //gpass command buffers executed by "late_gpass"
+    late_cmds: Mutex<Vec<CommandBuffer>>,
+}
+
+struct LightBuffers {
+    lighting_data: Buffer<GpuLightingData>,
+    point_lights: Buffer<GpuPointLight>,
 }
 
 impl HasRenderPass for DeferedPass {
     fn renderpass(&self) -> &dyn Renderpass { &self.renderpass }
+    fn resize(&mut self, width: u32, height: u32) -> eyre::Result<()> {
+        self.renderpass.resize(width, height)?;
+        Ok(())
+    }
 }
 
 impl DeferedPass {
-    pub fn new(core: &Arc<Core>, rp: &dyn Renderpass) -> DeferedPass {
+    //rp is the target of the lighting resolve
+    pub fn new(
+        core: &Arc<Core>,
+        rp: &dyn Renderpass,
+        shadow_set_layout: vk::DescriptorSetLayout,
+    ) -> eyre::Result<DeferedPass> {
         let (w, h) = rp.extends();
 
         let mut gpassbulder = RenderPassBuilder::new();
-        let albedo_spec = gpassbulder.add_attachment(vk::Format::R8G8B8A8_UNORM, Some(CLEAR_ZERO), true);
-        let depth = gpassbulder.add_attachment(
-            vk::Format::D16_UNORM,
-            Some(vk::ClearValue { depth_stencil: vk::ClearDepthStencilValue { depth: 1.0, stencil: 0 } }),
-            false,
-        );
-        let normal = gpassbulder.add_attachment(vk::Format::R16G16B16A16_SNORM, Some(CLEAR_ZERO), false);
+        let albedo_spec = gpassbulder.add_attachment(vk::Format::R8G8B8A8_UNORM, None, true);
+        let depth = gpassbulder.add_attachment(vk::Format::D16_UNORM, None, true);
+        let normal = gpassbulder.add_attachment(vk::Format::R16G16B16A16_SNORM, None, true);
         gpassbulder.add_subpass(&[albedo_spec, normal], Some(depth), &[]);
-        let renderpass = gpassbulder.build(core, w, h).unwrap();
+        let renderpass = gpassbulder.build(core, w, h)?;
+
+        let dset_layout = DescriptorSetLayoutBuilder::new()
+            .add_sampler(vk::ShaderStageFlags::FRAGMENT, 1) //albedo_spec
+            .add_sampler(vk::ShaderStageFlags::FRAGMENT, 1) //normal
+            .add_sampler(vk::ShaderStageFlags::FRAGMENT, 1) //depth
+            .build(core)?;
+        let light_set_layout = DescriptorSetLayoutBuilder::new()
+            .add_ubo(vk::ShaderStageFlags::FRAGMENT, 1)
+            .add_ssbo(vk::ShaderStageFlags::FRAGMENT, 1)
+            .build(core)?;
 
-        let (pipeline, dset_layout) = Self::create_pipeline(core, rp).unwrap();
+        let pipeline = Self::create_pipeline(
+            core,
+            rp,
+            &[dset_layout, light_set_layout, shadow_set_layout],
+            include_glsl!("res/screen_quad.vert"),
+            include_glsl!("res/final.frag"),
+        )?;
         let sampler = core.create_sampler(vk::Filter::NEAREST, None);
 
-        Self { renderpass, albedo_spec, normal, depth, pipeline, dset_layout, sampler }
+        let light_buffers = (0..2)
+            .map(|_| {
+                Ok(LightBuffers {
+                    lighting_data: core.create_buffer(vk::BufferUsageFlags::UNIFORM_BUFFER, 1, true)?,
+                    point_lights: core.create_buffer(
+                        vk::BufferUsageFlags::STORAGE_BUFFER,
+                        MAX_POINT_LIGHTS as u32,
+                        true,
+                    )?,
+                })
+            })
+            .collect::<eyre::Result<_>>()?;
+
+        Ok(Self {
+            renderpass,
+            albedo_spec,
+            normal,
+            depth,
+            pipeline,
+            dset_layout,
+            light_set_layout,
+            shadow_set_layout,
+            sampler,
+            light_buffers,
+            late_cmds: Mutex::new(vec![]),
+        })
+    }
+
+    //submits a command buffer clearing the gbuffer to "gpass", it has to be the first one of the frame
+    fn clear_gbuffer(&self, man: &RenderPassManager) -> eyre::Result<()> {
+        let gpass = man.get_subpass("gpass").unwrap();
+        let mut cmd = gpass.new_cmd()?;
+
+        let (width, height) = self.renderpass.extends();
+        let color = |attachment| vk::ClearAttachment {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            color_attachment: attachment,
+            clear_value: CLEAR_ZERO,
+        };
+        //colour attachments are numbered in the order of the subpass
+        let attachments = [
+            color(0),
+            color(1),
+            vk::ClearAttachment { aspect_mask: vk::ImageAspectFlags::DEPTH, color_attachment: 0, clear_value: CLEAR_DEPTH },
+        ];
+        let rect = vk::ClearRect {
+            rect: vk::Rect2D { offset: vk::Offset2D { x: 0, y: 0 }, extent: vk::Extent2D { width, height } },
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        unsafe {
+            cmd.device().cmd_clear_attachments(cmd.inner(), &attachments, &[rect]);
+        }
+
+        gpass.submit_cmd(cmd)
     }
 
-    fn create_pipeline(core: &Arc<Core>, rp: &dyn Renderpass) -> eyre::Result<(Arc<Pipeline>, vk::DescriptorSetLayout)> {
-        let dset_layout = DescriptorSetLayoutBuilder::new().add_sampler(vk::ShaderStageFlags::FRAGMENT, 1).build(core)?;
+    //cmd has to be recorded for the "gpass" subpass
+    pub fn submit_late_cmd(&self, cmd: CommandBuffer) { self.late_cmds.lock().unwrap().push(cmd); }
 
-        let layout = PipelineLayoutBuilder::new().add_set(dset_layout).build(core)?;
+    fn create_pipeline(
+        core: &Arc<Core>,
+        rp: &dyn Renderpass,
+        set_layouts: &[vk::DescriptorSetLayout],
+        vert: &[u32],
+        frag: &[u32],
+    ) -> eyre::Result<Arc<Pipeline>> {
+        let layout = set_layouts.iter().fold(PipelineLayoutBuilder::new(), |b, l| b.add_set(*l)).build(core)?;
 
         let pipeline = GPipelineBuilder::new()
             .set_depth_testing(false)
             .set_rasterization(vk::PolygonMode::FILL, vk::CullModeFlags::NONE)
             .set_topology(vk::PrimitiveTopology::TRIANGLE_LIST)
             .set_pipeline_layout(layout)
-            .add_shader_stage(
-                vk::ShaderStageFlags::VERTEX,
-                &ShaderModule::new(core, include_glsl!("res/screen_quad.vert"))?.module(),
-            )
-            .add_shader_stage(
-                vk::ShaderStageFlags::FRAGMENT,
-                &ShaderModule::new(core, include_glsl!("res/final.frag"))?.module(),
-            )
+            .add_shader_stage(vk::ShaderStageFlags::VERTEX, &ShaderModule::new(core, vert)?.module())
+            .add_shader_stage(vk::ShaderStageFlags::FRAGMENT, &ShaderModule::new(core, frag)?.module())
             .set_render_target(rp.get_subpasses()[0].get_render_target())
             .build(core)?;
 
-        Ok((pipeline, dset_layout))
+        Ok(pipeline)
+    }
+
+    //compiles the lighting shaders from res/ at runtime, rp is the target of the lighting resolve
+    fn reload_pipeline(&self, core: &Arc<Core>, rp: &dyn Renderpass) -> eyre::Result<Arc<Pipeline>> {
+        Self::create_pipeline(
+            core,
+            rp,
+            &[self.dset_layout, self.light_set_layout, self.shadow_set_layout],
+            &compile_glsl(LIGHTING_SHADERS[0])?,
+            &compile_glsl(LIGHTING_SHADERS[1])?,
+        )
     }
 
     pub fn register(self, man: &mut RenderPassManager, game: &mut Game) {
@@ -80,15 +199,69 @@ impl DeferedPass {
         );
     }
 
-    fn render_to_swapchain(&self, cmd: &mut CommandBuffer, game: &Game) -> eyre::Result<()> {
-        let image = self.renderpass.get_attachment(self.albedo_spec);
-        let dset = DescriptorSetBuilder::new().add_sampled_image(image, *self.sampler).build(
-            self.dset_layout,
-            game.world.fetch::<RenderGlobals>().frame_data().descriptor_pool.lock().unwrap().deref_mut(),
-        )?;
+    pub(super) fn update_light_buffers(&mut self, game: &Game, frame_index: usize) {
+        let transforms = game.world.read_storage::<Transform>();
+        let lights = game.world.read_storage::<Light>();
+        let cam_data = game.world.fetch::<CameraData>();
+
+        let (mut lighting_data, point_lights) = pack_lights(
+            &game.world.fetch::<LightingSettings>(),
+            cam_data.proj_view,
+            cam_data.position,
+            (&transforms, &lights).join().map(|(t, l)| (t.pos, l)),
+            MAX_POINT_LIGHTS,
+        );
+        lighting_data.fog = game.world.fetch::<FogSettings>().to_gpu(game.world.fetch::<ViewDistance>().blocks());
+
+        let cascades = game.world.fetch::<ShadowCascades>();
+        lighting_data.shadow_matrices = cascades.light_matrices();
+        lighting_data.cascade_splits = cascades.splits;
+        lighting_data.point_light_count[1] = cascades.settings.cascade_count as u32;
+
+        let buffers = &mut self.light_buffers[frame_index];
+        buffers.lighting_data.get_data_mut().unwrap()[0] = lighting_data;
+        buffers.point_lights.get_data_mut().unwrap()[..point_lights.len()].copy_from_slice(&point_lights);
+    }
+
+    fn render_lighting(
+        &self,
+        cmd: &mut CommandBuffer,
+        game: &Game,
+        shadow_set: vk::DescriptorSet,
+    ) -> eyre::Result<()> {
+        let frame_index = game.world.fetch::<FrameIndex>().index();
+
+        let globals = game.world.fetch::<RenderGlobals>();
+        let mut descriptor_cache = globals.descriptor_cache();
+
+        let sampler = *self.sampler;
+        let gbuffer_key = DescriptorKey::new(self.dset_layout)
+            .attachment("deferred_render", "albedo_spec", sampler)
+            .attachment("deferred_render", "normal", sampler)
+            .attachment("deferred_render", "depth", sampler);
+        let dset = descriptor_cache.get_or_build(gbuffer_key, |pool| {
+            Ok(DescriptorSetBuilder::new()
+                .add_sampled_image(self.renderpass.get_attachment(self.albedo_spec), sampler)
+                .add_sampled_image(self.renderpass.get_attachment(self.normal), sampler)
+                .add_sampled_image(self.renderpass.get_attachment(self.depth), sampler)
+                .build(self.dset_layout, pool)?)
+        })?;
+
+        let buffers = &self.light_buffers[frame_index];
+        let light_key = DescriptorKey::new(self.light_set_layout)
+            .buffer(buffers.lighting_data.inner())
+            .buffer(buffers.point_lights.inner());
+        let light_set = descriptor_cache.get_or_build(light_key, |pool| {
+            Ok(DescriptorSetBuilder::new()
+                .add_ubo(&[&buffers.lighting_data])
+                .add_ssbo(&[&buffers.point_lights])
+                .build(self.light_set_layout, pool)?)
+        })?;
 
         cmd.bind_pipeline(&self.pipeline);
         cmd.bind_descriptor_set(0, dset);
+        cmd.bind_descriptor_set(1, light_set);
+        cmd.bind_descriptor_set(2, shadow_set);
         unsafe {
             cmd.draw(3, 1, 0, 0);
         }
@@ -97,10 +270,131 @@ impl DeferedPass {
     }
 }
 
-pub fn init(game: &mut Game, rp: &dyn Renderpass) {
-    let mut man = RenderPassManager::new(&game.core);
+const LIGHTING_SHADERS: [&str; 2] = ["res/screen_quad.vert", "res/final.frag"];
+
+pub struct ReloadLightingPipeline;
+
+impl<'a> System<'a> for ReloadLightingPipeline {
+    type SystemData = (WriteExpect<'a, RenderPassManager>, ReadExpect<'a, HotReload>);
+
+    fn run(&mut self, (mut man, hot_reload): Self::SystemData) {
+        if !LIGHTING_SHADERS.iter().any(|s| hot_reload.is_changed(s)) {
+            return;
+        }
+
+        let deferred = man.get_renderpass_ref::<DeferedPass>("deferred_render").unwrap();
+        let hdr_target = man.get_renderpass_ref::<PostTarget>(HDR).unwrap();
+        match deferred.reload_pipeline(man.core(), &hdr_target.renderpass) {
+            Ok(pipeline) => {
+                man.get_renderpass::<DeferedPass>("deferred_render").unwrap().pipeline = pipeline;
+                eprintln!("reloaded the lighting pipeline");
+            }
+            Err(e) => eprintln!("failed to reload the lighting pipeline, keeping the old one: {e}"),
+        }
+    }
+}
+
+//lighting resolve into the hdr target, the post processing chain takes it from there to the swapchain
+fn record_lighting(cmd: &mut CommandBuffer, ctx: &PassContext) -> eyre::Result<()> {
+    let man = ctx.manager;
+    let shadow_set = man.get_renderpass_ref::<ShadowPass>("shadow_render").unwrap().build_descriptor_set(ctx.game)?;
 
-    DeferedPass::new(&game.core, rp).register(&mut man, game);
+    let lighting = man.get_subpass(HDR).unwrap();
+    let mut lighting_cmd = lighting.new_cmd()?;
+    let defered_renderer = man.get_renderpass_ref::<DeferedPass>("deferred_render").unwrap();
+    defered_renderer.render_lighting(&mut lighting_cmd, ctx.game, shadow_set)?;
+    lighting.submit_cmd(lighting_cmd)?;
+
+    man.execute_renderpass(cmd, HDR);
+    Ok(())
+}
+
+//draws the chunks found visible by "chunk_cull_late" into the gbuffer, on top of the early draws
+fn record_late_gbuffer(cmd: &mut CommandBuffer, ctx: &PassContext) -> eyre::Result<()> {
+    let deferred = ctx.manager.get_renderpass_ref::<DeferedPass>("deferred_render").unwrap();
+    let late_cmds = std::mem::take(&mut *deferred.late_cmds.lock().unwrap());
+    if late_cmds.is_empty() {
+        return Ok(());
+    }
+
+    deferred.renderpass.begin(cmd.inner(), false);
+    cmd.exectue_secondries(late_cmds);
+    deferred.renderpass.end(cmd.inner());
+    Ok(())
+}
+
+pub struct RenderPassPlugin<'a> {
+    pub swapchain: &'a dyn Renderpass,
+}
+
+impl<'a> Plugin for RenderPassPlugin<'a> {
+    fn name(&self) -> &'static str { "renderpasses" }
+    fn provides(&self) -> &'static [&'static str] { &["render_pass_manager", "gpass"] }
+    fn requires(&self) -> &'static [&'static str] { &["material_manager", "world_time", "voxel_world"] }
+    fn build(&self, game: &mut Game) -> eyre::Result<()> { init(game, self.swapchain) }
+}
+
+pub fn init(game: &mut Game, rp: &dyn Renderpass) -> eyre::Result<()> {
+    let core = game.core()?;
+    let mut man = RenderPassManager::new(&core, rp.extends());
+
+    let shadow_settings = ShadowSettings::default();
+    let shadow_pass = ShadowPass::new(&core, &shadow_settings)?;
+    let hdr_target = PostTarget::new(&core, HDR_FORMAT, rp.extends())?;
+    DeferedPass::new(&core, &hdr_target.renderpass, shadow_pass.set_layout)?.register(&mut man, game);
+    shadow_pass.register(&mut man);
+    hdr_target.register(&mut man, HDR);
+
+    man.import_resource(SWAPCHAIN, ResourceKind::Attachment);
+    man.add_resource("indirect_draws", ResourceKind::Buffer);
+    man.add_resource("shadow_map", ResourceKind::Attachment);
+    for gbuffer in ["gbuffer.albedo", "gbuffer.normal", "gbuffer.depth", HDR] {
+        man.add_resource(gbuffer, ResourceKind::Attachment);
+    }
+
+    man.add_pass(PassDesc::compute("chunk_cull").writes(&["indirect_draws"]), PassExec::Compute);
+    man.add_pass(
+        PassDesc::graphics("shadow_render").reads(&["indirect_draws"]).writes(&["shadow_map"]),
+        PassExec::RenderPass,
+    );
+    man.add_pass(
+        PassDesc::graphics("deferred_render")
+            .reads(&["indirect_draws"])
+            .writes(&["gbuffer.albedo", "gbuffer.normal", "gbuffer.depth"]),
+        PassExec::RenderPass,
+    );
+    //the pyramid is built from the early draws, the late ones are drawn before anything reads the gbuffer
+    hiz::init(game, &mut man)?;
+    man.add_pass(
+        PassDesc::graphics("late_gpass")
+            .reads(&["late_draws"])
+            .writes(&["gbuffer.albedo", "gbuffer.normal", "gbuffer.depth"]),
+        PassExec::Record(Box::new(record_late_gbuffer)),
+    );
+    man.add_pass(
+        PassDesc::graphics("lighting")
+            .reads(&["gbuffer.albedo", "gbuffer.normal", "gbuffer.depth", "shadow_map"])
+            .writes(&[HDR]),
+        PassExec::Record(Box::new(record_lighting)),
+    );
+
+    let post_chain = PostProcessChain::new(&core, &mut man, rp, default_nodes())?;
+
+    game.world.register::<Light>();
+    game.world.insert(LightingSettings::default());
+    game.world.insert(FogSettings::default());
+    game.add_system(Stage::RenderPrep, UpdateLighting, "update lighting", &[]);
+
+    game.world.insert(ShadowCascades::new(&core, shadow_settings)?);
+    game.add_system(Stage::RenderPrep, UpdateShadowCascades, "shadow cascades", &["update lighting"]);
+
+    {
+        let mut hot_reload = game.world.fetch_mut::<HotReload>();
+        for shader in LIGHTING_SHADERS.into_iter().chain(post_chain.shader_paths()) {
+            hot_reload.watch(shader);
+        }
+    }
+    game.add_system(Stage::Input, ReloadLightingPipeline, "reload lighting pipeline", &["hot reload"]);
 
     {
         let mut mat_man = game.world.fetch_mut::<MaterialManager>();
@@ -111,31 +405,58 @@ pub fn init(game: &mut Game, rp: &dyn Renderpass) {
     }
 
     game.world.insert(man);
+    game.world.insert(post_chain);
+
+    Ok(())
 }
 
 pub fn prepare_render(game: &mut Game, rp: &dyn Renderpass) -> eyre::Result<()> {
-    let mut man = game.world.fetch_mut::<RenderPassManager>();
-    let deferred_renderer = man.get_renderpass::<DeferedPass>("deferred_render").unwrap();
-    if deferred_renderer.renderpass.extends() != rp.extends() {
-        let (width, height) = rp.extends();
-        deferred_renderer.renderpass.resize(width, height)?;
-    }
+    resize_renderpasses(game, rp)?;
 
+    //before the systems record their gpass command buffers
+    let man = game.world.fetch::<RenderPassManager>();
+    let deferred = man.get_renderpass_ref::<DeferedPass>("deferred_render").unwrap();
+    deferred.clear_gbuffer(&man)?;
     Ok(())
 }
 
-pub fn render(game: &mut Game, cmd: &mut CommandBuffer, rp: &dyn Renderpass) -> eyre::Result<()> {
+fn resize_renderpasses(game: &mut Game, rp: &dyn Renderpass) -> eyre::Result<()> {
+    let (width, height) = rp.extends();
     let mut man = game.world.fetch_mut::<RenderPassManager>();
 
-    man.execute_compute_tasks(cmd);
+    //a minimized window has a zero sized swapchain, keep the old attachments until it comes back
+    if man.extent() == (width, height) || width == 0 || height == 0 {
+        return Ok(());
+    }
 
-    man.execute_renderpass(cmd, "deferred_render");
+    //the attachments and the descriptor sets sampling them may still be used by frames in flight
+    unsafe {
+        man.core().device().device_wait_idle()?;
+    }
 
-    rp.begin(cmd.inner(), true);
-    let defered_renderer = man.get_renderpass::<DeferedPass>("deferred_render").unwrap();
-    defered_renderer.render_to_swapchain(cmd, game)?;
+    let resized = man.resize((width, height))?;
+    if !resized.is_empty() {
+        game.world.fetch_mut::<HiZ>().invalidate();
+    }
 
-    rp.end(cmd.inner());
+    let globals = game.world.fetch::<RenderGlobals>();
+    let mut descriptor_cache = globals.descriptor_cache();
+    for name in resized {
+        descriptor_cache.invalidate_renderpass(name);
+    }
 
     Ok(())
 }
+
+pub fn render(game: &mut Game, cmd: &mut CommandBuffer, rp: &dyn Renderpass) -> eyre::Result<()> {
+    let mut man = game.world.fetch_mut::<RenderPassManager>();
+
+    //per frame buffers are written before recording, passes only get shared access
+    let frame_index = game.world.fetch::<FrameIndex>().index();
+    man.get_renderpass::<DeferedPass>("deferred_render").unwrap().update_light_buffers(game, frame_index);
+    game.world.fetch_mut::<PostProcessChain>().update_globals(game);
+    //after "hot reload" has waited for the gpu, the swapchain node needs the swapchain renderpass
+    game.world.fetch_mut::<PostProcessChain>().reload_pipelines(&man, rp, &game.world.fetch::<HotReload>());
+
+    man.execute(cmd, game, rp)
+}
@@ -1,28 +1,39 @@
 use std::{
     any::Any,
     collections::HashMap,
+    ops::DerefMut,
     sync::{
         mpsc::{sync_channel, Receiver, SyncSender},
         Arc, Mutex,
     },
 };
 
+use ash::vk;
 use magma_renderer::core::*;
 
 use crate::game::Game;
 
+use super::{
+    render_graph::{CompiledGraph, PassDesc, RenderGraph, ResourceKind},
+    resource_state::{BufferBarrier, BufferUsage, ResourceStateTracker},
+};
+
 pub trait HasRenderPass: Sync + Send + Any + 'static {
     fn renderpass(&self) -> &dyn Renderpass;
+    //called when the swapchain changes size, renderpasses with a fixed size keep the default
+    fn resize(&mut self, _width: u32, _height: u32) -> eyre::Result<()> { Ok(()) }
     // fn as_mut_any<'a>(&'a mut self) -> &'a mut dyn Any { self as &'a mut dyn Any }
 }
 
 pub trait HasRenderPassWithAny: HasRenderPass + Any {
     fn as_mut_any(&mut self) -> &mut dyn Any;
+    fn as_any(&self) -> &dyn Any;
     fn renderpass_(&self) -> &dyn Renderpass;
 }
 
 impl<T: HasRenderPass + Any> HasRenderPassWithAny for T {
     fn as_mut_any(&mut self) -> &mut dyn Any { self }
+    fn as_any(&self) -> &dyn Any { self }
     fn renderpass_(&self) -> &dyn Renderpass { self.renderpass() }
 }
 
@@ -49,10 +60,33 @@ pub struct SubpassRef<'a> {
 pub struct RenderPassManager {
     renderpasses: HashMap<&'static str, RenderPassData>,
     subpasses: HashMap<&'static str, SubpassData>,
-    compute_cmds: Mutex<Vec<CommandBuffer>>,
+    //pass the task was submitted to, none runs in the first compute pass of the frame
+    compute_cmds: Mutex<Vec<(Option<&'static str>, CommandBuffer, BufferUsage)>>,
+    //buffers the graphics passes of this frame read, they are transitioned after the compute tasks of the pass
+    graphics_usage: Mutex<Vec<(Option<&'static str>, BufferUsage)>>,
+    tracker: Mutex<ResourceStateTracker>,
+    graph: RenderGraph,
+    compiled_graph: Option<CompiledGraph>,
+    pass_execs: HashMap<&'static str, PassExec>,
+    extent: (u32, u32),
     core: Arc<Core>,
 }
 
+pub struct PassContext<'a> {
+    pub game: &'a Game,
+    pub manager: &'a RenderPassManager,
+    pub swapchain: &'a dyn Renderpass,
+}
+
+//how a pass of the render graph is recorded
+pub enum PassExec {
+    //executes the renderpass registered with the same name as the pass
+    RenderPass,
+    //executes the compute command buffers submitted to the pass and the ones submitted without one
+    Compute,
+    Record(Box<dyn Fn(&mut CommandBuffer, &PassContext) -> eyre::Result<()> + Send + Sync>),
+}
+
 pub enum SubpassAction {
     Inline(Box<dyn Fn(&mut CommandBuffer) -> () + Send + Sync>),
     Secondry(&'static str),
@@ -64,17 +98,43 @@ enum SubpassTask {
 }
 
 impl RenderPassManager {
-    pub fn new(core: &Arc<Core>) -> RenderPassManager {
+    //extent is the size of the swapchain, renderpasses are resized when it changes
+    pub fn new(core: &Arc<Core>, extent: (u32, u32)) -> RenderPassManager {
         Self {
             renderpasses: HashMap::new(),
             subpasses: HashMap::new(),
             compute_cmds: Mutex::new(vec![]),
+            graphics_usage: Mutex::new(vec![]),
+            tracker: Mutex::new(ResourceStateTracker::new()),
+            graph: RenderGraph::new(),
+            compiled_graph: None,
+            pass_execs: HashMap::new(),
+            extent,
             core: core.clone(),
         }
     }
     pub fn core(&self) -> &Arc<Core> { &self.core }
 
-    pub fn submit_compute(&self, cmd: CommandBuffer) { self.compute_cmds.lock().unwrap().push(cmd); }
+    //usage lists every buffer the command buffer touches, barriers against earlier work are inserted before it
+    pub fn submit_compute(&self, cmd: CommandBuffer, usage: BufferUsage) {
+        self.compute_cmds.lock().unwrap().push((None, cmd, usage));
+    }
+
+    //like submit_compute but runs in the named compute pass of the render graph
+    pub fn submit_compute_to(&self, pass: &'static str, cmd: CommandBuffer, usage: BufferUsage) {
+        self.compute_cmds.lock().unwrap().push((Some(pass), cmd, usage));
+    }
+
+    //buffers read by secondary command buffers of the renderpasses, usually written by a compute task
+    pub fn use_in_graphics(&self, usage: BufferUsage) { self.graphics_usage.lock().unwrap().push((None, usage)); }
+
+    //like use_in_graphics but for graphics passes after the named compute pass, they read what its tasks wrote
+    pub fn use_in_graphics_after(&self, pass: &'static str, usage: BufferUsage) {
+        self.graphics_usage.lock().unwrap().push((Some(pass), usage));
+    }
+
+    //should be called before destroying a buffer that was submitted with a usage
+    pub fn forget_buffer(&self, buffer: vk::Buffer) { self.tracker.lock().unwrap().forget(buffer); }
 
     pub fn register_renderpass(
         &mut self,
@@ -101,16 +161,120 @@ impl RenderPassManager {
         self.renderpasses.insert(name, RenderPassData { renderpass: renderpass, tasks: Mutex::new(tasks) });
     }
 
-    pub fn execute_compute_tasks(&mut self, cmd: &mut CommandBuffer) {
-        let cmds = std::mem::replace(self.compute_cmds.lock().unwrap().as_mut(), vec![]);
-        cmd.exectue_secondries(cmds);
+    pub fn add_resource(&mut self, name: &'static str, kind: ResourceKind) {
+        self.graph.add_resource(name, kind);
+        self.compiled_graph = None;
+    }
+
+    //the memory of transients is only valid between their first and last pass in the compiled order
+    pub fn add_transient(&mut self, name: &'static str, kind: ResourceKind, format: vk::Format) {
+        self.graph.add_transient(name, kind, format);
+        self.compiled_graph = None;
+    }
+
+    pub fn import_resource(&mut self, name: &'static str, kind: ResourceKind) {
+        self.graph.import_resource(name, kind);
+        self.compiled_graph = None;
+    }
+
+    pub fn add_pass(&mut self, pass: PassDesc, exec: PassExec) {
+        self.pass_execs.insert(pass.name, exec);
+        self.graph.add_pass(pass);
+        self.compiled_graph = None;
+    }
+
+    pub fn graph(&self) -> &RenderGraph { &self.graph }
+
+    pub fn compiled_graph(&mut self) -> eyre::Result<&CompiledGraph> {
+        if self.compiled_graph.is_none() {
+            self.compiled_graph = Some(self.graph.compile()?);
+        }
+        Ok(self.compiled_graph.as_ref().unwrap())
+    }
+
+    //records every pass of the graph in order with the barriers between them
+    pub fn execute(&mut self, cmd: &mut CommandBuffer, game: &Game, swapchain: &dyn Renderpass) -> eyre::Result<()> {
+        self.compiled_graph()?;
+        let compiled = self.compiled_graph.as_ref().unwrap();
+        let ctx = PassContext { game, manager: self, swapchain };
+
+        for (i, pass) in compiled.order.iter().enumerate() {
+            if let Some((src_stage, dst_stage)) = compiled.memory_barrier(i) {
+                let barrier = vk::MemoryBarrier::builder()
+                    .src_access_mask(vk::AccessFlags::MEMORY_WRITE)
+                    .dst_access_mask(vk::AccessFlags::MEMORY_READ | vk::AccessFlags::MEMORY_WRITE)
+                    .build();
+                unsafe {
+                    cmd.pipeline_barrier(src_stage, dst_stage, vk::DependencyFlags::empty(), &[barrier], &[], &[]);
+                }
+            }
+
+            match &self.pass_execs[pass] {
+                PassExec::RenderPass => self.execute_renderpass(cmd, pass),
+                PassExec::Compute => self.execute_compute_tasks(cmd, pass),
+                PassExec::Record(record) => record(cmd, &ctx)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn extent(&self) -> (u32, u32) { self.extent }
+
+    //returns the renderpasses whose attachments were recreated
+    pub fn resize(&mut self, (width, height): (u32, u32)) -> eyre::Result<Vec<&'static str>> {
+        let mut resized = vec![];
+        for (name, data) in &mut self.renderpasses {
+            let old_extent = data.get_renderpass().extends();
+            data.renderpass.resize(width, height)?;
+            if data.get_renderpass().extends() != old_extent {
+                resized.push(*name);
+            }
+        }
+        self.extent = (width, height);
+        Ok(resized)
+    }
+
+    //compute tasks are executed in submission order, each one waits only for the buffers it uses
+    pub fn execute_compute_tasks(&self, cmd: &mut CommandBuffer, pass: &str) {
+        let cmds = {
+            let mut compute_cmds = self.compute_cmds.lock().unwrap();
+            let (cmds, rest): (Vec<_>, Vec<_>) = std::mem::take(compute_cmds.deref_mut())
+                .into_iter()
+                .partition(|(target, ..)| target.map_or(true, |target| target == pass));
+            *compute_cmds = rest;
+            cmds
+        };
+        let mut tracker = self.tracker.lock().unwrap();
+
+        for (_, compute_cmd, usage) in cmds {
+            BufferBarrier::record(cmd, &tracker.transition(&usage));
+            cmd.exectue_secondries(vec![compute_cmd]);
+        }
+
+        let graphics_usage = {
+            let mut graphics_usages = self.graphics_usage.lock().unwrap();
+            let (usages, rest): (Vec<_>, Vec<_>) = std::mem::take(graphics_usages.deref_mut())
+                .into_iter()
+                .partition(|(target, _)| target.map_or(true, |target| target == pass));
+            *graphics_usages = rest;
+            usages.into_iter().fold(BufferUsage::new(), |mut all, (_, mut usage)| {
+                all.append(&mut usage);
+                all
+            })
+        };
+        BufferBarrier::record(cmd, &tracker.transition(&graphics_usage));
     }
 
     pub fn get_renderpass<T: HasRenderPass>(&mut self, name: &'static str) -> Option<&mut T> {
         self.renderpasses.get_mut(name).and_then(|rd| rd.renderpass.as_mut_any().downcast_mut())
     }
 
-    pub fn execute_renderpass(&mut self, cmd: &mut CommandBuffer, name: &'static str) {
+    pub fn get_renderpass_ref<T: HasRenderPass>(&self, name: &str) -> Option<&T> {
+        self.renderpasses.get(name).and_then(|rd| rd.renderpass.as_any().downcast_ref())
+    }
+
+    pub fn execute_renderpass(&self, cmd: &mut CommandBuffer, name: &str) {
         let renderpass = self.renderpasses.get(name).expect("couldn't find renderpass");
 
         let tasks = renderpass.tasks.lock().unwrap();
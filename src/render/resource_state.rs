@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+
+use ash::vk;
+use magma_renderer::core::CommandBuffer;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BufferAccess {
+    TransferRead,
+    TransferWrite,
+    ComputeRead,
+    ComputeWrite,
+    IndirectRead,
+    //storage or vertex buffer reads in the vertex shader
+    VertexRead,
+    FragmentRead,
+}
+
+impl BufferAccess {
+    pub fn stage(&self) -> vk::PipelineStageFlags {
+        match self {
+            BufferAccess::TransferRead | BufferAccess::TransferWrite => vk::PipelineStageFlags::TRANSFER,
+            BufferAccess::ComputeRead | BufferAccess::ComputeWrite => vk::PipelineStageFlags::COMPUTE_SHADER,
+            BufferAccess::IndirectRead => vk::PipelineStageFlags::DRAW_INDIRECT,
+            BufferAccess::VertexRead => vk::PipelineStageFlags::VERTEX_SHADER,
+            BufferAccess::FragmentRead => vk::PipelineStageFlags::FRAGMENT_SHADER,
+        }
+    }
+
+    pub fn access(&self) -> vk::AccessFlags {
+        match self {
+            BufferAccess::TransferRead => vk::AccessFlags::TRANSFER_READ,
+            BufferAccess::TransferWrite => vk::AccessFlags::TRANSFER_WRITE,
+            BufferAccess::ComputeRead => vk::AccessFlags::SHADER_READ,
+            BufferAccess::ComputeWrite => vk::AccessFlags::SHADER_WRITE,
+            BufferAccess::IndirectRead => vk::AccessFlags::INDIRECT_COMMAND_READ,
+            BufferAccess::VertexRead | BufferAccess::FragmentRead => vk::AccessFlags::SHADER_READ,
+        }
+    }
+
+    pub fn is_write(&self) -> bool { matches!(self, BufferAccess::TransferWrite | BufferAccess::ComputeWrite) }
+}
+
+//buffers a command buffer touches, in the order they are used
+#[derive(Clone, Debug, Default)]
+pub struct BufferUsage {
+    uses: Vec<(vk::Buffer, BufferAccess)>,
+}
+
+impl BufferUsage {
+    pub fn new() -> BufferUsage { Self::default() }
+
+    pub fn add(&mut self, buffer: vk::Buffer, access: BufferAccess) { self.uses.push((buffer, access)); }
+    pub fn append(&mut self, other: &mut BufferUsage) { self.uses.append(&mut other.uses); }
+
+    pub fn uses(&self) -> &[(vk::Buffer, BufferAccess)] { &self.uses }
+    pub fn is_empty(&self) -> bool { self.uses.is_empty() }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BufferBarrier {
+    pub buffer: vk::Buffer,
+    pub src_stage: vk::PipelineStageFlags,
+    pub dst_stage: vk::PipelineStageFlags,
+    pub src_access: vk::AccessFlags,
+    pub dst_access: vk::AccessFlags,
+}
+
+impl BufferBarrier {
+    pub fn between(buffer: vk::Buffer, src: BufferAccess, dst: BufferAccess) -> BufferBarrier {
+        Self {
+            buffer,
+            src_stage: src.stage(),
+            dst_stage: dst.stage(),
+            src_access: if src.is_write() { src.access() } else { vk::AccessFlags::empty() },
+            dst_access: dst.access(),
+        }
+    }
+
+    pub fn to_vk(&self) -> vk::BufferMemoryBarrier {
+        vk::BufferMemoryBarrier::builder()
+            .src_access_mask(self.src_access)
+            .dst_access_mask(self.dst_access)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .buffer(self.buffer)
+            .size(vk::WHOLE_SIZE)
+            .build()
+    }
+
+    //records every barrier with a single vkCmdPipelineBarrier
+    pub fn record(cmd: &mut CommandBuffer, barriers: &[BufferBarrier]) {
+        if barriers.is_empty() {
+            return;
+        }
+
+        let src_stage = barriers.iter().fold(vk::PipelineStageFlags::empty(), |s, b| s | b.src_stage);
+        let dst_stage = barriers.iter().fold(vk::PipelineStageFlags::empty(), |s, b| s | b.dst_stage);
+        let vk_barriers: Vec<_> = barriers.iter().map(|b| b.to_vk()).collect();
+
+        unsafe {
+            cmd.pipeline_barrier(src_stage, dst_stage, vk::DependencyFlags::empty(), &[], &vk_barriers, &[]);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct BufferState {
+    //stages and accesses of the last write
+    write: Option<(vk::PipelineStageFlags, vk::AccessFlags)>,
+    //stages that read the buffer since the last write
+    read_stages: vk::PipelineStageFlags,
+    //stages the last write has already been made visible to
+    visible_stages: vk::PipelineStageFlags,
+}
+
+/*
+    Tracks the last access of every buffer across submitted command buffers and returns the barriers
+    needed before the next one. Reads wait for the last write once per stage, writes wait for the
+    reads since the last write or, if there are none, for the last write.
+*/
+#[derive(Default)]
+pub struct ResourceStateTracker {
+    states: HashMap<vk::Buffer, BufferState>,
+}
+
+impl ResourceStateTracker {
+    pub fn new() -> ResourceStateTracker { Self::default() }
+
+    //barriers to record before a command buffer with the given usage, uses of the same buffer are merged
+    pub fn transition(&mut self, usage: &BufferUsage) -> Vec<BufferBarrier> {
+        let mut merged: Vec<(vk::Buffer, vk::PipelineStageFlags, vk::AccessFlags, bool)> = vec![];
+        for (buffer, access) in usage.uses() {
+            match merged.iter_mut().find(|(b, ..)| b == buffer) {
+                Some((_, stage, flags, write)) => {
+                    *stage |= access.stage();
+                    *flags |= access.access();
+                    *write |= access.is_write();
+                }
+                None => merged.push((*buffer, access.stage(), access.access(), access.is_write())),
+            }
+        }
+
+        let mut barriers = vec![];
+
+        for (buffer, dst_stage, dst_access, is_write) in merged {
+            let state = self.states.entry(buffer).or_default();
+
+            if is_write {
+                if !state.read_stages.is_empty() {
+                    //write after read only needs an execution dependency
+                    barriers.push(BufferBarrier {
+                        buffer,
+                        src_stage: state.read_stages,
+                        dst_stage,
+                        src_access: vk::AccessFlags::empty(),
+                        dst_access,
+                    });
+                } else if let Some((src_stage, src_access)) = state.write {
+                    barriers.push(BufferBarrier { buffer, src_stage, dst_stage, src_access, dst_access });
+                }
+
+                *state = BufferState {
+                    write: Some((dst_stage, dst_access)),
+                    read_stages: vk::PipelineStageFlags::empty(),
+                    visible_stages: vk::PipelineStageFlags::empty(),
+                };
+            } else {
+                if let Some((src_stage, src_access)) = state.write {
+                    if !state.visible_stages.contains(dst_stage) {
+                        barriers.push(BufferBarrier { buffer, src_stage, dst_stage, src_access, dst_access });
+                        state.visible_stages |= dst_stage;
+                    }
+                }
+                state.read_stages |= dst_stage;
+            }
+        }
+
+        barriers
+    }
+
+    //buffers that are destroyed should be forgotten so a new buffer with the same handle starts clean
+    pub fn forget(&mut self, buffer: vk::Buffer) { self.states.remove(&buffer); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ash::vk::Handle;
+
+    fn buffer(raw: u64) -> vk::Buffer { vk::Buffer::from_raw(raw) }
+
+    fn usage(uses: &[(vk::Buffer, BufferAccess)]) -> BufferUsage {
+        let mut usage = BufferUsage::new();
+        for (buffer, access) in uses {
+            usage.add(*buffer, *access);
+        }
+        usage
+    }
+
+    #[test]
+    fn first_use_needs_no_barrier() {
+        let mut tracker = ResourceStateTracker::new();
+        assert!(tracker.transition(&usage(&[(buffer(1), BufferAccess::TransferWrite)])).is_empty());
+
+        let mut tracker = ResourceStateTracker::new();
+        assert!(tracker.transition(&usage(&[(buffer(1), BufferAccess::ComputeRead)])).is_empty());
+    }
+
+    #[test]
+    fn read_after_write() {
+        let mut tracker = ResourceStateTracker::new();
+        tracker.transition(&usage(&[(buffer(1), BufferAccess::TransferWrite)]));
+
+        let barriers = tracker.transition(&usage(&[(buffer(1), BufferAccess::ComputeRead)]));
+        assert_eq!(barriers, vec![BufferBarrier::between(buffer(1), BufferAccess::TransferWrite, BufferAccess::ComputeRead)]);
+        assert_eq!(barriers[0].src_access, vk::AccessFlags::TRANSFER_WRITE);
+        assert_eq!(barriers[0].dst_access, vk::AccessFlags::SHADER_READ);
+    }
+
+    #[test]
+    fn read_after_read_needs_no_barrier() {
+        let mut tracker = ResourceStateTracker::new();
+        tracker.transition(&usage(&[(buffer(1), BufferAccess::TransferWrite)]));
+        assert_eq!(tracker.transition(&usage(&[(buffer(1), BufferAccess::ComputeRead)])).len(), 1);
+
+        //the write is already visible to the compute stage
+        assert!(tracker.transition(&usage(&[(buffer(1), BufferAccess::ComputeRead)])).is_empty());
+
+        //a new stage still has to wait for the write once
+        let barriers = tracker.transition(&usage(&[(buffer(1), BufferAccess::IndirectRead)]));
+        assert_eq!(barriers, vec![BufferBarrier::between(buffer(1), BufferAccess::TransferWrite, BufferAccess::IndirectRead)]);
+        assert!(tracker.transition(&usage(&[(buffer(1), BufferAccess::IndirectRead)])).is_empty());
+    }
+
+    #[test]
+    fn write_after_read() {
+        let mut tracker = ResourceStateTracker::new();
+        tracker.transition(&usage(&[(buffer(1), BufferAccess::ComputeWrite)]));
+        tracker.transition(&usage(&[(buffer(1), BufferAccess::IndirectRead)]));
+        tracker.transition(&usage(&[(buffer(1), BufferAccess::VertexRead)]));
+
+        let barriers = tracker.transition(&usage(&[(buffer(1), BufferAccess::TransferWrite)]));
+        assert_eq!(barriers.len(), 1);
+        assert_eq!(barriers[0].src_stage, vk::PipelineStageFlags::DRAW_INDIRECT | vk::PipelineStageFlags::VERTEX_SHADER);
+        assert_eq!(barriers[0].dst_stage, vk::PipelineStageFlags::TRANSFER);
+        //an execution dependency is enough
+        assert_eq!(barriers[0].src_access, vk::AccessFlags::empty());
+        assert_eq!(barriers[0].dst_access, vk::AccessFlags::TRANSFER_WRITE);
+    }
+
+    #[test]
+    fn write_after_write() {
+        let mut tracker = ResourceStateTracker::new();
+        tracker.transition(&usage(&[(buffer(1), BufferAccess::TransferWrite)]));
+
+        let barriers = tracker.transition(&usage(&[(buffer(1), BufferAccess::ComputeWrite)]));
+        assert_eq!(barriers, vec![BufferBarrier::between(buffer(1), BufferAccess::TransferWrite, BufferAccess::ComputeWrite)]);
+
+        //the reads after the new write wait for it, not for the transfer
+        let barriers = tracker.transition(&usage(&[(buffer(1), BufferAccess::FragmentRead)]));
+        assert_eq!(barriers, vec![BufferBarrier::between(buffer(1), BufferAccess::ComputeWrite, BufferAccess::FragmentRead)]);
+    }
+
+    #[test]
+    fn uses_of_one_buffer_are_merged() {
+        let mut tracker = ResourceStateTracker::new();
+        tracker.transition(&usage(&[(buffer(1), BufferAccess::TransferWrite), (buffer(2), BufferAccess::TransferWrite)]));
+
+        let barriers = tracker.transition(&usage(&[
+            (buffer(1), BufferAccess::ComputeRead),
+            (buffer(1), BufferAccess::ComputeWrite),
+            (buffer(2), BufferAccess::ComputeRead),
+        ]));
+        assert_eq!(barriers.len(), 2);
+
+        let first = barriers.iter().find(|b| b.buffer == buffer(1)).unwrap();
+        assert_eq!(first.src_access, vk::AccessFlags::TRANSFER_WRITE);
+        assert_eq!(first.dst_access, vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+    }
+
+    #[test]
+    fn forget() {
+        let mut tracker = ResourceStateTracker::new();
+        tracker.transition(&usage(&[(buffer(1), BufferAccess::TransferWrite), (buffer(2), BufferAccess::TransferWrite)]));
+
+        tracker.forget(buffer(1));
+        assert!(tracker.transition(&usage(&[(buffer(1), BufferAccess::ComputeRead)])).is_empty());
+        assert_eq!(tracker.transition(&usage(&[(buffer(2), BufferAccess::ComputeRead)])).len(), 1);
+    }
+}
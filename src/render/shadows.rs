@@ -0,0 +1,308 @@
+use std::sync::Arc;
+
+use ash::vk;
+use glam::*;
+use magma_renderer::core::*;
+use specs::prelude::*;
+
+use crate::game::{CameraData, FrameIndex, Game, RenderGlobals};
+
+use super::{descriptor_cache::DescriptorKey, lighting::LightingSettings, renderpassmanager::*};
+
+pub const MAX_CASCADES: usize = 4;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ShadowSettings {
+    pub cascade_count: usize,
+    //resolution of a single cascade, cascades are laid out in a grid of ATLAS_COLUMNS in the shadow map
+    pub resolution: u32,
+    //0.0 gives uniform splits, 1.0 logarithmic
+    pub split_lambda: f32,
+    //how far behind a cascade casters are still rendered, towards the sun
+    pub caster_margin: f32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self { Self { cascade_count: MAX_CASCADES, resolution: 2048, split_lambda: 0.75, caster_margin: 128.0 } }
+}
+
+//a row of 4 cascades at 2048 would be wider than the 4096 maxImageDimension2D every device supports
+pub const ATLAS_COLUMNS: usize = 2;
+
+impl ShadowSettings {
+    //cascades per row and rows of the shadow map, matches sun_shadow in final.frag
+    pub fn atlas_grid(&self) -> (usize, usize) {
+        (self.cascade_count.min(ATLAS_COLUMNS), (self.cascade_count + ATLAS_COLUMNS - 1) / ATLAS_COLUMNS)
+    }
+
+    pub fn atlas_extent(&self) -> (u32, u32) {
+        let (columns, rows) = self.atlas_grid();
+        (self.resolution * columns as u32, self.resolution * rows as u32)
+    }
+
+    //top left texel of a cascade in the shadow map
+    pub fn cascade_offset(&self, cascade: usize) -> (u32, u32) {
+        ((cascade % ATLAS_COLUMNS) as u32 * self.resolution, (cascade / ATLAS_COLUMNS) as u32 * self.resolution)
+    }
+}
+
+//far distance of every cascade, blends logarithmic and uniform splits
+pub fn cascade_splits(near: f32, far: f32, count: usize, lambda: f32) -> Vec<f32> {
+    (1..=count)
+        .map(|i| {
+            let p = i as f32 / count as f32;
+            let log = near * (far / near).powf(p);
+            let uniform = near + (far - near) * p;
+            lambda * log + (1.0 - lambda) * uniform
+        })
+        .collect()
+}
+
+//world space corners of the frustum, near corners first
+pub fn frustum_corners(inv_proj_view: Mat4) -> [Vec3; 8] {
+    let mut corners = [Vec3::ZERO; 8];
+    for (i, corner) in corners.iter_mut().enumerate() {
+        let ndc = vec3(
+            if i & 1 == 0 { -1.0 } else { 1.0 },
+            if i & 2 == 0 { -1.0 } else { 1.0 },
+            if i & 4 == 0 { 0.0 } else { 1.0 },
+        );
+        *corner = inv_proj_view.project_point3(ndc);
+    }
+    corners
+}
+
+/*
+    Orthographic light matrix covering the given frustum slice. The bounding sphere of the slice
+    is used so the size of the cascade doesn't change while the camera rotates and the origin is
+    snapped to shadow map texels, both stop shadow edges from shimmering.
+*/
+pub fn fit_light_matrix(corners: &[Vec3; 8], sun_direction: Vec3, resolution: u32, caster_margin: f32) -> Mat4 {
+    let center = corners.iter().copied().sum::<Vec3>() / 8.0;
+    let radius = corners.iter().map(|c| c.distance(center)).fold(0.0, f32::max);
+    let radius = (radius * 16.0).ceil() / 16.0;
+
+    let sun_direction = sun_direction.normalize();
+    let up = if sun_direction.y.abs() > 0.99 { Vec3::Z } else { Vec3::Y };
+    let view = Mat4::look_at_rh(center + sun_direction * (radius + caster_margin), center, up);
+
+    let mut proj = Mat4::orthographic_rh(-radius, radius, -radius, radius, 0.0, 2.0 * radius + caster_margin);
+    proj.col_mut(1)[1] *= -1.0; //same flip as Camera::proj
+
+    let half_resolution = resolution as f32 / 2.0;
+    let origin = (proj * view).project_point3(Vec3::ZERO).truncate() * half_resolution;
+    let offset = (origin.round() - origin) / half_resolution;
+    proj.col_mut(3)[0] += offset.x;
+    proj.col_mut(3)[1] += offset.y;
+
+    proj * view
+}
+
+//light matrix and far distance of every cascade, fovy is in degrees like Camera::fovy
+pub fn cascade_matrices(
+    view: Mat4,
+    fovy: f32,
+    aspect: f32,
+    znear: f32,
+    zfar: f32,
+    sun_direction: Vec3,
+    settings: &ShadowSettings,
+) -> Vec<(Mat4, f32)> {
+    let splits = cascade_splits(znear, zfar, settings.cascade_count, settings.split_lambda);
+
+    let mut near = znear;
+    splits
+        .into_iter()
+        .map(|far| {
+            let proj = Mat4::perspective_rh(fovy.to_radians(), aspect, near, far);
+            let corners = frustum_corners((proj * view).inverse());
+            near = far;
+            (fit_light_matrix(&corners, sun_direction, settings.resolution, settings.caster_margin), far)
+        })
+        .collect()
+}
+
+//per cascade cameras, chunks are drawn into the shadow map with the same path as the gpass
+pub struct ShadowCascades {
+    pub settings: ShadowSettings,
+    pub cameras: Vec<CameraData>,
+    pub splits: [f32; MAX_CASCADES],
+}
+
+impl ShadowCascades {
+    pub fn new(core: &Arc<Core>, settings: ShadowSettings) -> eyre::Result<ShadowCascades> {
+        Ok(Self {
+            settings,
+            cameras: (0..settings.cascade_count).map(|_| CameraData::new(core)).collect::<eyre::Result<_>>()?,
+            splits: [0.0; MAX_CASCADES],
+        })
+    }
+
+    pub fn light_matrices(&self) -> [[[f32; 4]; 4]; MAX_CASCADES] {
+        let mut matrices = [Mat4::IDENTITY.to_cols_array_2d(); MAX_CASCADES];
+        for (m, cam) in matrices.iter_mut().zip(&self.cameras) {
+            *m = cam.proj_view.to_cols_array_2d();
+        }
+        matrices
+    }
+
+    //region of the shadow map a cascade is drawn into
+    pub fn viewport(&self, cascade: usize) -> vk::Viewport {
+        let size = self.settings.resolution as f32;
+        let (x, y) = self.settings.cascade_offset(cascade);
+        vk::Viewport { x: x as f32, y: y as f32, width: size, height: size, min_depth: 0.0, max_depth: 1.0 }
+    }
+}
+
+pub struct UpdateShadowCascades;
+
+impl<'a> System<'a> for UpdateShadowCascades {
+    type SystemData = (
+        WriteExpect<'a, ShadowCascades>,
+        ReadExpect<'a, CameraData>,
+        ReadExpect<'a, LightingSettings>,
+        ReadExpect<'a, RenderGlobals>,
+        ReadExpect<'a, FrameIndex>,
+    );
+
+    fn run(&mut self, (mut cascades, cam_data, lighting, globals, frame_index): Self::SystemData) {
+        let settings = cascades.settings;
+        let mut descriptor_cache = globals.descriptor_cache();
+
+        let matrices = cascade_matrices(
+            cam_data.view,
+            cam_data.fovy,
+            cam_data.aspect_ratio,
+            cam_data.znear,
+            cam_data.zfar,
+            lighting.sun_direction,
+            &settings,
+        );
+        for (i, (light_matrix, far)) in matrices.into_iter().enumerate() {
+            cascades.splits[i] = far;
+
+            let camera = &mut cascades.cameras[i];
+            camera.proj_view = light_matrix;
+            let buffer = camera.update_and_get_buffer_data(frame_index.index(), &mut descriptor_cache).unwrap();
+            buffer.proj_view = light_matrix.to_cols_array_2d();
+        }
+    }
+}
+
+pub struct ShadowPass {
+    pub renderpass: MultiPassRenderPass,
+    pub depth: AttachmentIndex,
+    pub set_layout: vk::DescriptorSetLayout,
+    sampler: Handle<vk::Sampler>,
+}
+
+impl HasRenderPass for ShadowPass {
+    fn renderpass(&self) -> &dyn Renderpass { &self.renderpass }
+}
+
+impl ShadowPass {
+    pub fn new(core: &Arc<Core>, settings: &ShadowSettings) -> eyre::Result<ShadowPass> {
+        let mut builder = RenderPassBuilder::new();
+        let depth = builder.add_attachment(
+            vk::Format::D32_SFLOAT,
+            Some(vk::ClearValue { depth_stencil: vk::ClearDepthStencilValue { depth: 1.0, stencil: 0 } }),
+            true,
+        );
+        builder.add_subpass(&[], Some(depth), &[]);
+        let (width, height) = settings.atlas_extent();
+        let renderpass = builder.build(core, width, height)?;
+
+        let set_layout = DescriptorSetLayoutBuilder::new().add_sampler(vk::ShaderStageFlags::FRAGMENT, 1).build(core)?;
+        let sampler = core.create_sampler(vk::Filter::NEAREST, None);
+
+        Ok(Self { renderpass, depth, set_layout, sampler })
+    }
+
+    pub fn register(self, man: &mut RenderPassManager) {
+        man.register_renderpass(Box::new(self), "shadow_render", vec![SubpassAction::Secondry("shadow")]);
+    }
+
+    pub fn build_descriptor_set(&self, game: &Game) -> eyre::Result<vk::DescriptorSet> {
+        let globals = game.world.fetch::<RenderGlobals>();
+        let key = DescriptorKey::new(self.set_layout).attachment("shadow_render", "depth", *self.sampler);
+
+        globals.descriptor_cache().get_or_build(key, |pool| {
+            Ok(DescriptorSetBuilder::new()
+                .add_sampled_image(self.renderpass.get_attachment(self.depth), *self.sampler)
+                .build(self.set_layout, pool)?)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(a: Vec3, b: Vec3) { assert!(a.distance(b) < 1e-3, "{a} != {b}"); }
+
+    #[test]
+    fn splits_increase_and_end_at_far() {
+        for lambda in [0.0, 0.5, 0.75, 1.0] {
+            let splits = cascade_splits(0.1, 500.0, 4, lambda);
+            assert_eq!(splits.len(), 4);
+            assert!(splits[0] > 0.1);
+            assert!(splits.windows(2).all(|w| w[0] < w[1]), "{splits:?}");
+            assert!((splits[3] - 500.0).abs() < 1e-2, "{splits:?}");
+        }
+
+        assert_eq!(cascade_splits(20.0, 100.0, 4, 0.0), vec![40.0, 60.0, 80.0, 100.0]);
+        let log = cascade_splits(1.0, 10_000.0, 4, 1.0);
+        assert!((log[1] - 100.0).abs() < 1e-2, "{log:?}");
+    }
+
+    #[test]
+    fn frustum_corners_of_perspective() {
+        let proj = Mat4::perspective_rh(90f32.to_radians(), 1.0, 1.0, 10.0);
+        let corners = frustum_corners(proj.inverse());
+
+        assert_near(corners[0], vec3(-1.0, -1.0, -1.0));
+        assert_near(corners[3], vec3(1.0, 1.0, -1.0));
+        assert_near(corners[4], vec3(-10.0, -10.0, -10.0));
+        assert_near(corners[7], vec3(10.0, 10.0, -10.0));
+    }
+
+    #[test]
+    fn light_matrix_covers_slice_and_snaps_to_texels() {
+        let resolution = 2048;
+        let half_resolution = resolution as f32 / 2.0;
+        let sun = vec3(0.3, 1.0, 0.2);
+        let proj = Mat4::perspective_rh(70f32.to_radians(), 16.0 / 9.0, 0.5, 40.0);
+
+        for step in 0..20 {
+            let eye = vec3(step as f32 * 0.137, 20.0, step as f32 * -0.291);
+            let view = Mat4::look_to_rh(eye, vec3(1.0, -0.2, step as f32 * 0.1).normalize(), Vec3::Y);
+            let corners = frustum_corners((proj * view).inverse());
+            let light = fit_light_matrix(&corners, sun, resolution, 16.0);
+
+            for corner in corners {
+                let p = light.project_point3(corner);
+                assert!(p.x.abs() <= 1.0 + 1e-3 && p.y.abs() <= 1.0 + 1e-3, "{p}");
+                assert!(p.z >= 0.0 && p.z <= 1.0, "{p}");
+            }
+
+            //the world origin lands on a texel corner however the camera moved
+            let origin = light.project_point3(Vec3::ZERO).truncate() * half_resolution;
+            assert!((origin - origin.round()).abs().max_element() < 1e-2, "{origin}");
+        }
+    }
+
+    #[test]
+    fn cascades_fit_in_device_limits() {
+        let settings = ShadowSettings::default();
+        let (width, height) = settings.atlas_extent();
+        assert!(width <= 4096 && height <= 4096);
+
+        let offsets: Vec<_> = (0..settings.cascade_count).map(|c| settings.cascade_offset(c)).collect();
+        assert_eq!(offsets, vec![(0, 0), (2048, 0), (0, 2048), (2048, 2048)]);
+
+        let two = ShadowSettings { cascade_count: 2, ..settings };
+        assert_eq!(two.atlas_extent(), (4096, 2048));
+        let three = ShadowSettings { cascade_count: 3, ..settings };
+        assert_eq!(three.atlas_grid(), (2, 2));
+    }
+}